@@ -0,0 +1,19 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+use dezoomify_rs::custom_yaml::CustomDezoomer;
+use dezoomify_rs::dezoomer::{Dezoomer, DezoomerInput, PageContents, TileProvider};
+
+// Run with `cargo fuzz run custom_yaml` from the root of the repository.
+// Malformed files must be rejected with an error, never with a panic.
+fuzz_target!(|data: &[u8]| {
+    let input = DezoomerInput {
+        uri: "tiles.yaml".into(),
+        contents: PageContents::Success(data.to_vec()),
+    };
+    if let Ok(levels) = CustomDezoomer.zoom_levels(&input) {
+        for mut level in levels {
+            let _ = level.next_tiles(None);
+        }
+    }
+});
@@ -1,11 +1,24 @@
 use structopt::StructOpt;
 
 use crate::dezoomer::Dezoomer;
+use crate::generic;
 
 use super::{auto, stdin_line, Vec2d, ZoomError};
 use std::time::Duration;
 use std::path::PathBuf;
+use std::ops::RangeInclusive;
 use regex::Regex;
+use crate::encoder::{BitDepth, EncoderOptions, OverlapMode};
+use crate::encoder::xyz_encoder::XyzOptions;
+use crate::encoder::zip_encoder::TileFormat;
+use crate::encoder::split_encoder::SplitSpec;
+use crate::stitch_offset::{Offset, StitchOffsetMode};
+use crate::output_file::OverwritePolicy;
+use crate::browser_request::{BrowserRequest, parse_curl, parse_har_file};
+use crate::crop::CropFraction;
+use crate::custom_yaml::parse_header_object;
+use crate::tile::{Resample, TileStages};
+use crate::token::ChainSource;
 
 #[derive(StructOpt, Debug)]
 #[structopt(author, about)]
@@ -21,10 +34,59 @@ pub struct Arguments {
     #[structopt(short, long, default_value = "auto")]
     dezoomer: String,
 
+    /// A rhai script that computes the urls and positions of the tiles.
+    /// When it is given, the dezoomer option is ignored.
+    #[cfg(feature = "script")]
+    #[structopt(long, parse(from_os_str))]
+    pub script: Option<PathBuf>,
+
     /// If several zoom levels are available, then select the largest one
     #[structopt(short, long)]
     pub largest: bool,
 
+    /// Download only the smallest zoom level, to quickly get a thumbnail of the image.
+    /// Images that have a single zoom level are downloaded entirely.
+    #[structopt(long, conflicts_with_all = &["largest", "max-width", "max-height", "level"])]
+    pub preview: bool,
+
+    /// If several zoom levels are available, then select the one with the given index,
+    /// as displayed by --list-levels
+    #[structopt(long)]
+    pub level: Option<usize>,
+
+    /// Download all the zoom levels in the given range of indices, such as 2-5,
+    /// as displayed by --list-levels. Each level is saved to its own file:
+    /// `{level}` in the output file name is replaced by the index of the level,
+    /// which is otherwise appended to the file name.
+    #[structopt(long, parse(try_from_str = parse_level_range),
+        conflicts_with_all = &["largest", "preview", "level", "max-width", "max-height", "target-mp"])]
+    pub levels: Option<RangeInclusive<usize>>,
+
+    /// Only display the available zoom levels, and exit without downloading anything
+    #[structopt(long)]
+    pub list_levels: bool,
+
+    /// Treat the input as a gallery page, and download all the zoomable images it links to.
+    /// Each link is opened with the dezoomer given by --dezoomer, and the largest level of each image
+    /// is downloaded unless another one is selected. Links in which no zoomable image is found are skipped,
+    /// and when an image cannot be downloaded, the next ones still are.
+    /// The images are saved as with inputs that contain several images:
+    /// `{index}` and `{name}` in the output file name are replaced by the number and the title of each image.
+    #[structopt(long)]
+    pub gallery: bool,
+
+    /// With --gallery, a regular expression matching the links to the images in the gallery page,
+    /// such as `/viewer\?id=\d+`. When it has a group, the links are the text matched by the group.
+    /// By default, the links to the documents of the known zoomable image formats are used,
+    /// such as IIIF info.json files and manifests, DZI files, and Zoomify ImageProperties.xml files.
+    #[structopt(long)]
+    pub gallery_links: Option<Regex>,
+
+    /// Print a json document describing the dezoomers, output formats and optional features
+    /// supported by this program, and exit
+    #[structopt(long)]
+    pub capabilities: bool,
+
     /// If several zoom levels are available, then select the one with the largest width that
     /// is inferior to max-width.
     #[structopt(short = "w", long = "max-width")]
@@ -35,11 +97,39 @@ pub struct Arguments {
     #[structopt(short = "h", long = "max-height")]
     max_height: Option<u32>,
 
+    /// If several zoom levels are available, then select the largest one that has
+    /// at most the given number of megapixels (millions of pixels).
+    /// If all the levels are larger, the smallest one is selected.
+    #[structopt(long = "target-mp",
+        conflicts_with_all = &["largest", "preview", "level", "max-width", "max-height"])]
+    pub target_mp: Option<f64>,
+
+    /// Before downloading, check without downloading them that a sample of the tiles of each zoom level
+    /// are available, from the largest level to the smallest, and select the first complete level.
+    /// Avoids getting an image with missing tiles when the largest level is incomplete.
+    /// With the options that select a level by its size, only the levels they allow are checked.
+    #[structopt(long, conflicts_with_all = &["level", "levels"])]
+    pub prefer_complete: bool,
+
+    /// Stop without creating the image if it would have more than the given number of pixels.
+    /// The size is checked as soon as it is known, and while the tiles of images
+    /// whose size is found during the download are received.
+    /// With `--dimensions`, the forced dimensions are checked instead.
+    #[structopt(long)]
+    pub max_output_pixels: Option<u64>,
+
     /// Degree of parallelism to use. At most this number of
     /// tiles will be downloaded at the same time.
-    #[structopt(short = "n", long = "parallelism", default_value = "16")]
+    #[structopt(short = "n", long = "parallelism", alias = "fetch-concurrency", default_value = "16")]
     pub parallelism: usize,
 
+    /// Maximum number of tiles that are decoded at the same time.
+    /// Defaults to the number of processor cores, without exceeding the number of tiles
+    /// downloaded at the same time.
+    /// Lower it on a slow computer to let downloads continue while tiles are decoded.
+    #[structopt(long)]
+    pub decode_concurrency: Option<usize>,
+
     /// Number of new attempts to make when a tile load fails
     /// before giving up. Setting this to 0 is useful to speed up the
     /// generic dezoomer, which relies on failed tile loads to detect the
@@ -55,6 +145,52 @@ pub struct Arguments {
     #[structopt(long, default_value = "2s", parse(try_from_str = parse_duration))]
     pub retry_delay: Duration,
 
+    /// Maximum total number of retries for all the tiles of the image.
+    /// Once it is reached, failed tiles are not retried anymore.
+    /// Prevents sending a huge number of requests to a server that fails for most tiles.
+    /// Failures to resolve the name of the server are retried without using the budget.
+    #[structopt(long)]
+    pub retry_budget: Option<usize>,
+
+    /// A file that pauses the download while it exists.
+    /// Create it to stop requesting new tiles, for instance in order to free up bandwidth,
+    /// and delete it to resume the download. Requests that are already running are completed.
+    #[structopt(long, parse(from_os_str))]
+    pub pause_file: Option<PathBuf>,
+
+    /// Append a record of each tile request to the given file, as one json object per line:
+    /// the url of the tile, the HTTP status and ETag of the response, when it was received,
+    /// and its size in bytes. Documents the origin of each part of an archived image.
+    /// A tile that was retried has one record per attempt.
+    #[structopt(long, parse(from_os_str))]
+    pub provenance: Option<PathBuf>,
+
+    /// While the size of an image is found by probing the server for its tiles, as with generic templates,
+    /// regularly write a small image showing the tiles found so far to the given file.
+    /// Allows checking that the template is right before the whole image is downloaded.
+    /// The places where no tile was found yet are drawn as a checkerboard.
+    #[structopt(long, parse(from_os_str))]
+    pub discovery_preview: Option<PathBuf>,
+
+    /// Once the layout of the tiles of a generic template is found, save it to the given json file:
+    /// the template, the number of columns and rows, and the size of the tiles and of the image.
+    /// The file can be edited, and given to --load-grid to download the image again without searching for its size.
+    #[structopt(long, parse(from_os_str))]
+    pub save_grid: Option<PathBuf>,
+
+    /// Read the layout of the tiles of a generic template from a file written by --save-grid,
+    /// instead of searching for it by probing the server. Same as adding `#grid=FILE` at the end of the template.
+    /// The file cannot be used with another template than the one it was saved for,
+    /// and other inputs are refused.
+    #[structopt(long, parse(from_os_str))]
+    pub load_grid: Option<PathBuf>,
+
+    /// A directory in which tiles that were already downloaded are stored at the host and path of their url.
+    /// The tile at `http://example.com/tiles/0/1.jpg?v=2` is read from `DIR/example.com/tiles/0/1.jpg@v=2`
+    /// when it exists, and downloaded otherwise.
+    #[structopt(long, parse(from_os_str))]
+    pub local_root: Option<PathBuf>,
+
     /// A number between 0 and 100 expressing how much to compress the output image.
     /// For lossy output formats such as jpeg, this affects the quality of the resulting image.
     /// 0 means less compression, 100 means more compression.
@@ -62,6 +198,108 @@ pub struct Arguments {
     #[structopt(long, default_value = "20")]
     pub compression: u8,
 
+    /// Maximum size of the resulting jpeg file, in megabytes (millions of bytes).
+    /// The image is encoded several times, in order to find the best quality that gives a smaller file,
+    /// and `--compression` is ignored. When the image is split, each part has this maximum size.
+    /// Only applies to jpeg images.
+    #[structopt(long, parse(try_from_str = parse_megabytes))]
+    pub target_size: Option<u64>,
+
+    /// Force the size of the resulting image, given as WIDTHxHEIGHT, for instance `--dimensions 1024x768`.
+    /// Tiles that go beyond these dimensions are cropped,
+    /// and the parts of the image that are not covered by any tile are left empty.
+    #[structopt(long, parse(try_from_str = parse_dimensions))]
+    pub dimensions: Option<Vec2d>,
+
+    /// Download only a part of the image, given as fractions of its width and height:
+    /// LEFT,TOP,WIDTH,HEIGHT. For instance, `--crop-fraction 0,0,0.5,0.5` gives the top left quarter
+    /// of the image. The region is computed once the size of the image is known,
+    /// and only the tiles that overlap it are downloaded from then on.
+    #[structopt(long, conflicts_with = "dimensions", parse(try_from_str = parse_crop_fraction))]
+    pub crop_fraction: Option<CropFraction>,
+
+    /// Number of bits per color channel in the resulting image: 8 or 16.
+    /// By default, the bit depth of the tiles is kept when the output format supports it
+    /// (png and tiff), and 16-bit tiles are scaled down to 8 bits otherwise.
+    #[structopt(long, possible_values = &["8", "16"])]
+    pub bit_depth: Option<BitDepth>,
+
+    /// Convert the tiles that have an embedded ICC color profile to sRGB before assembling them,
+    /// for viewers that ignore color profiles. Converted tiles have 8 bits per channel.
+    /// Tiles without a color profile are left unchanged.
+    #[structopt(long)]
+    pub convert_srgb: bool,
+
+    /// Interpolation used to scale the tiles that do not have the size of the cell they cover
+    /// in the image, for sources that mix tiles of different resolutions.
+    #[structopt(long, default_value = "bilinear", possible_values = &["nearest", "bilinear", "lanczos"])]
+    pub resample: Resample,
+
+    /// Split the resulting image into the given number of parts, written as separate files.
+    /// The value is given as COLUMNSxROWS, for instance `--split 2x1`.
+    /// Parts are named after the output file, such as `out_0_0.png`, `out_1_0.png`,
+    /// and a json manifest file describing the position of each part is written next to them.
+    #[structopt(long, parse(try_from_str = parse_grid_size))]
+    pub split: Option<Vec2d>,
+
+    /// Split the resulting image into as few parts as possible,
+    /// such that no part is wider or higher than the given number of pixels.
+    /// Useful to save very large images in formats that have dimension limits.
+    #[structopt(long, conflicts_with = "split")]
+    pub split_max_dim: Option<u32>,
+
+    /// When the image is split into parts, maximum number of parts that are written at the same time
+    #[structopt(long, default_value = "4")]
+    pub split_parallelism: usize,
+
+    /// When the output path ends with `.xyz`, size in pixels of the square tiles
+    /// of the `{z}/{x}/{y}` tile pyramid
+    #[structopt(long, default_value = "256")]
+    pub xyz_tile_size: u32,
+
+    /// When the output path ends with `.xyz`, zoom level of the most zoomed out tiles that are written.
+    /// Defaults to the level at which the whole image fits in a single tile.
+    #[structopt(long)]
+    pub xyz_min_zoom: Option<u32>,
+
+    /// When the output path ends with `.xyz`, zoom level at which the image is shown at full resolution.
+    /// Defaults to the number of levels needed to go from a single tile to the full resolution.
+    #[structopt(long)]
+    pub xyz_max_zoom: Option<u32>,
+
+    /// When the output path ends with `.zip`, format of the tiles stored in the archive.
+    /// `source` keeps the files downloaded from the server, and stores the tiles that had to be
+    /// modified as png. `png` and `jpg` convert all the tiles.
+    /// Jpeg tiles are encoded with the quality given by `--compression`.
+    #[structopt(long, default_value = "source", possible_values = &["source", "png", "jpg", "jpeg"])]
+    pub output_tiles_format: TileFormat,
+
+    /// When the output path ends with `.tif` or `.tiff`, write the rows of the image to the file
+    /// as soon as all their tiles are downloaded, instead of assembling the whole image in memory.
+    /// The partially downloaded image can be opened while the download is running.
+    /// Images written this way are uncompressed. Png files are always written incrementally.
+    #[structopt(long)]
+    pub incremental: bool,
+
+    /// After the download, download the given percentage of the tiles a second time,
+    /// and check that they did not change, in order to detect unreliable servers.
+    /// The same tiles are checked every time for a given image.
+    /// If some tiles changed, they are reported and the program exits with an error.
+    #[structopt(long, parse(try_from_str = parse_percentage))]
+    pub verify_sample: Option<f64>,
+
+    /// Stop with an error, instead of only warning, when the first tiles of an image guessed
+    /// from a template suggest that the template is wrong:
+    /// when they are web pages instead of images, or when they are all identical
+    #[structopt(long)]
+    pub strict: bool,
+
+    /// Resolution of the image, in dots per inch, recorded in png, jpeg and tiff outputs
+    /// for printing. Tiff images with a resolution are written as with `--incremental`.
+    /// By default, no resolution is recorded.
+    #[structopt(long)]
+    pub dpi: Option<u16>,
+
     /// Sets an HTTP header to use on requests.
     /// This option can be repeated in order to set multiple headers.
     /// You can use `-H "Referer: URL"` where URL is the URL of the website's
@@ -74,6 +312,51 @@ pub struct Arguments {
     )]
     pub headers: Vec<(String, String)>,
 
+    /// Sets several HTTP headers at once, from a json object such as
+    /// `--headers-json '{"Referer": "http://example.com/", "Cookie": "a=b"}'`,
+    /// or from a file containing one, given as `--headers-json @headers.json`.
+    /// Yaml objects are also accepted, and values can be lists, as in yaml files.
+    /// Headers given with `-H` override these ones.
+    #[structopt(long, parse(try_from_str = parse_headers_json), number_of_values = 1)]
+    pub headers_json: Vec<HeaderObject>,
+
+    /// Use the url and the headers of a request copied from the developer tools of a browser
+    /// with "Copy as cURL", such as `--curl "curl 'https://example.com/info.json' -H 'Cookie: a=b'"`.
+    /// The url is used when no input url is given, and headers given with `-H` override the ones of the command.
+    #[structopt(long, parse(try_from_str = parse_curl))]
+    pub curl: Option<BrowserRequest>,
+
+    /// Use the url and the headers of the first request of a HAR file,
+    /// exported from the network tab of the developer tools of a browser, like `--curl`
+    #[structopt(long, parse(try_from_str = parse_har_file))]
+    pub har: Option<BrowserRequest>,
+
+    /// An url that gives a token for the requests of tiles, as the body of its response,
+    /// or in the `token` or `access_token` field of a json object.
+    /// The token is fetched before the first tile, and again when a tile is refused with a 401 or 403 status,
+    /// so that a long download does not fail when a short-lived token expires.
+    #[structopt(long)]
+    pub token_url: Option<String>,
+
+    /// For servers that give with each tile a token that has to be sent with the request of the next tile,
+    /// where the token is found in the responses: `header:NAME` for a header of the response,
+    /// or `body:REGULAR_EXPRESSION` for the text matched in the body, or by the group of the expression.
+    /// The tiles are then requested one after the other, the first one without a token.
+    #[structopt(long, conflicts_with = "token-url")]
+    pub chain_token: Option<ChainSource>,
+
+    /// The header in which the token given by --token-url or --chain-token is sent,
+    /// where {token} is replaced by the token
+    #[structopt(long, default_value = "Authorization: Bearer {token}")]
+    pub token_header: String,
+
+    /// Do not send the URL of the image page as the `Referer` of requests.
+    /// By default, tiles are requested with the URL of the page or file that
+    /// describes the image as their referer, since many servers require it.
+    /// A referer given with `-H` is still sent.
+    #[structopt(long)]
+    pub no_referer: bool,
+
     /// Maximum number of idle connections per host allowed at the same time
     #[structopt(long, default_value = "32")]
     pub max_idle_per_host: usize,
@@ -91,6 +374,56 @@ pub struct Arguments {
     #[structopt(long = "connect-timeout", default_value = "6s", parse(try_from_str = parse_duration))]
     pub connect_timeout: Duration,
 
+    /// Shift the tiles by the given number of pixels when stitching them together,
+    /// given as 'dx,dy'. Useful when a server consistently misplaces its tiles,
+    /// which produces visible seams in the resulting image.
+    /// The offsets can be fractional, such as '0.5,0' in cumulative mode
+    /// for tiles that are really 256.5 pixels apart.
+    #[structopt(long, allow_hyphen_values = true)]
+    pub stitch_offset: Option<Offset>,
+
+    /// How the stitch offset is applied: 'global' shifts all the tiles by the same amount,
+    /// 'cumulative' shifts each tile by the offset multiplied by its column and row number.
+    #[structopt(long, default_value = "global", possible_values = &["global", "cumulative"])]
+    pub stitch_offset_mode: StitchOffsetMode,
+
+    /// What is drawn where tiles overlap: 'crop' keeps the pixels of the last tile,
+    /// 'blend' composites each tile over the ones under it according to its transparency,
+    /// for sources whose tiles have feathered transparent edges meant to be blended.
+    /// Tiles are blended in the images that are assembled in memory, and in png images.
+    #[structopt(long, default_value = "crop", possible_values = &["crop", "blend"])]
+    pub overlap_mode: OverlapMode,
+
+    /// What to do when the output file given on the command line already exists:
+    /// 'never' stops with an error without downloading the image, 'always' replaces the file,
+    /// and 'ask' asks for confirmation, or refuses when the program is not run interactively.
+    /// When no output file is given, a new file name is always chosen.
+    #[structopt(long, default_value = "never", possible_values = &["never", "ask", "always"])]
+    pub overwrite: OverwritePolicy,
+
+    /// Do not download the image if the output file already exists.
+    /// Useful when running the same command again to complete an interrupted batch of downloads.
+    /// When no output file name is given, the file checked is the one named after the image.
+    #[structopt(long)]
+    pub skip_existing: bool,
+
+    /// Do not download the image if the output file already exists
+    /// and was modified less than the given number of days ago.
+    /// Older output files are downloaded again.
+    #[structopt(long)]
+    pub skip_newer_than: Option<u64>,
+
+    /// Once the image is written, rename it after the SHA-256 hash of its contents,
+    /// keeping its extension. Two downloads of the same image then have the same file name.
+    /// Not available when the image is split into several files.
+    #[structopt(long)]
+    pub hash_name: bool,
+
+    /// With --hash-name, also write the original name of the file
+    /// to a text file named after the hash
+    #[structopt(long, requires = "hash-name")]
+    pub name_sidecar: bool,
+
     /// Level of logging verbosity. Set it to "debug" to get all logging messages.
     #[structopt(long, default_value="warn")]
     pub logging: String,
@@ -102,34 +435,109 @@ impl Default for Arguments {
             input_uri: None,
             outfile: None,
             dezoomer: "auto".to_string(),
+            #[cfg(feature = "script")]
+            script: None,
             largest: false,
+            preview: false,
+            level: None,
+            levels: None,
+            list_levels: false,
+            gallery: false,
+            gallery_links: None,
+            capabilities: false,
             max_width: None,
             max_height: None,
+            target_mp: None,
+            prefer_complete: false,
+            max_output_pixels: None,
             parallelism: 16,
+            decode_concurrency: None,
             retries: 1,
             compression: 20,
+            target_size: None,
+            dimensions: None,
+            crop_fraction: None,
+            bit_depth: None,
+            convert_srgb: false,
+            resample: Resample::Bilinear,
+            split: None,
+            split_max_dim: None,
+            split_parallelism: 4,
+            xyz_tile_size: 256,
+            xyz_min_zoom: None,
+            xyz_max_zoom: None,
+            output_tiles_format: TileFormat::Source,
+            incremental: false,
+            verify_sample: None,
+            strict: false,
+            dpi: None,
             retry_delay: Duration::from_secs(2),
+            retry_budget: None,
+            pause_file: None,
+            provenance: None,
+            discovery_preview: None,
+            save_grid: None,
+            load_grid: None,
+            local_root: None,
             headers: vec![],
+            headers_json: vec![],
+            curl: None,
+            har: None,
+            token_url: None,
+            chain_token: None,
+            token_header: "Authorization: Bearer {token}".into(),
             max_idle_per_host: 32,
+            no_referer: false,
             accept_invalid_certs: false,
             timeout: Duration::from_secs(30),
             connect_timeout: Duration::from_secs(6),
+            stitch_offset: None,
+            stitch_offset_mode: StitchOffsetMode::Global,
+            overlap_mode: OverlapMode::Crop,
+            overwrite: OverwritePolicy::Never,
+            skip_existing: false,
+            skip_newer_than: None,
+            hash_name: false,
+            name_sidecar: false,
             logging: "warn".to_string(),
         }
     }
 }
 
 impl Arguments {
+    /// The input url, or the url of the request imported from a browser
+    pub(crate) fn input_uri(&self) -> Option<&String> {
+        self.input_uri.as_ref()
+            .or_else(|| self.browser_requests().find_map(|r| r.url.as_ref()))
+    }
+
+    fn browser_requests(&self) -> impl Iterator<Item = &BrowserRequest> {
+        self.curl.iter().chain(&self.har)
+    }
+
     pub fn choose_input_uri(&self) -> Result<String, ZoomError> {
-        match &self.input_uri {
-            Some(uri) => Ok(uri.clone()),
+        let uri = match self.input_uri() {
+            Some(uri) => uri.clone(),
             None => {
                 println!("Enter an URL or a path to a tiles.yaml file: ");
-                stdin_line()
+                stdin_line()?
             }
-        }
+        };
+        Ok(match &self.load_grid {
+            // Only the generic dezoomer reads the layout of the tiles from a file
+            Some(grid) if matches!(self.dezoomer.as_str(), "auto" | "generic") && generic::is_template(&uri) => {
+                format!("{}#grid={}", uri, grid.to_string_lossy())
+            }
+            Some(_) => return Err(ZoomError::GridWithoutTemplate { uri }),
+            None => uri,
+        })
     }
     pub fn find_dezoomer(&self) -> Result<Box<dyn Dezoomer>, ZoomError> {
+        #[cfg(feature = "script")]
+        if let Some(script) = &self.script {
+            let script = std::fs::read_to_string(script)?;
+            return Ok(Box::new(crate::script::ScriptDezoomer::new(script)));
+        }
         auto::all_dezoomers(true)
             .into_iter()
             .find(|d| d.name() == self.dezoomer)
@@ -140,6 +548,8 @@ impl Arguments {
     pub fn best_size<I: Iterator<Item = Vec2d>>(&self, sizes: I) -> Option<Vec2d> {
         if self.largest {
             sizes.max_by_key(|s| s.area())
+        } else if self.preview {
+            sizes.min_by_key(|s| s.area())
         } else if self.max_width.is_some() || self.max_height.is_some() {
             sizes
                 .filter(|s| {
@@ -147,13 +557,71 @@ impl Arguments {
                         && self.max_height.map(|h| s.y <= h).unwrap_or(true)
                 })
                 .max_by_key(|s| s.area())
+        } else if let Some(megapixels) = self.target_mp {
+            let sizes: Vec<Vec2d> = sizes.collect();
+            let max_area = megapixels * 1e6;
+            sizes.iter().copied()
+                .filter(|s| s.area() as f64 <= max_area)
+                .max_by_key(|s| s.area())
+                .or_else(|| sizes.iter().copied().min_by_key(|s| s.area()))
+        } else if self.gallery {
+            // The levels of the images of a gallery are not asked for one by one
+            sizes.max_by_key(|s| s.area())
         } else {
             None
         }
     }
 
+    /// The headers imported from a browser request, then the ones given as an object, then the ones given with `-H`
     pub fn headers(&self) -> impl Iterator<Item = (&String, &String)> {
-        self.headers.iter().map(|(k, v)| (k, v))
+        self.browser_requests()
+            .flat_map(|r| &r.headers)
+            .chain(self.headers_json.iter().flat_map(|HeaderObject(headers)| headers))
+            .chain(&self.headers)
+            .map(|(k, v)| (k, v))
+    }
+
+    pub fn tile_stages(&self) -> TileStages {
+        let cores = std::thread::available_parallelism().map(|n| n.get()).unwrap_or(1);
+        TileStages::new(self.parallelism, self.decode_concurrency_with_cores(cores))
+    }
+
+    /// Decoding is limited by the processor: decoding more tiles at once than there are cores
+    /// only delays all of them
+    fn decode_concurrency_with_cores(&self, cores: usize) -> usize {
+        self.decode_concurrency.unwrap_or_else(|| cores.min(self.parallelism).max(1))
+    }
+
+    /// If an existing output file should not be replaced, returns the maximum age it can have
+    pub fn skip_existing_max_age(&self) -> Option<Option<Duration>> {
+        match self.skip_newer_than {
+            Some(days) => Some(Some(Duration::from_secs(days * 24 * 60 * 60))),
+            None if self.skip_existing => Some(None),
+            None => None
+        }
+    }
+
+    pub(crate) fn encoder_options(&self) -> EncoderOptions {
+        let split = self.split.map(SplitSpec::Grid)
+            .or_else(|| self.split_max_dim.map(SplitSpec::MaxDim));
+        EncoderOptions {
+            compression: self.compression,
+            split,
+            split_parallelism: self.split_parallelism,
+            bit_depth: self.bit_depth,
+            source: self.input_uri().cloned(),
+            dimensions: self.dimensions,
+            xyz: XyzOptions {
+                tile_size: self.xyz_tile_size,
+                min_zoom: self.xyz_min_zoom,
+                max_zoom: self.xyz_max_zoom,
+            },
+            tiles_format: self.output_tiles_format,
+            incremental: self.incremental,
+            dpi: self.dpi,
+            target_size: self.target_size,
+            overlap: self.overlap_mode,
+        }
     }
 }
 
@@ -166,6 +634,72 @@ fn parse_header(s: &str) -> Result<(String, String), &'static str> {
     }
 }
 
+/// Headers given together as a json or yaml object
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HeaderObject(pub Vec<(String, String)>);
+
+fn parse_headers_json(s: &str) -> Result<HeaderObject, String> {
+    let contents = match s.strip_prefix('@') {
+        Some(path) => std::fs::read_to_string(path)
+            .map_err(|e| format!("Unable to read the headers file '{}': {}", path, e))?,
+        None => s.to_string(),
+    };
+    parse_header_object(&contents).map(HeaderObject).map_err(|e| format!(
+        "Invalid headers: {}. Expected an object such as '{{\"Referer\": \"http://example.com/\"}}'", e
+    ))
+}
+
+fn parse_grid_size(s: &str) -> Result<Vec2d, &'static str> {
+    parse_size_pair(s).ok_or("Invalid grid size. Expected 'COLUMNSxROWS', such as '2x1'")
+}
+
+fn parse_level_range(s: &str) -> Result<RangeInclusive<usize>, &'static str> {
+    let err_msg = "Invalid range of levels. Expected 'FIRST-LAST', such as '2-5'";
+    let mut bounds = s.splitn(2, '-').map(|n| n.trim().parse::<usize>());
+    match (bounds.next(), bounds.next()) {
+        (Some(Ok(first)), Some(Ok(last))) if first <= last => Ok(first..=last),
+        (Some(Ok(level)), None) => Ok(level..=level),
+        _ => Err(err_msg),
+    }
+}
+
+fn parse_percentage(s: &str) -> Result<f64, &'static str> {
+    s.trim().trim_end_matches('%').parse::<f64>().ok()
+        .filter(|p| (0. ..=100.).contains(p))
+        .ok_or("Invalid percentage. Expected a number between 0 and 100")
+}
+
+fn parse_crop_fraction(s: &str) -> Result<CropFraction, &'static str> {
+    let parts: Vec<f64> = s.split(',').map(|n| n.trim().parse::<f64>()).collect::<Result<_, _>>()
+        .map_err(|_| "Invalid region. Expected four numbers: 'LEFT,TOP,WIDTH,HEIGHT', such as '0,0,0.5,0.5'")?;
+    match parts[..] {
+        [x, y, width, height] => CropFraction::new(x, y, width, height)
+            .ok_or("Invalid region. The fractions must describe a non-empty part of the image, between 0 and 1"),
+        _ => Err("Invalid region. Expected four numbers: 'LEFT,TOP,WIDTH,HEIGHT', such as '0,0,0.5,0.5'"),
+    }
+}
+
+fn parse_megabytes(s: &str) -> Result<u64, &'static str> {
+    s.trim().parse::<f64>().ok()
+        .filter(|&mb| mb > 0.)
+        .map(|mb| (mb * 1e6) as u64)
+        .ok_or("Invalid file size. Expected a positive number of megabytes, such as '2.5'")
+}
+
+fn parse_dimensions(s: &str) -> Result<Vec2d, &'static str> {
+    parse_size_pair(s).ok_or("Invalid dimensions. Expected 'WIDTHxHEIGHT', such as '1024x768'")
+}
+
+/// Parses two positive numbers separated by an 'x'
+fn parse_size_pair(s: &str) -> Option<Vec2d> {
+    let mut parts = s.splitn(2, ['x', 'X'])
+        .map(|n| n.trim().parse::<u32>().ok().filter(|&n| n > 0));
+    match (parts.next(), parts.next()) {
+        (Some(Some(x)), Some(Some(y))) => Some(Vec2d { x, y }),
+        _ => None
+    }
+}
+
 fn parse_duration(s: &str) -> Result<Duration, &'static str> {
     let err_msg = "Invalid duration. \
                         A duration is a number followed by a unit, such as '10ms' or '5s'";
@@ -209,6 +743,87 @@ fn test_headers_and_input() -> Result<(), structopt::clap::Error> {
     Ok(())
 }
 
+#[test]
+fn test_headers_from_curl() -> Result<(), structopt::clap::Error> {
+    let args: Arguments = StructOpt::from_iter_safe(&[
+        "dezoomify-rs",
+        "--curl",
+        "curl 'http://example.com/info.json' -H 'Referer: http://example.com/' -H 'X-Token: abc'",
+        "-H",
+        "X-Token: override",
+    ])?;
+    assert_eq!(args.choose_input_uri().unwrap(), "http://example.com/info.json");
+    let headers: Vec<_> = args.headers().map(|(k, v)| format!("{}: {}", k, v)).collect();
+    assert_eq!(headers, vec!["Referer: http://example.com/", "X-Token: abc", "X-Token: override"]);
+    Ok(())
+}
+
+#[test]
+fn test_headers_json() -> Result<(), structopt::clap::Error> {
+    let headers_file = std::env::temp_dir().join("dezoomify-rs-headers.yaml");
+    std::fs::write(&headers_file, "Accept-Language: [fr, en]").unwrap();
+    let file_arg = format!("@{}", headers_file.to_string_lossy());
+    let args: Arguments = StructOpt::from_iter_safe(&[
+        "dezoomify-rs",
+        "--headers-json",
+        r#"{"Referer": "http://example.com/", "Cookie": ["a=b", "c=d"], "X-Token": "abc"}"#,
+        "--headers-json",
+        &file_arg,
+        "-H",
+        "X-Token: override",
+    ])?;
+    let map = crate::network::header_map(args.headers(), &args, None).unwrap();
+    assert_eq!(map["Referer"], "http://example.com/");
+    assert_eq!(map["Cookie"], "a=b; c=d");
+    assert_eq!(map["Accept-Language"], "fr, en");
+    assert_eq!(map["X-Token"], "override");
+    let error = parse_headers_json(r#"{"Referer": "http://example.com/""#).unwrap_err();
+    assert!(error.starts_with("Invalid headers"), "{}", error);
+    assert!(parse_headers_json("@/nonexistent/headers.json").is_err());
+    Ok(())
+}
+
+#[test]
+fn test_target_megapixels() -> Result<(), structopt::clap::Error> {
+    let args: Arguments = StructOpt::from_iter_safe(&["dezoomify-rs", "--target-mp", "20"])?;
+    let levels = [
+        Vec2d { x: 8000, y: 6000 },
+        Vec2d { x: 1000, y: 750 },
+        Vec2d { x: 5000, y: 3750 },
+        Vec2d { x: 4000, y: 3000 },
+        Vec2d { x: 6000, y: 4000 },
+    ];
+    // 24 MP is the closest to 20, but it is too large
+    assert_eq!(args.best_size(levels.iter().copied()), Some(Vec2d { x: 5000, y: 3750 }));
+    let args: Arguments = StructOpt::from_iter_safe(&["dezoomify-rs", "--target-mp", "0.5"])?;
+    assert_eq!(args.best_size(levels.iter().copied()), Some(Vec2d { x: 1000, y: 750 }));
+    for other in &["--max-width", "--max-height"] {
+        let parsed = Arguments::from_iter_safe(&["dezoomify-rs", "--target-mp", "20", other, "5000"]);
+        assert!(parsed.is_err(), "--target-mp should conflict with {}", other);
+    }
+    Ok(())
+}
+
+#[test]
+fn test_load_grid_only_for_templates() {
+    let input = |args: &[&str]| -> Result<String, ZoomError> {
+        let args: Arguments = StructOpt::from_iter_safe(args).unwrap();
+        args.choose_input_uri()
+    };
+    let template = "http://example.com/{{X}}_{{Y}}.jpg";
+    assert_eq!(input(&["dezoomify-rs", "--load-grid", "grid.json", template]).unwrap(),
+               "http://example.com/{{X}}_{{Y}}.jpg#grid=grid.json");
+    assert_eq!(input(&["dezoomify-rs", "--dezoomer", "generic", "--load-grid", "grid.json", template]).unwrap(),
+               "http://example.com/{{X}}_{{Y}}.jpg#grid=grid.json");
+    for args in &[
+        &["dezoomify-rs", "--load-grid", "grid.json", "http://example.com/info.json"][..],
+        &["dezoomify-rs", "--dezoomer", "iiif", "--load-grid", "grid.json", template][..],
+    ] {
+        assert!(matches!(input(args), Err(ZoomError::GridWithoutTemplate { .. })), "{:?}", args);
+    }
+    assert_eq!(input(&["dezoomify-rs", "http://example.com/info.json"]).unwrap(), "http://example.com/info.json");
+}
+
 #[test]
 fn test_parse_duration() {
     assert_eq!(parse_duration("2s"), Ok(Duration::from_secs(2)));
@@ -221,3 +836,39 @@ fn test_parse_duration() {
     assert!(parse_duration("1j").is_err());
     assert!(parse_duration("").is_err());
 }
+
+#[test]
+fn test_parse_level_range() {
+    assert_eq!(parse_level_range("2-5"), Ok(2..=5));
+    assert_eq!(parse_level_range("3"), Ok(3..=3));
+    assert!(parse_level_range("5-2").is_err());
+    assert!(parse_level_range("2-").is_err());
+}
+
+#[test]
+fn test_parse_crop_fraction() {
+    assert_eq!(parse_crop_fraction("0,0,0.5,0.5"), Ok(CropFraction { x: 0., y: 0., width: 0.5, height: 0.5 }));
+    assert!(parse_crop_fraction("0.5, 0.5, 0.5, 0.6").is_err());
+    assert!(parse_crop_fraction("0,0,1").is_err());
+    assert!(parse_crop_fraction("a,0,1,1").is_err());
+}
+
+#[test]
+fn test_parse_grid_size() {
+    assert_eq!(parse_grid_size("2x1"), Ok(Vec2d { x: 2, y: 1 }));
+    assert_eq!(parse_grid_size("10 X 3"), Ok(Vec2d { x: 10, y: 3 }));
+    assert!(parse_grid_size("0x1").is_err());
+    assert!(parse_grid_size("2").is_err());
+    assert!(parse_grid_size("axb").is_err());
+}
+
+#[test]
+fn test_decode_concurrency_defaults_to_core_count() {
+    let mut args = Arguments::default();
+    assert_eq!(args.decode_concurrency_with_cores(4), 4);
+    assert_eq!(args.decode_concurrency_with_cores(64), args.parallelism);
+    args.parallelism = 2;
+    assert_eq!(args.decode_concurrency_with_cores(4), 2);
+    args.decode_concurrency = Some(8);
+    assert_eq!(args.decode_concurrency_with_cores(4), 8);
+}
@@ -0,0 +1,233 @@
+use log::warn;
+use serde::Deserialize;
+
+/// A request copied from the developer tools of a web browser,
+/// whose url and headers are used to download the image
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BrowserRequest {
+    pub url: Option<String>,
+    pub headers: Vec<(String, String)>,
+}
+
+impl BrowserRequest {
+    fn new(url: Option<String>, headers: Vec<(String, String)>, cookies: Vec<String>) -> Self {
+        let mut headers: Vec<(String, String)> = headers.into_iter()
+            .filter(|(name, _)| is_replayable(name))
+            .collect();
+        if !cookies.is_empty() && !headers.iter().any(|(name, _)| name.eq_ignore_ascii_case("cookie")) {
+            headers.push(("Cookie".into(), cookies.join("; ")));
+        }
+        BrowserRequest { url, headers }
+    }
+}
+
+/// Headers that describe the connection of the browser rather than the request,
+/// and that would break the requests of dezoomify-rs if they were sent again
+fn is_replayable(name: &str) -> bool {
+    // HTTP/2 pseudo-headers such as :authority start with a colon
+    !name.starts_with(':') && !["host", "content-length", "accept-encoding", "connection"]
+        .iter().any(|h| name.eq_ignore_ascii_case(h))
+}
+
+/// Parses a command copied with "Copy as cURL"
+pub fn parse_curl(command: &str) -> Result<BrowserRequest, String> {
+    let words = shell_words(command)?;
+    let mut words = words.into_iter();
+    if words.next().as_deref() != Some("curl") {
+        return Err("The command should start with 'curl'".into());
+    }
+    let mut url = None;
+    let mut positional = vec![];
+    let mut headers = vec![];
+    let mut cookies = vec![];
+    while let Some(word) = words.next() {
+        let (option, inline_value) = match word.strip_prefix("--").and_then(|w| w.split_once('=')) {
+            Some((option, value)) => (format!("--{}", option), Some(value.to_string())),
+            None => (word, None),
+        };
+        let mut value = || inline_value.clone().or_else(|| words.next())
+            .ok_or_else(|| format!("Missing value for the curl option {}", option));
+        match option.as_str() {
+            "-H" | "--header" => match value()?.split_once(':') {
+                Some((name, value)) => headers.push((name.trim().into(), value.trim().into())),
+                None => warn!("Ignoring a curl header without a value"),
+            },
+            "-b" | "--cookie" => {
+                let cookie = value()?;
+                if cookie.contains('=') { cookies.push(cookie) } else { warn!("Ignoring the cookie file {}", cookie) }
+            }
+            "-A" | "--user-agent" => headers.push(("User-Agent".into(), value()?)),
+            "-e" | "--referer" => headers.push(("Referer".into(), value()?)),
+            "--url" => url = Some(value()?),
+            // Options whose value is not used
+            "-X" | "--request" | "-d" | "--data" | "--data-raw" | "--data-binary" | "--data-urlencode" |
+            "-o" | "--output" | "-u" | "--user" | "-x" | "--proxy" | "-m" | "--max-time" |
+            "--connect-timeout" => { value()?; }
+            option if option.starts_with('-') => {}
+            _ => positional.push(option.clone()),
+        }
+    }
+    let url = url.or_else(|| {
+        let i = positional.iter().position(|w| w.contains("://")).unwrap_or(0);
+        positional.into_iter().nth(i)
+    });
+    Ok(BrowserRequest::new(url, headers, cookies))
+}
+
+/// Splits a command into words, as a POSIX shell does, with the quoting used by browsers:
+/// single and double quotes, ANSI-C quoted strings ($'...'), backslashes, and line continuations
+fn shell_words(command: &str) -> Result<Vec<String>, String> {
+    let mut words = vec![];
+    let mut word: Option<String> = None;
+    let mut chars = command.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some('\n') => {}
+                Some('\r') if chars.peek() == Some(&'\n') => { chars.next(); }
+                Some(c) => word.get_or_insert_with(String::new).push(c),
+                None => {}
+            },
+            '\'' => {
+                let w = word.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some('\'') => break,
+                        Some(c) => w.push(c),
+                        None => return Err("Unterminated single quote in the curl command".into()),
+                    }
+                }
+            }
+            '$' if chars.peek() == Some(&'\'') => {
+                chars.next();
+                let w = word.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some('\'') => break,
+                        Some('\\') => match chars.next() {
+                            Some('n') => w.push('\n'),
+                            Some('t') => w.push('\t'),
+                            Some('r') => w.push('\r'),
+                            Some(c) => w.push(c),
+                            None => return Err("Unterminated quote in the curl command".into()),
+                        },
+                        Some(c) => w.push(c),
+                        None => return Err("Unterminated quote in the curl command".into()),
+                    }
+                }
+            }
+            '"' => {
+                let w = word.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') if matches!(chars.peek(), Some('"') | Some('\\') | Some('$') | Some('`')) => {
+                            w.push(chars.next().expect("peeked"));
+                        }
+                        Some(c) => w.push(c),
+                        None => return Err("Unterminated double quote in the curl command".into()),
+                    }
+                }
+            }
+            c if c.is_whitespace() => words.extend(word.take()),
+            c => word.get_or_insert_with(String::new).push(c),
+        }
+    }
+    words.extend(word);
+    Ok(words)
+}
+
+#[derive(Deserialize)]
+struct Har { log: HarLog }
+
+#[derive(Deserialize)]
+struct HarLog { entries: Vec<HarEntry> }
+
+#[derive(Deserialize)]
+struct HarEntry { request: HarRequest }
+
+#[derive(Deserialize)]
+struct HarRequest {
+    url: String,
+    #[serde(default)]
+    headers: Vec<HarField>,
+    #[serde(default)]
+    cookies: Vec<HarField>,
+}
+
+#[derive(Deserialize)]
+struct HarField { name: String, value: String }
+
+/// Parses the first request of a HAR file, exported from the network tab of the developer tools
+pub fn parse_har(contents: &str) -> Result<BrowserRequest, String> {
+    let har: Har = serde_json::from_str(contents).map_err(|e| format!("Invalid HAR file: {}", e))?;
+    let request = har.log.entries.into_iter().next()
+        .ok_or("The HAR file does not contain any request")?
+        .request;
+    let headers = request.headers.into_iter().map(|h| (h.name, h.value)).collect();
+    let cookies = request.cookies.into_iter().map(|c| format!("{}={}", c.name, c.value)).collect();
+    Ok(BrowserRequest::new(Some(request.url), headers, cookies))
+}
+
+pub fn parse_har_file(path: &str) -> Result<BrowserRequest, String> {
+    let contents = std::fs::read_to_string(path)
+        .map_err(|e| format!("Unable to read the HAR file '{}': {}", path, e))?;
+    parse_har(&contents)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header_pairs(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs.iter().map(|&(k, v)| (k.to_string(), v.to_string())).collect()
+    }
+
+    #[test]
+    fn test_parse_curl() {
+        let command = r#"curl 'https://example.com/image/ImageProperties.xml?v=1' \
+  -H 'Accept: */*' \
+  -H 'Accept-Encoding: gzip, deflate, br' \
+  -H $'X-Quoted: it\'s' \
+  -H "Referer: https://example.com/viewer" \
+  -b 'session=abc; consent=yes' \
+  --compressed"#;
+        let request = parse_curl(command).unwrap();
+        assert_eq!(request.url.as_deref(), Some("https://example.com/image/ImageProperties.xml?v=1"));
+        assert_eq!(request.headers, header_pairs(&[
+            ("Accept", "*/*"),
+            ("X-Quoted", "it's"),
+            ("Referer", "https://example.com/viewer"),
+            ("Cookie", "session=abc; consent=yes"),
+        ]));
+
+        let request = parse_curl("curl -X GET --url=http://example.com/a.xml -A agent").unwrap();
+        assert_eq!(request.url.as_deref(), Some("http://example.com/a.xml"));
+        assert_eq!(request.headers, header_pairs(&[("User-Agent", "agent")]));
+        assert!(parse_curl("wget http://example.com/").is_err());
+        assert!(parse_curl("curl 'http://example.com/").is_err());
+    }
+
+    #[test]
+    fn test_parse_har() {
+        let har = r#"{"log": {"version": "1.2", "entries": [{
+            "request": {
+                "method": "GET",
+                "url": "https://example.com/info.json",
+                "headers": [
+                    {"name": ":authority", "value": "example.com"},
+                    {"name": "referer", "value": "https://example.com/viewer"}
+                ],
+                "cookies": [{"name": "session", "value": "abc"}, {"name": "consent", "value": "yes"}]
+            },
+            "response": {"status": 200}
+        }]}}"#;
+        let request = parse_har(har).unwrap();
+        assert_eq!(request.url.as_deref(), Some("https://example.com/info.json"));
+        assert_eq!(request.headers, header_pairs(&[
+            ("referer", "https://example.com/viewer"),
+            ("Cookie", "session=abc; consent=yes"),
+        ]));
+        assert!(parse_har(r#"{"log": {"entries": []}}"#).is_err());
+    }
+}
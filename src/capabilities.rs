@@ -0,0 +1,50 @@
+use serde::Serialize;
+
+use crate::auto::all_dezoomers;
+use crate::encoder::output_formats;
+
+/// What this build of dezoomify-rs supports, for tools that integrate it
+#[derive(Debug, Serialize)]
+pub struct Capabilities {
+    pub version: &'static str,
+    /// The names that can be given to --dezoomer
+    pub dezoomers: Vec<&'static str>,
+    /// The extensions of the output files that can be written
+    pub output_formats: Vec<&'static str>,
+    /// The optional features this build was compiled with
+    pub features: Vec<&'static str>,
+}
+
+impl Capabilities {
+    pub fn of_this_build() -> Self {
+        Capabilities {
+            version: env!("CARGO_PKG_VERSION"),
+            dezoomers: all_dezoomers(true).iter().map(|d| d.name()).collect(),
+            output_formats: output_formats(),
+            features: compiled_features(),
+        }
+    }
+}
+
+fn compiled_features() -> Vec<&'static str> {
+    let mut features = vec![];
+    if cfg!(feature = "script") { features.push("script"); }
+    if cfg!(feature = "websocket") { features.push("websocket"); }
+    features
+}
+
+#[test]
+fn test_capabilities() {
+    let json = serde_json::to_value(Capabilities::of_this_build()).unwrap();
+    let dezoomers = json["dezoomers"].as_array().unwrap();
+    for name in &["generic", "custom", "iiif", "auto"] {
+        assert!(dezoomers.contains(&(*name).into()), "missing dezoomer {}", name);
+    }
+    let formats = json["output_formats"].as_array().unwrap();
+    for format in &["png", "jpg", "iiif", "xyz", "zip", "tiff", "bmp"] {
+        assert!(formats.contains(&(*format).into()), "missing output format {}", format);
+    }
+    assert!(!formats.contains(&"webp".into()));
+    assert_eq!(json["version"], env!("CARGO_PKG_VERSION"));
+    assert_eq!(json["features"].as_array().unwrap().contains(&"script".into()), cfg!(feature = "script"));
+}
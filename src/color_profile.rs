@@ -0,0 +1,221 @@
+use std::io::Read;
+
+use image::DynamicImage;
+use log::{debug, warn};
+use qcms::{DataType, Intent, Profile, Transform};
+
+/// Finds the ICC color profile embedded in a jpeg or png file
+pub fn icc_profile(bytes: &[u8]) -> Option<Vec<u8>> {
+    jpeg_icc_profile(bytes).or_else(|| png_icc_profile(bytes))
+}
+
+/// In jpeg files, the profile is split in chunks stored in APP2 segments
+fn jpeg_icc_profile(bytes: &[u8]) -> Option<Vec<u8>> {
+    const SOI: &[u8] = &[0xFF, 0xD8];
+    const APP2: u8 = 0xE2;
+    const START_OF_SCAN: u8 = 0xDA;
+    const ICC_HEADER: &[u8] = b"ICC_PROFILE\0";
+    if !bytes.starts_with(SOI) { return None; }
+    let mut chunks = vec![];
+    let mut pos = SOI.len();
+    while let [0xFF, marker, len_hi, len_lo, ..] = bytes[pos..] {
+        if marker == START_OF_SCAN { break; }
+        let len = usize::from(u16::from_be_bytes([len_hi, len_lo]));
+        let segment = bytes.get(pos + 4..pos + 2 + len)?;
+        if marker == APP2 && segment.starts_with(ICC_HEADER) {
+            // The header is followed by the index of the chunk and the number of chunks
+            let sequence_number = *segment.get(ICC_HEADER.len())?;
+            chunks.push((sequence_number, segment.get(ICC_HEADER.len() + 2..)?));
+        }
+        pos += 2 + len;
+    }
+    if chunks.is_empty() { return None; }
+    chunks.sort_by_key(|&(sequence_number, _)| sequence_number);
+    Some(chunks.into_iter().flat_map(|(_, chunk)| chunk.iter().copied()).collect())
+}
+
+/// In png files, the profile is stored compressed in the iCCP chunk, before the image data
+fn png_icc_profile(bytes: &[u8]) -> Option<Vec<u8>> {
+    const SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+    if !bytes.starts_with(SIGNATURE) { return None; }
+    let mut pos = SIGNATURE.len();
+    while let Some(&[l0, l1, l2, l3, ..]) = bytes.get(pos..) {
+        let len = u32::from_be_bytes([l0, l1, l2, l3]) as usize;
+        // The length, the type, the data and the crc of the chunk
+        let chunk_end = pos.checked_add(12)?.checked_add(len)?;
+        if chunk_end > bytes.len() { return None; }
+        let chunk_type = &bytes[pos + 4..pos + 8];
+        let data = &bytes[pos + 8..pos + 8 + len];
+        match chunk_type {
+            b"IDAT" => return None,
+            b"iCCP" => {
+                // The profile name and the compression method come before the compressed data
+                let name_end = data.iter().position(|&b| b == 0)?;
+                let mut profile = vec![];
+                flate2::read::ZlibDecoder::new(data.get(name_end + 2..)?)
+                    .read_to_end(&mut profile).ok()?;
+                return Some(profile);
+            }
+            _ => pos = chunk_end,
+        }
+    }
+    None
+}
+
+/// Converts the pixels of an image from the given ICC color profile to sRGB.
+/// The resulting image has 8 bits per channel.
+/// The image is returned unchanged if the profile is invalid or cannot be applied to it.
+pub fn convert_to_srgb(image: DynamicImage, icc: &[u8]) -> DynamicImage {
+    let profile = match Profile::new_from_slice(icc, false) {
+        Some(profile) => profile,
+        None => {
+            warn!("Ignoring the invalid color profile of a tile");
+            return image;
+        }
+    };
+    if profile.is_sRGB() { return image; }
+    let mut srgb = Profile::new_sRGB();
+    srgb.precache_output_transform();
+    let has_alpha = image.color().has_alpha();
+    let data_type = if has_alpha { DataType::RGBA8 } else { DataType::RGB8 };
+    let transform = match Transform::new(&profile, &srgb, data_type, Intent::default()) {
+        Some(transform) => transform,
+        None => {
+            warn!("Unable to convert a tile from its color profile to sRGB");
+            return image;
+        }
+    };
+    debug!("Converting a tile to sRGB");
+    if has_alpha {
+        let mut pixels = image.into_rgba8();
+        transform.apply(&mut pixels);
+        DynamicImage::ImageRgba8(pixels)
+    } else {
+        let mut pixels = image.into_rgb8();
+        transform.apply(&mut pixels);
+        DynamicImage::ImageRgb8(pixels)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::io::Write;
+
+    use image::{GenericImageView, ImageBuffer, ImageOutputFormat, Rgb};
+
+    use super::*;
+
+    /// An RGB display profile with linear curves, in which the red and green primaries
+    /// of sRGB are swapped: its pure red is sRGB's pure green, and the other way around.
+    pub(crate) fn swapped_primaries_profile() -> Vec<u8> {
+        fn s15_fixed16(v: f64) -> [u8; 4] { ((v * 65536.).round() as i32).to_be_bytes() }
+        fn xyz(v: [f64; 3]) -> Vec<u8> {
+            let mut tag = b"XYZ \0\0\0\0".to_vec();
+            v.iter().for_each(|&c| tag.extend(&s15_fixed16(c)));
+            tag
+        }
+        // Identity curve
+        let curve = b"curv\0\0\0\0\0\0\0\0".to_vec();
+        let tags: Vec<(&[u8], Vec<u8>)> = vec![
+            (b"wtpt", xyz([0.9642, 1.0, 0.8249])),
+            (b"rXYZ", xyz([0.3851, 0.7169, 0.0971])),
+            (b"gXYZ", xyz([0.4361, 0.2225, 0.0139])),
+            (b"bXYZ", xyz([0.1431, 0.0606, 0.7141])),
+            (b"rTRC", curve.clone()),
+            (b"gTRC", curve.clone()),
+            (b"bTRC", curve),
+        ];
+        let mut table = (tags.len() as u32).to_be_bytes().to_vec();
+        let mut data = vec![];
+        let data_start = 128 + 4 + 12 * tags.len();
+        for (signature, tag) in &tags {
+            table.extend(*signature);
+            table.extend(&((data_start + data.len()) as u32).to_be_bytes());
+            table.extend(&(tag.len() as u32).to_be_bytes());
+            data.extend(tag);
+        }
+        let mut header = vec![0u8; 128];
+        header[8] = 2; // version 2.0
+        header[12..16].copy_from_slice(b"mntr");
+        header[16..20].copy_from_slice(b"RGB ");
+        header[20..24].copy_from_slice(b"XYZ ");
+        header[36..40].copy_from_slice(b"acsp");
+        let mut profile = [header, table, data].concat();
+        let len = (profile.len() as u32).to_be_bytes();
+        profile[0..4].copy_from_slice(&len);
+        profile
+    }
+
+    /// A png image with the given embedded profile
+    pub(crate) fn png_with_profile(image: &DynamicImage, icc: &[u8]) -> Vec<u8> {
+        let mut png = vec![];
+        image.write_to(&mut png, ImageOutputFormat::Png).unwrap();
+        let mut compressed = flate2::write::ZlibEncoder::new(vec![], flate2::Compression::default());
+        compressed.write_all(icc).unwrap();
+        let chunk_data = [&b"test\0\0"[..], &compressed.finish().unwrap()].concat();
+        let mut chunk = (chunk_data.len() as u32).to_be_bytes().to_vec();
+        chunk.extend(b"iCCP");
+        chunk.extend(&chunk_data);
+        let mut crc = flate2::Crc::new();
+        crc.update(&chunk[4..]);
+        chunk.extend(&crc.sum().to_be_bytes());
+        // Right after the IHDR chunk, which is 25 bytes long, after the 8 bytes of the signature
+        png.splice(33..33, chunk);
+        png
+    }
+
+    #[test]
+    fn test_icc_profile_extraction() {
+        let icc = swapped_primaries_profile();
+        let image = DynamicImage::ImageRgb8(ImageBuffer::from_pixel(2, 2, Rgb([255, 0, 0])));
+        assert_eq!(icc_profile(&png_with_profile(&image, &icc)), Some(icc.clone()));
+
+        let mut jpeg = vec![];
+        image.write_to(&mut jpeg, ImageOutputFormat::Jpeg(90)).unwrap();
+        assert_eq!(icc_profile(&jpeg), None);
+        // Split the profile in two APP2 segments, stored in the wrong order
+        let (first, second) = icc.split_at(100);
+        for (index, chunk) in [(2u8, second), (1u8, first)].iter() {
+            let mut segment = vec![0xFF, 0xE2];
+            segment.extend(&((chunk.len() + 16) as u16).to_be_bytes());
+            segment.extend(b"ICC_PROFILE\0");
+            segment.extend(&[*index, 2]);
+            segment.extend(*chunk);
+            jpeg.splice(2..2, segment);
+        }
+        assert_eq!(icc_profile(&jpeg), Some(icc));
+        assert!(image::load_from_memory(&jpeg).is_ok());
+    }
+
+    #[test]
+    fn test_truncated_png() {
+        let image = DynamicImage::ImageRgb8(ImageBuffer::from_pixel(2, 2, Rgb([255, 0, 0])));
+        let mut png = vec![];
+        image.write_to(&mut png, ImageOutputFormat::Png).unwrap();
+        // Cut at each byte of the signature and of the IHDR chunk, up to the middle of its crc
+        for len in 0..=31 {
+            assert_eq!(icc_profile(&png[..len]), None, "truncated to {} bytes", len);
+        }
+        // A chunk whose length goes past the end of the file
+        let mut long_chunk = png[..33].to_vec();
+        long_chunk.extend(&u32::MAX.to_be_bytes());
+        long_chunk.extend(b"tEXt");
+        assert_eq!(icc_profile(&long_chunk), None);
+    }
+
+    #[test]
+    fn test_convert_to_srgb() {
+        let icc = swapped_primaries_profile();
+        let image = DynamicImage::ImageRgb8(ImageBuffer::from_fn(2, 1, |x, _| {
+            if x == 0 { Rgb([255, 0, 0]) } else { Rgb([0, 0, 255]) }
+        }));
+        let converted = convert_to_srgb(image, &icc);
+        let [r, g, b, _] = converted.get_pixel(0, 0).0;
+        assert!(r < 3 && g > 252 && b < 3, "red should become green, got {:?}", (r, g, b));
+        let [r, g, b, _] = converted.get_pixel(1, 0).0;
+        assert!(r < 3 && g < 3 && b > 252, "blue should be unchanged, got {:?}", (r, g, b));
+
+        let image = DynamicImage::ImageRgb8(ImageBuffer::from_pixel(1, 1, Rgb([1, 2, 3])));
+        assert_eq!(convert_to_srgb(image.clone(), b"not a profile"), image);
+    }
+}
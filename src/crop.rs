@@ -0,0 +1,158 @@
+use crate::dezoomer::TileReference;
+use crate::tile::Tile;
+use crate::Vec2d;
+
+/// A part of an image, given as fractions of its width and height,
+/// for images whose size is not known before they are downloaded
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CropFraction {
+    pub x: f64,
+    pub y: f64,
+    pub width: f64,
+    pub height: f64,
+}
+
+impl CropFraction {
+    /// Returns None if the region is empty or is not inside of the image
+    pub fn new(x: f64, y: f64, width: f64, height: f64) -> Option<Self> {
+        let inside = |start: f64, len: f64| start >= 0. && len > 0. && start + len <= 1.;
+        if inside(x, width) && inside(y, height) { Some(CropFraction { x, y, width, height }) } else { None }
+    }
+
+    /// The pixels of an image of the given size that are in the region.
+    /// Partially covered pixels are included.
+    pub fn resolve(&self, size: Vec2d) -> Region {
+        let range = |start: f64, len: f64, total: u32| {
+            let total = f64::from(total);
+            let first = (start * total).floor().min(total - 1.).max(0.);
+            let end = ((start + len) * total).ceil().min(total).max(first + 1.);
+            (first as u32, (end - first) as u32)
+        };
+        let (x, width) = range(self.x, self.width, size.x);
+        let (y, height) = range(self.y, self.height, size.y);
+        Region { position: Vec2d { x, y }, size: Vec2d { x: width, y: height } }
+    }
+}
+
+/// A rectangle of pixels in an image
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Region {
+    pub position: Vec2d,
+    pub size: Vec2d,
+}
+
+impl Region {
+    fn end(&self) -> Vec2d { self.position + self.size }
+
+    fn overlaps(&self, position: Vec2d, size: Vec2d) -> bool {
+        let end = position + size;
+        position.x < self.end().x && position.y < self.end().y
+            && end.x > self.position.x && end.y > self.position.y
+    }
+
+    /// The part of the tile that is inside of the region, positioned in the region
+    fn fit(&self, tile: Tile) -> Option<Tile> {
+        if !self.overlaps(tile.position, tile.size()) { return None; }
+        let start = tile.position.max(self.position);
+        let size = tile.bottom_right().min(self.end()) - start;
+        let (image, encoded) = if size == tile.size() { (tile.image, tile.encoded) } else {
+            let Vec2d { x, y } = start - tile.position;
+            (tile.image.crop_imm(x, y, size.x, size.y), None)
+        };
+        Some(Tile { position: start - self.position, image, encoded })
+    }
+}
+
+/// Restricts the download of a zoom level to a region given as fractions of the image.
+/// The region is known once the size of the image is. Until then, the tiles are all downloaded
+/// and kept, which is needed by the levels that find their size by probing tiles.
+pub struct Crop {
+    fraction: Option<CropFraction>,
+    region: Option<Region>,
+    pending: Vec<Tile>,
+}
+
+impl Crop {
+    pub fn new(fraction: Option<CropFraction>) -> Self {
+        Crop { fraction, region: None, pending: vec![] }
+    }
+
+    /// Resolves the region, and returns the size of the resulting image
+    pub fn set_image_size(&mut self, size: Vec2d) -> Vec2d {
+        if let Some(fraction) = self.fraction {
+            let region = *self.region.get_or_insert_with(|| fraction.resolve(size));
+            region.size
+        } else {
+            size
+        }
+    }
+
+    /// Whether a tile may have pixels in the region.
+    /// Tiles of an unknown size are always downloaded.
+    pub fn is_needed(&self, tile_ref: &TileReference, tile_size: Option<Vec2d>) -> bool {
+        match (self.region, tile_ref.cell_size.or(tile_size)) {
+            (Some(region), Some(size)) => region.overlaps(tile_ref.position, size),
+            _ => true,
+        }
+    }
+
+    /// The part of a downloaded tile that has to be added to the resulting image
+    pub fn place(&mut self, tile: Tile) -> Option<Tile> {
+        if self.fraction.is_none() { return Some(tile); }
+        match self.region {
+            Some(region) => region.fit(tile),
+            None => {
+                self.pending.push(tile);
+                None
+            }
+        }
+    }
+
+    /// The tiles that were downloaded before the region was known.
+    /// When the size of the image is never given, it is the extent of the downloaded tiles.
+    pub fn take_pending(&mut self, finished: bool) -> Vec<Tile> {
+        if finished && self.region.is_none() && !self.pending.is_empty() {
+            let extent = self.pending.iter().fold(Vec2d::default(), |e, t| e.max(t.bottom_right()));
+            self.set_image_size(extent);
+        }
+        match self.region {
+            Some(region) => self.pending.drain(..).filter_map(|tile| region.fit(tile)).collect(),
+            None => vec![],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{DynamicImage, ImageBuffer, Rgba};
+
+    use super::*;
+
+    fn tile(x: u32, y: u32) -> Tile {
+        let image = DynamicImage::ImageRgba8(ImageBuffer::from_pixel(10, 10, Rgba([0; 4])));
+        Tile { position: Vec2d { x, y }, image, encoded: None }
+    }
+
+    #[test]
+    fn test_fractional_region() {
+        let fraction = CropFraction::new(0.25, 0., 0.5, 0.34).unwrap();
+        let region = fraction.resolve(Vec2d { x: 30, y: 30 });
+        // Partially covered pixels are included
+        assert_eq!(region, Region { position: Vec2d { x: 7, y: 0 }, size: Vec2d { x: 16, y: 11 } });
+        assert!(CropFraction::new(0.5, 0., 0.6, 1.).is_none());
+        assert!(CropFraction::new(0., 0., 0., 1.).is_none());
+
+        let mut crop = Crop::new(Some(fraction));
+        assert!(crop.place(tile(0, 0)).is_none(), "the region is not known yet");
+        assert_eq!(crop.set_image_size(Vec2d { x: 30, y: 30 }), region.size);
+        let pending = crop.take_pending(false);
+        assert_eq!(pending.len(), 1);
+        assert_eq!((pending[0].position, pending[0].size()), (Vec2d { x: 0, y: 0 }, Vec2d { x: 3, y: 10 }));
+        let placed = crop.place(tile(10, 10)).unwrap();
+        assert_eq!((placed.position, placed.size()), (Vec2d { x: 3, y: 10 }, Vec2d { x: 10, y: 1 }));
+        assert!(crop.place(tile(20, 20)).is_none());
+        let reference = |x, y| TileReference { url: String::new(), position: Vec2d { x, y }, cell_size: None };
+        assert!(crop.is_needed(&reference(20, 0), Some(Vec2d::square(10))));
+        assert!(!crop.is_needed(&reference(0, 20), Some(Vec2d::square(10))));
+    }
+}
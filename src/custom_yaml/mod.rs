@@ -1,11 +1,12 @@
 use std::collections::HashMap;
 
-use serde::Deserialize;
+use serde::{Deserialize, Deserializer};
 
 use crate::network::default_headers;
 use crate::dezoomer::*;
 use crate::TileReference;
 
+mod tile_list;
 mod tile_set;
 mod variable;
 
@@ -21,24 +22,125 @@ impl Dezoomer for CustomDezoomer {
     fn zoom_levels(&mut self, data: &DezoomerInput) -> Result<ZoomLevels, DezoomerError> {
         self.assert(data.uri.ends_with("tiles.yaml"))?;
         let contents = data.with_contents()?.contents;
-        let dezoomer: CustomYamlTiles =
+        let value: serde_yaml::Value =
             serde_yaml::from_slice(&contents).map_err(DezoomerError::wrap)?;
-        single_level(dezoomer)
+        match value.get("images").and_then(serde_yaml::Value::as_sequence) {
+            Some(images) => images.iter()
+                .map(|image| {
+                    let mut tiles = CustomYamlTiles::parse(with_shared_settings(image, &value))?;
+                    tiles.distinct_image = true;
+                    Ok(Box::new(tiles) as ZoomLevel)
+                })
+                .collect(),
+            None => single_level(CustomYamlTiles::parse(value)?),
+        }
+    }
+}
+
+/// Adds the settings that are at the top level of a file describing several images
+/// to the description of one of them. Lists, such as variables, are concatenated,
+/// and the settings of the image take precedence over the shared ones.
+fn with_shared_settings(image: &serde_yaml::Value, shared: &serde_yaml::Value) -> serde_yaml::Value {
+    use serde_yaml::Value;
+    match (image, shared) {
+        (Value::Mapping(image), Value::Mapping(shared)) => {
+            let mut merged = image.clone();
+            for (key, shared_value) in shared {
+                if key.as_str() == Some("images") { continue; }
+                let value = match image.get(key) {
+                    Some(value) => with_shared_settings(value, shared_value),
+                    None => shared_value.clone(),
+                };
+                merged.insert(key.clone(), value);
+            }
+            Value::Mapping(merged)
+        }
+        (Value::Sequence(image), Value::Sequence(shared)) => {
+            Value::Sequence(shared.iter().chain(image).cloned().collect())
+        }
+        (image, _) => image.clone(),
     }
 }
 
 
 #[derive(Deserialize)]
 struct CustomYamlTiles {
+    /// The title of the image, used to name the output file
+    name: Option<String>,
     #[serde(flatten)]
-    tile_set: tile_set::TileSet,
-    #[serde(default = "default_headers")]
+    tile_source: tile_list::TileSource,
+    #[serde(default = "default_headers", deserialize_with = "deserialize_headers")]
     headers: HashMap<String, String>,
+    /// Headers to use only for requests to a given host, in addition to `headers`
+    #[serde(default, deserialize_with = "deserialize_host_headers")]
+    host_headers: HashMap<String, HashMap<String, String>>,
+    /// Set to `exif` to place the tiles according to their own metadata
+    #[serde(default)]
+    position_from: PositionSource,
+    /// The tiles generated from `tile_source`, filled in by the dezoomer
+    #[serde(skip)]
+    tiles: Vec<TileReference>,
+    /// Whether these tiles are one of the `images` of the file
+    #[serde(skip)]
+    distinct_image: bool,
+}
+
+impl CustomYamlTiles {
+    fn parse(value: serde_yaml::Value) -> Result<Self, DezoomerError> {
+        let mut tiles: CustomYamlTiles = serde_yaml::from_value(value).map_err(DezoomerError::wrap)?;
+        // Generate all the tiles now, so that an invalid file is reported as an error
+        tiles.tiles = tiles.tile_source.tiles()
+            .map_err(|source| DezoomerError::Other { source })?;
+        Ok(tiles)
+    }
+}
+
+/// A header can be given a list of values, for servers that expect a repeated header.
+/// The values are combined into a single header, which http defines as equivalent.
+fn deserialize_headers<'de, D>(deserializer: D) -> Result<HashMap<String, String>, D::Error>
+    where D: Deserializer<'de> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Values {
+        One(String),
+        Many(Vec<String>),
+    }
+    let headers = HashMap::<String, Values>::deserialize(deserializer)?;
+    Ok(headers.into_iter().map(|(name, values)| {
+        let value = match values {
+            Values::One(value) => value,
+            // Cookies are the exception to the rule that values are separated by commas
+            Values::Many(values) if name.eq_ignore_ascii_case("cookie") => values.join("; "),
+            Values::Many(values) => values.join(", "),
+        };
+        (name, value)
+    }).collect())
+}
+
+#[derive(Deserialize)]
+struct Headers(#[serde(deserialize_with = "deserialize_headers")] HashMap<String, String>);
+
+/// Parses a json or yaml object of headers, whose values can be lists as in a yaml file.
+/// The headers are sorted by name.
+pub(crate) fn parse_header_object(s: &str) -> Result<Vec<(String, String)>, serde_yaml::Error> {
+    let Headers(headers) = serde_yaml::from_str(s)?;
+    let mut headers: Vec<_> = headers.into_iter().collect();
+    headers.sort();
+    Ok(headers)
+}
+
+fn deserialize_host_headers<'de, D>(deserializer: D) -> Result<HashMap<String, HashMap<String, String>>, D::Error>
+    where D: Deserializer<'de> {
+    let hosts = HashMap::<String, Headers>::deserialize(deserializer)?;
+    Ok(hosts.into_iter().map(|(host, Headers(headers))| (host, headers)).collect())
 }
 
 impl std::fmt::Debug for CustomYamlTiles {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        write!(f, "Custom tiles")
+        match &self.name {
+            Some(name) => write!(f, "Custom tiles '{}'", name),
+            None => write!(f, "Custom tiles"),
+        }
     }
 }
 
@@ -47,19 +149,32 @@ impl TileProvider for CustomYamlTiles {
         if previous.is_some() {
             return vec![];
         }
-        let tiles_result:Result<Vec<_>, _> = self.tile_set.into_iter().collect();
-        match tiles_result{
-            Ok(tiles) => tiles,
-            Err(err) => {
-                log::error!("Invalid tiles.yaml file: {}\n", err);
-                vec![]
-            }
-        }
+        std::mem::take(&mut self.tiles)
+    }
+
+    fn known_tiles(&self) -> Option<Vec<TileReference>> {
+        Some(self.tiles.clone())
     }
 
     fn http_headers(&self) -> HashMap<String, String> {
         self.headers.clone()
     }
+
+    fn host_http_headers(&self) -> HashMap<String, HashMap<String, String>> {
+        self.host_headers.clone()
+    }
+
+    fn position_source(&self) -> PositionSource {
+        self.position_from
+    }
+
+    fn title(&self) -> Option<String> {
+        self.name.clone()
+    }
+
+    fn is_distinct_image(&self) -> bool {
+        self.distinct_image
+    }
 }
 
 #[test]
@@ -84,3 +199,120 @@ fn test_has_default_user_agent() {
         "There should be a user agent"
     );
 }
+
+#[test]
+fn test_host_headers() {
+    let conf: CustomYamlTiles = serde_yaml::from_str(r#"
+url_template: "http://{{host}}.example.com/{{x}}.jpg"
+variables:
+  - { name: x, from: 0, to: 1 }
+headers:
+  Referer: "http://base.example.com/"
+host_headers:
+  a.example.com:
+    Referer: "http://a.example.com/viewer"
+"#).unwrap();
+    assert_eq!(conf.host_http_headers()["a.example.com"]["Referer"], "http://a.example.com/viewer");
+    assert_eq!(conf.http_headers()["Referer"], "http://base.example.com/");
+}
+
+#[test]
+fn test_header_with_several_values() {
+    let conf: CustomYamlTiles = serde_yaml::from_str(r#"
+url_template: "http://example.com/{{x}}.jpg"
+variables: [{ name: x, from: 0, to: 1 }]
+headers:
+  Cookie: [session=abc, consent=yes]
+  Accept: [image/webp, image/jpeg]
+  Referer: "http://example.com/"
+host_headers:
+  example.com:
+    Accept: [image/png]
+"#).unwrap();
+    let headers = conf.http_headers();
+    assert_eq!(headers["Cookie"], "session=abc; consent=yes");
+    assert_eq!(headers["Accept"], "image/webp, image/jpeg");
+    assert_eq!(headers["Referer"], "http://example.com/");
+    assert_eq!(conf.host_http_headers()["example.com"]["Accept"], "image/png");
+}
+
+#[test]
+fn test_invalid_tile_set_is_an_error() {
+    let input = DezoomerInput {
+        uri: "tiles.yaml".into(),
+        contents: PageContents::Success(
+            b"url_template: \"{{x}}.jpg\"\nvariables: [{name: x, from: 0, to: 2}]\nx_template: \"x - 1\"".to_vec()
+        ),
+    };
+    let err = CustomDezoomer.zoom_levels(&input).unwrap_err();
+    assert!(err.to_string().contains("Number too large"), "unexpected error: {}", err);
+}
+
+#[test]
+fn test_malformed_files_do_not_panic() {
+    let inputs = [
+        // Used to divide by zero
+        "url_template: a\nvariables: [{name: x, from: 0, to: 1, step: 0}]",
+        // Used to overflow
+        "url_template: a\nvariables: [{name: x, from: -9223372036854775808, to: 9223372036854775807}]",
+        "url_template: a\nvariables: [{name: x, from: 9223372036854775806, to: 9223372036854775807, step: 9}]",
+        "url_template: \"{{x/0}}\"\nvariables: [{name: x, from: 0, to: 1}]",
+        "url_template: a\nvariables: [{name: x, from: 0, to: 1}]\nx_template: \"x - 5\"",
+        "url_template: \"{{\"\nvariables: 7",
+        "",
+    ];
+    for yaml in inputs.iter() {
+        let input = DezoomerInput {
+            uri: "tiles.yaml".into(),
+            contents: PageContents::Success(yaml.as_bytes().to_vec()),
+        };
+        if let Ok(levels) = CustomDezoomer.zoom_levels(&input) {
+            for mut level in levels {
+                level.next_tiles(None);
+            }
+        }
+    }
+    for yaml in inputs[..2].iter() {
+        let input = DezoomerInput {
+            uri: "tiles.yaml".into(),
+            contents: PageContents::Success(yaml.as_bytes().to_vec()),
+        };
+        assert!(CustomDezoomer.zoom_levels(&input).is_err(), "{} should be rejected", yaml);
+    }
+}
+
+#[test]
+fn test_several_images() {
+    let input = DezoomerInput {
+        uri: "tiles.yaml".into(),
+        contents: PageContents::Success(br#"
+variables:
+  - { name: tile_size, value: 10 }
+headers:
+  Referer: "http://shared.example.com/"
+images:
+  - name: first page
+    url_template: "p1/{{x * tile_size}}.jpg"
+    variables: [{ name: x, from: 0, to: 1 }]
+    x_template: "x * tile_size"
+    y_template: "0"
+  - name: second page
+    url_template: "p2/{{x}}.jpg"
+    variables: [{ name: x, from: 0, to: 2 }]
+    y_template: "0"
+    headers:
+      Referer: "http://second.example.com/"
+"#.to_vec()),
+    };
+    let mut levels = CustomDezoomer.zoom_levels(&input).unwrap();
+    assert_eq!(levels.len(), 2);
+    assert!(levels.iter().all(|l| l.is_distinct_image()));
+    assert_eq!(levels[0].title().as_deref(), Some("first page"));
+    assert_eq!(levels[0].http_headers()["Referer"], "http://shared.example.com/");
+    assert_eq!(levels[1].http_headers()["Referer"], "http://second.example.com/");
+    let urls = |level: &mut ZoomLevel| -> Vec<String> {
+        level.next_tiles(None).into_iter().map(|t| t.url).collect()
+    };
+    assert_eq!(urls(&mut levels[0]), vec!["p1/0.jpg", "p1/10.jpg"]);
+    assert_eq!(urls(&mut levels[1]), vec!["p2/0.jpg", "p2/1.jpg", "p2/2.jpg"]);
+}
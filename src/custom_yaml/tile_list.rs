@@ -0,0 +1,108 @@
+use serde::{de, Deserialize, Deserializer};
+
+use custom_error::custom_error;
+
+use crate::{TileReference, Vec2d};
+
+use super::tile_set::TileSet;
+
+/// Where the tiles described in a tiles.yaml file come from
+#[derive(Debug)]
+pub enum TileSource {
+    /// Tiles generated from an url template and variables
+    Template(TileSet),
+    /// An explicit list of tiles, that can be parts of a larger file
+    List(Vec<TileEntry>),
+}
+
+impl<'de> Deserialize<'de> for TileSource {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+        where D: Deserializer<'de> {
+        #[derive(Deserialize)]
+        struct TileList {
+            tiles: Vec<TileEntry>,
+        }
+        // Choose the format explicitly instead of using an untagged enum,
+        // in order to keep meaningful error messages
+        let value = serde_yaml::Value::deserialize(deserializer)?;
+        if value.get("tiles").is_some() {
+            TileList::deserialize(value).map(|l| TileSource::List(l.tiles))
+        } else {
+            TileSet::deserialize(value).map(TileSource::Template)
+        }.map_err(de::Error::custom)
+    }
+}
+
+impl TileSource {
+    pub fn tiles(&self) -> Result<Vec<TileReference>, Box<dyn std::error::Error>> {
+        Ok(match self {
+            TileSource::Template(tile_set) => tile_set.into_iter().collect::<Result<_, _>>()?,
+            TileSource::List(entries) => entries.iter().map(TileEntry::tile_reference).collect::<Result<_, _>>()?,
+        })
+    }
+}
+
+/// A single tile. When `offset` and `length` are given,
+/// the tile is stored at this position inside the file at `url`.
+/// When `width` and `height` are given, the tile is scaled to this size.
+#[derive(Deserialize, Debug)]
+pub struct TileEntry {
+    url: String,
+    x: u32,
+    y: u32,
+    offset: Option<u64>,
+    length: Option<u64>,
+    width: Option<u32>,
+    height: Option<u32>,
+}
+
+impl TileEntry {
+    fn tile_reference(&self) -> Result<TileReference, TileEntryError> {
+        let url = match (self.offset, self.length) {
+            (None, None) => self.url.clone(),
+            (offset, Some(length)) if length > 0 => {
+                let first = offset.unwrap_or(0);
+                let last = first.checked_add(length - 1)
+                    .ok_or_else(|| TileEntryError::BadRange { url: self.url.clone() })?;
+                format!("{}#bytes={}-{}", self.url, first, last)
+            }
+            _ => return Err(TileEntryError::BadRange { url: self.url.clone() }),
+        };
+        let cell_size = match (self.width, self.height) {
+            (Some(x), Some(y)) => Some(Vec2d { x, y }),
+            _ => None,
+        };
+        Ok(TileReference { url, position: Vec2d { x: self.x, y: self.y }, cell_size })
+    }
+}
+
+custom_error! {pub TileEntryError
+    BadRange{url: String} = "invalid byte range for the tile at '{url}': \
+                             'length' must be a positive number of bytes",
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tile_list() {
+        let source: TileSource = serde_yaml::from_str("
+tiles:
+  - { url: blob.bin, offset: 10, length: 5, x: 0, y: 0 }
+  - { url: other.jpg, x: 256, y: 0, width: 256, height: 256 }
+").unwrap();
+        let tiles = source.tiles().unwrap();
+        assert_eq!(tiles[1].cell_size, Some(Vec2d { x: 256, y: 256 }));
+        let urls: Vec<_> = tiles.into_iter().map(|t| t.url).collect();
+        assert_eq!(urls, vec!["blob.bin#bytes=10-14", "other.jpg"]);
+    }
+
+    #[test]
+    fn empty_range() {
+        let source: TileSource = serde_yaml::from_str(
+            "tiles: [{ url: blob.bin, offset: 10, length: 0, x: 0, y: 0 }]"
+        ).unwrap();
+        assert!(source.tiles().is_err());
+    }
+}
@@ -1,4 +1,4 @@
-use std::convert::TryInto;
+use std::convert::{TryFrom, TryInto};
 use std::str::FromStr;
 
 use regex::Regex;
@@ -8,18 +8,70 @@ use custom_error::custom_error;
 use lazy_static::lazy_static;
 
 use crate::{TileReference, Vec2d};
+use crate::generic::IndexFormat;
 
 use super::variable::{BadVariableError, Variables};
 
 #[derive(Deserialize, Debug)]
+#[serde(try_from = "TileSetConfig")]
 pub struct TileSet {
     variables: Variables,
     url_template: UrlTemplate,
+    x_template: IntTemplate,
+    y_template: IntTemplate,
+    presence: PresenceMap,
+    shard_hosts: Vec<String>,
+}
+
+/// A tile set as it is written in the yaml file, with its url template not yet parsed
+#[derive(Deserialize)]
+struct TileSetConfig {
+    variables: Variables,
+    url_template: String,
+    /// How the indices are written in `url_template`
+    #[serde(default)]
+    format: TemplateFormat,
 
     #[serde(default = "default_x_template")]
     x_template: IntTemplate,
     #[serde(default = "default_y_template")]
     y_template: IntTemplate,
+    /// Which of the tiles generated by the variables exist
+    #[serde(default)]
+    presence: PresenceMap,
+    /// The hosts among which `{{SHARD}}` is chosen in `url_template`, with `shard_index`
+    #[serde(default)]
+    shard_hosts: Vec<String>,
+}
+
+impl TryFrom<TileSetConfig> for TileSet {
+    type Error = UrlTemplateError;
+
+    fn try_from(config: TileSetConfig) -> Result<Self, Self::Error> {
+        let url_template = match config.format {
+            TemplateFormat::Expressions => config.url_template.parse()?,
+            TemplateFormat::Printf => UrlTemplate::from_printf(&config.url_template)?,
+        };
+        Ok(TileSet {
+            variables: config.variables,
+            url_template,
+            x_template: config.x_template,
+            y_template: config.y_template,
+            presence: config.presence,
+            shard_hosts: config.shard_hosts,
+        })
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+enum TemplateFormat {
+    /// `{{ expressions }}` evaluated with the variables
+    #[default]
+    Expressions,
+    /// printf-style integers, such as `%03d`: the first one is x, the second one is y.
+    /// They can also be numbered explicitly, as in `%2$d` for y.
+    Printf,
 }
 
 fn default_x_template() -> IntTemplate {
@@ -35,34 +87,62 @@ impl<'a> IntoIterator for &'a TileSet {
     type IntoIter = Box<dyn Iterator<Item = Self::Item> + 'a>;
 
     fn into_iter(self) -> Self::IntoIter {
-        Box::new(self.variables.iter_contexts().map(move |ctx| {
+        let contexts = self.variables.iter_contexts()
+            .zip(self.presence.iter())
+            .filter_map(|(ctx, present)| if present { Some(ctx) } else { None });
+        Box::new(contexts.map(move |ctx| {
             let ctx = ctx?;
             Ok(TileReference {
-                url: self.url_template.eval(&ctx)?,
+                url: self.url_template.eval(&ctx, &self.shard_hosts)?,
                 position: Vec2d {
                     x: self.x_template.eval(&ctx)?,
                     y: self.y_template.eval(&ctx)?,
                 },
+                cell_size: None,
             })
         }))
     }
 }
 
+/// Lengths of the alternating runs of present and missing tiles, in the order in which
+/// the variables generate them, starting with present tiles.
+/// The tiles after the last run are present.
+#[derive(Deserialize, Debug, Default)]
+struct PresenceMap(Vec<u64>);
+
+impl PresenceMap {
+    fn iter(&self) -> impl Iterator<Item=bool> + '_ {
+        let runs = self.0.iter().zip([true, false].iter().cycle())
+            .flat_map(|(&len, &present)| std::iter::repeat_n(present, len as usize));
+        runs.chain(std::iter::repeat(true))
+    }
+}
+
 #[derive(Debug)]
 struct IntTemplate(String);
 
 impl IntTemplate {
     fn eval<C: evalexpr::Context>(&self, context: &C) -> Result<u32, UrlTemplateError> {
-        let template: evalexpr::Node =
-            evalexpr::build_operator_tree(&self.0).map_err(|source| {
-                UrlTemplateError::BadExpression {
-                    expr: self.0.clone(),
-                    source,
-                }
-            })?;
-        let evaluated_int = template.eval_int_with_context(context)?;
+        let evaluated_int = self.node()?.eval_int_with_context(context)?;
         Ok(evaluated_int.try_into()?)
     }
+
+    /// Evaluates to either a string, or a positive integer written in decimal
+    fn eval_to_string<C: evalexpr::Context>(&self, context: &C) -> Result<String, UrlTemplateError> {
+        match self.node()?.eval_with_context(context)? {
+            evalexpr::Value::String(s) => Ok(s),
+            value => Ok(u32::try_from(value.as_int()?)?.to_string()),
+        }
+    }
+
+    fn node(&self) -> Result<evalexpr::Node, UrlTemplateError> {
+        evalexpr::build_operator_tree(&self.0).map_err(|source| {
+            UrlTemplateError::BadExpression {
+                expr: self.0.clone(),
+                source,
+            }
+        })
+    }
 }
 
 impl FromStr for IntTemplate {
@@ -89,9 +169,67 @@ struct UrlTemplate {
 }
 
 impl UrlTemplate {
-    fn eval<C: evalexpr::Context>(&self, context: &C) -> Result<String, UrlTemplateError> {
-        self.parts.iter().map(|p| p.eval(context)).collect()
+    fn eval<C: evalexpr::Context>(&self, context: &C, shard_hosts: &[String]) -> Result<String, UrlTemplateError> {
+        let eval_with_shard = |shard: &str| -> Result<String, UrlTemplateError> {
+            self.parts.iter().map(|p| p.eval(context, shard)).collect()
+        };
+        if !self.parts.iter().any(|p| matches!(p, UrlPart::Shard)) {
+            return eval_with_shard("");
+        }
+        if shard_hosts.is_empty() { return Err(UrlTemplateError::NoShardHosts); }
+        // The shard depends only on the path, so it can be computed before the host is known
+        let unsharded = eval_with_shard("")?;
+        eval_with_shard(&shard_hosts[shard_index(url_path(&unsharded), shard_hosts.len())])
     }
+
+    /// Parses a template in which x and y are written as printf-style integers.
+    /// Only `%d` and `%0Nd` are supported, and `%%` for a percent sign.
+    fn from_printf(s: &str) -> Result<Self, UrlTemplateError> {
+        lazy_static! {
+            static ref RE: Regex = Regex::new(r"%%|%(?:(\d+)\$)?([^%a-zA-Z]*[a-zA-Z])?").unwrap();
+        }
+        let mut parts = vec![];
+        let mut cursor = 0usize;
+        let mut next_index = 1;
+        for caps in RE.captures_iter(s) {
+            let m = caps.get(0).unwrap();
+            parts.push(UrlPart::constant(&s[cursor..m.start()]));
+            cursor = m.end();
+            if m.as_str() == "%%" { parts.push(UrlPart::constant("%")); continue; }
+            let format = IndexFormat::parse_printf(&format!("%{}", caps.get(2).map_or("", |f| f.as_str())))
+                .ok_or_else(|| UrlTemplateError::UnsupportedPrintfSpecifier {
+                    specifier: m.as_str().to_string(),
+                    template: s.to_string(),
+                })?;
+            let index = match caps.get(1) {
+                Some(index) => index.as_str().parse().unwrap_or(0),
+                None => { next_index += 1; next_index - 1 }
+            };
+            let variable = match index {
+                1 => "x",
+                2 => "y",
+                _ => return Err(UrlTemplateError::BadPrintfTemplate { template: s.to_string() }),
+            };
+            parts.push(UrlPart::Formatted(variable.parse()?, format));
+        }
+        parts.push(UrlPart::constant(&s[cursor..]));
+        Ok(UrlTemplate { parts })
+    }
+}
+
+/// Chooses a shard the way CDNs that spread the tiles on several hosts commonly do:
+/// from the CRC-32 checksum of the path of the tile, as used in zip and png files.
+/// The hash function is fixed, since it is part of the format of the yaml files.
+fn shard_index(path: &str, shard_count: usize) -> usize {
+    let mut crc = flate2::Crc::new();
+    crc.update(path.as_bytes());
+    crc.sum() as usize % shard_count
+}
+
+/// The part of a url that comes after its host: its path and its query string
+fn url_path(url: &str) -> &str {
+    let host_start = url.find("://").map_or(0, |i| i + 3);
+    url[host_start..].find('/').map_or("", |i| &url[host_start + i..])
 }
 
 impl FromStr for UrlTemplate {
@@ -107,7 +245,7 @@ impl FromStr for UrlTemplate {
             let prev = &s[cursor..m.start()];
             parts.push(UrlPart::Constant(String::from(prev)));
             let expr_src = &s[m.start() + 2..m.end() - 2];
-            parts.push(UrlPart::expression(expr_src)?);
+            parts.push(if expr_src.trim() == "SHARD" { UrlPart::Shard } else { UrlPart::expression(expr_src)? });
             cursor = m.end();
         }
         parts.push(UrlPart::constant(&s[cursor..]));
@@ -129,6 +267,10 @@ impl<'de> Deserialize<'de> for UrlTemplate {
 enum UrlPart {
     Constant(String),
     Expression(IntTemplate),
+    /// An integer with a minimum number of digits
+    Formatted(IntTemplate, IndexFormat),
+    /// One of the `shard_hosts`, chosen from the path of the tile
+    Shard,
 }
 
 impl UrlPart {
@@ -138,10 +280,12 @@ impl UrlPart {
     fn expression(s: &str) -> Result<UrlPart, UrlTemplateError> {
         s.parse().map(UrlPart::Expression)
     }
-    fn eval<C: evalexpr::Context>(&self, context: &C) -> Result<String, UrlTemplateError> {
+    fn eval<C: evalexpr::Context>(&self, context: &C, shard: &str) -> Result<String, UrlTemplateError> {
         match self {
             UrlPart::Constant(s) => Ok(s.clone()),
-            UrlPart::Expression(expr) => Ok(format!("{}", expr.eval(context)?)),
+            UrlPart::Expression(expr) => expr.eval_to_string(context),
+            UrlPart::Formatted(expr, format) => Ok(format.format(expr.eval(context)?)),
+            UrlPart::Shard => Ok(shard.to_string()),
         }
     }
 }
@@ -150,7 +294,13 @@ custom_error! {pub UrlTemplateError
     BadExpression{expr:String, source:evalexpr::EvalexprError} = "'{expr}' is not a valid expression: {source}",
     EvalError{source:evalexpr::EvalexprError} = "{source}",
     NumberError{source:std::num::TryFromIntError} = "Number too large: {source}",
-    BadVariable{source: BadVariableError} = "Invalid variable: {source}"
+    BadVariable{source: BadVariableError} = "Invalid variable: {source}",
+    NoShardHosts = "The url template contains {{{{SHARD}}}}, but no shard_hosts are given",
+    BadPrintfTemplate{template: String} =
+        "The printf url template '{template}' uses more than two integers: only x (%1$d) and y (%2$d) are available",
+    UnsupportedPrintfSpecifier{specifier: String, template: String} =
+        "Unsupported specifier '{specifier}' in the printf url template '{template}': \
+         only integers written as %d or %0Nd are supported, and %% for a percent sign",
 }
 
 #[cfg(test)]
@@ -161,7 +311,7 @@ mod tests {
 
     use crate::TileReference;
 
-    use super::super::tile_set::{IntTemplate, TileSet, UrlTemplate, UrlTemplateError};
+    use super::super::tile_set::{IntTemplate, TileSet, UrlTemplate, UrlTemplateError, shard_index};
     use super::super::variable::{VarOrConst, Variables};
 
     #[test]
@@ -170,7 +320,7 @@ mod tests {
         let mut ctx = evalexpr::HashMapContext::new();
         ctx.set_value("x".into(), 0.into())?;
         ctx.set_value("y".into(), 10.into())?;
-        assert_eq!(tpl.eval(&ctx)?, "a 0 b 10 c");
+        assert_eq!(tpl.eval(&ctx, &[])?, "a 0 b 10 c");
         Ok(())
     }
 
@@ -184,6 +334,8 @@ mod tests {
             url_template: UrlTemplate::from_str("{{x}}/{{y}}").unwrap(),
             x_template: IntTemplate::from_str("x").unwrap(),
             y_template: IntTemplate::from_str("y").unwrap(),
+            presence: Default::default(),
+            shard_hosts: vec![],
         };
         let tile_refs: Vec<_> = ts.into_iter().collect::<Result<_, _>>().unwrap();
         let expected: Vec<_> = vec!["0 0 0/0", "0 1 0/1", "1 0 1/0", "1 1 1/1"]
@@ -217,4 +369,139 @@ url_template: "{{x*tile_size}}/{{y*tile_size}}"
             .unwrap();
         assert_eq!(expected, tile_refs);
     }
+
+    #[test]
+    fn tileset_with_enumerated_values() {
+        let serialized = r#"
+variables:
+    - name: region
+      values: [north, south, east]
+    - name: y
+      from: 0
+      to: 1
+url_template: "{{region}}/{{y}}.jpg"
+x_template: "region_index * 100"
+y_template: "y * 100"
+        "#;
+        let ts: TileSet = serde_yaml::from_str(serialized).unwrap();
+        let tile_refs: Vec<_> = ts.into_iter().collect::<Result<_, _>>().unwrap();
+        let expected: Vec<_> = vec![
+            "0 0 north/0.jpg", "0 100 north/1.jpg",
+            "100 0 south/0.jpg", "100 100 south/1.jpg",
+            "200 0 east/0.jpg", "200 100 east/1.jpg",
+        ]
+            .into_iter()
+            .map(TileReference::from_str)
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(expected, tile_refs);
+    }
+
+    #[test]
+    fn tileset_with_presence_map() {
+        let serialized = r#"
+variables:
+    - { name: x, from: 0, to: 2 }
+    - { name: y, from: 0, to: 2 }
+url_template: "{{x}}/{{y}}"
+# 0/0 and 0/1 exist, 0/2, 1/0 and 1/1 are missing, 1/2 exists, 2/0 is missing
+presence: [2, 3, 1, 1]
+        "#;
+        let ts: TileSet = serde_yaml::from_str(serialized).unwrap();
+        let urls: Vec<_> = ts.into_iter().map(|t| t.unwrap().url).collect();
+        assert_eq!(urls, vec!["0/0", "0/1", "1/2", "2/1", "2/2"]);
+    }
+
+    #[test]
+    fn tileset_with_dependent_ranges() {
+        let serialized = r#"
+variables:
+    - { name: x, from: 0, to: 2 }
+    - { name: y, from: 0, to: "x" }
+url_template: "{{x}}/{{y}}"
+        "#;
+        let ts: TileSet = serde_yaml::from_str(serialized).unwrap();
+        let urls: Vec<_> = ts.into_iter().map(|t| t.unwrap().url).collect();
+        assert_eq!(urls, vec!["0/0", "1/0", "1/1", "2/0", "2/1", "2/2"]);
+    }
+
+    /// Bitwise CRC-32, as described in the png specification
+    fn reference_crc32(bytes: &[u8]) -> u32 {
+        let mut crc = !0u32;
+        for &byte in bytes {
+            crc ^= u32::from(byte);
+            for _ in 0..8 {
+                crc = if crc & 1 == 1 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+            }
+        }
+        !crc
+    }
+
+    #[test]
+    fn tileset_with_shards() {
+        assert_eq!(reference_crc32(b"123456789"), 0xCBF4_3926);
+        let serialized = r#"
+variables:
+    - { name: x, from: 0, to: 9 }
+    - { name: y, from: 0, to: 1 }
+url_template: "https://{{SHARD}}.example.com/tiles/{{x}}_{{y}}.jpg?v=2"
+shard_hosts: [t0, t1, t2, t3]
+        "#;
+        let ts: TileSet = serde_yaml::from_str(serialized).unwrap();
+        let mut used_hosts = std::collections::HashSet::new();
+        for tile in &ts {
+            let url = tile.unwrap().url;
+            let path = &url[url.find("/tiles").unwrap()..];
+            let expected = reference_crc32(path.as_bytes()) % 4;
+            assert_eq!(shard_index(path, 4), expected as usize);
+            assert!(url.starts_with(&format!("https://t{}.example.com/", expected)), "{}", url);
+            used_hosts.insert(expected);
+        }
+        assert_eq!(used_hosts.len(), 4, "the tiles should be spread on all the hosts");
+
+        let ts: TileSet = serde_yaml::from_str(r#"
+variables: [{ name: x, value: 0 }]
+url_template: "https://{{SHARD}}/{{x}}.jpg"
+        "#).unwrap();
+        assert!(matches!(ts.into_iter().next(), Some(Err(UrlTemplateError::NoShardHosts))));
+    }
+
+    #[test]
+    fn shard_hash_is_crc32() {
+        // The check value of CRC-32, which is smaller than the number of shards
+        assert_eq!(shard_index("123456789", u32::MAX as usize), 0xCBF4_3926);
+        assert_eq!(shard_index("/tiles/0_0.jpg", 4), 3);
+        assert_eq!(shard_index("/tiles/3_1.jpg", 4), 2);
+        assert_eq!(shard_index("/tiles/3_1.jpg", 5), 0xD3_3E6E % 5);
+    }
+
+    #[test]
+    fn tileset_with_printf_template() {
+        let printf: TileSet = serde_yaml::from_str(r#"
+variables:
+    - { name: x, from: 0, to: 11 }
+    - { name: y, from: 7, to: 8 }
+url_template: "http://example.com/100%%/tile_%03d_%03d.jpg?v=%1$d"
+format: printf
+        "#).unwrap();
+        let tiles: Vec<_> = printf.into_iter().collect::<Result<_, _>>().unwrap();
+        assert_eq!(tiles.len(), 24);
+        for tile in tiles {
+            let (x, y) = (tile.position.x, tile.position.y);
+            let generic = crate::generic::fill_template("http://example.com/100%/tile_{{X:03}}_{{Y:03}}.jpg?v={{X}}", x, y, 0);
+            assert_eq!(tile.url, generic);
+        }
+
+        // The indices can be numbered, to write y before x
+        let reordered = UrlTemplate::from_printf("%2$d/%1$02d.jpg").unwrap();
+        let mut ctx = evalexpr::HashMapContext::new();
+        ctx.set_value("x".into(), 3.into()).unwrap();
+        ctx.set_value("y".into(), 10.into()).unwrap();
+        assert_eq!(reordered.eval(&ctx, &[]).unwrap(), "10/03.jpg");
+        assert!(UrlTemplate::from_printf("%d_%d_%d").is_err());
+        for template in &["%5d/%d.jpg", "%s/%d.jpg", "%d/%x.jpg", "%d/%d.jpg?q=100%", "%d/%d%20.jpg"] {
+            let err = UrlTemplate::from_printf(template).err();
+            assert!(matches!(err, Some(UrlTemplateError::UnsupportedPrintfSpecifier { .. })), "{}: {:?}", template, err);
+        }
+    }
 }
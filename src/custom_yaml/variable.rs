@@ -1,5 +1,6 @@
-use evalexpr::HashMapContext;
-use itertools::Itertools;
+use std::convert::TryFrom;
+
+use evalexpr::{HashMapContext, Value};
 use regex::Regex;
 use serde::Deserialize;
 
@@ -21,17 +22,31 @@ fn default_step() -> i64 {
     1
 }
 
+fn check_name(name: &str) -> Result<(), BadVariableError> {
+    lazy_static! {
+        static ref RE: Regex = Regex::new(r"^\w+$").unwrap();
+    }
+    if RE.is_match(name) {
+        Ok(())
+    } else {
+        Err(BadVariableError::BadName { name: name.to_string() })
+    }
+}
+
 impl Variable {
     fn check(&self) -> Result<(), BadVariableError> {
-        lazy_static! {
-            static ref RE: Regex = Regex::new(r"^\w+$").unwrap();
-        }
-        if !RE.is_match(&self.name) {
-            return Err(BadVariableError::BadName {
+        check_name(&self.name)?;
+        if self.step == 0 {
+            return Err(BadVariableError::ZeroStep {
                 name: self.name.clone(),
             });
         }
-        let steps = (self.to - self.from) / self.step;
+        let steps = self.to.checked_sub(self.from)
+            .and_then(|range| range.checked_div(self.step))
+            .ok_or_else(|| BadVariableError::TooManyValues {
+                name: self.name.clone(),
+                steps: i64::MAX,
+            })?;
         if steps < 0 {
             return Err(BadVariableError::Infinite {
                 name: self.name.clone(),
@@ -55,12 +70,12 @@ pub struct VariableIterator {
     from: i64,
     to: i64,
     step: i64,
-    current: i64,
+    /// None once the next value would overflow
+    current: Option<i64>,
 }
 
 impl<'a> VariableIterator {
-    fn in_range(&'a self) -> bool {
-        let i = self.current;
+    fn in_range(&'a self, i: i64) -> bool {
         (self.from <= i && i <= self.to) || (self.to <= i && i <= self.from)
     }
 }
@@ -69,13 +84,9 @@ impl Iterator for VariableIterator {
     type Item = i64;
 
     fn next(&mut self) -> Option<Self::Item> {
-        if self.in_range() {
-            let current = self.current;
-            self.current += self.step;
-            Some(current)
-        } else {
-            None
-        }
+        let current = self.current.filter(|&i| self.in_range(i))?;
+        self.current = if self.step == 0 { None } else { current.checked_add(self.step) };
+        Some(current)
     }
 }
 
@@ -88,11 +99,74 @@ impl<'a> IntoIterator for &'a Variable {
             from: self.from,
             to: self.to,
             step: self.step,
-            current: self.from,
+            current: Some(self.from),
+        }
+    }
+}
+
+/// A bound of the range of a dependent variable: a number, or an expression
+/// using the values of the variables that come before it, such as `x + 1`
+#[derive(Clone, Debug, Deserialize)]
+#[serde(untagged)]
+pub enum Bound {
+    Value(i64),
+    Expression(String),
+}
+
+impl Bound {
+    fn check(&self) -> Result<(), BadVariableError> {
+        if let Bound::Expression(expression) = self { evalexpr::build_operator_tree(expression)?; }
+        Ok(())
+    }
+
+    fn eval(&self, context: &HashMapContext) -> Result<i64, BadVariableError> {
+        match self {
+            Bound::Value(value) => Ok(*value),
+            Bound::Expression(expression) => Ok(evalexpr::eval_int_with_context(expression, context)?),
         }
     }
 }
 
+/// Represents a Variable whose range depends on the values of the variables before it,
+/// such as `{ name: y, from: 0, to: "x" }` for a triangular grid.
+/// For the values of the previous variables that give an empty range, it takes no value.
+#[derive(Clone, Debug, Deserialize)]
+pub struct DependentVariable {
+    name: String,
+    from: Bound,
+    to: Bound,
+    #[serde(default = "default_step")]
+    step: i64,
+}
+
+impl DependentVariable {
+    fn check(&self) -> Result<(), BadVariableError> {
+        check_name(&self.name)?;
+        self.from.check()?;
+        self.to.check()?;
+        if self.step == 0 {
+            return Err(BadVariableError::ZeroStep { name: self.name.clone() });
+        }
+        Ok(())
+    }
+
+    fn resolve(&self, context: &HashMapContext) -> Result<VariableIterator, BadVariableError> {
+        let var = Variable {
+            name: self.name.clone(),
+            from: self.from.eval(context)?,
+            to: self.to.eval(context)?,
+            step: self.step,
+        };
+        let is_empty = var.to.checked_sub(var.from)
+            .is_some_and(|range| range != 0 && range.signum() != var.step.signum());
+        if is_empty {
+            return Ok(VariableIterator { from: var.from, to: var.to, step: var.step, current: None });
+        }
+        var.check()?;
+        Ok(var.into_iter())
+    }
+}
+
 /// Represents a Variable that can have only a single value
 #[derive(Deserialize, Clone, Debug)]
 pub struct Constant {
@@ -100,14 +174,32 @@ pub struct Constant {
     value: i64,
 }
 
+/// Represents a Variable that takes each of the given strings in turn.
+/// The position of the current string in the list is available as `{name}_index`,
+/// so that it can be used to compute the position of tiles.
+#[derive(Deserialize, Clone, Debug)]
+pub struct Enumeration {
+    name: String,
+    values: Vec<String>,
+}
+
+impl Enumeration {
+    fn index_name(&self) -> String {
+        format!("{}_index", self.name)
+    }
+}
+
 #[derive(Deserialize, Clone, Debug)]
 #[serde(untagged)]
 pub enum VarOrConst {
     Var(Variable),
     Const(Constant),
+    Enum(Enumeration),
+    Dependent(DependentVariable),
 }
 
 impl VarOrConst {
+    #[cfg(test)]
     pub fn var(name: &str, from: i64, to: i64, step: i64) -> Result<VarOrConst, BadVariableError> {
         let var = Variable {
             name: name.to_string(),
@@ -121,30 +213,95 @@ impl VarOrConst {
         match self {
             VarOrConst::Var(v) => v.name(),
             VarOrConst::Const(c) => &c.name,
+            VarOrConst::Enum(e) => &e.name,
+            VarOrConst::Dependent(d) => &d.name,
         }
     }
-}
-
-impl<'a> IntoIterator for &'a VarOrConst {
-    type Item = i64;
-    type IntoIter = VariableIterator;
 
-    fn into_iter(self) -> Self::IntoIter {
-        match self {
+    fn values(&self, previous: &[(String, Value)]) -> Result<VariableIterator, BadVariableError> {
+        Ok(match self {
             VarOrConst::Var(v) => v.into_iter(),
             VarOrConst::Const(c) => VariableIterator {
                 from: c.value,
                 to: c.value,
-                current: c.value,
+                current: Some(c.value),
                 step: 1,
             },
-        }
+            // The values of an enumeration are iterated on by index
+            VarOrConst::Enum(e) => VariableIterator {
+                from: 0,
+                to: e.values.len() as i64 - 1,
+                current: if e.values.is_empty() { None } else { Some(0) },
+                step: 1,
+            },
+            VarOrConst::Dependent(d) => d.resolve(&context(previous)?)?,
+        })
+    }
+
+    /// The names and values of the variables set by each value of this one,
+    /// given the values of the variables before it
+    fn bindings(&self, previous: &[(String, Value)])
+                -> Result<impl Iterator<Item = Vec<(String, Value)>> + '_, BadVariableError> {
+        Ok(self.values(previous)?.map(move |i| match self {
+            VarOrConst::Enum(e) => vec![
+                (e.name.clone(), e.values[i as usize].as_str().into()),
+                (e.index_name(), i.into()),
+            ],
+            _ => vec![(self.name().to_string(), i.into())],
+        }))
     }
 }
 
+fn context(bindings: &[(String, Value)]) -> Result<HashMapContext, BadVariableError> {
+    use evalexpr::Context;
+    let mut ctx = HashMapContext::new();
+    for (var_name, var_value) in bindings {
+        ctx.set_value(var_name.clone(), var_value.clone())?;
+    }
+    Ok(ctx)
+}
+
+type Contexts<'a> = Box<dyn Iterator<Item = Result<HashMapContext, BadVariableError>> + 'a>;
+
+/// All the combinations of values of the variables, given the values of the ones before them.
+/// The values are evaluated in order, so that the range of a variable can depend on the previous ones.
+fn expand(vars: &[VarOrConst], previous: Vec<(String, Value)>) -> Contexts<'_> {
+    let (var, next_vars) = match vars.split_first() {
+        Some(split) => split,
+        None => return Box::new(std::iter::once(context(&previous))),
+    };
+    match var.bindings(&previous) {
+        Ok(bindings) => Box::new(bindings.flat_map(move |binding| {
+            let mut bound = previous.clone();
+            bound.extend(binding);
+            expand(next_vars, bound)
+        })),
+        Err(e) => Box::new(std::iter::once(Err(e))),
+    }
+}
+
+/// The variables of a tile set. They are validated when they are deserialized,
+/// so that a malformed file is rejected before any tile is generated.
 #[derive(Deserialize, Debug)]
+#[serde(try_from = "Vec<VarOrConst>")]
 pub struct Variables(Vec<VarOrConst>);
 
+impl TryFrom<Vec<VarOrConst>> for Variables {
+    type Error = BadVariableError;
+
+    fn try_from(vars: Vec<VarOrConst>) -> Result<Self, Self::Error> {
+        for var in vars.iter() {
+            match var {
+                Var(v) => v.check()?,
+                VarOrConst::Enum(e) => check_name(&e.name)?,
+                VarOrConst::Dependent(d) => d.check()?,
+                VarOrConst::Const(_) => {}
+            }
+        }
+        Ok(Variables(vars))
+    }
+}
+
 impl Variables {
     #[cfg(test)]
     pub fn new(vars: Vec<VarOrConst>) -> Variables {
@@ -153,19 +310,9 @@ impl Variables {
     pub fn iter_contexts(
         &self,
     ) -> impl Iterator<Item = Result<HashMapContext, BadVariableError>> + '_ {
-        self.0
-            .iter()
-            .map(|variable| variable.into_iter().map(move |val| (variable.name(), val)))
-            .multi_cartesian_product()
-            .map(|var_values| {
-                // Iterator on all the combination of values for the variables
-                use evalexpr::Context;
-                let mut ctx = HashMapContext::new();
-                for (var_name, var_value) in var_values {
-                    ctx.set_value(var_name.into(), var_value.into())?;
-                }
-                Ok(ctx)
-            })
+        // Without variables, there is no tile
+        let vars = if self.0.is_empty() { None } else { Some(&self.0) };
+        vars.into_iter().flat_map(|vars| expand(vars, vec![]))
     }
 }
 
@@ -173,6 +320,7 @@ custom_error! {pub BadVariableError
     BadName{name: String} = "invalid variable name: '{name}'",
     TooManyValues{name:String, steps:i64}= "the range of values for {name} is too wide: {steps} steps",
     Infinite{name:String}= "the range of values for {name} is incorrect",
+    ZeroStep{name:String}= "the step of {name} cannot be zero",
     EvalError{source:evalexpr::EvalexprError} = "{source}",
 }
 
@@ -209,6 +357,61 @@ mod tests {
             .contains("invalid variable name"))
     }
 
+    #[test]
+    fn dependent_ranges() {
+        let vars: Variables = serde_yaml::from_str("[
+            {name: x, from: 0, to: 3},
+            {name: y, from: 0, to: x},
+        ]").unwrap();
+        let values: Vec<(i64, i64)> = vars.iter_contexts()
+            .map(|ctx| {
+                let ctx = ctx.unwrap();
+                let get = |name| ctx.get_value(name).unwrap().as_int().unwrap();
+                (get("x"), get("y"))
+            })
+            .collect();
+        // A triangular grid, in which y goes from 0 to x
+        assert_eq!(values, vec![
+            (0, 0),
+            (1, 0), (1, 1),
+            (2, 0), (2, 1), (2, 2),
+            (3, 0), (3, 1), (3, 2), (3, 3),
+        ]);
+        let empty: Variables = serde_yaml::from_str("[{name: x, from: 0, to: 2}, {name: y, from: 1, to: 'x - 1'}]")
+            .unwrap();
+        assert_eq!(empty.iter_contexts().count(), 1, "only x=2 gives a non-empty range to y");
+        let parsed: Result<Variables, _> = serde_yaml::from_str("[{name: y, from: 0, to: '(x'}]");
+        assert!(parsed.is_err());
+        let undefined: Variables = serde_yaml::from_str("[{name: y, from: 0, to: 'z'}]").unwrap();
+        assert!(undefined.iter_contexts().next().unwrap().is_err());
+    }
+
+    #[test]
+    fn variable_validity_check_step() {
+        let err = VarOrConst::var("x", 0, 10, 0).unwrap_err();
+        assert!(err.to_string().contains("cannot be zero"), "{}", err);
+        let err = VarOrConst::var("x", i64::MIN, i64::MAX, 1).unwrap_err();
+        assert!(err.to_string().contains("too wide"), "{}", err);
+    }
+
+    #[test]
+    fn variable_iteration_does_not_overflow() {
+        let var = Variable {
+            name: "x".to_string(),
+            from: i64::MAX - 1,
+            to: i64::MAX,
+            step: 2,
+        };
+        assert_eq!(var.into_iter().collect::<Vec<i64>>(), vec![i64::MAX - 1]);
+    }
+
+    #[test]
+    fn invalid_variables_are_rejected_when_parsing() {
+        let parsed: Result<Variables, _> = serde_yaml::from_str("[{name: x, from: 0, to: 5, step: 0}]");
+        let err = parsed.unwrap_err().to_string();
+        assert!(err.contains("cannot be zero"), "{}", err);
+    }
+
     #[test]
     fn iter_contexts() {
         let vars = Variables(vec![
@@ -229,4 +432,17 @@ mod tests {
         assert_eq!(Some(&1.into()), ctxs[3].get_value("x"));
         assert_eq!(Some(&9.into()), ctxs[3].get_value("y"));
     }
+
+    #[test]
+    fn enumerated_values() {
+        let vars: Variables = serde_yaml::from_str("[{name: side, values: [north, south]}]").unwrap();
+        let ctxs: Vec<_> = vars.iter_contexts().collect::<Result<_, _>>().unwrap();
+        assert_eq!(2, ctxs.len());
+        assert_eq!(Some(&"south".into()), ctxs[1].get_value("side"));
+        assert_eq!(Some(&1.into()), ctxs[1].get_value("side_index"));
+        let empty: Variables = serde_yaml::from_str("[{name: side, values: []}]").unwrap();
+        assert_eq!(empty.iter_contexts().count(), 0);
+        let parsed: Result<Variables, _> = serde_yaml::from_str("[{name: 'a b', values: [c]}]");
+        assert!(parsed.unwrap_err().to_string().contains("invalid variable name"));
+    }
 }
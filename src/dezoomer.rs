@@ -3,11 +3,13 @@ use std::collections::HashMap;
 use std::error::Error;
 use std::fmt::Debug;
 use std::str::FromStr;
+use std::time::Duration;
 
 pub use crate::errors::DezoomerError;
 
 pub use super::Vec2d;
 use super::ZoomError;
+use crate::generic::GridFile;
 use std::fmt;
 use crate::dezoomer::PageContents::Success;
 
@@ -104,6 +106,8 @@ pub struct TileFetchResult {
     pub count: u64,
     pub successes: u64,
     pub tile_size: Option<Vec2d>,
+    /// Time taken to download the tiles
+    pub duration: Option<Duration>,
 }
 
 impl TileFetchResult {
@@ -115,6 +119,19 @@ impl TileFetchResult {
     }
 }
 
+/// The layout of the tiles of an image, once it is known
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TileGrid {
+    /// Position of the top left tile
+    pub origin: Vec2d,
+    /// Number of columns and rows of tiles
+    pub tile_count: Vec2d,
+    /// Size of a single full tile
+    pub tile_size: Vec2d,
+    /// The width and height of the resulting image
+    pub image_size: Vec2d,
+}
+
 type PostProcessResult = Result<Vec<u8>, Box<dyn Error + Send>>;
 // TODO : fix
 // see: https://github.com/rust-lang/rust/issues/63033
@@ -124,17 +141,42 @@ pub enum PostProcessFn {
     None,
 }
 
+/// Where the position of a tile in the image comes from
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PositionSource {
+    /// The position given by the dezoomer in the tile reference
+    #[default]
+    Reference,
+    /// The XPosition and YPosition tags, in pixels, of the XMP metadata of the tile.
+    /// The position given by the dezoomer is used for tiles that do not have them.
+    Exif,
+}
+
 /// A single tiled image
 pub trait TileProvider: Debug {
     /// Provide a list of image tiles. Should be called repetitively until it returns
     /// an empty list. Each new call takes the results of the previous tile fetch as a parameter.
     fn next_tiles(&mut self, previous: Option<TileFetchResult>) -> Vec<TileReference>;
 
+    /// All the tiles of the level, when they are known before it is downloaded.
+    /// Listing them does not change the state of the level.
+    fn known_tiles(&self) -> Option<Vec<TileReference>> {
+        None
+    }
+
     /// A function that takes the downloaded tile bytes and decodes them
     fn post_process_fn(&self) -> PostProcessFn {
         PostProcessFn::None
     }
 
+    /// Where to read the positions of the tiles from.
+    /// When they are read from the tiles themselves, they are known only after decoding,
+    /// so the size of the level should not be hinted.
+    fn position_source(&self) -> PositionSource {
+        PositionSource::Reference
+    }
+
     /// The name of the format
     fn name(&self) -> String {
         format!("{:?}", self)
@@ -143,6 +185,15 @@ pub trait TileProvider: Debug {
     /// The title of the image
     fn title(&self) -> Option<String> { None }
 
+    /// Whether this level is one of several distinct images described by the same input,
+    /// such as the pages of a book, instead of one of the sizes of a single image.
+    /// When all the levels are distinct images, they are all downloaded.
+    fn is_distinct_image(&self) -> bool { false }
+
+    /// Whether the tiles are guessed from a template rather than described by the server.
+    /// The first tiles of such levels are checked for signs that the template is wrong.
+    fn is_guessed(&self) -> bool { false }
+
     /// The width and height of the image. Can be unknown when dezooming starts
     fn size_hint(&self) -> Option<Vec2d> {
         None
@@ -152,6 +203,23 @@ pub trait TileProvider: Debug {
     fn http_headers(&self) -> HashMap<String, String> {
         HashMap::new()
     }
+
+    /// Additional http headers to use when requesting tiles from specific hosts.
+    /// They take precedence over the headers returned by `http_headers`.
+    fn host_http_headers(&self) -> HashMap<String, HashMap<String, String>> {
+        HashMap::new()
+    }
+
+    /// The layout of the tiles. Can be unknown until all the tiles have been listed
+    fn tile_grid(&self) -> Option<TileGrid> {
+        None
+    }
+
+    /// For levels whose tiles follow a template, the layout of the tiles once it is known,
+    /// which can be saved in order to download the level again without searching for it
+    fn grid_file(&self) -> Option<GridFile> {
+        None
+    }
 }
 
 /// Used to iterate over all the batches of tiles in a zoom level
@@ -179,6 +247,12 @@ impl<'a> ZoomLevelIter<'a> {
     pub fn size_hint(&self) -> Option<Vec2d> {
         self.zoom_level.size_hint()
     }
+    pub fn tile_grid(&self) -> Option<TileGrid> {
+        self.zoom_level.tile_grid()
+    }
+    pub fn grid_file(&self) -> Option<GridFile> {
+        self.zoom_level.grid_file()
+    }
 }
 
 /// Shortcut to return a single zoom level from a dezoomer
@@ -197,11 +271,16 @@ pub trait TilesRect: Debug {
         TileReference {
             url: self.tile_url(pos),
             position: self.tile_size() * pos,
+            cell_size: None,
         }
     }
     fn post_process_fn(&self) -> PostProcessFn {
         PostProcessFn::None
     }
+    /// Headers to use when requesting tiles
+    fn http_headers(&self) -> HashMap<String, String> {
+        HashMap::new()
+    }
 
     fn tile_count(&self) -> u32 {
         let Vec2d { x, y } = self.size().ceil_div(self.tile_size());
@@ -216,13 +295,16 @@ impl<T: TilesRect> TileProvider for T {
         if previous.is_some() {
             return vec![];
         }
+        self.known_tiles().unwrap_or_default()
+    }
 
+    fn known_tiles(&self) -> Option<Vec<TileReference>> {
         let tile_size = self.tile_size();
         let Vec2d { x: w, y: h } = self.size().ceil_div(tile_size);
         let this: &T = self.borrow(); // Immutable borrow
-        (0..h)
+        Some((0..h)
             .flat_map(move |y| (0..w).map(move |x| this.tile_ref(Vec2d { x, y })))
-            .collect()
+            .collect())
     }
 
     fn post_process_fn(&self) -> PostProcessFn {
@@ -247,10 +329,16 @@ impl<T: TilesRect> TileProvider for T {
     }
 
     fn http_headers(&self) -> HashMap<String, String> {
-        let mut headers = HashMap::new();
-        // By default, use the first tile as the referer, so that it is on the same domain
-        headers.insert("Referer".into(), self.tile_url(Vec2d::default()));
-        headers
+        TilesRect::http_headers(self)
+    }
+
+    fn tile_grid(&self) -> Option<TileGrid> {
+        Some(TileGrid {
+            origin: self.tile_ref(Vec2d::default()).position,
+            tile_count: self.size().ceil_div(self.tile_size()),
+            tile_size: self.tile_size(),
+            image_size: self.size(),
+        })
     }
 }
 
@@ -258,6 +346,9 @@ impl<T: TilesRect> TileProvider for T {
 pub struct TileReference {
     pub url: String,
     pub position: Vec2d,
+    /// The size the tile covers in the image, for sources that mix tiles of different resolutions.
+    /// Tiles of another size are scaled to it.
+    pub cell_size: Option<Vec2d>,
 }
 
 impl FromStr for TileReference {
@@ -275,6 +366,7 @@ impl FromStr for TileReference {
             Ok(TileReference {
                 url: String::from(url),
                 position: Vec2d { x, y },
+                cell_size: None,
             })
         } else {
             Err(make_error())
@@ -320,6 +412,7 @@ mod tests {
                 count: 0,
                 successes: 0,
                 tile_size: None,
+                duration: None,
             });
         };
         assert_eq!(
@@ -328,20 +421,30 @@ mod tests {
                 TileReference {
                     url: "0,0".into(),
                     position: Vec2d { x: 0, y: 0 },
+                    cell_size: None,
                 },
                 TileReference {
                     url: "1,0".into(),
                     position: Vec2d { x: 60, y: 0 },
+                    cell_size: None,
                 },
                 TileReference {
                     url: "0,1".into(),
                     position: Vec2d { x: 0, y: 60 },
+                    cell_size: None,
                 },
                 TileReference {
                     url: "1,1".into(),
                     position: Vec2d { x: 60, y: 60 },
+                    cell_size: None,
                 }
             ]
         );
+        assert_eq!(lvl.tile_grid(), Some(TileGrid {
+            origin: Vec2d { x: 0, y: 0 },
+            tile_count: Vec2d { x: 2, y: 2 },
+            tile_size: Vec2d { x: 60, y: 60 },
+            image_size: Vec2d { x: 100, y: 100 },
+        }));
     }
 }
@@ -124,6 +124,7 @@ impl TilesRect for DziLevel {
         TileReference {
             url: self.tile_url(pos),
             position: self.tile_size() * pos - delta,
+            cell_size: None,
         }
     }
 
@@ -1,10 +1,10 @@
 use std::path::{PathBuf, Path};
 use std::io;
-use image::{GenericImage, ImageBuffer, Pixel, ImageResult};
-use log::debug;
+use image::{DynamicImage, GenericImageView, ImageBuffer, Pixel, ImageResult};
+use log::{debug, warn};
 
 use crate::Vec2d;
-use crate::encoder::{Encoder, crop_tile};
+use crate::encoder::{BitDepth, Encoder, OverlapMode, crop_tile, draw_tile};
 use crate::tile::Tile;
 use crate::ZoomError;
 use std::io::BufWriter;
@@ -20,34 +20,65 @@ fn empty_buffer(size: Vec2d) -> CanvasBuffer {
 }
 
 pub struct Canvas {
-    image: CanvasBuffer,
+    /// Allocated when the first tile is added, once the bit depth of the image is known
+    image: Option<DynamicImage>,
+    size: Vec2d,
+    bit_depth: Option<BitDepth>,
     destination: PathBuf,
     image_writer: ImageWriter,
+    overlap: OverlapMode,
 }
 
 
 impl Canvas {
-    pub fn new(destination: PathBuf, size: Vec2d, image_writer: ImageWriter) -> Result<Self, ZoomError> {
+    pub fn new(destination: PathBuf, size: Vec2d, image_writer: ImageWriter, bit_depth: Option<BitDepth>, overlap: OverlapMode) -> Result<Self, ZoomError> {
         Ok(Canvas {
-            image: empty_buffer(size),
+            image: None,
+            size,
+            bit_depth,
             destination,
             image_writer,
+            overlap,
+        })
+    }
+
+    fn image(&mut self, bit_depth: BitDepth) -> &mut DynamicImage {
+        let size = self.size;
+        self.image.get_or_insert_with(|| match bit_depth {
+            BitDepth::Eight => DynamicImage::ImageRgba8(empty_buffer(size)),
+            BitDepth::Sixteen => DynamicImage::new_rgba16(size.x, size.y),
         })
     }
 }
 
 impl Encoder for Canvas {
     fn add_tile(&mut self, tile: Tile) -> io::Result<()> {
-        let sub_tile = crop_tile(&tile, self.size());
-        let Vec2d { x, y } = tile.position();
+        let size = self.size;
+        let bit_depth = self.bit_depth.unwrap_or_else(|| BitDepth::of(&tile.image));
+        let position = tile.position();
+        let overlap = self.overlap;
+        let (sub_width, sub_height) = crop_tile(&tile, size).dimensions();
+        if sub_width == 0 || sub_height == 0 {
+            debug!("Ignoring {:?}, which is outside of the image", tile);
+            return Ok(());
+        }
         debug!("Copying tile data from {:?}", tile);
-        self.image.copy_from(&sub_tile, x, y).map_err(|_err| {
+        let copied = match self.image(bit_depth) {
+            DynamicImage::ImageRgba16(image) => {
+                let tile16 = tile.image.to_rgba16();
+                draw_tile(image, &tile16.view(0, 0, sub_width, sub_height), position, overlap)
+            }
+            image => draw_tile(image, &crop_tile(&tile, size), position, overlap),
+        };
+        copied.map_err(|_err| {
             io::Error::new(io::ErrorKind::InvalidData, "tile too large for image")
         })
     }
 
     fn finalize(&mut self) -> io::Result<()> {
-        self.image_writer.write(&self.image, &self.destination).map_err(|e| {
+        self.image(BitDepth::Eight);
+        let image = self.image.as_ref().expect("the image was just created");
+        self.image_writer.write(image, &self.destination).map_err(|e| {
             match e {
                 image::ImageError::IoError(e) => e,
                 other => io::Error::new(io::ErrorKind::Other, other)
@@ -56,22 +87,55 @@ impl Encoder for Canvas {
         Ok(())
     }
 
-    fn size(&self) -> Vec2d { self.image.dimensions().into() }
+    fn size(&self) -> Vec2d { self.size }
 }
 
 pub enum ImageWriter {
     Generic,
-    Jpeg { quality: u8 },
+    /// When a target size in bytes is given, the quality is the one that is tried first
+    Jpeg { quality: u8, dpi: Option<u16>, target_size: Option<u64> },
+    /// Opaque images are written with 24 bits per pixel, which all programs can read,
+    /// and the other ones with an alpha channel, in a version 4 header
+    Bmp,
 }
 
+/// Maximum number of times an image is encoded to find the quality that gives the target file size.
+/// Qualities go from 1 to 100, so a binary search needs at most 7 of them.
+const MAX_QUALITY_ITERATIONS: usize = 8;
+
 impl ImageWriter {
-    fn write(&self, image: &CanvasBuffer, destination: &Path) -> ImageResult<()> {
+    fn write(&self, image: &DynamicImage, destination: &Path) -> ImageResult<()> {
         match *self {
-            ImageWriter::Jpeg { quality } => {
-                let file = File::create(destination)?;
-                let fout = &mut BufWriter::new(file);
-                let mut encoder = image::codecs::jpeg::JpegEncoder::new_with_quality(fout, quality);
-                encoder.encode(image, image.width(), image.height(), Pix::COLOR_TYPE)?;
+            ImageWriter::Jpeg { quality, dpi, target_size } => {
+                let converted;
+                let image = match image.as_rgba8() {
+                    Some(image) => image,
+                    None => { converted = image.to_rgba8(); &converted }
+                };
+                let fout = &mut BufWriter::new(File::create(destination)?);
+                if let Some(target_size) = target_size {
+                    let (quality, bytes) = encode_jpeg_under(image, target_size, quality, dpi)?;
+                    debug!("Encoded the jpeg image with a quality of {} in {} bytes", quality, bytes.len());
+                    io::Write::write_all(fout, &bytes)?;
+                } else {
+                    encode_jpeg(fout, image, quality, dpi)?;
+                }
+            },
+            ImageWriter::Bmp => {
+                let fout = &mut BufWriter::new(File::create(destination)?);
+                let mut encoder = image::codecs::bmp::BmpEncoder::new(fout);
+                let converted;
+                let rgba = match image.as_rgba8() {
+                    Some(image) => image,
+                    None => { converted = image.to_rgba8(); &converted }
+                };
+                if rgba.pixels().all(|p| p[3] == u8::MAX) {
+                    // The only copy of the pixels, without their alpha channel
+                    let rgb: Vec<u8> = rgba.as_raw().chunks_exact(4).flat_map(|p| &p[..3]).copied().collect();
+                    encoder.encode(&rgb, rgba.width(), rgba.height(), image::ColorType::Rgb8)?;
+                } else {
+                    encoder.encode(rgba, rgba.width(), rgba.height(), image::ColorType::Rgba8)?;
+                }
             },
             ImageWriter::Generic => {
                 image.save(destination)?;
@@ -80,3 +144,138 @@ impl ImageWriter {
         Ok(())
     }
 }
+
+fn encode_jpeg<W: io::Write>(mut out: W, image: &CanvasBuffer, quality: u8, dpi: Option<u16>) -> ImageResult<()> {
+    let mut encoder = image::codecs::jpeg::JpegEncoder::new_with_quality(&mut out, quality);
+    if let Some(dpi) = dpi {
+        encoder.set_pixel_density(image::codecs::jpeg::PixelDensity::dpi(dpi));
+    }
+    encoder.encode(image, image.width(), image.height(), Pix::COLOR_TYPE)
+}
+
+/// Finds, by a binary search starting from the given quality, the best quality
+/// whose encoded image is at most `target_size` bytes, and returns it with the encoded image.
+/// When no quality gives a small enough file, the lowest quality is used.
+fn encode_jpeg_under(image: &CanvasBuffer, target_size: u64, first_quality: u8, dpi: Option<u16>)
+                     -> ImageResult<(u8, Vec<u8>)> {
+    let encode = |quality: u8| -> ImageResult<Vec<u8>> {
+        let mut bytes = vec![];
+        encode_jpeg(&mut bytes, image, quality, dpi)?;
+        Ok(bytes)
+    };
+    let (mut low, mut high) = (1u8, 100u8);
+    let mut quality = first_quality.clamp(low, high);
+    let mut best = None;
+    for _ in 0..MAX_QUALITY_ITERATIONS {
+        let bytes = encode(quality)?;
+        debug!("A jpeg quality of {} gives {} bytes", quality, bytes.len());
+        if bytes.len() as u64 <= target_size {
+            best = Some((quality, bytes));
+            low = quality + 1;
+        } else {
+            high = quality - 1;
+        }
+        if low > high { break; }
+        quality = low + (high - low) / 2;
+    }
+    match best {
+        Some(best) => Ok(best),
+        None => {
+            warn!("The image is larger than the target size of {} bytes, even with the lowest jpeg quality", target_size);
+            Ok((1, encode(1)?))
+        }
+    }
+}
+
+#[test]
+fn test_jpeg_target_size() {
+    // A noisy image, that does not compress well
+    let image = ImageBuffer::from_fn(200, 200, |x, y| {
+        let v = ((x * 7919 + y * 104_729) % 251) as u8;
+        Pix::from_channels(v, v.wrapping_mul(3), v.wrapping_mul(7), 255)
+    });
+    let default_quality = 80;
+    let mut default_bytes = vec![];
+    encode_jpeg(&mut default_bytes, &image, default_quality, None).unwrap();
+    let target_size = default_bytes.len() as u64 / 2;
+    let (quality, bytes) = encode_jpeg_under(&image, target_size, default_quality, None).unwrap();
+    assert!(bytes.len() as u64 <= target_size, "{} bytes for a target of {}", bytes.len(), target_size);
+    assert!(quality < default_quality, "quality {}", quality);
+    // The next quality would be too large
+    let mut larger = vec![];
+    encode_jpeg(&mut larger, &image, quality + 1, None).unwrap();
+    assert!(larger.len() as u64 > target_size);
+    assert!(image::load_from_memory(&bytes).is_ok());
+    // Impossible targets give the smallest possible file
+    assert_eq!(encode_jpeg_under(&image, 10, default_quality, None).unwrap().0, 1);
+}
+
+#[test]
+fn test_bmp_round_trip() {
+    let dir = tempdir::TempDir::new("dezoomify-rs-bmp").unwrap();
+    let tile = |x: u32, y: u32, color: [u8; 3]| Tile {
+        position: Vec2d { x, y },
+        image: DynamicImage::ImageRgb8(ImageBuffer::from_fn(2, 3, |i, j| {
+            image::Rgb([color[0], color[1] + i as u8, color[2] + j as u8])
+        })),
+        encoded: None,
+    };
+    let stitch = |name: &str, tiles: Vec<Tile>| {
+        let path = dir.path().join(name);
+        let mut canvas = Canvas::new(path.clone(), Vec2d { x: 4, y: 3 }, ImageWriter::Bmp, None, OverlapMode::Crop).unwrap();
+        for tile in tiles { canvas.add_tile(tile).unwrap(); }
+        canvas.finalize().unwrap();
+        image::open(path).unwrap()
+    };
+    let opaque = stitch("opaque.bmp", vec![tile(0, 0, [10, 20, 30]), tile(2, 0, [200, 100, 50])]);
+    assert_eq!(opaque.color(), image::ColorType::Rgb8);
+    assert_eq!(opaque.dimensions(), (4, 3));
+    assert_eq!(opaque.get_pixel(1, 2), image::Rgba([10, 21, 32, 255]));
+    assert_eq!(opaque.get_pixel(3, 0), image::Rgba([200, 101, 50, 255]));
+    // The part of the image without tiles stays transparent
+    let transparent = stitch("transparent.bmp", vec![tile(0, 0, [10, 20, 30])]);
+    assert_eq!(transparent.color(), image::ColorType::Rgba8);
+    assert_eq!(transparent.get_pixel(1, 1), image::Rgba([10, 21, 31, 255]));
+    assert_eq!(transparent.get_pixel(3, 2)[3], 0);
+    assert!(crate::encoder::check_bmp_size(Vec2d { x: 30_000, y: 30_000 }).is_ok());
+    match crate::encoder::check_bmp_size(Vec2d { x: 40_000, y: 30_000 }) {
+        Err(ZoomError::BmpTooLarge { width: 40_000, height: 30_000, megabytes: 4800 }) => {}
+        other => panic!("The bmp image should be too large, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_blended_overlap() {
+    // Two tiles with feathered edges: a red one, and a blue one that overlaps its last two columns
+    let tile = |x: u32, color: [u8; 3], alphas: [u8; 4]| Tile {
+        position: Vec2d { x, y: 0 },
+        image: DynamicImage::ImageRgba8(ImageBuffer::from_fn(4, 1, |i, _| {
+            image::Rgba([color[0], color[1], color[2], alphas[i as usize]])
+        })),
+        encoded: None,
+    };
+    let stitch = |overlap: OverlapMode| {
+        let mut canvas = Canvas::new(PathBuf::new(), Vec2d { x: 6, y: 1 }, ImageWriter::Generic, None, overlap).unwrap();
+        canvas.add_tile(tile(0, [255, 0, 0], [255, 255, 255, 128])).unwrap();
+        canvas.add_tile(tile(2, [0, 0, 255], [64, 128, 255, 255])).unwrap();
+        canvas.image.unwrap().to_rgba8()
+    };
+    let cropped = stitch(OverlapMode::Crop);
+    assert_eq!(cropped.get_pixel(2, 0), &image::Rgba([0, 0, 255, 64]));
+    let blended = stitch(OverlapMode::Blend);
+    // Porter-Duff "over": the blue tile over the red one
+    let over = |red_alpha: f32, blue_alpha: f32| {
+        let alpha = blue_alpha + red_alpha * (1. - blue_alpha);
+        let red = red_alpha * (1. - blue_alpha) / alpha;
+        [red * 255., 0., blue_alpha / alpha * 255., alpha * 255.]
+    };
+    for (x, expected) in [(2, over(1., 64. / 255.)), (3, over(128. / 255., 128. / 255.))].iter() {
+        let actual = blended.get_pixel(*x, 0);
+        for (a, e) in actual.0.iter().zip(expected.iter()) {
+            assert!((f32::from(*a) - e).abs() <= 1., "pixel {}: {:?}, expected {:?}", x, actual, expected);
+        }
+    }
+    // Outside of the overlap, the pixels of each tile are unchanged
+    assert_eq!(blended.get_pixel(0, 0), &image::Rgba([255, 0, 0, 255]));
+    assert_eq!(blended.get_pixel(5, 0), &image::Rgba([0, 0, 255, 255]));
+}
@@ -0,0 +1,186 @@
+use std::sync::Arc;
+
+use image::{DynamicImage, GenericImage, GenericImageView};
+use log::debug;
+
+use crate::encoder::{BitDepth, OverlapMode, draw_tile};
+use crate::tile::Tile;
+use crate::Vec2d;
+
+/// Position and size of a tile, with the file it was decoded from when it was kept
+type TileArea = (Vec2d, Vec2d, Option<Arc<[u8]>>);
+
+/// An image that is enlarged as tiles are added to it,
+/// used to assemble tiles while the final size of the image is still unknown.
+/// Each dimension is at least doubled when the canvas has to grow,
+/// so that the pixels are copied only a logarithmic number of times.
+/// The canvas has an alpha channel only once a tile has one, or when tiles are blended.
+pub struct GrowingCanvas {
+    /// Allocated when the first tile is added, once the bit depth of the image is known
+    image: Option<DynamicImage>,
+    /// Areas covered by the tiles, in the order in which they were added
+    tiles: Vec<TileArea>,
+    /// Bottom right corner of the area covered by tiles
+    extent: Vec2d,
+    bit_depth: Option<BitDepth>,
+    alpha: bool,
+    overlap: OverlapMode,
+}
+
+impl GrowingCanvas {
+    pub fn new(bit_depth: Option<BitDepth>, overlap: OverlapMode) -> Self {
+        let alpha = overlap == OverlapMode::Blend;
+        GrowingCanvas { image: None, tiles: vec![], extent: Vec2d::default(), bit_depth, alpha, overlap }
+    }
+
+    /// Size of the smallest image that contains all the tiles added so far
+    pub fn extent(&self) -> Vec2d { self.extent }
+
+    pub fn add_tile(&mut self, tile: Tile) {
+        let bit_depth = self.bit_depth.unwrap_or_else(|| BitDepth::of(&tile.image));
+        self.bit_depth = Some(bit_depth);
+        if !self.alpha && tile.image.color().has_alpha() {
+            self.alpha = true;
+            self.image = self.image.take().map(|image| with_alpha(image, bit_depth));
+        }
+        self.extent = self.extent.max(tile.bottom_right());
+        let needed = self.extent;
+        let position = tile.position();
+        let overlap = self.overlap;
+        self.tiles.push((tile.position(), tile.size(), tile.encoded.clone()));
+        let alpha = self.alpha;
+        // Canvases without alpha channel are only used when tiles are not blended
+        let copied = match self.reserve(needed, bit_depth, alpha) {
+            DynamicImage::ImageRgb8(image) => image.copy_from(&tile.image.to_rgb8(), position.x, position.y),
+            DynamicImage::ImageRgb16(image) => image.copy_from(&tile.image.to_rgb16(), position.x, position.y),
+            DynamicImage::ImageRgba16(image) => draw_tile(image, &tile.image.to_rgba16(), position, overlap),
+            image => draw_tile(image, &tile.image, position, overlap),
+        };
+        copied.expect("the canvas was enlarged to fit the tile");
+    }
+
+    /// The tiles that were added, cut back out of the canvas.
+    /// Where tiles overlap, all of them contain the pixels of the last one,
+    /// as when they are added in order to an image of known size.
+    /// Blended tiles are given as a single tile covering all of them,
+    /// since blending their composited pixels again would change them.
+    pub fn into_tiles(self) -> impl Iterator<Item=Tile> {
+        let image = self.image;
+        let tiles = if self.overlap == OverlapMode::Blend && !self.tiles.is_empty() {
+            vec![(Vec2d::default(), self.extent, None)]
+        } else {
+            self.tiles
+        };
+        tiles.into_iter().filter_map(move |(position, size, encoded)| {
+            let image = image.as_ref()?.crop_imm(position.x, position.y, size.x, size.y);
+            Some(Tile { image, position, encoded })
+        })
+    }
+
+    /// Enlarge the canvas, if needed, so that it is at least of the given size
+    fn reserve(&mut self, needed: Vec2d, bit_depth: BitDepth, alpha: bool) -> &mut DynamicImage {
+        let current = self.image.as_ref()
+            .map(|image| Vec2d { x: image.width(), y: image.height() })
+            .unwrap_or_default();
+        if needed.x > current.x || needed.y > current.y {
+            let size = Vec2d {
+                x: if needed.x > current.x { needed.x.max(current.x.saturating_mul(2)) } else { current.x },
+                y: if needed.y > current.y { needed.y.max(current.y.saturating_mul(2)) } else { current.y },
+            };
+            debug!("Growing the canvas from {} to {}", current, size);
+            let mut grown = match (bit_depth, alpha) {
+                (BitDepth::Eight, false) => DynamicImage::new_rgb8(size.x, size.y),
+                (BitDepth::Eight, true) => DynamicImage::new_rgba8(size.x, size.y),
+                (BitDepth::Sixteen, false) => DynamicImage::new_rgb16(size.x, size.y),
+                (BitDepth::Sixteen, true) => DynamicImage::new_rgba16(size.x, size.y),
+            };
+            if let Some(old) = self.image.take() {
+                // The old canvas has the same color type as the new one
+                match (&mut grown, &old) {
+                    (DynamicImage::ImageRgb8(image), DynamicImage::ImageRgb8(old)) => image.copy_from(old, 0, 0),
+                    (DynamicImage::ImageRgb16(image), DynamicImage::ImageRgb16(old)) => image.copy_from(old, 0, 0),
+                    (DynamicImage::ImageRgba16(image), DynamicImage::ImageRgba16(old)) => image.copy_from(old, 0, 0),
+                    (image, old) => image.copy_from(old, 0, 0),
+                }.expect("the grown canvas is larger than the old one");
+            }
+            self.image = Some(grown);
+        }
+        self.image.as_mut().expect("the canvas was just allocated")
+    }
+}
+
+/// Adds an alpha channel to the canvas, where the pixels that were drawn are opaque
+fn with_alpha(image: DynamicImage, bit_depth: BitDepth) -> DynamicImage {
+    match bit_depth {
+        BitDepth::Eight => DynamicImage::ImageRgba8(image.to_rgba8()),
+        BitDepth::Sixteen => DynamicImage::ImageRgba16(image.to_rgba16()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{ColorType, ImageBuffer, Rgb, Rgba};
+
+    use super::*;
+
+    fn tile(x: u32, y: u32, color: u8) -> Tile {
+        Tile {
+            position: Vec2d { x, y },
+            image: DynamicImage::ImageRgb8(ImageBuffer::from_pixel(3, 2, Rgb([color, color, color]))),
+            encoded: None,
+        }
+    }
+
+    #[test]
+    fn test_growth() {
+        let mut canvas = GrowingCanvas::new(None, OverlapMode::Crop);
+        assert_eq!(GrowingCanvas::new(None, OverlapMode::Crop).into_tiles().count(), 0);
+        canvas.add_tile(tile(3, 0, 1));
+        assert_eq!(canvas.image.as_ref().unwrap().dimensions(), (6, 2));
+        canvas.add_tile(tile(6, 2, 2));
+        // At least doubled in each direction
+        assert_eq!(canvas.image.as_ref().unwrap().dimensions(), (12, 4));
+        canvas.add_tile(tile(1, 0, 3));
+        assert_eq!(canvas.extent(), Vec2d { x: 9, y: 4 });
+
+        let tiles: Vec<Tile> = canvas.into_tiles().collect();
+        let positions: Vec<Vec2d> = tiles.iter().map(|t| t.position()).collect();
+        assert_eq!(positions, vec![Vec2d { x: 3, y: 0 }, Vec2d { x: 6, y: 2 }, Vec2d { x: 1, y: 0 }]);
+        assert!(tiles.iter().all(|t| t.image.dimensions() == (3, 2)));
+        assert!(tiles.iter().all(|t| t.image.color() == ColorType::Rgb8), "the tiles have no alpha channel");
+        // The first tile was partly covered by the last one
+        assert_eq!(tiles[0].image.get_pixel(0, 0), Rgba([3, 3, 3, 255]));
+        assert_eq!(tiles[0].image.get_pixel(1, 0), Rgba([1, 1, 1, 255]));
+        assert_eq!(tiles[1].image.get_pixel(2, 1), Rgba([2, 2, 2, 255]));
+        assert_eq!(tiles[2].image.get_pixel(0, 0), Rgba([3, 3, 3, 255]));
+    }
+
+    #[test]
+    fn test_sixteen_bits() {
+        let mut canvas = GrowingCanvas::new(None, OverlapMode::Crop);
+        canvas.add_tile(Tile {
+            position: Vec2d { x: 1, y: 0 },
+            image: DynamicImage::ImageRgb16(ImageBuffer::from_pixel(1, 1, Rgb([1000, 2000, 3000]))),
+            encoded: None,
+        });
+        canvas.add_tile(Tile { position: Vec2d { x: 0, y: 1 }, ..tile(0, 0, 0) });
+        let result = canvas.into_tiles().next().unwrap().image;
+        assert_eq!(result.color(), ColorType::Rgb16);
+        assert_eq!(result.to_rgba16().get_pixel(0, 0), &Rgba([1000, 2000, 3000, u16::MAX]));
+    }
+
+    #[test]
+    fn test_alpha_channel_added_by_a_tile() {
+        let mut canvas = GrowingCanvas::new(None, OverlapMode::Crop);
+        canvas.add_tile(tile(0, 0, 1));
+        canvas.add_tile(Tile {
+            position: Vec2d { x: 3, y: 0 },
+            image: DynamicImage::ImageRgba8(ImageBuffer::from_pixel(1, 1, Rgba([2, 2, 2, 128]))),
+            encoded: None,
+        });
+        let tiles: Vec<Tile> = canvas.into_tiles().collect();
+        assert!(tiles.iter().all(|t| t.image.color() == ColorType::Rgba8));
+        assert_eq!(tiles[0].image.get_pixel(0, 0), Rgba([1, 1, 1, 255]), "the first tile stays opaque");
+        assert_eq!(tiles[1].image.get_pixel(0, 0), Rgba([2, 2, 2, 128]));
+    }
+}
@@ -95,7 +95,7 @@ struct IIIFTileSaver {
 }
 
 impl TileSaver for IIIFTileSaver {
-    fn save_tile(&self, size: Vec2d, tile: Tile) -> io::Result<()> {
+    fn save_tile(&self, _scale_factor: u32, size: Vec2d, tile: Tile) -> io::Result<()> {
         let tile_size = tile.size();
         let region = format!("{},{},{},{}",
                              tile.position.x, tile.position.y,
@@ -1,17 +1,24 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 
-use image::{DynamicImage, GenericImageView, SubImage};
-use log::debug;
+use image::{DynamicImage, GenericImage, GenericImageView, ImageFormat, ImageOutputFormat, ImageResult, Pixel, Primitive, Rgba, SubImage};
+use log::{debug, warn};
 
 use crate::{max_size_in_rect, Vec2d, ZoomError};
 use crate::tile::Tile;
 use crate::encoder::canvas::ImageWriter;
+use crate::encoder::split_encoder::SplitSpec;
 
 pub mod canvas;
 pub mod png_encoder;
+pub mod tiff_encoder;
 pub mod pixel_streamer;
 pub mod tile_buffer;
 pub mod iiif_encoder;
+pub mod split_encoder;
+pub mod zip_encoder;
+pub mod xyz_encoder;
+pub mod growing_canvas;
 mod retiler;
 
 pub trait Encoder: Send + 'static {
@@ -23,23 +30,253 @@ pub trait Encoder: Send + 'static {
     fn size(&self) -> Vec2d;
 }
 
-fn encoder_for_name(destination: PathBuf, size: Vec2d, compression: u8) -> Result<Box<dyn Encoder>, ZoomError> {
+/// Settings that control how the final image is written
+#[derive(Debug, Clone)]
+pub struct EncoderOptions {
+    /// Between 0 and 100. See `Arguments::compression`
+    pub compression: u8,
+    /// If set, the image is written as several independent image files
+    pub split: Option<SplitSpec>,
+    /// Maximum number of image parts that are encoded at the same time
+    pub split_parallelism: usize,
+    /// If set, all tiles are converted to this depth. Otherwise, the depth of the tiles is kept
+    /// when the output format supports it.
+    pub bit_depth: Option<BitDepth>,
+    /// The url of the zoomable image, recorded in the outputs that describe their source
+    pub source: Option<String>,
+    /// If set, the size of the image, regardless of the size of the zoom level
+    pub dimensions: Option<Vec2d>,
+    /// Layout of the tile pyramid written for `.xyz` outputs
+    pub xyz: xyz_encoder::XyzOptions,
+    /// Format of the individual tiles stored in `.zip` outputs
+    pub tiles_format: zip_encoder::TileFormat,
+    /// Write the rows of tiff images as soon as they are complete
+    pub incremental: bool,
+    /// Resolution recorded in png, jpeg and tiff images, in dots per inch
+    pub dpi: Option<u16>,
+    /// Maximum size of jpeg files, in bytes. See `Arguments::target_size`
+    pub target_size: Option<u64>,
+    /// What is drawn where tiles overlap
+    pub overlap: OverlapMode,
+}
+
+impl EncoderOptions {
+    /// Whether the image written at the destination stores the files the tiles were decoded from
+    pub fn stores_encoded_tiles(&self, destination: &Path) -> bool {
+        self.split.is_none()
+            && destination.extension().unwrap_or_default() == "zip"
+            && self.tiles_format == zip_encoder::TileFormat::Source
+    }
+}
+
+/// The options of an image written with the default command line arguments
+impl Default for EncoderOptions {
+    fn default() -> Self {
+        EncoderOptions {
+            compression: 20,
+            split: None,
+            split_parallelism: 4,
+            bit_depth: None,
+            source: None,
+            dimensions: None,
+            xyz: Default::default(),
+            tiles_format: Default::default(),
+            incremental: false,
+            dpi: None,
+            target_size: None,
+            overlap: Default::default(),
+        }
+    }
+}
+
+/// How the pixels of overlapping tiles are combined
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OverlapMode {
+    /// The pixels of the last tile replace the ones of the tiles under it
+    #[default]
+    Crop,
+    /// The last tile is composited over the tiles under it, according to its alpha channel
+    Blend,
+}
+
+impl FromStr for OverlapMode {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "crop" => Ok(OverlapMode::Crop),
+            "blend" => Ok(OverlapMode::Blend),
+            _ => Err("Invalid overlap mode. Expected 'crop' or 'blend'"),
+        }
+    }
+}
+
+/// Draws a tile at the given position of an image, over the tiles that were drawn before it.
+/// Blended pixels are copied unchanged where nothing was drawn yet.
+pub fn draw_tile<I, J, S>(image: &mut I, tile: &J, position: Vec2d, overlap: OverlapMode) -> ImageResult<()>
+    where I: GenericImage<Pixel=Rgba<S>>, J: GenericImageView<Pixel=Rgba<S>>, S: Primitive + Default + 'static {
+    match overlap {
+        OverlapMode::Crop => image.copy_from(tile, position.x, position.y),
+        OverlapMode::Blend => {
+            for (x, y, pixel) in tile.pixels() {
+                let (x, y) = (position.x + x, position.y + y);
+                if !image.in_bounds(x, y) { continue; }
+                let mut under = image.get_pixel(x, y);
+                if under[3] == S::default() { under = pixel } else { under.blend(&pixel) }
+                image.put_pixel(x, y, under);
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Number of bits per color channel
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BitDepth {
+    Eight,
+    Sixteen,
+}
+
+impl BitDepth {
+    /// The bit depth of a decoded image
+    pub fn of(image: &DynamicImage) -> BitDepth {
+        match image {
+            DynamicImage::ImageLuma16(_) | DynamicImage::ImageLumaA16(_) |
+            DynamicImage::ImageRgb16(_) | DynamicImage::ImageRgba16(_) => BitDepth::Sixteen,
+            _ => BitDepth::Eight,
+        }
+    }
+
+    pub fn bytes(self) -> usize {
+        match self {
+            BitDepth::Eight => 1,
+            BitDepth::Sixteen => 2,
+        }
+    }
+}
+
+impl FromStr for BitDepth {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "8" => Ok(BitDepth::Eight),
+            "16" => Ok(BitDepth::Sixteen),
+            _ => Err("Invalid bit depth. Expected 8 or 16"),
+        }
+    }
+}
+
+/// Outputs that are not single images, written by the encoders of this crate
+const STRUCTURED_FORMATS: &[&str] = &["iiif", "xyz", "zip"];
+
+/// Formats written by the generic canvas, in addition to png and jpeg
+const CANVAS_FORMATS: &[ImageFormat] = &[
+    ImageFormat::Bmp, ImageFormat::Gif, ImageFormat::Ico, ImageFormat::Tiff,
+    ImageFormat::Tga, ImageFormat::Pnm, ImageFormat::Farbfeld, ImageFormat::Avif, ImageFormat::WebP,
+];
+
+/// The file extensions of all the outputs that can be written
+pub fn output_formats() -> Vec<&'static str> {
+    let images = [ImageFormat::Png, ImageFormat::Jpeg].iter().chain(CANVAS_FORMATS)
+        .filter(|format| can_encode(**format))
+        .flat_map(|format| format.extensions_str().iter().copied());
+    images.chain(STRUCTURED_FORMATS.iter().copied()).collect()
+}
+
+/// Whether the image library was compiled with an encoder for the format
+fn can_encode(format: ImageFormat) -> bool {
+    // Tiff files can be saved to paths, but not written to arbitrary streams
+    format == ImageFormat::Tiff ||
+        format.can_write() && !matches!(ImageOutputFormat::from(format), ImageOutputFormat::Unsupported(_))
+}
+
+fn encoder_for_name(destination: PathBuf, size: Vec2d, options: &EncoderOptions) -> Result<Box<dyn Encoder>, ZoomError> {
     let extension = destination.extension().unwrap_or_default();
-    if extension == "png" {
+    let compression = options.compression;
+    let overlap = options.overlap;
+    let warn_unblended = || if overlap == OverlapMode::Blend {
+        warn!("Overlapping tiles are only blended in images that are assembled in memory: \
+               they are cropped in {} outputs", extension.to_string_lossy());
+    };
+    if let Some(spec) = options.split {
+        debug!("Splitting the image into several parts");
+        Ok(Box::new(split_encoder::SplitEncoder::new(destination, size, spec, options)?))
+    } else if extension == "png" && overlap == OverlapMode::Blend {
+        // The streaming encoder writes each pixel once, and cannot draw a tile over another one
+        debug!("Assembling the png image in memory to blend the overlapping tiles");
+        if options.dpi.is_some() {
+            warn!("The resolution given by --dpi is not recorded in png images with blended tiles");
+        }
+        Ok(Box::new(canvas::Canvas::new(destination, size, ImageWriter::Generic, options.bit_depth, overlap)?))
+    } else if extension == "png" {
         debug!("Using the streaming png encoder");
-        Ok(Box::new(png_encoder::PngEncoder::new(destination, size, compression)?))
+        Ok(Box::new(png_encoder::PngEncoder::new(destination, size, compression, options.bit_depth, options.dpi)?))
+    } else if (options.incremental || options.dpi.is_some()) && (extension == "tif" || extension == "tiff") {
+        // The tiff encoder of the image library cannot record a resolution
+        debug!("Using the streaming tiff encoder");
+        warn_unblended();
+        Ok(Box::new(tiff_encoder::TiffEncoder::new(destination, size, options.bit_depth, options.dpi)?))
+    } else if extension == "zip" {
+        debug!("Storing the individual tiles in a zip archive");
+        warn_unblended();
+        let quality = 100u8.saturating_sub(compression);
+        Ok(Box::new(zip_encoder::ZipEncoder::new(
+            destination, size, options.source.clone(), options.tiles_format, quality,
+        )?))
     } else if extension == "iiif" {
         debug!("Using the iiif tiling encoder");
+        warn_unblended();
 	let quality = 100u8.saturating_sub(compression);
         Ok(Box::new(iiif_encoder::IiifEncoder::new(destination, size, quality)?))
+    } else if extension == "xyz" {
+        debug!("Using the xyz tiling encoder");
+        warn_unblended();
+        let quality = 100u8.saturating_sub(compression);
+        Ok(Box::new(xyz_encoder::XyzEncoder::new(destination, size, quality, options.xyz)?))
     } else if extension == "jpeg" || extension == "jpg" {
         debug!("Using the jpeg encoder with a quality of {}", compression);
-        let image_writer = ImageWriter::Jpeg { quality: 100u8.saturating_sub(compression) };
-        Ok(Box::new(canvas::Canvas::new(destination, size, image_writer)?))
+        let quality = 100u8.saturating_sub(compression);
+        let image_writer = ImageWriter::Jpeg { quality, dpi: options.dpi, target_size: options.target_size };
+        Ok(Box::new(canvas::Canvas::new(destination, size, image_writer, Some(BitDepth::Eight), overlap)?))
+    } else if extension == "bmp" {
+        debug!("Using the bmp encoder");
+        check_bmp_size(size)?;
+        Ok(Box::new(canvas::Canvas::new(destination, size, ImageWriter::Bmp, Some(BitDepth::Eight), overlap)?))
     } else {
         debug!("Using the generic canvas implementation {}", &destination.to_string_lossy());
-        Ok(Box::new(canvas::Canvas::new(destination, size, ImageWriter::Generic)?))
+        if options.target_size.is_some() {
+            warn!("The file size given by --target-size is only used for jpeg images");
+        }
+        if options.dpi.is_some() {
+            warn!("The resolution given by --dpi is only recorded in png, jpeg and tiff images");
+        }
+        // Among the formats written by the canvas, only tiff can store 16 bits per channel
+        let bit_depth = if extension == "tif" || extension == "tiff" {
+            options.bit_depth
+        } else {
+            options.bit_depth.or(Some(BitDepth::Eight))
+        };
+        Ok(Box::new(canvas::Canvas::new(destination, size, ImageWriter::Generic, bit_depth, overlap)?))
+    }
+}
+
+/// Size of the bmp files above which a warning is displayed
+const BMP_WARNING_BYTES: u64 = 1 << 30;
+
+/// Bmp files are not compressed, and record their size on 32 bits.
+/// Images that would be too large are refused before their tiles are downloaded.
+fn check_bmp_size(size: Vec2d) -> Result<(), ZoomError> {
+    // With an alpha channel: 4 bytes per pixel, and a version 4 header
+    let bytes = 4 * u64::from(size.x) * u64::from(size.y) + 122;
+    if bytes > u64::from(u32::MAX) {
+        return Err(ZoomError::BmpTooLarge { width: size.x, height: size.y, megabytes: bytes / 1_000_000 });
+    }
+    if bytes > BMP_WARNING_BYTES {
+        warn!("Bmp images are not compressed: the image of {}x{} pixels will take up to {} MB",
+              size.x, size.y, bytes / 1_000_000);
     }
+    Ok(())
 }
 
 /// If a tile is larger than the advertised image size, then crop it to fit in the canvas
@@ -3,35 +3,41 @@ use std::convert::TryFrom;
 use std::io::{self, Write};
 
 use log::debug;
-use image::{Pixel, Rgb, GenericImageView, Rgba};
+use image::{DynamicImage, Pixel, Rgb, GenericImageView, Rgba};
 
 use crate::{Vec2d, max_size_in_rect};
 use crate::tile::Tile;
-use crate::encoder::crop_tile;
+use crate::encoder::{BitDepth, crop_tile};
 use std::sync::Arc;
 
-const BYTES_PER_PIXEL: usize = Rgb::<u8>::CHANNEL_COUNT as usize;
+const CHANNELS_PER_PIXEL: usize = Rgb::<u8>::CHANNEL_COUNT as usize;
 
 /// A structure to which you write tiles, not necessarily in order,
-/// and that itself writes RGB pixels to its writer, ordered from top left to bottom right
+/// and that itself writes RGB pixels to its writer, ordered from top left to bottom right.
+/// 16-bit channels are written in big endian order.
 pub struct PixelStreamer<W: Write> {
     strips: BTreeMap<usize, ImageStrip>,
     writer: W,
     size: Vec2d,
     current_index: usize,
+    bit_depth: BitDepth,
 }
 
 impl<W: Write> PixelStreamer<W> {
-    pub fn new(writer: W, size: Vec2d) -> Self {
+    pub fn new(writer: W, size: Vec2d, bit_depth: BitDepth) -> Self {
         PixelStreamer {
             strips: BTreeMap::new(),
             writer,
             size,
             current_index: 0,
+            bit_depth,
         }
     }
 
-    pub fn add_tile(&mut self, tile: Tile) -> io::Result<()> {
+    pub fn add_tile(&mut self, mut tile: Tile) -> io::Result<()> {
+        if self.bit_depth == BitDepth::Sixteen && tile.image.as_rgb16().is_none() {
+            tile.image = DynamicImage::ImageRgb16(tile.image.to_rgb16());
+        }
         for strip in ImageStrip::in_tile(tile, self.size) {
             let key = strip.pixel_index(self.size);
             self.strips.insert(key, strip);
@@ -47,7 +53,7 @@ impl<W: Write> PixelStreamer<W> {
                 let start_strip_idx = self.current_index - start;
                 // The strip may have already been written, in which case we just ignore it
                 if start_strip_idx < strip_size {
-                    strip.write_pixels(self.size, start_strip_idx, &mut self.writer)?;
+                    strip.write_pixels(self.size, start_strip_idx, self.bit_depth, &mut self.writer)?;
                     debug!("Wrote a strip at position {} of size {}, skipping {} pixels",
                            self.current_index, strip_size, start_strip_idx);
                     self.current_index += strip_size - start_strip_idx;
@@ -75,13 +81,16 @@ impl<W: Write> PixelStreamer<W> {
         if until > self.current_index {
             let remaining = until - self.current_index;
             debug!("Filling incomplete image with {} pixels", remaining);
-            let blank = vec![0; remaining * BYTES_PER_PIXEL];
+            let blank = vec![0; remaining * CHANNELS_PER_PIXEL * self.bit_depth.bytes()];
             self.writer.write_all(&blank)?;
             self.current_index = until;
         }
         Ok(())
     }
 
+    /// Write the pixels that are ready to the underlying writer
+    pub fn flush(&mut self) -> io::Result<()> { self.writer.flush() }
+
     pub fn into_writer(self) -> W { self.writer }
 }
 
@@ -108,9 +117,19 @@ impl ImageStrip {
     pub fn size(&self, canvas_size: Vec2d) -> usize {
         max_size_in_rect(self.source.position, self.source.size(), canvas_size).x as usize
     }
-    pub fn write_pixels<W: Write>(&self, image_size: Vec2d, start_at: usize, writer: &mut W) -> io::Result<()> {
-        let img = self.cropped(image_size);
+    pub fn write_pixels<W: Write>(&self, image_size: Vec2d, start_at: usize, bit_depth: BitDepth, writer: &mut W) -> io::Result<()> {
         let x0 = u32::try_from(start_at).unwrap();
+        if bit_depth == BitDepth::Sixteen {
+            let img = self.source.image.as_rgb16().expect("16-bit tiles are converted when they are added");
+            let width = max_size_in_rect(self.source.position, self.source.size(), image_size).x;
+            for x in x0..width {
+                for channel in img.get_pixel(x, self.line).0.iter() {
+                    writer.write_all(&channel.to_be_bytes())?;
+                }
+            }
+            return Ok(());
+        }
+        let img = self.cropped(image_size);
         for x in x0..img.width() {
             let rgb: Rgb<u8> = img.get_pixel(x, self.line).to_rgb();
             writer.write_all(&rgb.0)?;
@@ -145,6 +164,7 @@ mod tests {
                     /* pixel 0,0 */ 1, 2, 3, /* pixel 1,0 */ 4, 5, 6,
                     /* pixel 0,1 */ 7, 8, 9, /* pixel 1,1 */ 10, 11, 12,
                 ]).unwrap()),
+                encoded: None,
             },
             Tile {
                 position: Vec2d { x: 2, y: 0 },
@@ -154,6 +174,7 @@ mod tests {
                     /* pixel 2,2 */ 02, 02, 02, /* pixel 3,2 */ 12, 12, 12,
                     /* pixel 2,3 */ 03, 03, 03, /* pixel 3,3 */ 13, 13, 13,
                 ]).unwrap()),
+                encoded: None,
             },
             Tile {
                 position: Vec2d { x: 0, y: 2 },
@@ -161,12 +182,14 @@ mod tests {
                     /* pixel 0,2 */ 100, 100, 100, /* pixel 1,2 */ 200, 200, 200,
                     /* pixel 0,3 */ 200, 200, 200, /* pixel 1,3 */ 99, 99, 99,
                 ]).unwrap()),
+                encoded: None,
             },
             Tile {
                 position: Vec2d { x: 1, y: 0 },
                 image: DynamicImage::ImageRgb8(ImageBuffer::from_raw(2, 1, vec![
                     /* pixel 1,0 */ 4, 5, 6, /* pixel 2,0 */ 00, 00, 00,
                 ]).unwrap()),
+                encoded: None,
             }
         ][i].clone()
     }
@@ -234,7 +257,7 @@ mod tests {
 
     fn assert_state_after_tiles(tile_indices: &[usize], expected: Vec<u8>) {
         let mut out = vec![];
-        let mut streamer = PixelStreamer::new(&mut out, Vec2d { x: 4, y: 4 });
+        let mut streamer = PixelStreamer::new(&mut out, Vec2d { x: 4, y: 4 }, BitDepth::Eight);
         for &i in tile_indices {
             streamer.add_tile(tiles(i)).unwrap();
         }
@@ -244,7 +267,7 @@ mod tests {
     #[test]
     fn finalize_empty() {
         let mut out = vec![];
-        let mut streamer = PixelStreamer::new(&mut out, Vec2d { x: 2, y: 2 });
+        let mut streamer = PixelStreamer::new(&mut out, Vec2d { x: 2, y: 2 }, BitDepth::Eight);
         streamer.finalize().unwrap();
         assert_eq!(&out, &[ // No tile, the image is completely black
             0, 0, 0, /**/0, 0, 0,
@@ -255,7 +278,7 @@ mod tests {
     #[test]
     fn finalize_only_tile2() {
         let mut out = vec![];
-        let mut streamer = PixelStreamer::new(&mut out, Vec2d { x: 2, y: 5 });
+        let mut streamer = PixelStreamer::new(&mut out, Vec2d { x: 2, y: 5 }, BitDepth::Eight);
         streamer.add_tile(tiles(2)).unwrap();
         streamer.finalize().unwrap();
         assert_eq!(&out, &[ // No tile, the image is completely black
@@ -268,12 +291,25 @@ mod tests {
         );
     }
 
+    #[test]
+    fn sixteen_bits() {
+        let mut out = vec![];
+        let mut streamer = PixelStreamer::new(&mut out, Vec2d { x: 2, y: 1 }, BitDepth::Sixteen);
+        streamer.add_tile(Tile {
+            position: Vec2d { x: 1, y: 0 },
+            image: DynamicImage::ImageRgb16(ImageBuffer::from_raw(1, 1, vec![0x0102, 0x0304, 0x0506]).unwrap()),
+            encoded: None,
+        }).unwrap();
+        streamer.finalize().unwrap();
+        assert_eq!(&out, &[0, 0, 0, 0, 0, 0, 1, 2, 3, 4, 5, 6]);
+    }
+
     #[test]
     fn tile_too_large() {
         let mut out = vec![];
         // Creating a 1x3 image and adding a 2x2 tile at position (0,2)
         // Since the tile doesn't fit, it must be cropped
-        let mut streamer = PixelStreamer::new(&mut out, Vec2d { x: 1, y: 3 });
+        let mut streamer = PixelStreamer::new(&mut out, Vec2d { x: 1, y: 3 }, BitDepth::Eight);
         streamer.add_tile(tiles(2)).unwrap();
         streamer.finalize().unwrap();
         assert_eq!(&out, &[ // No tile, the image is completely black
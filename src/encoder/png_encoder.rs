@@ -5,43 +5,69 @@ use std::io;
 use crate::{Vec2d, ZoomError};
 use crate::tile::Tile;
 
-use super::Encoder;
+use super::{BitDepth, Encoder};
 use super::pixel_streamer::PixelStreamer;
 
 pub struct PngEncoder {
+    /// Created when the first tile is added, once the bit depth of the image is known
     pixel_streamer: Option<PixelStreamer<png::StreamWriter<'static, File>>>,
+    file: Option<File>,
     size: Vec2d,
+    compression: u8,
+    bit_depth: Option<BitDepth>,
+    dpi: Option<u16>,
 }
 
 impl PngEncoder {
-    pub fn new(destination: PathBuf, size: Vec2d, compression: u8) -> Result<Self, ZoomError> {
-        let file = OpenOptions::new().write(true).create(true).open(destination)?;
-        let mut encoder = png::Encoder::new(file, size.x, size.y);
-        encoder.set_color(png::ColorType::RGB);
-        encoder.set_depth(png::BitDepth::Eight);
-        encoder.set_compression(match compression {
-            0 => png::Compression::Rle,
-            1..=9 => png::Compression::Huffman,
-            10..=19 => png::Compression::Fast,
-            20..=60 => png::Compression::Default,
-            _ => png::Compression::Best,
-        });
-        let writer = encoder.write_header()?
-            .into_stream_writer_with_size(128 * 1024);
-        let pixel_streamer = Some(PixelStreamer::new(writer, size));
-        Ok(PngEncoder { pixel_streamer, size })
+    pub fn new(destination: PathBuf, size: Vec2d, compression: u8, bit_depth: Option<BitDepth>, dpi: Option<u16>) -> Result<Self, ZoomError> {
+        let file = Some(OpenOptions::new().write(true).create(true).open(destination)?);
+        Ok(PngEncoder { pixel_streamer: None, file, size, compression, bit_depth, dpi })
     }
+
+    fn pixel_streamer(&mut self, bit_depth: BitDepth) -> io::Result<&mut PixelStreamer<png::StreamWriter<'static, File>>> {
+        if let Some(file) = self.file.take() {
+            let mut encoder = png::Encoder::new(file, self.size.x, self.size.y);
+            encoder.set_color(png::ColorType::RGB);
+            encoder.set_depth(match bit_depth {
+                BitDepth::Eight => png::BitDepth::Eight,
+                BitDepth::Sixteen => png::BitDepth::Sixteen,
+            });
+            encoder.set_compression(match self.compression {
+                0 => png::Compression::Rle,
+                1..=9 => png::Compression::Huffman,
+                10..=19 => png::Compression::Fast,
+                20..=60 => png::Compression::Default,
+                _ => png::Compression::Best,
+            });
+            let mut writer = encoder.write_header()?;
+            if let Some(dpi) = self.dpi {
+                writer.write_chunk(png::chunk::pHYs, &physical_dimensions(dpi))?;
+            }
+            let writer = writer.into_stream_writer_with_size(128 * 1024);
+            self.pixel_streamer = Some(PixelStreamer::new(writer, self.size, bit_depth));
+        }
+        Ok(self.pixel_streamer
+            .as_mut()
+            .expect("tried to add a tile in a finalized image"))
+    }
+}
+
+/// The contents of a pHYs chunk, which gives the resolution in pixels per meter
+fn physical_dimensions(dpi: u16) -> Vec<u8> {
+    let pixels_per_meter = (f64::from(dpi) / 0.0254).round() as u32;
+    let mut chunk = pixels_per_meter.to_be_bytes().repeat(2);
+    chunk.push(1); // The unit is the meter
+    chunk
 }
 
 impl Encoder for PngEncoder {
     fn add_tile(&mut self, tile: Tile) -> io::Result<()> {
-        self.pixel_streamer
-            .as_mut()
-            .expect("tried to add a tile in a finalized image")
-            .add_tile(tile)
+        let bit_depth = self.bit_depth.unwrap_or_else(|| BitDepth::of(&tile.image));
+        self.pixel_streamer(bit_depth)?.add_tile(tile)
     }
 
     fn finalize(&mut self) -> io::Result<()> {
+        self.pixel_streamer(self.bit_depth.unwrap_or(BitDepth::Eight))?;
         let mut pixel_streamer = self.pixel_streamer
             .take().expect("Tried to finalize an image twice");
         pixel_streamer.finalize()?;
@@ -68,13 +94,14 @@ mod tests {
     fn test_png_create() {
         let destination = temp_dir().join("dezoomify-rs-png-test.png");
         let size = Vec2d { x: 2, y: 2 };
-        let mut encoder = PngEncoder::new(destination.clone(), size, 1).unwrap();
+        let mut encoder = PngEncoder::new(destination.clone(), size, 1, None, None).unwrap();
 
         encoder.add_tile(Tile {
             position: Vec2d { x: 1, y: 1 },
             image: DynamicImage::ImageRgb8(
                 ImageBuffer::from_raw(1, 1, vec![1, 2, 3, ]).unwrap()
             ),
+            encoded: None,
         }).unwrap();
 
         encoder.finalize().unwrap();
@@ -85,4 +112,39 @@ mod tests {
             vec![empty, empty, empty, Rgb::from([1, 2, 3])]
         );
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_png_bit_depth() {
+        let tile = || Tile {
+            position: Vec2d { x: 0, y: 0 },
+            image: DynamicImage::ImageRgb16(
+                ImageBuffer::from_raw(1, 1, vec![0x1234, 0xffff, 0x0000]).unwrap()
+            ),
+            encoded: None,
+        };
+        let encode = |name: &str, bit_depth: Option<BitDepth>| {
+            let destination = temp_dir().join(name);
+            let mut encoder = PngEncoder::new(destination.clone(), Vec2d { x: 1, y: 1 }, 1, bit_depth, None).unwrap();
+            encoder.add_tile(tile()).unwrap();
+            encoder.finalize().unwrap();
+            image::open(&destination).unwrap()
+        };
+        // By default, the depth of the source is preserved
+        let preserved = encode("dezoomify-rs-png-test-16.png", None);
+        assert_eq!(preserved.as_rgb16().unwrap().as_raw(), &vec![0x1234, 0xffff, 0x0000]);
+        let reduced = encode("dezoomify-rs-png-test-8.png", Some(BitDepth::Eight));
+        assert_eq!(reduced.as_rgb8().unwrap().as_raw(), &vec![0x12, 0xff, 0x00]);
+    }
+
+    #[test]
+    fn test_png_dpi() {
+        let destination = temp_dir().join("dezoomify-rs-png-test-dpi.png");
+        let mut encoder = PngEncoder::new(destination.clone(), Vec2d { x: 1, y: 1 }, 1, None, Some(300)).unwrap();
+        encoder.finalize().unwrap();
+        let decoder = png::Decoder::new(File::open(&destination).unwrap());
+        let (_, reader) = decoder.read_info().unwrap();
+        let dims = reader.info().pixel_dims.expect("the image should have a pHYs chunk");
+        assert_eq!((dims.xppu, dims.yppu, dims.unit), (11811, 11811, png::Unit::Meter));
+        assert_eq!(image::open(&destination).unwrap().to_rgb8().into_raw(), vec![0, 0, 0]);
+    }
+}
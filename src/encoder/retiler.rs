@@ -15,7 +15,9 @@ use crate::errors::image_error_to_io_error;
 use crate::Vec2d;
 
 pub trait TileSaver {
-    fn save_tile(&self, size: Vec2d, tile: Tile) -> io::Result<()>;
+    /// Saves a target tile. `size` is the area it covers in the original image,
+    /// and `scale_factor` the factor by which that area was scaled down.
+    fn save_tile(&self, scale_factor: u32, size: Vec2d, tile: Tile) -> io::Result<()>;
 }
 
 /**
@@ -99,6 +101,7 @@ impl<T: TileSaver> Retiler<T> {
             Some(Tile {
                 position: scaled_top_left,
                 image: tile.image.resize_exact(scaled_size.x, scaled_size.y, FilterType::Gaussian),
+                encoded: None,
             })
         };
         let scaled_tile = scaled_tile.as_ref().unwrap_or(tile);
@@ -162,7 +165,7 @@ impl<T: TileSaver> Retiler<T> {
     }
 
     pub fn tile_save(&self, position: Vec2d, size: Vec2d, image: DynamicImage) -> io::Result<()> {
-        self.tile_saver.save_tile(size, Tile { position, image })
+        self.tile_saver.save_tile(self.scale_factor, size, Tile { position, image, encoded: None })
     }
 
     pub fn level_count(&self) -> u32 {
@@ -273,7 +276,7 @@ mod tests {
     }
 
     impl TileSaver for TestTileSaver {
-        fn save_tile(&self, size: Vec2d, tile: Tile) -> io::Result<()> {
+        fn save_tile(&self, _scale_factor: u32, size: Vec2d, tile: Tile) -> io::Result<()> {
             self.added.borrow_mut().push((size, tile));
             Ok(())
         }
@@ -296,10 +299,12 @@ mod tests {
         retiler.add_tile(&Tile {
             image: plain_image(Vec2d { x: 2, y: 1 }, 64),
             position: Vec2d { x: 0, y: 0 },
+            encoded: None,
         }).unwrap();
         retiler.add_tile(&Tile {
             image: plain_image(Vec2d { x: 2, y: 2 }, 16),
             position: Vec2d { x: 0, y: 1 },
+            encoded: None,
         }).unwrap();
         retiler.finalize();
         /* We created the following image :
@@ -322,9 +327,9 @@ mod tests {
             ]).unwrap());
         assert_eq!(tile_saver.get_added(), vec![
             //   ( covered size , Tile {position in target, size in target, pixels })
-            (Vec2d { x: 2, y: 2 }, Tile { position: Vec2d { x: 0, y: 0 }, image: expected_first_tile }),
-            (Vec2d { x: 2, y: 1 }, Tile { position: Vec2d { x: 0, y: 2 }, image: plain_image(Vec2d { x: 2, y: 1 }, 16) }),
-            (image_size, Tile { position: Vec2d { x: 0, y: 0 }, image: expected_zoomed_out_tile }),
+            (Vec2d { x: 2, y: 2 }, Tile { position: Vec2d { x: 0, y: 0 }, image: expected_first_tile, encoded: None }),
+            (Vec2d { x: 2, y: 1 }, Tile { position: Vec2d { x: 0, y: 2 }, image: plain_image(Vec2d { x: 2, y: 1 }, 16), encoded: None }),
+            (image_size, Tile { position: Vec2d { x: 0, y: 0 }, image: expected_zoomed_out_tile, encoded: None }),
         ]);
     }
 }
\ No newline at end of file
@@ -0,0 +1,328 @@
+use std::ffi::OsString;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{sync_channel, SyncSender};
+use std::thread::JoinHandle;
+
+use image::GenericImageView;
+use log::debug;
+use serde::Serialize;
+
+use crate::{Vec2d, ZoomError};
+use crate::errors::make_io_err;
+use crate::tile::Tile;
+
+use super::{Encoder, encoder_for_name, EncoderOptions};
+
+/// How the final image should be split into several parts
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SplitSpec {
+    /// Split the image in a fixed number of columns and rows
+    Grid(Vec2d),
+    /// Split the image in as few parts as possible,
+    /// such that no part is wider or higher than the given number of pixels
+    MaxDim(u32),
+}
+
+impl SplitSpec {
+    /// Number of columns and rows of parts for an image of the given size
+    pub fn part_count(&self, size: Vec2d) -> Vec2d {
+        match *self {
+            SplitSpec::Grid(grid) => grid.max(Vec2d::square(1)),
+            SplitSpec::MaxDim(max_dim) => size.ceil_div(max_dim.max(1)).max(Vec2d::square(1)),
+        }
+    }
+}
+
+/// The path of the file that describes the position of each part of a split image
+pub fn manifest_path(destination: &Path) -> PathBuf {
+    part_path(destination, "manifest", "json")
+}
+
+fn part_path(destination: &Path, suffix: &str, extension: &str) -> PathBuf {
+    let mut name: OsString = destination.file_stem().map(OsString::from).unwrap_or_default();
+    name.push("_");
+    name.push(suffix);
+    name.push(".");
+    name.push(extension);
+    destination.with_file_name(name)
+}
+
+struct Part {
+    position: Vec2d,
+    size: Vec2d,
+    file: PathBuf,
+    /// Index of the writer thread that encodes this part
+    writer: usize,
+}
+
+/// An encoder that writes the image as a grid of independent image files.
+/// Since each part is written to a distinct file, parts are encoded concurrently
+/// by a bounded number of writer threads.
+pub struct SplitEncoder {
+    parts: Vec<Part>,
+    writers: Vec<PartWriter>,
+    manifest: PathBuf,
+    size: Vec2d,
+}
+
+impl SplitEncoder {
+    pub fn new(destination: PathBuf, size: Vec2d, spec: SplitSpec, options: &EncoderOptions) -> Result<Self, ZoomError> {
+        let _ = std::fs::remove_file(&destination);
+        let count = spec.part_count(size);
+        let part_size = size.ceil_div(count);
+        let extension = destination.extension().unwrap_or_default().to_string_lossy().to_string();
+        let part_options = EncoderOptions { split: None, ..options.clone() };
+        let writer_count = options.split_parallelism.max(1).min(count.area() as usize);
+        let mut encoders: Vec<Vec<(usize, Box<dyn Encoder>)>> = (0..writer_count).map(|_| vec![]).collect();
+        let mut parts = vec![];
+        for row in 0..count.y {
+            for col in 0..count.x {
+                let position = part_size * Vec2d { x: col, y: row };
+                let size = (position + part_size).min(size) - position;
+                if size.area() == 0 { continue; }
+                let file = part_path(&destination, &format!("{}_{}", col, row), &extension);
+                debug!("Creating image part at {} of size {} in {:?}", position, size, file);
+                let encoder = encoder_for_name(file.clone(), size, &part_options)?;
+                let writer = parts.len() % writer_count;
+                encoders[writer].push((parts.len(), encoder));
+                parts.push(Part { position, size, file, writer });
+            }
+        }
+        let writers = encoders.into_iter().map(PartWriter::spawn).collect();
+        Ok(SplitEncoder { parts, writers, manifest: manifest_path(&destination), size })
+    }
+}
+
+impl Encoder for SplitEncoder {
+    fn add_tile(&mut self, tile: Tile) -> io::Result<()> {
+        for (index, part) in self.parts.iter().enumerate() {
+            if let Some(sub_tile) = tile_in_region(&tile, part.position, part.size) {
+                self.writers[part.writer].send(index, sub_tile)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn finalize(&mut self) -> io::Result<()> {
+        // Wait for all the writers, even if one of them failed
+        let results: Vec<io::Result<()>> = self.writers.drain(..).map(PartWriter::finish).collect();
+        results.into_iter().collect::<io::Result<()>>()?;
+        let manifest = Manifest {
+            width: self.size.x,
+            height: self.size.y,
+            parts: self.parts.iter().map(|p| ManifestPart {
+                file: p.file.file_name().unwrap_or_default().to_string_lossy().to_string(),
+                x: p.position.x,
+                y: p.position.y,
+                width: p.size.x,
+                height: p.size.y,
+            }).collect(),
+        };
+        debug!("Writing the split image manifest to {:?}", self.manifest);
+        let manifest_str = serde_json::to_string_pretty(&manifest)?;
+        OpenOptions::new().write(true).create(true).truncate(true)
+            .open(&self.manifest)?
+            .write_all(manifest_str.as_bytes())
+    }
+
+    fn size(&self) -> Vec2d { self.size }
+}
+
+/// A thread that owns the encoders of some of the parts
+struct PartWriter {
+    sender: SyncSender<(usize, Tile)>,
+    /// Taken when the thread is joined
+    handle: Option<JoinHandle<io::Result<()>>>,
+}
+
+impl PartWriter {
+    fn spawn(mut encoders: Vec<(usize, Box<dyn Encoder>)>) -> PartWriter {
+        let (sender, receiver) = sync_channel::<(usize, Tile)>(16);
+        let handle = std::thread::spawn(move || {
+            for (index, tile) in receiver {
+                if let Some((_, encoder)) = encoders.iter_mut().find(|(i, _)| *i == index) {
+                    encoder.add_tile(tile)?;
+                }
+            }
+            for (_, encoder) in encoders.iter_mut() {
+                encoder.finalize()?;
+            }
+            Ok(())
+        });
+        PartWriter { sender, handle: Some(handle) }
+    }
+
+    /// When the thread stopped because of an error, returns that error
+    fn send(&mut self, index: usize, tile: Tile) -> io::Result<()> {
+        if self.sender.send((index, tile)).is_ok() { return Ok(()); }
+        join_writer(self.handle.take())?;
+        Err(make_io_err("an image part writer stopped unexpectedly"))
+    }
+
+    fn finish(self) -> io::Result<()> {
+        let PartWriter { sender, handle } = self;
+        drop(sender);
+        join_writer(handle)
+    }
+}
+
+/// Waits for a writer thread and returns its result.
+/// A thread that was already joined has returned its error to the tile that could not be sent.
+fn join_writer(handle: Option<JoinHandle<io::Result<()>>>) -> io::Result<()> {
+    match handle {
+        Some(handle) => handle.join().map_err(|_| make_io_err("an image part writer panicked"))?,
+        None => Err(make_io_err("an image part writer stopped unexpectedly")),
+    }
+}
+
+#[derive(Serialize)]
+struct Manifest {
+    width: u32,
+    height: u32,
+    parts: Vec<ManifestPart>,
+}
+
+#[derive(Serialize)]
+struct ManifestPart {
+    file: String,
+    x: u32,
+    y: u32,
+    width: u32,
+    height: u32,
+}
+
+/// Returns the part of the tile that is inside the given region,
+/// with a position relative to the top left corner of the region
+fn tile_in_region(tile: &Tile, region_position: Vec2d, region_size: Vec2d) -> Option<Tile> {
+    let top_left = tile.position().max(region_position);
+    let bottom_right = tile.bottom_right().min(region_position + region_size);
+    if bottom_right.x <= top_left.x || bottom_right.y <= top_left.y {
+        return None;
+    }
+    let Vec2d { x, y } = top_left - tile.position();
+    let Vec2d { x: w, y: h } = bottom_right - top_left;
+    let image = if (w, h) == tile.image.dimensions() {
+        tile.image.clone()
+    } else {
+        tile.image.crop_imm(x, y, w, h)
+    };
+    Some(Tile { image, position: top_left - region_position, encoded: None })
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{DynamicImage, ImageBuffer, Rgb};
+
+    use super::*;
+
+    #[test]
+    fn test_split_in_two() {
+        let destination = std::env::temp_dir().join("dezoomify-rs-split-test.png");
+        let size = Vec2d { x: 5, y: 2 };
+        let options = EncoderOptions {
+            compression: 0,
+            split: Some(SplitSpec::Grid(Vec2d { x: 2, y: 1 })),
+            split_parallelism: 1,
+            ..Default::default()
+        };
+        let mut encoder = encoder_for_name(destination.clone(), size, &options).unwrap();
+        let pixels: Vec<u8> = (0..size.area() as u8).flat_map(|i| vec![i, i, i]).collect();
+        encoder.add_tile(Tile {
+            position: Vec2d { x: 0, y: 0 },
+            image: DynamicImage::ImageRgb8(ImageBuffer::from_raw(5, 2, pixels).unwrap()),
+            encoded: None,
+        }).unwrap();
+        encoder.finalize().unwrap();
+
+        let left = image::open(temp_dir_file("dezoomify-rs-split-test_0_0.png")).unwrap().to_rgb8();
+        let right = image::open(temp_dir_file("dezoomify-rs-split-test_1_0.png")).unwrap().to_rgb8();
+        assert_eq!(left.dimensions(), (3, 2));
+        assert_eq!(right.dimensions(), (2, 2));
+        for (x, y, &pix) in left.enumerate_pixels() {
+            let i = (y * 5 + x) as u8;
+            assert_eq!(pix, Rgb([i, i, i]));
+        }
+        for (x, y, &pix) in right.enumerate_pixels() {
+            let i = (y * 5 + x + 3) as u8;
+            assert_eq!(pix, Rgb([i, i, i]));
+        }
+
+        let manifest: serde_json::Value = serde_json::from_slice(
+            &std::fs::read(manifest_path(&destination)).unwrap()
+        ).unwrap();
+        assert_eq!(manifest["width"], 5);
+        assert_eq!(manifest["parts"][1]["file"], "dezoomify-rs-split-test_1_0.png");
+        assert_eq!(manifest["parts"][1]["x"], 3);
+        assert_eq!(manifest["parts"][1]["width"], 2);
+    }
+
+    #[test]
+    fn test_concurrent_writers_match_serial_writer() {
+        let size = Vec2d { x: 7, y: 5 };
+        let pixels: Vec<u8> = (0..size.area() as u8).flat_map(|i| vec![i * 3, i * 5, i * 7]).collect();
+        let split_with = |name: &str, split_parallelism: usize| {
+            let options = EncoderOptions {
+                compression: 0,
+                split: Some(SplitSpec::Grid(Vec2d { x: 3, y: 2 })),
+                split_parallelism,
+                ..Default::default()
+            };
+            let destination = temp_dir_file(name);
+            let mut encoder = encoder_for_name(destination, size, &options).unwrap();
+            // Two overlapping tiles, covering all the parts
+            for &position in &[Vec2d { x: 0, y: 0 }, Vec2d { x: 2, y: 1 }] {
+                let tile_size = size - position;
+                let tile_pixels: Vec<u8> = pixels[..(tile_size.area() * 3) as usize].to_vec();
+                encoder.add_tile(Tile {
+                    position,
+                    image: DynamicImage::ImageRgb8(ImageBuffer::from_raw(tile_size.x, tile_size.y, tile_pixels).unwrap()),
+                    encoded: None,
+                }).unwrap();
+            }
+            encoder.finalize().unwrap();
+        };
+        split_with("dezoomify-rs-split-serial.png", 1);
+        split_with("dezoomify-rs-split-concurrent.png", 4);
+        for row in 0..2 {
+            for col in 0..3 {
+                let serial = std::fs::read(temp_dir_file(&format!("dezoomify-rs-split-serial_{}_{}.png", col, row))).unwrap();
+                let concurrent = std::fs::read(temp_dir_file(&format!("dezoomify-rs-split-concurrent_{}_{}.png", col, row))).unwrap();
+                assert_eq!(serial, concurrent, "part {},{} differs", col, row);
+            }
+        }
+    }
+
+    struct FailingEncoder;
+
+    impl Encoder for FailingEncoder {
+        fn add_tile(&mut self, _tile: Tile) -> io::Result<()> {
+            Err(make_io_err("no space left on device"))
+        }
+        fn finalize(&mut self) -> io::Result<()> { Ok(()) }
+        fn size(&self) -> Vec2d { Vec2d::square(1) }
+    }
+
+    #[test]
+    fn test_writer_error_is_returned() {
+        let mut writer = PartWriter::spawn(vec![(0, Box::new(FailingEncoder))]);
+        let tile = || Tile { position: Vec2d::default(), image: DynamicImage::new_rgb8(1, 1), encoded: None };
+        // The tiles are queued until the thread stops and the channel is closed
+        let error = (0..100).find_map(|_| writer.send(0, tile()).err())
+            .expect("the writer should have stopped");
+        assert_eq!(error.to_string(), "no space left on device");
+    }
+
+    fn temp_dir_file(name: &str) -> PathBuf {
+        std::env::temp_dir().join(name)
+    }
+
+    #[test]
+    fn test_part_count() {
+        let size = Vec2d { x: 70_000, y: 1000 };
+        assert_eq!(SplitSpec::MaxDim(65_535).part_count(size), Vec2d { x: 2, y: 1 });
+        assert_eq!(SplitSpec::Grid(Vec2d { x: 3, y: 2 }).part_count(size), Vec2d { x: 3, y: 2 });
+    }
+}
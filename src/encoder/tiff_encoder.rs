@@ -0,0 +1,265 @@
+use std::convert::TryFrom;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::PathBuf;
+
+use log::debug;
+
+use crate::{Vec2d, ZoomError};
+use crate::errors::make_io_err;
+use crate::tile::Tile;
+
+use super::{BitDepth, Encoder};
+use super::pixel_streamer::PixelStreamer;
+
+/// Approximate size of a strip of rows in the file, in bytes
+const STRIP_BYTES: u64 = 64 * 1024;
+
+/// Writes an uncompressed tiff file, whose rows are written as soon as they are complete.
+/// The whole layout of the file is known in advance, so the header is written first,
+/// and a partially downloaded image can be opened while the rest of it is downloading.
+pub struct TiffEncoder {
+    /// Created when the first tile is added, once the bit depth of the image is known
+    pixel_streamer: Option<PixelStreamer<BufWriter<File>>>,
+    file: Option<File>,
+    size: Vec2d,
+    bit_depth: Option<BitDepth>,
+    dpi: Option<u16>,
+}
+
+impl TiffEncoder {
+    pub fn new(destination: PathBuf, size: Vec2d, bit_depth: Option<BitDepth>, dpi: Option<u16>) -> Result<Self, ZoomError> {
+        let file = Some(OpenOptions::new().write(true).create(true).truncate(true).open(destination)?);
+        Ok(TiffEncoder { pixel_streamer: None, file, size, bit_depth, dpi })
+    }
+
+    fn pixel_streamer(&mut self, bit_depth: BitDepth) -> io::Result<&mut PixelStreamer<BufWriter<File>>> {
+        if let Some(file) = self.file.take() {
+            let mut writer = BufWriter::new(file);
+            writer.write_all(&TiffLayout::new(self.size, bit_depth, self.dpi).header()?)?;
+            self.pixel_streamer = Some(PixelStreamer::new(writer, self.size, bit_depth));
+        }
+        Ok(self.pixel_streamer
+            .as_mut()
+            .expect("tried to add a tile in a finalized image"))
+    }
+}
+
+impl Encoder for TiffEncoder {
+    fn add_tile(&mut self, tile: Tile) -> io::Result<()> {
+        let bit_depth = self.bit_depth.unwrap_or_else(|| BitDepth::of(&tile.image));
+        let pixel_streamer = self.pixel_streamer(bit_depth)?;
+        pixel_streamer.add_tile(tile)?;
+        // Make the completed rows visible in the file
+        pixel_streamer.flush()
+    }
+
+    fn finalize(&mut self) -> io::Result<()> {
+        self.pixel_streamer(self.bit_depth.unwrap_or(BitDepth::Eight))?;
+        let mut pixel_streamer = self.pixel_streamer
+            .take().expect("Tried to finalize an image twice");
+        pixel_streamer.finalize()?;
+        Ok(())
+    }
+
+    fn size(&self) -> Vec2d {
+        self.size
+    }
+}
+
+/// Position of the pixels of each strip of rows in the file
+struct TiffLayout {
+    size: Vec2d,
+    bit_depth: BitDepth,
+    rows_per_strip: u32,
+    dpi: Option<u16>,
+}
+
+impl TiffLayout {
+    fn new(size: Vec2d, bit_depth: BitDepth, dpi: Option<u16>) -> Self {
+        let row_bytes = (u64::from(size.x) * 3 * bit_depth.bytes() as u64).max(1);
+        let rows_per_strip = u32::try_from(STRIP_BYTES / row_bytes).unwrap_or(u32::MAX).max(1);
+        TiffLayout { size, bit_depth, rows_per_strip, dpi }
+    }
+
+    fn row_bytes(&self) -> u64 { u64::from(self.size.x) * 3 * self.bit_depth.bytes() as u64 }
+
+    fn strip_count(&self) -> u32 {
+        self.size.y.div_ceil(self.rows_per_strip).max(1)
+    }
+
+    /// The header, the directory describing the image, and the values it refers to.
+    /// The pixels of the image come right after it.
+    fn header(&self) -> io::Result<Vec<u8>> {
+        const ENTRY_COUNT: u32 = 13;
+        let strip_count = self.strip_count();
+        let directory_end = 8 + 2 + 12 * ENTRY_COUNT + 4;
+        let bits_offset = directory_end;
+        let resolution_offset = bits_offset + 6;
+        let strip_offsets_offset = resolution_offset + 8;
+        let strip_counts_offset = strip_offsets_offset + 4 * strip_count;
+        let pixels_offset = strip_counts_offset + 4 * strip_count;
+
+        let image_bytes = self.row_bytes() * u64::from(self.size.y);
+        if u64::from(pixels_offset) + image_bytes > u64::from(u32::MAX) {
+            return Err(make_io_err(format!(
+                "The image is too large to be stored in a tiff file ({} bytes). Use png instead.",
+                image_bytes
+            )));
+        }
+        let strip_bytes = self.row_bytes() * u64::from(self.rows_per_strip);
+        let strips: Vec<(u32, u32)> = (0..u64::from(strip_count)).map(|i| {
+            let start = strip_bytes * i;
+            let len = strip_bytes.min(image_bytes - start.min(image_bytes));
+            (pixels_offset + start as u32, len as u32)
+        }).collect();
+
+        let bits = match self.bit_depth {
+            BitDepth::Eight => 8u16,
+            BitDepth::Sixteen => 16,
+        };
+        // The pixel streamer writes 16-bit channels in big endian order, like this header
+        let mut header = b"MM\0\x2a".to_vec();
+        header.extend(&8u32.to_be_bytes());
+        header.extend(&(ENTRY_COUNT as u16).to_be_bytes());
+        let mut entry = |tag: u16, field_type: u16, count: u32, value: u32| {
+            header.extend(&tag.to_be_bytes());
+            header.extend(&field_type.to_be_bytes());
+            header.extend(&count.to_be_bytes());
+            // Short values that fit in the entry are stored in its first bytes
+            if field_type == SHORT && count == 1 {
+                header.extend(&(value as u16).to_be_bytes());
+                header.extend(&[0, 0]);
+            } else {
+                header.extend(&value.to_be_bytes());
+            }
+        };
+        // The entries are sorted by tag
+        entry(256, LONG, 1, self.size.x); // ImageWidth
+        entry(257, LONG, 1, self.size.y); // ImageLength
+        entry(258, SHORT, 3, bits_offset); // BitsPerSample
+        entry(259, SHORT, 1, 1); // Compression: none
+        entry(262, SHORT, 1, 2); // PhotometricInterpretation: RGB
+        let single_strip = strip_count == 1;
+        entry(273, LONG, strip_count, if single_strip { strips[0].0 } else { strip_offsets_offset }); // StripOffsets
+        entry(277, SHORT, 1, 3); // SamplesPerPixel
+        entry(278, LONG, 1, self.rows_per_strip); // RowsPerStrip
+        entry(279, LONG, strip_count, if single_strip { strips[0].1 } else { strip_counts_offset }); // StripByteCounts
+        entry(282, RATIONAL, 1, resolution_offset); // XResolution
+        entry(283, RATIONAL, 1, resolution_offset); // YResolution
+        entry(284, SHORT, 1, 1); // PlanarConfiguration: chunky
+        entry(296, SHORT, 1, if self.dpi.is_some() { 2 } else { 1 }); // ResolutionUnit: inch, or none
+        header.extend(&0u32.to_be_bytes());
+        debug_assert_eq!(header.len() as u32, directory_end);
+
+        (0..3).for_each(|_| header.extend(&bits.to_be_bytes()));
+        header.extend(&u32::from(self.dpi.unwrap_or(1)).to_be_bytes());
+        header.extend(&1u32.to_be_bytes());
+        // Single strips are stored in the entries, but the space is kept to keep the layout simple
+        strips.iter().for_each(|(offset, _)| header.extend(&offset.to_be_bytes()));
+        strips.iter().for_each(|(_, len)| header.extend(&len.to_be_bytes()));
+        debug_assert_eq!(header.len() as u32, pixels_offset);
+        debug!("Writing a tiff file with {} strips of {} rows", strip_count, self.rows_per_strip);
+        Ok(header)
+    }
+}
+
+const SHORT: u16 = 3;
+const LONG: u16 = 4;
+const RATIONAL: u16 = 5;
+
+#[cfg(test)]
+mod tests {
+    use std::env::temp_dir;
+
+    use image::{DynamicImage, ImageBuffer, Rgb};
+
+    use super::*;
+
+    fn tile(x: u32, y: u32, color: [u8; 3]) -> Tile {
+        Tile {
+            position: Vec2d { x, y },
+            image: DynamicImage::ImageRgb8(ImageBuffer::from_pixel(2, 2, Rgb(color))),
+            encoded: None,
+        }
+    }
+
+    /// The type and the value of a tag of the first directory of a big endian tiff file.
+    /// Rational values are given as a numerator and a denominator.
+    fn tag_value(file: &[u8], tag: u16) -> (u16, u32, u32) {
+        let u16_at = |pos: usize| u16::from_be_bytes([file[pos], file[pos + 1]]);
+        let u32_at = |pos: usize| u32::from_be_bytes([file[pos], file[pos + 1], file[pos + 2], file[pos + 3]]);
+        let directory = u32_at(4) as usize;
+        let entry = (0..usize::from(u16_at(directory)))
+            .map(|i| directory + 2 + 12 * i)
+            .find(|&entry| u16_at(entry) == tag)
+            .unwrap_or_else(|| panic!("no tag {}", tag));
+        match u16_at(entry + 2) {
+            SHORT => (SHORT, u32::from(u16_at(entry + 8)), 1),
+            RATIONAL => {
+                let offset = u32_at(entry + 8) as usize;
+                (RATIONAL, u32_at(offset), u32_at(offset + 4))
+            }
+            field_type => (field_type, u32_at(entry + 8), 1),
+        }
+    }
+
+    #[test]
+    fn test_rows_are_written_incrementally() {
+        let destination = temp_dir().join("dezoomify-rs-tiff-test.tiff");
+        let size = Vec2d { x: 4, y: 4 };
+        let mut encoder = TiffEncoder::new(destination.clone(), size, None, None).unwrap();
+        let file_len = || std::fs::metadata(&destination).unwrap().len();
+        let header_len = TiffLayout::new(size, BitDepth::Eight, None).header().unwrap().len() as u64;
+
+        encoder.add_tile(tile(0, 2, [3, 3, 3])).unwrap();
+        assert_eq!(file_len(), header_len, "no row is complete");
+        encoder.add_tile(tile(0, 0, [1, 1, 1])).unwrap();
+        assert_eq!(file_len(), header_len + 2 * 3, "the first row is not complete");
+        encoder.add_tile(tile(2, 0, [2, 2, 2])).unwrap();
+        assert_eq!(file_len(), header_len + 2 * 4 * 3 + 2 * 3, "the first two rows are complete");
+        encoder.add_tile(tile(2, 2, [4, 4, 4])).unwrap();
+        encoder.finalize().unwrap();
+        assert_eq!(file_len(), header_len + 4 * 4 * 3);
+
+        // The result is the same as with the encoder that assembles the whole image in memory
+        let expected = ImageBuffer::from_fn(4, 4, |x, y| Rgb([1 + x as u8 / 2 + 2 * (y as u8 / 2); 3]));
+        let image = image::open(&destination).unwrap();
+        assert_eq!(image.as_rgb8().unwrap(), &expected);
+    }
+
+    #[test]
+    fn test_strips() {
+        let destination = temp_dir().join("dezoomify-rs-tiff-strips-test.tiff");
+        // Rows of 3 * 2 * 10923 bytes: 65538 bytes, so that each strip has a single row
+        let size = Vec2d { x: 10923, y: 3 };
+        let mut encoder = TiffEncoder::new(destination.clone(), size, Some(BitDepth::Sixteen), Some(300)).unwrap();
+        assert_eq!(TiffLayout::new(size, BitDepth::Sixteen, None).strip_count(), 3);
+        encoder.add_tile(Tile {
+            position: Vec2d { x: 0, y: 1 },
+            image: DynamicImage::ImageRgb16(ImageBuffer::from_pixel(size.x, 1, Rgb([0x1234, 0, 0xffff]))),
+            encoded: None,
+        }).unwrap();
+        encoder.finalize().unwrap();
+        let image = image::open(&destination).unwrap();
+        let image = image.as_rgb16().unwrap();
+        assert_eq!(image.dimensions(), (size.x, size.y));
+        assert_eq!(image.get_pixel(5, 0), &Rgb([0, 0, 0]));
+        assert_eq!(image.get_pixel(10922, 1), &Rgb([0x1234, 0, 0xffff]));
+        let file = std::fs::read(&destination).unwrap();
+        assert_eq!(tag_value(&file, 282), (RATIONAL, 300, 1), "XResolution");
+        assert_eq!(tag_value(&file, 283), (RATIONAL, 300, 1), "YResolution");
+        assert_eq!(tag_value(&file, 296), (SHORT, 2, 1), "ResolutionUnit: inch");
+    }
+
+    #[test]
+    fn test_no_resolution() {
+        let destination = temp_dir().join("dezoomify-rs-tiff-no-dpi-test.tiff");
+        let mut encoder = TiffEncoder::new(destination.clone(), Vec2d { x: 2, y: 2 }, None, None).unwrap();
+        encoder.add_tile(tile(0, 0, [1, 2, 3])).unwrap();
+        encoder.finalize().unwrap();
+        let file = std::fs::read(&destination).unwrap();
+        assert_eq!(tag_value(&file, 282), (RATIONAL, 1, 1), "XResolution");
+        assert_eq!(tag_value(&file, 296), (SHORT, 1, 1), "ResolutionUnit: none");
+    }
+}
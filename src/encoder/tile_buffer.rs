@@ -3,11 +3,13 @@ use std::path::PathBuf;
 /**
 Used to receive tiles asynchronously and provide them to the encoder
 */
+use image::GenericImageView;
 use log::debug;
 use tokio::sync::mpsc;
 
-use crate::{Vec2d, ZoomError};
-use crate::encoder::{Encoder, encoder_for_name};
+use crate::{max_size_in_rect, Vec2d, ZoomError};
+use crate::encoder::{Encoder, encoder_for_name, EncoderOptions};
+use crate::encoder::growing_canvas::GrowingCanvas;
 use crate::tile::Tile;
 use log::warn;
 
@@ -15,8 +17,8 @@ use log::warn;
 pub enum TileBuffer {
     Buffering {
         destination: PathBuf,
-        buffer: Vec<Tile>,
-        compression: u8,
+        buffer: GrowingCanvas,
+        options: EncoderOptions,
     },
     Writing {
         tile_sender: mpsc::Sender<TileBufferMsg>,
@@ -28,22 +30,34 @@ impl TileBuffer {
     /// Create an encoder for an image of the given size at the path
     /// Errors out if the encoder cannot create files with the given extension
     /// or at the given size
-    pub async fn new(destination: PathBuf, compression: u8) -> Result<Self, ZoomError> {
+    pub async fn new(destination: PathBuf, options: EncoderOptions) -> Result<Self, ZoomError> {
         Ok(TileBuffer::Buffering {
             destination,
-            buffer: vec![],
-            compression,
+            buffer: GrowingCanvas::new(options.bit_depth, options.overlap),
+            options,
         })
     }
 
+    /// Whether the tiles given to the buffer should keep the files they were decoded from
+    pub fn stores_encoded_tiles(&self) -> bool {
+        match self {
+            TileBuffer::Buffering { destination, options, .. } => options.stores_encoded_tiles(destination),
+            TileBuffer::Writing { .. } => false,
+        }
+    }
+
     pub async fn set_size(&mut self, size: Vec2d) -> Result<(), ZoomError> {
         let next_state = match self {
-            TileBuffer::Buffering { buffer, destination, compression } => {
+            TileBuffer::Buffering { buffer, destination, options } => {
+                let size = options.dimensions.unwrap_or(size);
                 debug!("Creating a tile writer for an image of size {}", size);
-                let mut e = encoder_for_name(destination.clone(), size, *compression)?;
-                debug!("Adding buffered tiles: {:?}", buffer);
-                for tile in buffer.drain(..) { e.add_tile(tile)?; }
-                buffer_tiles(e).await
+                let mut e = encoder_for_name(destination.clone(), size, options)?;
+                // When the dimensions are forced, the tiles are expected to be cropped
+                let mut bounds = ImageBounds { size, warn: options.dimensions.is_none() };
+                let buffer = std::mem::replace(buffer, GrowingCanvas::new(options.bit_depth, options.overlap));
+                debug!("Adding the tiles buffered in a canvas of size {}", buffer.extent());
+                for tile in buffer.into_tiles().filter_map(|tile| bounds.fit(tile)) { e.add_tile(tile)?; }
+                buffer_tiles(e, bounds).await
             }
             TileBuffer::Writing { .. } => unreachable!("The size of the image can be set only once")
         };
@@ -55,7 +69,7 @@ impl TileBuffer {
     pub async fn add_tile(&mut self, tile: Tile) {
         match self {
             TileBuffer::Buffering { buffer, .. } => {
-                buffer.push(tile)
+                buffer.add_tile(tile)
             }
             TileBuffer::Writing { tile_sender, .. } => {
                 tile_sender.send(TileBufferMsg::AddTile(tile))
@@ -67,10 +81,7 @@ impl TileBuffer {
     /// To be called when no more tile will be added
     pub async fn finalize(&mut self) -> Result<(), ZoomError> {
         if let TileBuffer::Buffering { buffer, .. } = self {
-            let size = buffer.iter().map(|t| t.position + t.size()).fold(
-                Vec2d { x: 0, y: 0 },
-                Vec2d::max,
-            );
+            let size = buffer.extent();
             self.set_size(size).await?;
         }
         let (tile_sender, error_receiver) = match self {
@@ -90,13 +101,44 @@ pub enum TileBufferMsg {
     Close,
 }
 
-async fn buffer_tiles(mut encoder: Box<dyn Encoder>) -> TileBuffer {
+/// Keeps the tiles inside of the image.
+/// The size of an image declared in its metadata can be inconsistent with the positions of its tiles.
+struct ImageBounds {
+    size: Vec2d,
+    /// Whether to warn about the next tile that does not fit in the image
+    warn: bool,
+}
+
+impl ImageBounds {
+    /// Crops the parts of the tile that are outside of the image.
+    /// Returns None if the whole tile is outside of the image.
+    fn fit(&mut self, tile: Tile) -> Option<Tile> {
+        let Tile { position, image, encoded } = tile;
+        let size = Vec2d::from(image.dimensions());
+        let end = position + size;
+        if end.x <= self.size.x && end.y <= self.size.y { return Some(Tile { position, image, encoded }); }
+        if self.warn {
+            warn!("A tile of size {} at position {} does not fit in the image of size {}. \
+                   The parts of the tiles that are outside of the image are ignored.", size, position, self.size);
+            self.warn = false;
+        }
+        if position.x >= self.size.x || position.y >= self.size.y { return None; }
+        let Vec2d { x: width, y: height } = max_size_in_rect(position, size, self.size);
+        Some(Tile { position, image: image.crop_imm(0, 0, width, height), encoded: None })
+    }
+}
+
+async fn buffer_tiles(mut encoder: Box<dyn Encoder>, mut bounds: ImageBounds) -> TileBuffer {
     let (tile_sender, mut tile_receiver) = mpsc::channel(1024);
     let (error_sender, error_receiver) = mpsc::channel(1);
     tokio::spawn(async move {
         while let Some(msg) = tile_receiver.recv().await {
             match msg {
                 TileBufferMsg::AddTile(tile) => {
+                    let tile = match bounds.fit(tile) {
+                        Some(tile) => tile,
+                        None => continue,
+                    };
                     debug!("Sending tile to encoder: {:?}", tile);
                     let result = tokio::task::block_in_place(|| encoder.add_tile(tile));
                     if let Err(err) = result {
@@ -117,4 +159,82 @@ async fn buffer_tiles(mut encoder: Box<dyn Encoder>) -> TileBuffer {
         tile_sender,
         error_receiver,
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use image::{DynamicImage, ImageBuffer, Rgb};
+
+    use super::*;
+
+    /// Tiles of a 3x2 grid of 4x3 pixels tiles, with smaller tiles on the edges
+    fn generic_grid() -> Vec<Tile> {
+        let mut tiles = vec![];
+        for (i, &(x, y)) in [(2, 1), (0, 0), (1, 0), (0, 1), (2, 0), (1, 1)].iter().enumerate() {
+            let width = if x == 2 { 2 } else { 4 };
+            let height = if y == 1 { 1 } else { 3 };
+            let image = ImageBuffer::from_fn(width, height, |px, py| {
+                Rgb([i as u8 * 40, (px * 50) as u8, (py * 70) as u8])
+            });
+            tiles.push(Tile {
+                position: Vec2d { x: x * 4, y: y * 3 },
+                image: DynamicImage::ImageRgb8(image),
+                encoded: None,
+            });
+        }
+        tiles
+    }
+
+    /// Writes the grid, with the size of the image set after the given number of tiles
+    async fn write_grid(name: &str, known_size: Option<(usize, Vec2d)>) -> DynamicImage {
+        let destination = std::env::temp_dir().join(name);
+        let options = EncoderOptions { compression: 0, split_parallelism: 1, ..Default::default() };
+        let mut buffer = TileBuffer::new(destination.clone(), options).await.unwrap();
+        for (i, tile) in generic_grid().into_iter().enumerate() {
+            match known_size {
+                Some((after, size)) if after == i => buffer.set_size(size).await.unwrap(),
+                _ => {}
+            }
+            buffer.add_tile(tile).await;
+        }
+        buffer.finalize().await.unwrap();
+        image::open(&destination).unwrap()
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_grown_canvas_matches_known_size() {
+        for extension in &["png", "tif"] {
+            let size = Vec2d { x: 10, y: 4 };
+            let known = write_grid(&format!("dezoomify-rs-known-size.{}", extension), Some((0, size))).await;
+            let grown = write_grid(&format!("dezoomify-rs-grown.{}", extension), None).await;
+            // Like the generic dezoomer, that learns the size of the image after a few tiles
+            let late = write_grid(&format!("dezoomify-rs-late-size.{}", extension), Some((3, size))).await;
+            assert_eq!(grown.dimensions(), (10, 4));
+            for other in &[grown, late] {
+                assert_eq!(known.color(), other.color(), "different {} color types", extension);
+                assert_eq!(known.as_bytes(), other.as_bytes(), "different {} outputs", extension);
+            }
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_tiles_outside_of_the_declared_size_are_cropped() {
+        let grown = write_grid("dezoomify-rs-overshoot-grown.png", None).await.to_rgba8();
+        // The declared size of the image is smaller than the grid of tiles
+        let size = Vec2d { x: 9, y: 4 };
+        let known = write_grid("dezoomify-rs-overshoot-known.png", Some((0, size))).await.to_rgba8();
+        let late = write_grid("dezoomify-rs-overshoot-late.png", Some((3, size))).await.to_rgba8();
+        let expected = image::imageops::crop_imm(&grown, 0, 0, size.x, size.y).to_image();
+        assert_eq!(known, expected);
+        assert_eq!(late, expected);
+
+        let mut bounds = ImageBounds { size, warn: true };
+        let tiles = generic_grid();
+        assert_eq!(bounds.fit(tiles[1].clone()).map(|t| t.size()), Some(Vec2d { x: 4, y: 3 }));
+        assert!(bounds.warn, "the tile fits in the image");
+        let cropped = bounds.fit(tiles[0].clone()).unwrap();
+        assert_eq!((cropped.position, cropped.size()), (Vec2d { x: 8, y: 3 }, Vec2d { x: 1, y: 1 }));
+        assert!(!bounds.warn, "a warning is logged for the first tile that does not fit");
+        let outside = Tile { position: Vec2d { x: 9, y: 0 }, image: DynamicImage::new_rgb8(1, 1), encoded: None };
+        assert!(bounds.fit(outside).is_none());
+    }
+}
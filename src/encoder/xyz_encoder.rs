@@ -0,0 +1,244 @@
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use image::{DynamicImage, GenericImage, GenericImageView, ImageOutputFormat};
+use log::{debug, warn};
+use serde::Serialize;
+
+use crate::{Vec2d, ZoomError};
+use crate::encoder::retiler::{Retiler, TileSaver};
+use crate::errors::image_error_to_io_error;
+use crate::tile::Tile;
+
+use super::Encoder;
+
+/// Layout of an xyz tile pyramid
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct XyzOptions {
+    /// Width and height of the tiles, in pixels
+    pub tile_size: u32,
+    /// Zoom level of the most zoomed out tiles that are written
+    pub min_zoom: Option<u32>,
+    /// Zoom level at which the image is shown at full resolution
+    pub max_zoom: Option<u32>,
+}
+
+impl Default for XyzOptions {
+    fn default() -> Self {
+        XyzOptions { tile_size: 256, min_zoom: None, max_zoom: None }
+    }
+}
+
+/// Writes the image as a `{z}/{x}/{y}.jpg` tile pyramid, as used by slippy map viewers,
+/// together with a `leaflet.html` page to view it.
+pub struct XyzEncoder {
+    retiler: Retiler<XyzTileSaver>,
+    root_path: PathBuf,
+    layout: ZoomLayout,
+}
+
+impl XyzEncoder {
+    pub fn new(destination: PathBuf, size: Vec2d, quality: u8, options: XyzOptions) -> Result<Self, ZoomError> {
+        let _ = std::fs::remove_file(&destination);
+        debug!("Creating xyz directory at {:?}", &destination);
+        std::fs::create_dir(&destination)?;
+        let tile_size = Vec2d::square(options.tile_size.max(1));
+        let level_count = level_count(size, tile_size);
+        let layout = ZoomLayout::new(level_count, options);
+        if options.max_zoom.is_some() && layout.max_zoom + 1 < level_count {
+            warn!("Zoom levels below 0 are not written: the image will not fit in a single tile at zoom 0");
+        }
+        let tile_saver = XyzTileSaver { root_path: destination.clone(), quality, tile_size, layout };
+        Ok(XyzEncoder {
+            retiler: Retiler::new(size, tile_size, Arc::new(tile_saver), 1),
+            root_path: destination,
+            layout,
+        })
+    }
+}
+
+impl Encoder for XyzEncoder {
+    fn add_tile(&mut self, tile: Tile) -> io::Result<()> {
+        self.retiler.add_tile(&tile)
+    }
+
+    fn finalize(&mut self) -> io::Result<()> {
+        self.retiler.finalize();
+        let size = self.size();
+        // In leaflet's simple coordinate system, a unit is a pixel at zoom 0
+        let scale = f64::from(2u32.pow(self.layout.max_zoom));
+        let config = ViewerConfig {
+            url: "{z}/{x}/{y}.jpg",
+            tile_size: self.retiler.tile_size.x,
+            width: size.x,
+            height: size.y,
+            min_zoom: self.layout.min_zoom,
+            max_zoom: self.layout.max_zoom,
+            bounds: [[-f64::from(size.y) / scale, 0.], [0., f64::from(size.x) / scale]],
+        };
+        let config_str = serde_json::to_string(&config)?;
+        let viewer_path = self.root_path.join("leaflet.html");
+        debug!("Writing viewer page to {:?}", viewer_path);
+        let viewer_buf = include_str!("./viewer_files/leaflet.html")
+            .replace("{/*DEZOOMIFY_CONFIG*/}", &config_str);
+        OpenOptions::new().write(true).create(true).truncate(true)
+            .open(viewer_path)?
+            .write_all(viewer_buf.as_bytes())
+    }
+
+    fn size(&self) -> Vec2d {
+        self.retiler.size()
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ViewerConfig {
+    url: &'static str,
+    tile_size: u32,
+    width: u32,
+    height: u32,
+    min_zoom: u32,
+    max_zoom: u32,
+    /// South west and north east corners of the image
+    bounds: [[f64; 2]; 2],
+}
+
+/// Number of levels of the retiler: the image is halved until it fits in a single tile
+fn level_count(size: Vec2d, tile_size: Vec2d) -> u32 {
+    let mut count = 1;
+    while !(size / 2u32.pow(count - 1)).fits_inside(tile_size) {
+        count += 1;
+    }
+    count
+}
+
+/// Which zoom level each level of the retiler is written at
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct ZoomLayout {
+    min_zoom: u32,
+    max_zoom: u32,
+}
+
+impl ZoomLayout {
+    fn new(level_count: u32, options: XyzOptions) -> Self {
+        let max_zoom = options.max_zoom.unwrap_or(level_count - 1);
+        let smallest_written = max_zoom.saturating_sub(level_count - 1);
+        let min_zoom = options.min_zoom.unwrap_or(0).max(smallest_written).min(max_zoom);
+        ZoomLayout { min_zoom, max_zoom }
+    }
+
+    /// The zoom level of tiles scaled down by the given factor, if they are written
+    fn zoom(&self, scale_factor: u32) -> Option<u32> {
+        let zoom = self.max_zoom.checked_sub(scale_factor.trailing_zeros())?;
+        Some(zoom).filter(|&z| z >= self.min_zoom)
+    }
+}
+
+struct XyzTileSaver {
+    root_path: PathBuf,
+    quality: u8,
+    tile_size: Vec2d,
+    layout: ZoomLayout,
+}
+
+impl TileSaver for XyzTileSaver {
+    fn save_tile(&self, scale_factor: u32, _size: Vec2d, tile: Tile) -> io::Result<()> {
+        let zoom = match self.layout.zoom(scale_factor) {
+            Some(zoom) => zoom,
+            None => return Ok(()),
+        };
+        let Vec2d { x, y } = tile.position / (self.tile_size * scale_factor);
+        let image_dir_path = self.root_path.join(zoom.to_string()).join(x.to_string());
+        let image_path = image_dir_path.join(format!("{}.jpg", y));
+        debug!("Writing tile to {:?}", image_path);
+        std::fs::create_dir_all(&image_dir_path)?;
+        // Viewers stretch every tile to the full tile size, so the tiles on the edges are padded
+        let image = if tile.size() == self.tile_size { tile.image } else {
+            let mut padded = DynamicImage::new_rgb8(self.tile_size.x, self.tile_size.y);
+            padded.copy_from(&tile.image, 0, 0).map_err(image_error_to_io_error)?;
+            padded
+        };
+        debug_assert_eq!(image.dimensions(), (self.tile_size.x, self.tile_size.y));
+        let file = &mut BufWriter::new(File::create(&image_path)?);
+        image.write_to(file, ImageOutputFormat::Jpeg(self.quality)).map_err(image_error_to_io_error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{ImageBuffer, Rgb};
+
+    use super::*;
+
+    fn encode(name: &str, size: Vec2d, options: XyzOptions) -> PathBuf {
+        let destination = std::env::temp_dir().join(name);
+        let _ = std::fs::remove_dir_all(&destination);
+        let mut encoder = XyzEncoder::new(destination.clone(), size, 90, options).unwrap();
+        encoder.add_tile(Tile {
+            position: Vec2d { x: 0, y: 0 },
+            image: DynamicImage::ImageRgb8(ImageBuffer::from_pixel(size.x, size.y, Rgb([200, 100, 0]))),
+            encoded: None,
+        }).unwrap();
+        encoder.finalize().unwrap();
+        destination
+    }
+
+    fn viewer_config(destination: &std::path::Path) -> serde_json::Value {
+        let html = std::fs::read_to_string(destination.join("leaflet.html")).unwrap();
+        let start = html.find("const config = ").unwrap() + "const config = ".len();
+        let end = start + html[start..].find(";\n").unwrap();
+        serde_json::from_str(&html[start..end]).unwrap()
+    }
+
+    #[test]
+    fn test_xyz_pyramid() {
+        let options = XyzOptions { tile_size: 4, ..Default::default() };
+        let destination = encode("dezoomify-rs-xyz-test.xyz", Vec2d { x: 10, y: 6 }, options);
+        // 10x6 at zoom 2, 5x3 at zoom 1, 3x2 at zoom 0
+        let expected = [
+            "0/0/0.jpg",
+            "1/0/0.jpg", "1/1/0.jpg",
+            "2/0/0.jpg", "2/0/1.jpg", "2/1/0.jpg", "2/1/1.jpg", "2/2/0.jpg", "2/2/1.jpg",
+        ];
+        for path in &expected {
+            let tile = image::open(destination.join(path)).unwrap();
+            assert_eq!(tile.dimensions(), (4, 4), "{}", path);
+        }
+        assert!(!destination.join("1/0/1.jpg").exists());
+        assert!(!destination.join("3").exists());
+
+        let config = viewer_config(&destination);
+        assert_eq!(config["url"], "{z}/{x}/{y}.jpg");
+        assert_eq!(config["tileSize"], 4);
+        assert_eq!(config["minZoom"], 0);
+        assert_eq!(config["maxZoom"], 2);
+        assert_eq!(config["bounds"], serde_json::json!([[-1.5, 0.0], [0.0, 2.5]]));
+    }
+
+    #[test]
+    fn test_xyz_zoom_range() {
+        let options = XyzOptions { tile_size: 4, min_zoom: Some(5), max_zoom: Some(6) };
+        let destination = encode("dezoomify-rs-xyz-range-test.xyz", Vec2d { x: 10, y: 6 }, options);
+        assert!(destination.join("6/2/1.jpg").exists());
+        assert!(destination.join("5/1/0.jpg").exists());
+        assert!(!destination.join("4").exists());
+        let config = viewer_config(&destination);
+        assert_eq!(config["minZoom"], 5);
+        assert_eq!(config["maxZoom"], 6);
+        assert_eq!(config["bounds"], serde_json::json!([[-6. / 64., 0.0], [0.0, 10. / 64.]]));
+    }
+
+    #[test]
+    fn test_zoom_layout() {
+        let layout = ZoomLayout::new(3, XyzOptions { max_zoom: Some(1), ..Default::default() });
+        assert_eq!((layout.zoom(1), layout.zoom(2), layout.zoom(4)), (Some(1), Some(0), None));
+        let layout = ZoomLayout::new(3, XyzOptions { min_zoom: Some(1), ..Default::default() });
+        assert_eq!((layout.zoom(1), layout.zoom(4)), (Some(2), None));
+        let layout = ZoomLayout::new(3, XyzOptions { min_zoom: Some(0), max_zoom: Some(10), ..Default::default() });
+        assert_eq!(layout.min_zoom, 8);
+    }
+}
@@ -0,0 +1,257 @@
+use std::collections::BTreeSet;
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use image::{GenericImageView, ImageOutputFormat};
+use log::debug;
+use serde::Serialize;
+use zip::write::FileOptions;
+use zip::{CompressionMethod, ZipWriter};
+
+use crate::{Vec2d, ZoomError};
+use crate::encoder::{crop_tile, Encoder};
+use crate::errors::image_error_to_io_error;
+use crate::tile::Tile;
+
+/// Name of the file that describes the tiles inside the archive
+pub const MANIFEST_NAME: &str = "manifest.json";
+
+/// The format in which the tiles are stored in the archive
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TileFormat {
+    /// The files downloaded from the server, unchanged.
+    /// Tiles whose pixels had to be modified are stored as png.
+    #[default]
+    Source,
+    Png,
+    /// Lossy format, without transparency
+    Jpeg,
+}
+
+impl TileFormat {
+    fn extension(self) -> &'static str {
+        match self {
+            TileFormat::Source | TileFormat::Png => "png",
+            TileFormat::Jpeg => "jpg",
+        }
+    }
+}
+
+impl FromStr for TileFormat {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "source" => Ok(TileFormat::Source),
+            "png" => Ok(TileFormat::Png),
+            "jpg" | "jpeg" => Ok(TileFormat::Jpeg),
+            _ => Err("Invalid tile format. Expected 'source', 'png' or 'jpg'"),
+        }
+    }
+}
+
+/// An encoder that does not stitch the tiles, but stores them in a zip archive,
+/// together with a json manifest giving their positions.
+/// Tiles are written to the archive as soon as they are received.
+pub struct ZipEncoder {
+    writer: Option<ZipWriter<File>>,
+    size: Vec2d,
+    source: Option<String>,
+    format: TileFormat,
+    /// Quality of jpeg tiles, between 0 and 100
+    quality: u8,
+    tiles: Vec<ManifestTile>,
+}
+
+impl ZipEncoder {
+    pub fn new(destination: PathBuf, size: Vec2d, source: Option<String>, format: TileFormat, quality: u8)
+               -> Result<Self, ZoomError> {
+        let file = File::create(destination)?;
+        Ok(ZipEncoder { writer: Some(ZipWriter::new(file)), size, source, format, quality, tiles: vec![] })
+    }
+
+    fn writer(&mut self) -> &mut ZipWriter<File> {
+        self.writer.as_mut().expect("tried to add a tile to a finalized archive")
+    }
+}
+
+impl Encoder for ZipEncoder {
+    fn add_tile(&mut self, tile: Tile) -> io::Result<()> {
+        let sub_tile = crop_tile(&tile, self.size);
+        let (width, height) = sub_tile.dimensions();
+        if width == 0 || height == 0 { return Ok(()); }
+        let Vec2d { x, y } = tile.position();
+        // Tiles that are cropped to the size of the image lose their original file
+        let source = match (&tile.encoded, self.format) {
+            (Some(bytes), TileFormat::Source) if (width, height) == tile.image.dimensions() =>
+                image::guess_format(bytes).ok().map(|format| (bytes, format)),
+            _ => None,
+        };
+        let extension = source.map_or(self.format.extension(), |(_, format)| format.extensions_str()[0]);
+        let file = format!("tiles/{}_{}.{}", x, y, extension);
+        debug!("Adding {} to the zip archive", file);
+        // The tiles are already compressed, there is no use compressing them again
+        let options = FileOptions::default().compression_method(CompressionMethod::Stored);
+        if let Some((bytes, _)) = source {
+            let writer = self.writer();
+            writer.start_file(file.as_str(), options).map_err(zip_to_io_error)?;
+            writer.write_all(bytes)?;
+        } else {
+            let image = image::DynamicImage::ImageRgba8(sub_tile.to_image());
+            let (image, output_format) = match self.format {
+                TileFormat::Source | TileFormat::Png => (image, ImageOutputFormat::Png),
+                TileFormat::Jpeg =>
+                    (image::DynamicImage::ImageRgb8(image.into_rgb8()), ImageOutputFormat::Jpeg(self.quality)),
+            };
+            let writer = self.writer();
+            writer.start_file(file.as_str(), options).map_err(zip_to_io_error)?;
+            image.write_to(writer, output_format).map_err(image_error_to_io_error)?;
+        }
+        self.tiles.push(ManifestTile { file, x, y, width, height, column: 0, row: 0 });
+        Ok(())
+    }
+
+    fn finalize(&mut self) -> io::Result<()> {
+        // Grid coordinates are known only once all the tiles have been received
+        let columns: BTreeSet<u32> = self.tiles.iter().map(|t| t.x).collect();
+        let rows: BTreeSet<u32> = self.tiles.iter().map(|t| t.y).collect();
+        for tile in self.tiles.iter_mut() {
+            tile.column = columns.range(..tile.x).count();
+            tile.row = rows.range(..tile.y).count();
+        }
+        let manifest = Manifest {
+            width: self.size.x,
+            height: self.size.y,
+            source: self.source.clone(),
+            tiles: std::mem::take(&mut self.tiles),
+        };
+        let manifest_str = serde_json::to_string_pretty(&manifest)?;
+        let mut writer = self.writer.take().expect("Tried to finalize an archive twice");
+        writer.start_file(MANIFEST_NAME, FileOptions::default()).map_err(zip_to_io_error)?;
+        writer.write_all(manifest_str.as_bytes())?;
+        writer.finish().map_err(zip_to_io_error)?;
+        Ok(())
+    }
+
+    fn size(&self) -> Vec2d { self.size }
+}
+
+fn zip_to_io_error(err: zip::result::ZipError) -> io::Error {
+    match err {
+        zip::result::ZipError::Io(err) => err,
+        other => io::Error::other(other),
+    }
+}
+
+#[derive(Serialize)]
+struct Manifest {
+    width: u32,
+    height: u32,
+    /// The url of the zoomable image the tiles come from
+    source: Option<String>,
+    tiles: Vec<ManifestTile>,
+}
+
+#[derive(Serialize)]
+struct ManifestTile {
+    file: String,
+    x: u32,
+    y: u32,
+    width: u32,
+    height: u32,
+    column: usize,
+    row: usize,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use image::{DynamicImage, ImageBuffer, Rgb};
+
+    use super::*;
+
+    #[test]
+    fn test_zip_of_tiles() {
+        let destination = std::env::temp_dir().join("dezoomify-rs-zip-test.zip");
+        let source = Some("http://example.com/image.dzi".to_string());
+        let mut encoder = ZipEncoder::new(destination.clone(), Vec2d { x: 3, y: 2 }, source, TileFormat::Png, 80).unwrap();
+        for &x in &[2, 0] {
+            encoder.add_tile(Tile {
+                position: Vec2d { x, y: 0 },
+                image: DynamicImage::ImageRgb8(ImageBuffer::from_pixel(2, 2, Rgb([x as u8, 1, 2]))),
+                encoded: None,
+            }).unwrap();
+        }
+        encoder.finalize().unwrap();
+
+        let mut archive = zip::ZipArchive::new(File::open(&destination).unwrap()).unwrap();
+        let mut names: Vec<_> = archive.file_names().map(String::from).collect();
+        names.sort();
+        assert_eq!(names, vec!["manifest.json", "tiles/0_0.png", "tiles/2_0.png"]);
+
+        let mut tile = vec![];
+        archive.by_name("tiles/2_0.png").unwrap().read_to_end(&mut tile).unwrap();
+        let tile = image::load_from_memory(&tile).unwrap();
+        // The tile is cropped to the size of the image
+        assert_eq!(tile.dimensions(), (1, 2));
+        assert_eq!(tile.to_rgb8().get_pixel(0, 0), &Rgb([2, 1, 2]));
+
+        let manifest: serde_json::Value =
+            serde_json::from_reader(archive.by_name(MANIFEST_NAME).unwrap()).unwrap();
+        assert_eq!(manifest["source"], "http://example.com/image.dzi");
+        assert_eq!(manifest["tiles"][0]["file"], "tiles/2_0.png");
+        assert_eq!(manifest["tiles"][0]["column"], 1);
+        assert_eq!(manifest["tiles"][1]["column"], 0);
+        assert_eq!(manifest["tiles"][0]["width"], 1);
+    }
+
+    #[test]
+    fn test_jpeg_tiles() {
+        let destination = std::env::temp_dir().join("dezoomify-rs-zip-jpeg-test.zip");
+        let size = Vec2d { x: 2, y: 2 };
+        let mut encoder = ZipEncoder::new(destination.clone(), size, None, TileFormat::Jpeg, 90).unwrap();
+        encoder.add_tile(Tile {
+            position: Vec2d { x: 0, y: 0 },
+            image: DynamicImage::ImageRgb8(ImageBuffer::from_pixel(2, 2, Rgb([200, 100, 0]))),
+            encoded: None,
+        }).unwrap();
+        encoder.finalize().unwrap();
+
+        let mut archive = zip::ZipArchive::new(File::open(&destination).unwrap()).unwrap();
+        let mut tile = vec![];
+        archive.by_name("tiles/0_0.jpg").unwrap().read_to_end(&mut tile).unwrap();
+        assert_eq!(image::guess_format(&tile).unwrap(), image::ImageFormat::Jpeg);
+        assert_eq!(image::load_from_memory(&tile).unwrap().dimensions(), (2, 2));
+        let manifest: serde_json::Value =
+            serde_json::from_reader(archive.by_name(MANIFEST_NAME).unwrap()).unwrap();
+        assert_eq!(manifest["tiles"][0]["file"], "tiles/0_0.jpg");
+        assert!("webp".parse::<TileFormat>().is_err());
+    }
+
+    #[test]
+    fn test_source_tiles() {
+        let destination = std::env::temp_dir().join("dezoomify-rs-zip-source-test.zip");
+        let size = Vec2d { x: 3, y: 2 };
+        let image = DynamicImage::ImageRgb8(ImageBuffer::from_pixel(2, 2, Rgb([200, 100, 0])));
+        let mut jpeg = vec![];
+        image.write_to(&mut jpeg, ImageOutputFormat::Jpeg(90)).unwrap();
+        let mut encoder = ZipEncoder::new(destination.clone(), size, None, TileFormat::Source, 90).unwrap();
+        for &x in &[0, 2] {
+            let encoded = Some(jpeg.clone().into());
+            encoder.add_tile(Tile { position: Vec2d { x, y: 0 }, image: image.clone(), encoded }).unwrap();
+        }
+        encoder.finalize().unwrap();
+
+        let mut archive = zip::ZipArchive::new(File::open(&destination).unwrap()).unwrap();
+        let mut tile = vec![];
+        archive.by_name("tiles/0_0.jpg").unwrap().read_to_end(&mut tile).unwrap();
+        assert_eq!(tile, jpeg);
+        // The tile that is cropped to the size of the image cannot be stored unchanged
+        let mut cropped = vec![];
+        archive.by_name("tiles/2_0.png").unwrap().read_to_end(&mut cropped).unwrap();
+        assert_eq!(image::load_from_memory(&cropped).unwrap().dimensions(), (1, 2));
+    }
+}
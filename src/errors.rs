@@ -10,10 +10,35 @@ custom_error! {
     Networking{source: reqwest::Error} = "network error: {source}",
     Dezoomer{source: DezoomerError} = "Dezoomer error: {source}",
     NoLevels = "A zoomable image was found, but it did not contain any zoom level",
+    NoSuchLevel{index: usize, count: usize} =
+        "There is no zoom level {index}: only {count} levels were found",
     NoTile = "Could not get any tile for the image",
     PartialDownload{successful_tiles: u64, total_tiles: u64} =
         "Only {successful_tiles} tiles out of {total_tiles} could be downloaded. \
         The resulting image was still created.",
+    TileVerification{changed: usize, checked: usize} =
+        "{changed} of the {checked} tiles that were downloaded a second time had changed. \
+        The server may be unreliable. The resulting image was still created.",
+    SuspiciousTemplate{reasons: String} =
+        "The first tiles suggest that the input is wrong, so the download was stopped:\n{reasons}",
+    OutputTooLarge{width: u32, height: u32, max_pixels: u64} =
+        "The image would be at least {width}x{height} pixels, more than the {max_pixels} pixels \
+        allowed by --max-output-pixels, so the download was stopped.",
+    BmpTooLarge{width: u32, height: u32, megabytes: u64} =
+        "A bmp image of {width}x{height} pixels would take {megabytes} MB, more than the 4 GB \
+        that bmp files can hold. Use another format, or split the image with --split.",
+    EmptyGallery{uri: String} =
+        "No zoomable image was found in the links of the gallery page '{uri}'",
+    IncompleteGallery{failed: usize, total: usize} =
+        "{failed} of the {total} images of the gallery could not be downloaded. \
+        The other ones were still saved.",
+    BrokenTokenChain{uri: String} =
+        "The response for '{uri}' had no token for the next request, \
+        so no other tile could be requested with --chain-token",
+    GridWithoutTemplate{uri: String} =
+        "--load-grid can only be used with the url template of a generic image, not with '{uri}'",
+    OutputExists{path: String} =
+        "The output file '{path}' already exists. Use --overwrite always to replace it.",
     Image{source: image::ImageError} = "invalid image error: {source}",
     PostProcessing{source: Box<dyn Error>} = "unable to process the downloaded tile: {source}",
     Io{source: std::io::Error} = "Input/Output error: {source}",
@@ -31,12 +56,17 @@ custom_error! {
     BufferToImage{source: BufferToImageError} = "{}",
     WriteError{source: SendError<TileBufferMsg>} = "Unable to write tile {:?}",
     PngError{source: png::EncodingError} = "PNG encoding error: {}",
+    ByteRangeOutOfBounds{first: u64, last: u64, len: u64} =
+        "The byte range {first}-{last} is outside of the {len} bytes of the file",
+    TruncatedResponse{received: u64, expected: u64} =
+        "The server announced a response of {expected} bytes, but only {received} bytes were received",
 }
 
 custom_error! {
     pub BufferToImageError
     Image{source: image::ImageError} = "invalid image error: {source}",
     PostProcessing{e: Box<dyn Error + Send>} = "unable to process the downloaded tile: {e}",
+    NotAnImage{kind: &'static str} = "the server sent {kind} instead of an image",
 }
 
 custom_error! {pub DezoomerError
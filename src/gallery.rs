@@ -0,0 +1,71 @@
+use itertools::Itertools;
+use lazy_static::lazy_static;
+use regex::Regex;
+
+use crate::network::resolve_relative;
+
+lazy_static! {
+    /// The urls in the attributes of html elements
+    static ref LINK_ATTRIBUTE: Regex = Regex::new(
+        r#"(?i)\b(?:href|src|data-src|data-url|data-manifest)\s*=\s*(?:"([^"]*)"|'([^']*)'|([^\s"'>]+))"#
+    ).unwrap();
+    /// The urls of the documents that describe zoomable images, in the formats that are recognized
+    static ref VIEWER_LINK: Regex = Regex::new(
+        r"(?i)((^|/)(info\.json|manifest(\.json)?|ImageProperties\.xml)|\.dzi|\.pff|artsandculture\.google\.com/asset/.+)([?#].*)?$"
+    ).unwrap();
+}
+
+/// The links of a gallery page to the zoomable images it shows, in the order of the page, without duplicates.
+/// With a pattern, the links are its matches anywhere in the page, or the first group of the matches if it has one.
+/// Otherwise, they are the links of the page to documents that describe zoomable images.
+pub fn gallery_links(page: &str, page_uri: &str, pattern: Option<&Regex>) -> Vec<String> {
+    let links: Vec<&str> = match pattern {
+        Some(pattern) => pattern.captures_iter(page)
+            .filter_map(|c| c.get(1).or_else(|| c.get(0)))
+            .map(|m| m.as_str())
+            .collect(),
+        None => LINK_ATTRIBUTE.captures_iter(page)
+            .filter_map(|c| c.get(1).or_else(|| c.get(2)).or_else(|| c.get(3)))
+            .map(|m| m.as_str())
+            .filter(|link| VIEWER_LINK.is_match(link))
+            .collect(),
+    };
+    links.into_iter()
+        .map(|link| resolve_relative(page_uri, &link.replace("&amp;", "&")))
+        .unique()
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PAGE: &str = r#"<html><body>
+        <a href="/viewer?id=1"><img src="thumbs/1.jpg"></a>
+        <a href="https://iiif.example.com/image/1/info.json">IIIF</a>
+        <a href='images/2/ImageProperties.xml'>Zoomify</a>
+        <div class="zoom" data-src=images/3.dzi></div>
+        <a href="https://iiif.example.com/image/1/info.json">IIIF, again</a>
+        <a href="manifest.json?lang=en&amp;v=2">Manifest</a>
+        <script>openViewer("/tiles/4/info.json")</script>
+    </body></html>"#;
+
+    #[test]
+    fn test_known_viewer_links() {
+        assert_eq!(gallery_links(PAGE, "http://example.com/gallery/page.html", None), vec![
+            "https://iiif.example.com/image/1/info.json",
+            "http://example.com/gallery/images/2/ImageProperties.xml",
+            "http://example.com/gallery/images/3.dzi",
+            "http://example.com/gallery/manifest.json?lang=en&v=2",
+        ]);
+    }
+
+    #[test]
+    fn test_links_matching_a_pattern() {
+        let pattern = Regex::new(r#"openViewer\("([^"]+)"\)|/viewer\?id=\d+"#).unwrap();
+        assert_eq!(gallery_links(PAGE, "http://example.com/gallery/page.html", Some(&pattern)), vec![
+            "http://example.com/viewer?id=1",
+            "http://example.com/tiles/4/info.json",
+        ]);
+    }
+}
@@ -24,14 +24,124 @@ impl Dichotomy {
     }
 }
 
+/// Finds the last valid index, starting from an approximate value given by the user.
+/// The guesses move away from the first one by steps that double,
+/// until the limit is surrounded, and then the interval is halved.
+#[derive(Debug)]
+pub struct HintedDichotomy {
+    guess: u32,
+    min: Option<u32>,
+    max: Option<u32>,
+    step: u32,
+}
+
+impl HintedDichotomy {
+    pub fn new(hint: u32) -> Self {
+        HintedDichotomy { guess: hint, min: None, max: None, step: (hint / 8).max(1) }
+    }
+
+    /// The first index that is tried
+    pub fn first_guess(&self) -> u32 { self.guess }
+
+    fn next(&mut self, previous_success: bool) -> Option<u32> {
+        if previous_success {
+            self.min = Some(self.guess);
+        } else {
+            self.max = Some(self.guess);
+        }
+        let next = match (self.min, self.max) {
+            (Some(min), Some(max)) => min.saturating_add(max.saturating_sub(min) / 2),
+            (Some(min), None) => min.saturating_add(self.step),
+            (None, Some(max)) if max > 0 => max.saturating_sub(self.step),
+            _ => return None,
+        };
+        if self.max.is_none() || self.min.is_none() { self.step = self.step.saturating_mul(2); }
+        if Some(next) == self.min { return None; }
+        self.guess = next;
+        Some(next)
+    }
+
+    /// The last valid index, once the search is over
+    fn best_guess(&self) -> u32 { self.min.unwrap_or(0) }
+}
+
+/// Finds the last valid index by probing several indices at once.
+/// All the indices before the limit are valid, so the number of successful probes
+/// tells which ones succeeded.
+#[derive(Debug)]
+pub struct ParallelDichotomy {
+    /// The last index known to be valid
+    min: u32,
+    /// The first index known to be invalid
+    max: Option<u32>,
+    /// Distance between the probes, while no invalid index was found
+    step: u32,
+    width: u32,
+    probes: Vec<u32>,
+}
+
+impl ParallelDichotomy {
+    pub fn new(min: u32, width: u32) -> Self {
+        let mut dichotomy = ParallelDichotomy { min, max: None, step: 1, width: width.max(1), probes: vec![] };
+        dichotomy.probes = dichotomy.guesses();
+        dichotomy
+    }
+
+    fn guesses(&self) -> Vec<u32> {
+        let mut guesses: Vec<u32> = match self.max {
+            None => (1..=self.width).map(|i| self.min.saturating_add(i.saturating_mul(self.step))).collect(),
+            Some(max) => {
+                let gap = max - self.min;
+                let n = self.width.min(gap.saturating_sub(1));
+                (1..=n).map(|i| self.min + (u64::from(i) * u64::from(gap) / u64::from(n + 1)) as u32).collect()
+            }
+        };
+        guesses.dedup();
+        guesses.retain(|&g| g > self.min);
+        guesses
+    }
+
+    /// The indices to probe, in increasing order
+    pub fn probes(&self) -> &[u32] { &self.probes }
+
+    fn next(&mut self, successes: usize) -> Option<Vec<u32>> {
+        let successes = successes.min(self.probes.len());
+        if let Some(&last_valid) = self.probes[..successes].last() { self.min = last_valid; }
+        match self.probes.get(successes) {
+            Some(&first_invalid) => self.max = Some(first_invalid),
+            None if self.max.is_none() => self.step = self.step.saturating_mul(self.width + 1),
+            None => {}
+        }
+        self.probes = self.guesses();
+        if self.probes.is_empty() { None } else { Some(self.probes.clone()) }
+    }
+}
+
 #[derive(Debug)]
 pub enum Dichotomy2d {
     Diagonal(Dichotomy),
     Orientation { diagonal: u32 },
     LastDim { diagonal: u32, is_landscape: bool, last_dim: Dichotomy },
+    /// When the approximate number of columns is known, the first row is searched first
+    FirstRow(HintedDichotomy),
+    Rows { last_column: u32, rows: Dichotomy },
+    /// The ends of several rows are probed at once, once the number of columns is known
+    RowEnds { last_column: u32, rows: ParallelDichotomy },
+    /// The tiles of the first row are probed one after the other, starting after the given column
+    LinearRow { x: u32 },
+    /// Then the tiles of the last column, down from the given row
+    LinearColumn { last_column: u32, y: u32 },
+    /// The probing is over, and found the bottom right tile, if the image has any
+    LinearEnd { last_tile: Option<(u32, u32)> },
 }
 
 impl Dichotomy2d {
+    /// A search that probes the tiles of the first row, then of the last column, one by one,
+    /// once the top left tile was probed
+    pub fn linear() -> Self {
+        Dichotomy2d::LinearRow { x: 0 }
+    }
+
     pub fn next(&mut self, previous_success: bool) -> Option<(u32, u32)> {
         let mut next = None;
         let res = match self {
@@ -68,6 +178,42 @@ impl Dichotomy2d {
                     (*diagonal, next)
                 })
             }
+            Dichotomy2d::FirstRow(columns) => {
+                columns.next(previous_success).map(|x| (x, 0)).or_else(|| {
+                    let rows = Dichotomy::default();
+                    let last_column = columns.best_guess();
+                    let first = (last_column, rows.best_guess());
+                    next = Some(Dichotomy2d::Rows { last_column, rows });
+                    Some(first)
+                })
+            }
+            Dichotomy2d::Rows { last_column, rows } => {
+                rows.next(previous_success).map(|y| (*last_column, y))
+            }
+            Dichotomy2d::RowEnds { .. } => unreachable!("The ends of the rows are probed with next_batch"),
+            Dichotomy2d::LinearRow { x } => {
+                if previous_success {
+                    *x += 1;
+                    Some((*x, 0))
+                } else if *x > 0 {
+                    let last_column = *x - 1;
+                    next = Some(Dichotomy2d::LinearColumn { last_column, y: 1 });
+                    Some((last_column, 1))
+                } else {
+                    next = Some(Dichotomy2d::LinearEnd { last_tile: None });
+                    None
+                }
+            }
+            Dichotomy2d::LinearColumn { last_column, y } => {
+                if previous_success {
+                    *y += 1;
+                    Some((*last_column, *y))
+                } else {
+                    next = Some(Dichotomy2d::LinearEnd { last_tile: Some((*last_column, *y - 1)) });
+                    None
+                }
+            }
+            Dichotomy2d::LinearEnd { .. } => None,
         };
         if let Some(next) = next {
             *self = next;
@@ -76,6 +222,42 @@ impl Dichotomy2d {
     }
 }
 
+impl Dichotomy2d {
+    /// The tiles to probe next, given the number of tiles of the previous batch that exist.
+    /// When `row_probes` is more than one, the search of the number of rows
+    /// probes the ends of that many rows at once.
+    pub fn next_batch(&mut self, successes: u64, row_probes: u32) -> Option<Vec<(u32, u32)>> {
+        if let Dichotomy2d::RowEnds { last_column, rows } = self {
+            let last_column = *last_column;
+            return rows.next(successes as usize).map(|ys| ys.into_iter().map(|y| (last_column, y)).collect());
+        }
+        let next = self.next(successes > 0)?;
+        let known_row_end = match *self {
+            Dichotomy2d::Rows { last_column, .. } => Some((last_column, 0)),
+            Dichotomy2d::LastDim { diagonal, is_landscape: false, .. } => Some((diagonal, diagonal)),
+            _ => None,
+        };
+        match known_row_end {
+            Some((last_column, last_row)) if row_probes > 1 => {
+                let rows = ParallelDichotomy::new(last_row, row_probes);
+                let probes = rows.probes().iter().map(|&y| (last_column, y)).collect();
+                *self = Dichotomy2d::RowEnds { last_column, rows };
+                Some(probes)
+            }
+            _ => Some(vec![next]),
+        }
+    }
+
+    /// The bottom right tile, for the searches whose last probed tile may be missing
+    pub fn last_tile(&self) -> Option<(u32, u32)> {
+        match self {
+            Dichotomy2d::RowEnds { last_column, rows } => Some((*last_column, rows.min)),
+            Dichotomy2d::LinearEnd { last_tile } => *last_tile,
+            _ => None,
+        }
+    }
+}
+
 impl Default for Dichotomy2d {
     fn default() -> Self {
         Dichotomy2d::Diagonal(Default::default())
@@ -96,6 +278,40 @@ fn test_dichotomy1d() {
     }
 }
 
+#[test]
+fn test_hinted_dichotomy() {
+    for mystery in 0..300 {
+        for &hint in &[1, 50, 99, 100, 250] {
+            let mut d = Dichotomy2d::FirstRow(HintedDichotomy::new(hint));
+            let mut guess = (hint, 0);
+            let mut tries = 1;
+            while let Some(g) = d.next(guess.0 <= mystery && guess.1 <= 2) {
+                guess = g;
+                tries += 1;
+                assert!(tries <= 40, "guessed {:?} on {}th try", g, tries);
+            }
+            assert_eq!(guess, (mystery, 2), "hint {}, {} tries", hint, tries);
+        }
+    }
+}
+
+#[test]
+fn test_hinted_dichotomy_large_bounds() {
+    for &mystery in &[u32::MAX / 2, u32::MAX - 3, u32::MAX] {
+        for &hint in &[1, u32::MAX / 2, u32::MAX - 1, u32::MAX] {
+            let mut d = HintedDichotomy::new(hint);
+            let mut guess = hint;
+            let mut tries = 1;
+            while let Some(g) = d.next(guess <= mystery) {
+                guess = g;
+                tries += 1;
+                assert!(tries <= 100, "guessed {} on {}th try", g, tries);
+            }
+            assert_eq!(d.best_guess(), mystery, "hint {}, {} tries", hint, tries);
+        }
+    }
+}
+
 #[test]
 fn test_dichotomy2d() {
     for x in 0..10 {
@@ -113,3 +329,41 @@ fn test_dichotomy2d() {
         }
     }
 }
+
+#[test]
+fn test_linear_probing() {
+    for x in 0..10 {
+        for y in 0..10 {
+            let mut d = Dichotomy2d::linear();
+            let mut tries = 1;
+            let mut guess = (0, 0);
+            while let Some(g) = d.next(guess.0 <= x && guess.1 <= y) {
+                assert_eq!(g, if tries <= x + 1 { (tries, 0) } else { (x, tries - x - 1) });
+                guess = g;
+                tries += 1;
+            }
+            // Only the two probes that end the search are outside of the image
+            assert_eq!(tries, x + y + 3);
+            assert_eq!(d.last_tile(), Some((x, y)));
+            assert_eq!(d.next(true), None, "the search is over");
+        }
+    }
+}
+
+#[test]
+fn test_parallel_dichotomy() {
+    for mystery in 0..300 {
+        for &width in &[1, 2, 4, 8] {
+            let mut d = ParallelDichotomy::new(0, width);
+            let mut rounds = 1;
+            let mut probes = d.probes().to_vec();
+            while let Some(next) = d.next(probes.iter().filter(|&&p| p <= mystery).count()) {
+                assert!(next.len() <= width as usize);
+                probes = next;
+                rounds += 1;
+                assert!(rounds <= 20, "{} rounds with {} probes", rounds, width);
+            }
+            assert_eq!(d.min, mystery, "{} probes", width);
+        }
+    }
+}
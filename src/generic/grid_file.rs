@@ -0,0 +1,205 @@
+use std::path::Path;
+
+use custom_error::custom_error;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::dezoomer::{TileFetchResult, TileGrid, TileProvider, TileReference};
+use crate::{Vec2d, ZoomError};
+
+use super::fill_template;
+
+/// The layout of the tiles of a generic template, read from a json or yaml file
+/// given at the end of the template, as in `http://example.com/{{X}}_{{Y}}.jpg#grid=grid.yaml`.
+/// When the grid is known, the tiles are listed without probing the server.
+/// The grid found by probing the tiles of a template can be saved to such a file with `--save-grid`.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct GridFile {
+    /// The template whose tiles the grid describes. When it is set, the grid can only be used with it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub template: Option<String>,
+    /// The values of X and Y for the top left tile
+    #[serde(default)]
+    pub origin: [u32; 2],
+    pub cols: u32,
+    pub rows: u32,
+    /// Width and height of the tiles, in pixels
+    pub tile_size: [u32; 2],
+    #[serde(default)]
+    pub scan_order: ScanOrder,
+    /// A printf-style format such as `%03d`, for the placeholders of the template
+    /// that do not set their own number of digits
+    #[serde(default)]
+    pub index_format: IndexFormat,
+    /// Width and height of the image, when the tiles of the last column or row are smaller.
+    /// Defaults to the size of the grid.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub image_size: Option<[u32; 2]>,
+}
+
+/// The order in which the tiles are listed, and so downloaded
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum ScanOrder {
+    /// From left to right, then from top to bottom
+    #[default]
+    RowMajor,
+    /// From top to bottom, then from left to right
+    ColumnMajor,
+}
+
+/// The minimum number of digits of the tile indices, padded with zeroes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct IndexFormat {
+    pub padding: usize,
+}
+
+impl IndexFormat {
+    /// Parses a printf-style integer format: `%d`, or `%0Nd` for N digits padded with zeroes
+    pub fn parse_printf(format: &str) -> Option<Self> {
+        let digits = format.strip_prefix('%')?.strip_suffix('d')?;
+        if digits.is_empty() { return Some(IndexFormat { padding: 0 }); }
+        let padding = digits.strip_prefix('0')?.parse().ok()?;
+        Some(IndexFormat { padding })
+    }
+
+    pub fn format(&self, index: u32) -> String {
+        format!("{index:0padding$}", index = index, padding = self.padding)
+    }
+}
+
+impl<'de> Deserialize<'de> for IndexFormat {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let format = String::deserialize(deserializer)?;
+        IndexFormat::parse_printf(&format).ok_or_else(|| serde::de::Error::custom(
+            format!("Invalid index format '{}'. Expected a format such as '%d' or '%03d'", format)
+        ))
+    }
+}
+
+impl Serialize for IndexFormat {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let format = if self.padding == 0 { "%d".to_string() } else { format!("%0{}d", self.padding) };
+        serializer.serialize_str(&format)
+    }
+}
+
+custom_error! {pub GridFileError
+    EmptyGrid = "The grid file describes an empty grid: cols, rows and tile_size must all be positive",
+    TemplateMismatch{expected: String, found: String} =
+        "The grid file was saved for the template '{expected}', and cannot be used with '{found}'",
+}
+
+impl GridFile {
+    pub fn parse(contents: &[u8]) -> Result<Self, Box<dyn std::error::Error>> {
+        // Json documents are also valid yaml
+        let grid: GridFile = serde_yaml::from_slice(contents)?;
+        if grid.cols == 0 || grid.rows == 0 || grid.tile_size.contains(&0) {
+            return Err(GridFileError::EmptyGrid.into());
+        }
+        Ok(grid)
+    }
+
+    /// The grid of a template whose size was found by probing its tiles
+    pub fn of_template(template: &str, grid: TileGrid) -> Self {
+        GridFile {
+            template: Some(template.to_string()),
+            // The tiles of templates are numbered from zero
+            origin: [0, 0],
+            cols: grid.tile_count.x,
+            rows: grid.tile_count.y,
+            tile_size: [grid.tile_size.x, grid.tile_size.y],
+            scan_order: ScanOrder::RowMajor,
+            index_format: IndexFormat::default(),
+            image_size: Some([grid.image_size.x, grid.image_size.y]),
+        }
+    }
+
+    /// Fails if the grid was saved for another template
+    pub fn check_template(&self, template: &str) -> Result<(), GridFileError> {
+        match &self.template {
+            Some(expected) if expected != template => Err(GridFileError::TemplateMismatch {
+                expected: expected.clone(),
+                found: template.to_string(),
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Writes the grid as a json document
+    pub fn save(&self, path: &Path) -> Result<(), ZoomError> {
+        let json = serde_json::to_vec_pretty(self).expect("grids are always serializable");
+        std::fs::write(path, json)?;
+        Ok(())
+    }
+
+    fn tile_size(&self) -> Vec2d { Vec2d { x: self.tile_size[0], y: self.tile_size[1] } }
+
+    fn tile_count(&self) -> Vec2d { Vec2d { x: self.cols, y: self.rows } }
+
+    fn image_size(&self) -> Vec2d {
+        match self.image_size {
+            Some([x, y]) => Vec2d { x, y },
+            None => self.tile_size() * self.tile_count(),
+        }
+    }
+}
+
+/// A level of the generic dezoomer whose grid is given in a file
+#[derive(Debug)]
+pub struct GridLevel {
+    pub url_template: String,
+    pub grid: GridFile,
+}
+
+impl GridLevel {
+    fn tile_ref(&self, col: u32, row: u32) -> TileReference {
+        let [x, y] = self.grid.origin;
+        TileReference {
+            url: fill_template(&self.url_template, x + col, y + row, self.grid.index_format.padding),
+            position: Vec2d { x: col, y: row } * self.grid.tile_size(),
+            cell_size: None,
+        }
+    }
+}
+
+impl TileProvider for GridLevel {
+    fn next_tiles(&mut self, previous: Option<TileFetchResult>) -> Vec<TileReference> {
+        if previous.is_some() { return vec![]; }
+        self.known_tiles().unwrap_or_default()
+    }
+
+    fn known_tiles(&self) -> Option<Vec<TileReference>> {
+        let GridFile { cols, rows, .. } = self.grid;
+        Some(match self.grid.scan_order {
+            ScanOrder::RowMajor => (0..rows)
+                .flat_map(|row| (0..cols).map(move |col| (col, row)))
+                .map(|(col, row)| self.tile_ref(col, row)).collect(),
+            ScanOrder::ColumnMajor => (0..cols)
+                .flat_map(|col| (0..rows).map(move |row| (col, row)))
+                .map(|(col, row)| self.tile_ref(col, row)).collect(),
+        })
+    }
+
+    fn name(&self) -> String {
+        format!("Generic image with template {} and a grid of {}x{} tiles",
+                self.url_template, self.grid.cols, self.grid.rows)
+    }
+
+    fn size_hint(&self) -> Option<Vec2d> {
+        Some(self.grid.image_size())
+    }
+
+    fn tile_grid(&self) -> Option<TileGrid> {
+        Some(TileGrid {
+            origin: Vec2d::default(),
+            tile_count: self.grid.tile_count(),
+            tile_size: self.grid.tile_size(),
+            image_size: self.size_hint()?,
+        })
+    }
+
+    fn grid_file(&self) -> Option<GridFile> {
+        Some(GridFile { template: Some(self.url_template.clone()), ..self.grid.clone() })
+    }
+}
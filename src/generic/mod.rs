@@ -1,18 +1,35 @@
 use std::collections::HashSet;
+use std::str::FromStr;
+use std::time::Duration;
 
+use custom_error::custom_error;
 use lazy_static::lazy_static;
+use log::debug;
 use regex::Regex;
 
-use crate::dezoomer::{Dezoomer, DezoomerError, DezoomerInput, single_level, TileFetchResult, TileProvider, TileReference, ZoomLevels};
+use crate::dezoomer::{Dezoomer, DezoomerError, DezoomerInput, single_level, TileFetchResult, TileGrid, TileProvider, TileReference, ZoomLevels};
 use crate::Vec2d;
 
 mod dichotomy_2d;
+mod grid_file;
+
+pub use grid_file::GridFile;
+pub(crate) use grid_file::IndexFormat;
 
 /// A dezoomer that takes an image tile URL template like
 /// `http://example.com/image_{{X}}_{{Y}}.jpg`
 /// and automatically figures out the dimensions of the image.
+/// When the template ends with `#approx-cols=N`, the search for the last column starts
+/// around N, which avoids many requests for wide images.
+/// When it ends with `#discovery=linear` or `#discovery=bisection`, the tiles are probed one by one,
+/// or by halving intervals (see [`Discovery`]).
+/// When it ends with `#grid=FILE`, the layout of the tiles is read from the given file
+/// instead of being guessed (see [`GridFile`](grid_file::GridFile)).
 #[derive(Default)]
-pub struct GenericDezoomer;
+pub struct GenericDezoomer {
+    /// The template, and the uri of the file describing its grid, while the file is loaded
+    grid_template: Option<(String, String)>,
+}
 
 impl Dezoomer for GenericDezoomer {
     fn name(&self) -> &'static str {
@@ -20,19 +37,49 @@ impl Dezoomer for GenericDezoomer {
     }
 
     fn zoom_levels(&mut self, data: &DezoomerInput) -> Result<ZoomLevels, DezoomerError> {
+        if let Some((url_template, grid_uri)) = &self.grid_template {
+            if &data.uri == grid_uri {
+                let grid = grid_file::GridFile::parse(data.with_contents()?.contents)
+                    .map_err(|source| DezoomerError::Other { source })?;
+                grid.check_template(url_template).map_err(|e| DezoomerError::Other { source: e.into() })?;
+                let url_template = url_template.clone();
+                return single_level(grid_file::GridLevel { url_template, grid });
+            }
+        }
         self.assert(TEMPLATE_RE.is_match(&data.uri))?;
+        let (url_template, options) = TemplateOptions::split(&data.uri).map_err(DezoomerError::wrap)?;
+        if let Some(grid_uri) = options.grid {
+            // The options of the search are not needed once the grid is known
+            self.grid_template = Some((url_template.to_string(), grid_uri.clone()));
+            return Err(DezoomerError::NeedsData { uri: grid_uri });
+        }
+        let (dichotomy, last_tile, discovery) = match (options.approx_cols, options.discovery.unwrap_or(Discovery::Auto)) {
+            (_, Discovery::Linear) => (dichotomy_2d::Dichotomy2d::linear(), (0, 0), Discovery::Linear),
+            // The approximate number of columns is a starting point for the bisection
+            (Some(cols), _) => {
+                let columns = dichotomy_2d::HintedDichotomy::new(cols.max(1) - 1);
+                let first_tile = (columns.first_guess(), 0);
+                (dichotomy_2d::Dichotomy2d::FirstRow(columns), first_tile, Discovery::Bisection)
+            }
+            (None, discovery) => (Default::default(), (0, 0), discovery),
+        };
         let dezoomer = ZoomLevel {
-            url_template: data.uri.clone(),
-            dichotomy: Default::default(),
-            last_tile: (0, 0),
+            url_template: url_template.to_string(),
+            dichotomy,
+            last_tile,
             done: HashSet::new(),
             tile_size: None,
             image_size: None,
+            row_probes: options.row_probes.unwrap_or(1),
+            discovery,
         };
         single_level(dezoomer)
     }
 }
 
+/// Whether the uri is a template in which the generic dezoomer replaces the coordinates of the tiles
+pub(crate) fn is_template(uri: &str) -> bool { TEMPLATE_RE.is_match(uri) }
+
 lazy_static! {
     static ref TEMPLATE_RE: Regex = Regex::new(r"(?xi)
     \{\{
@@ -40,8 +87,94 @@ lazy_static! {
         (?::0(?P<zeroes>\d+))?
      \}\}
     ").unwrap();
+    static ref OPTION_RE: Regex = Regex::new(r"#([a-z][a-z-]*)=([^#]*)$").unwrap();
+}
+
+/// The options of the search for the size of the image,
+/// given as `#key=value` fragments at the end of the template, in any order
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct TemplateOptions {
+    /// The approximate number of columns, from which the search for the last one starts
+    approx_cols: Option<u32>,
+    /// The number of rows whose last tile is probed at the same time
+    row_probes: Option<u32>,
+    discovery: Option<Discovery>,
+    /// The file that describes the layout of the tiles
+    grid: Option<String>,
+}
+
+impl TemplateOptions {
+    /// Separates the template from the options at its end.
+    /// A byte range (`#bytes=first-last`) is part of the urls of the tiles, and is kept in the template.
+    fn split(uri: &str) -> Result<(&str, TemplateOptions), TemplateOptionError> {
+        let mut options = TemplateOptions::default();
+        let mut template = uri;
+        while let Some(caps) = OPTION_RE.captures(template) {
+            let (key, value) = (&caps[1], &caps[2]);
+            if key == "bytes" { break; }
+            let number = || value.parse::<u32>().map_err(|_| TemplateOptionError::BadValue {
+                key: key.to_string(), value: value.to_string(), expected: "a number",
+            });
+            let duplicated = match key {
+                "approx-cols" => options.approx_cols.replace(number()?).is_some(),
+                "row-probes" => options.row_probes.replace(number()?).is_some(),
+                "discovery" => options.discovery.replace(value.parse().map_err(|expected| {
+                    TemplateOptionError::BadValue { key: key.to_string(), value: value.to_string(), expected }
+                })?).is_some(),
+                "grid" if !value.is_empty() => options.grid.replace(value.to_string()).is_some(),
+                "grid" => return Err(TemplateOptionError::BadValue {
+                    key: key.to_string(), value: value.to_string(), expected: "the path of a file",
+                }),
+                _ => return Err(TemplateOptionError::UnknownOption { key: key.to_string() }),
+            };
+            if duplicated { return Err(TemplateOptionError::Duplicated { key: key.to_string() }); }
+            template = &template[..caps.get(0).unwrap().start()];
+        }
+        Ok((template, options))
+    }
+}
+
+custom_error! {pub TemplateOptionError
+    UnknownOption{key: String} = "Unknown option '#{key}=' at the end of the template. \
+                                  The options are approx-cols, row-probes, discovery and grid",
+    BadValue{key: String, value: String, expected: &'static str} =
+        "Invalid value '{value}' for the option '#{key}=' of the template: expected {expected}",
+    Duplicated{key: String} = "The option '#{key}=' is given several times at the end of the template",
+}
+
+/// How the number of columns and rows of tiles is found
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Discovery {
+    /// Linear probing when the first tile is received within [`LINEAR_PROBING_MAX_LATENCY`],
+    /// and bisection otherwise
+    Auto,
+    /// The tiles of the first row, then the ones of the last column, are probed one after the other.
+    /// All the probed tiles but the two that end the search are part of the image,
+    /// and are not downloaded a second time.
+    Linear,
+    /// The last column and row are searched by halving intervals, which takes fewer requests,
+    /// many of them for tiles outside of the image
+    Bisection,
+}
+
+impl FromStr for Discovery {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(Discovery::Auto),
+            "linear" => Ok(Discovery::Linear),
+            "bisection" => Ok(Discovery::Bisection),
+            _ => Err("'auto', 'linear' or 'bisection'"),
+        }
+    }
 }
 
+/// When the server answers quickly, probing the tiles one by one takes little time,
+/// and no request is wasted on tiles outside of the image.
+/// When it is slow, the number of requests made one after the other matters most.
+const LINEAR_PROBING_MAX_LATENCY: Duration = Duration::from_millis(50);
+
 struct ZoomLevel {
     url_template: String,
     dichotomy: dichotomy_2d::Dichotomy2d,
@@ -49,33 +182,56 @@ struct ZoomLevel {
     tile_size: Option<Vec2d>,
     image_size: Option<Vec2d>,
     done: HashSet<(u32, u32)>,
+    /// Number of rows whose existence is checked at the same time
+    row_probes: u32,
+    /// `Auto` until the first tile is received
+    discovery: Discovery,
+}
+
+/// Replaces the placeholders of the template by the given indices.
+/// `default_padding` is the number of digits of the placeholders that do not set their own.
+pub(crate) fn fill_template(url_template: &str, x: u32, y: u32, default_padding: usize) -> String {
+    TEMPLATE_RE.replace_all(url_template, |caps: &regex::Captures| {
+        let dimension = caps.name("dimension")
+            .expect("missing dimension")
+            .as_str()
+            .chars().next().expect("empty dim")
+            .to_ascii_lowercase();
+        let num = match dimension {
+            'x' => x,
+            'y' => y,
+            _ => unreachable!("The dimension is either x or y")
+        };
+        let padding: usize = caps.name("zeroes")
+            .and_then(|m| m.as_str().parse().ok())
+            .unwrap_or(default_padding);
+        IndexFormat { padding }.format(num)
+    }).to_string()
 }
 
 impl ZoomLevel {
     fn tile_url_at(&self, x: u32, y: u32) -> String {
-        TEMPLATE_RE.replace_all(&self.url_template, |caps: &regex::Captures| {
-            let dimension = caps.name("dimension")
-                .expect("missing dimension")
-                .as_str()
-                .chars().next().expect("empty dim")
-                .to_ascii_lowercase();
-            let num = match dimension {
-                'x' => x,
-                'y' => y,
-                _ => unreachable!("The dimension is either x or y")
-            };
-            let padding: usize = caps.name("zeroes")
-                .and_then(|m| m.as_str().parse().ok())
-                .unwrap_or(0);
-            format!("{num:0padding$}", num = num, padding = padding)
-        }).to_string()
+        fill_template(&self.url_template, x, y, 0)
+    }
+    /// Chooses how to search for the size of the image, given the time it took to get the first tile
+    fn choose_discovery(&mut self, latency: Option<Duration>) {
+        self.discovery = match latency {
+            Some(latency) if latency <= LINEAR_PROBING_MAX_LATENCY => {
+                self.dichotomy = dichotomy_2d::Dichotomy2d::linear();
+                Discovery::Linear
+            }
+            _ => Discovery::Bisection,
+        };
+        debug!("The first tile took {:?}, using {:?} discovery", latency, self.discovery);
     }
+
     fn tile_ref_at(&self, x: u32, y: u32) -> TileReference {
         let tile_size = self.tile_size.unwrap_or(Vec2d { x: 0, y: 0 });
         let position = Vec2d { x, y } * tile_size;
         TileReference {
             url: self.tile_url_at(x, y),
             position,
+            cell_size: None,
         }
     }
 }
@@ -84,11 +240,13 @@ impl TileProvider for ZoomLevel {
     fn next_tiles(&mut self, previous: Option<TileFetchResult>) -> Vec<TileReference> {
         if let Some(p) = previous {
             self.tile_size = self.tile_size.or(p.tile_size);
-            if let Some((x, y)) = self.dichotomy.next(p.is_success()) {
-                self.last_tile = (x, y);
-                self.done.insert((x, y));
-                vec![self.tile_ref_at(x, y)]
+            if self.discovery == Discovery::Auto { self.choose_discovery(p.duration); }
+            if let Some(probes) = self.dichotomy.next_batch(if p.is_success() { p.successes } else { 0 }, self.row_probes) {
+                self.last_tile = *probes.last().expect("an empty batch of probes");
+                self.done.extend(probes.iter().copied());
+                probes.into_iter().map(|(x, y)| self.tile_ref_at(x, y)).collect()
             } else if !self.done.is_empty() {
+                if let Some(last_tile) = self.dichotomy.last_tile() { self.last_tile = last_tile; }
                 let mut res = vec![];
                 let last_tile_pos = Vec2d {
                     x: self.last_tile.0,
@@ -114,9 +272,22 @@ impl TileProvider for ZoomLevel {
     fn name(&self) -> String {
         format!("Generic image with template {}", self.url_template)
     }
+    fn is_guessed(&self) -> bool { true }
     fn size_hint(&self) -> Option<Vec2d> {
         self.image_size
     }
+    fn tile_grid(&self) -> Option<TileGrid> {
+        let (image_size, tile_size) = (self.image_size?, self.tile_size?);
+        Some(TileGrid {
+            origin: Vec2d::default(),
+            tile_count: Vec2d { x: self.last_tile.0 + 1, y: self.last_tile.1 + 1 },
+            tile_size,
+            image_size,
+        })
+    }
+    fn grid_file(&self) -> Option<GridFile> {
+        Some(GridFile::of_template(&self.url_template, self.tile_grid()?))
+    }
 }
 
 impl std::fmt::Debug for ZoomLevel {
@@ -131,7 +302,7 @@ fn test_generic_dezoomer() {
     use std::collections::HashSet;
     use crate::dezoomer::PageContents;
     let uri = "{{X}},{{Y}}".to_string();
-    let mut lvl = GenericDezoomer {}
+    let mut lvl = GenericDezoomer::default()
         .zoom_levels(&DezoomerInput {
             uri,
             contents: PageContents::Unknown,
@@ -158,6 +329,7 @@ fn test_generic_dezoomer() {
             count,
             successes: successes.len() as u64,
             tile_size: Some(Vec2d { x: 4, y: 5 }),
+            duration: None,
         });
         all_tiles.extend(successes);
         tries += 1;
@@ -168,29 +340,41 @@ fn test_generic_dezoomer() {
         TileReference {
             url: "0,0".into(),
             position: Vec2d { x: 0, y: 0 },
+            cell_size: None,
         },
         TileReference {
             url: "1,0".into(),
             position: Vec2d { x: 4, y: 0 },
+            cell_size: None,
         },
         TileReference {
             url: "2,0".into(),
             position: Vec2d { x: 8, y: 0 },
+            cell_size: None,
         },
         TileReference {
             url: "0,1".into(),
             position: Vec2d { x: 0, y: 5 },
+            cell_size: None,
         },
         TileReference {
             url: "1,1".into(),
             position: Vec2d { x: 4, y: 5 },
+            cell_size: None,
         },
         TileReference {
             url: "2,1".into(),
             position: Vec2d { x: 8, y: 5 },
+            cell_size: None,
         },
     ].into_iter().collect();
     assert_eq!(all_tiles, expected);
+    assert_eq!(zoom_level_iter.tile_grid(), Some(TileGrid {
+        origin: Vec2d { x: 0, y: 0 },
+        tile_count: Vec2d { x: 3, y: 2 },
+        tile_size: Vec2d { x: 4, y: 5 },
+        image_size: Vec2d { x: 12, y: 10 },
+    }));
 }
 
 #[test]
@@ -203,7 +387,222 @@ fn test_url_templating() {
         tile_size: None,
         image_size: None,
         done: Default::default(),
+        row_probes: 1,
+        discovery: Discovery::Auto,
     };
     assert_eq!(lvl.tile_url_at(10, 11), "http://x.com/00010_11");
     assert_eq!(lvl.tile_url_at(123, 1), "http://x.com/00123_1");
-}
\ No newline at end of file
+}
+
+#[test]
+fn test_approximate_column_count() {
+    use crate::dezoomer::PageContents;
+    // Counts the requests needed to find the size of a grid of 200x3 tiles
+    let requests = |uri: &str| {
+        let mut lvl = GenericDezoomer::default()
+            .zoom_levels(&DezoomerInput { uri: uri.into(), contents: PageContents::Unknown })
+            .unwrap().into_iter().next().unwrap();
+        let mut zoom_level_iter = crate::dezoomer::ZoomLevelIter::new(&mut lvl);
+        let mut count = 0;
+        while let Some(tiles) = zoom_level_iter.next_tile_references() {
+            let successes = tiles.iter().filter(|t| {
+                let mut coords = t.url.split(',').map(|c| c.parse::<u32>().unwrap());
+                coords.next().unwrap() < 200 && coords.next().unwrap() < 3
+            }).count() as u64;
+            if tiles.len() == 1 { count += 1; }
+            zoom_level_iter.set_fetch_result(TileFetchResult {
+                count: tiles.len() as u64,
+                successes,
+                tile_size: Some(Vec2d { x: 4, y: 5 }),
+                duration: None,
+            });
+        }
+        assert_eq!(zoom_level_iter.size_hint(), Some(Vec2d { x: 800, y: 15 }), "{}", uri);
+        count
+    };
+    let without_hint = requests("{{X}},{{Y}}");
+    let with_hint = requests("{{X}},{{Y}}#approx-cols=190");
+    assert!(with_hint < without_hint, "{} requests with the hint, {} without", with_hint, without_hint);
+    // Probing the tiles of the first row one by one would take 200 requests
+    assert!(with_hint < 15, "{} requests", with_hint);
+    let options = TemplateOptions { approx_cols: Some(12), ..Default::default() };
+    assert_eq!(TemplateOptions::split("a_{{x}}.jpg#approx-cols=12").unwrap(), ("a_{{x}}.jpg", options));
+}
+
+#[test]
+fn test_parallel_row_probes() {
+    use crate::dezoomer::PageContents;
+    // Finds the size of a grid of 20 columns and `rows` rows, and counts the batches of requests
+    let search = |uri: &str, rows: u32| {
+        let mut lvl = GenericDezoomer::default()
+            .zoom_levels(&DezoomerInput { uri: uri.into(), contents: PageContents::Unknown })
+            .unwrap().into_iter().next().unwrap();
+        let mut zoom_level_iter = crate::dezoomer::ZoomLevelIter::new(&mut lvl);
+        let mut batches = 0;
+        while let Some(tiles) = zoom_level_iter.next_tile_references() {
+            let successes = tiles.iter().filter(|t| {
+                let mut coords = t.url.split(',').map(|c| c.parse::<u32>().unwrap());
+                coords.next().unwrap() < 20 && coords.next().unwrap() < rows
+            }).count() as u64;
+            batches += 1;
+            zoom_level_iter.set_fetch_result(TileFetchResult {
+                count: tiles.len() as u64,
+                successes,
+                tile_size: Some(Vec2d { x: 4, y: 5 }),
+                duration: None,
+            });
+        }
+        (zoom_level_iter.size_hint(), batches)
+    };
+    for &rows in &[2, 3, 20, 21, 150, 1000] {
+        for template in &["{{X}},{{Y}}", "{{X}},{{Y}}#approx-cols=18"] {
+            let (sequential, sequential_batches) = search(template, rows);
+            let (parallel, parallel_batches) = search(&format!("{}#row-probes=8", template), rows);
+            assert_eq!(sequential, Some(Vec2d { x: 80, y: 5 * rows }), "{} with {} rows", template, rows);
+            assert_eq!(parallel, sequential, "{} with {} rows", template, rows);
+            if rows >= 150 {
+                assert!(parallel_batches < sequential_batches,
+                        "{} batches with parallel probes, {} without", parallel_batches, sequential_batches);
+            }
+        }
+    }
+    // The row probes can be given before or after the other options
+    for &rows in &[3, 150] {
+        let probes_last = search("{{X}},{{Y}}#approx-cols=18#row-probes=8", rows);
+        let probes_first = search("{{X}},{{Y}}#row-probes=8#approx-cols=18", rows);
+        assert_eq!(probes_first, probes_last, "{} rows", rows);
+        assert_eq!(search("{{X}},{{Y}}#row-probes=8#discovery=linear", rows),
+                   search("{{X}},{{Y}}#discovery=linear#row-probes=8", rows), "{} rows", rows);
+    }
+    let options = TemplateOptions { row_probes: Some(4), ..Default::default() };
+    assert_eq!(TemplateOptions::split("a_{{x}}.jpg#row-probes=4").unwrap(), ("a_{{x}}.jpg", options.clone()));
+    let options = TemplateOptions { discovery: Some(Discovery::Linear), ..options };
+    assert_eq!(TemplateOptions::split("a_{{x}}.jpg#row-probes=4#discovery=linear").unwrap(), ("a_{{x}}.jpg", options.clone()));
+    assert_eq!(TemplateOptions::split("a_{{x}}.jpg#discovery=linear#row-probes=4").unwrap(), ("a_{{x}}.jpg", options));
+}
+
+#[test]
+fn test_template_options() {
+    let options = TemplateOptions {
+        approx_cols: Some(12),
+        row_probes: None,
+        discovery: Some(Discovery::Bisection),
+        grid: Some("grid.yaml".into()),
+    };
+    for uri in &[
+        "a_{{x}}.jpg#approx-cols=12#discovery=bisection#grid=grid.yaml",
+        "a_{{x}}.jpg#grid=grid.yaml#approx-cols=12#discovery=bisection",
+        "a_{{x}}.jpg#discovery=bisection#grid=grid.yaml#approx-cols=12",
+    ] {
+        assert_eq!(TemplateOptions::split(uri).unwrap(), ("a_{{x}}.jpg", options.clone()), "{}", uri);
+    }
+    // The fragments that are not options are part of the urls of the tiles
+    let (template, _) = TemplateOptions::split("a_{{x}}.bin#bytes=0-99#approx-cols=12").unwrap();
+    assert_eq!(template, "a_{{x}}.bin#bytes=0-99");
+    assert_eq!(TemplateOptions::split("a_{{x}}.jpg#page").unwrap(), ("a_{{x}}.jpg#page", Default::default()));
+
+    let error = |uri| TemplateOptions::split(uri).unwrap_err();
+    assert!(matches!(error("a_{{x}}.jpg#aprox-cols=12"), TemplateOptionError::UnknownOption { .. }));
+    assert!(matches!(error("a_{{x}}.jpg#approx-cols=many"), TemplateOptionError::BadValue { .. }));
+    assert!(matches!(error("a_{{x}}.jpg#discovery=fast"), TemplateOptionError::BadValue { .. }));
+    assert!(matches!(error("a_{{x}}.jpg#grid="), TemplateOptionError::BadValue { .. }));
+    assert!(matches!(error("a_{{x}}.jpg#approx-cols=1#approx-cols=2"), TemplateOptionError::Duplicated { .. }));
+}
+
+#[test]
+fn test_discovery_from_latency() {
+    use crate::dezoomer::PageContents;
+    let existing_tiles = ["0,0", "1,0", "2,0", "0,1", "1,1", "2,1"];
+    let discover = |latency: Duration| {
+        let mut lvl = GenericDezoomer::default()
+            .zoom_levels(&DezoomerInput { uri: "{{X}},{{Y}}".into(), contents: PageContents::Unknown })
+            .unwrap().into_iter().next().unwrap();
+        let mut zoom_level_iter = crate::dezoomer::ZoomLevelIter::new(&mut lvl);
+        let mut probes = vec![];
+        while let Some(tiles) = zoom_level_iter.next_tile_references() {
+            let successes = tiles.iter().filter(|t| existing_tiles.contains(&t.url.as_str())).count();
+            if zoom_level_iter.tile_grid().is_none() { probes.extend(tiles.iter().map(|t| t.url.clone())); }
+            zoom_level_iter.set_fetch_result(TileFetchResult {
+                count: tiles.len() as u64,
+                successes: successes as u64,
+                tile_size: Some(Vec2d { x: 4, y: 5 }),
+                duration: Some(latency),
+            });
+        }
+        assert_eq!(zoom_level_iter.tile_grid().map(|g| g.tile_count), Some(Vec2d { x: 3, y: 2 }));
+        probes
+    };
+    // A slow server: the diagonal is searched by bisection
+    assert_eq!(discover(Duration::from_secs(1))[..2], ["0,0", "4,4"]);
+    // A fast server: the first row, then the last column, are probed tile by tile
+    assert_eq!(discover(Duration::from_millis(5)), ["0,0", "1,0", "2,0", "3,0", "2,1", "2,2"]);
+}
+
+#[test]
+fn test_grid_file() {
+    use crate::dezoomer::PageContents;
+    let uri = "http://x.com/{{X}}_{{Y:02}}.jpg#grid=grid.yaml";
+    let mut dezoomer = GenericDezoomer::default();
+    match dezoomer.zoom_levels(&DezoomerInput { uri: uri.into(), contents: PageContents::Unknown }) {
+        Err(DezoomerError::NeedsData { uri }) => assert_eq!(uri, "grid.yaml"),
+        other => panic!("The grid file should be requested, got {:?}", other.map(|_| ())),
+    }
+    let grid = r#"
+origin: [1, 1]
+cols: 2
+rows: 3
+tile_size: [256, 128]
+scan_order: column-major
+index_format: "%03d"
+"#;
+    let mut lvl = dezoomer
+        .zoom_levels(&DezoomerInput { uri: "grid.yaml".into(), contents: PageContents::Success(grid.into()) })
+        .unwrap().into_iter().next().unwrap();
+    let mut zoom_level_iter = crate::dezoomer::ZoomLevelIter::new(&mut lvl);
+    assert_eq!(zoom_level_iter.size_hint(), Some(Vec2d { x: 512, y: 384 }));
+    let tiles: Vec<(String, Vec2d)> = zoom_level_iter.next_tile_references().unwrap()
+        .into_iter().map(|t| (t.url, t.position)).collect();
+    let expected: Vec<(String, Vec2d)> = [
+        ("001_01", 0, 0), ("001_02", 0, 128), ("001_03", 0, 256),
+        ("002_01", 256, 0), ("002_02", 256, 128), ("002_03", 256, 256),
+    ].iter().map(|&(name, x, y)| (format!("http://x.com/{}.jpg", name), Vec2d { x, y })).collect();
+    assert_eq!(tiles, expected);
+    // All the tiles are known in advance: nothing is probed
+    zoom_level_iter.set_fetch_result(TileFetchResult { count: 6, successes: 6, tile_size: Some(Vec2d { x: 256, y: 128 }), duration: None });
+    assert!(zoom_level_iter.next_tile_references().is_none());
+    assert_eq!(zoom_level_iter.tile_grid().map(|g| g.tile_count), Some(Vec2d { x: 2, y: 3 }));
+
+    assert!(grid_file::GridFile::parse(b"{\"cols\": 0, \"rows\": 1, \"tile_size\": [1, 1]}").is_err());
+    assert!(grid_file::GridFile::parse(b"{\"cols\": 1, \"rows\": 1, \"tile_size\": [1, 1], \"index_format\": \"%x\"}").is_err());
+}
+
+#[test]
+fn test_saved_grid() {
+    use crate::dezoomer::PageContents;
+    let template = "http://x.com/{{X}}_{{Y}}.jpg";
+    let tile_grid = TileGrid {
+        origin: Vec2d::default(),
+        tile_count: Vec2d { x: 3, y: 2 },
+        tile_size: Vec2d::square(256),
+        image_size: Vec2d { x: 700, y: 500 },
+    };
+    let dir = tempdir::TempDir::new("dezoomify-rs-grid").unwrap();
+    let path = dir.path().join("grid.json");
+    GridFile::of_template(template, tile_grid).save(&path).unwrap();
+    let contents = std::fs::read(&path).unwrap();
+
+    let load = |uri: &str| {
+        let mut dezoomer = GenericDezoomer::default();
+        assert!(dezoomer.zoom_levels(&DezoomerInput { uri: uri.into(), contents: PageContents::Unknown }).is_err());
+        dezoomer.zoom_levels(&DezoomerInput { uri: "grid.json".into(), contents: PageContents::Success(contents.clone()) })
+    };
+    // The options of the search for the size are ignored
+    let mut lvl = load(&format!("{}#discovery=linear#grid=grid.json", template)).unwrap().into_iter().next().unwrap();
+    let zoom_level_iter = crate::dezoomer::ZoomLevelIter::new(&mut lvl);
+    assert_eq!(zoom_level_iter.tile_grid(), Some(tile_grid));
+    assert_eq!(zoom_level_iter.grid_file(), Some(GridFile::of_template(template, tile_grid)));
+    match load("http://x.com/{{Y}}_{{X}}.jpg#grid=grid.json") {
+        Err(DezoomerError::Other { source }) => assert!(source.to_string().contains(template), "{}", source),
+        other => panic!("The grid of another template should be refused, got {:?}", other.map(|_| ())),
+    }
+}
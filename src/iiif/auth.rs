@@ -0,0 +1,95 @@
+use serde::Deserialize;
+use serde_json::Value;
+
+/// Access control for an image, as described by the IIIF Authentication API 1.0.
+/// See https://iiif.io/api/auth/1.0/
+#[derive(Debug, Clone, PartialEq)]
+pub struct AuthService {
+    /// The page where the user has to log in to get access to the image
+    pub login: Option<String>,
+    /// The service that gives an access token to logged in users
+    pub token: String,
+}
+
+#[derive(Deserialize)]
+struct WithServices {
+    #[serde(default)]
+    service: Value,
+}
+
+/// Finds the token service advertised in an info.json file, if any
+pub fn find_auth_service(raw_info: &[u8]) -> Option<AuthService> {
+    let info: WithServices = serde_json::from_slice(raw_info).ok()?;
+    find_in(&info.service, None)
+}
+
+fn find_in(service: &Value, login: Option<&str>) -> Option<AuthService> {
+    match service {
+        Value::Array(services) => services.iter().find_map(|s| find_in(s, login)),
+        Value::Object(obj) => {
+            let id = obj.get("@id").or_else(|| obj.get("id")).and_then(Value::as_str);
+            let kind = ["profile", "type", "@type"].iter()
+                .filter_map(|&k| obj.get(k).and_then(Value::as_str))
+                .collect::<Vec<_>>();
+            let is = |suffixes: &[&str]| kind.iter().any(|k| suffixes.iter().any(|s| k.ends_with(s)));
+            if is(&["/auth/1/token", "AuthTokenService1"]) {
+                id.map(|token| AuthService { login: login.map(String::from), token: token.to_string() })
+            } else {
+                let is_login = is(&["/auth/1/login", "/auth/1/clickthrough", "/auth/1/kiosk",
+                    "/auth/1/external", "AuthCookieService1"]);
+                obj.get("service").and_then(|s| find_in(s, if is_login { id } else { login }))
+            }
+        }
+        _ => None,
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct TokenResponse {
+    access_token: Option<String>,
+    error: Option<String>,
+    description: Option<String>,
+}
+
+/// Reads the response of a token service
+pub fn parse_token(raw: &[u8]) -> Result<String, String> {
+    let response: TokenResponse = serde_json::from_slice(raw).map_err(|e| e.to_string())?;
+    match response {
+        TokenResponse { access_token: Some(token), .. } => Ok(token),
+        TokenResponse { error, description, .. } => Err(format!(
+            "{}: {}",
+            error.as_deref().unwrap_or("no access token"),
+            description.as_deref().unwrap_or("the token service did not give an access token")
+        )),
+    }
+}
+
+#[test]
+fn test_find_auth_service() {
+    let info = br#"{
+      "@id": "https://example.com/iiif/image",
+      "width": 100, "height": 100,
+      "service": {
+        "@context": "http://iiif.io/api/auth/1/context.json",
+        "@id": "https://example.com/login",
+        "profile": "http://iiif.io/api/auth/1/login",
+        "service": [
+          { "@id": "https://example.com/logout", "profile": "http://iiif.io/api/auth/1/logout" },
+          { "@id": "https://example.com/token", "profile": "http://iiif.io/api/auth/1/token" }
+        ]
+      }
+    }"#;
+    assert_eq!(find_auth_service(info), Some(AuthService {
+        login: Some("https://example.com/login".into()),
+        token: "https://example.com/token".into(),
+    }));
+    assert_eq!(find_auth_service(br#"{"width": 1, "height": 1}"#), None);
+}
+
+#[test]
+fn test_parse_token() {
+    assert_eq!(parse_token(br#"{"accessToken": "abc", "expiresIn": 3600}"#), Ok("abc".into()));
+    let err = parse_token(br#"{"error": "missingCredentials", "description": "log in first"}"#).unwrap_err();
+    assert_eq!(err, "missingCredentials: log in first");
+}
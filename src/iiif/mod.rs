@@ -1,8 +1,10 @@
+use std::collections::HashMap;
 use std::sync::Arc;
 
 use custom_error::custom_error;
-use log::{info, debug};
+use log::{info, debug, warn};
 
+use auth::AuthService;
 use tile_info::ImageInfo;
 
 use crate::dezoomer::*;
@@ -10,12 +12,23 @@ use crate::iiif::tile_info::TileSizeFormat;
 use crate::json_utils::all_json;
 use crate::max_size_in_rect;
 
+pub mod auth;
 pub mod tile_info;
 
 /// Dezoomer for the International Image Interoperability Framework.
 /// See https://iiif.io/
 #[derive(Default)]
-pub struct IIIF;
+pub struct IIIF {
+    /// An info.json file that advertises an authentication service,
+    /// waiting for the response of the token service
+    pending_auth: Option<PendingAuth>,
+}
+
+struct PendingAuth {
+    info_uri: String,
+    raw_info: Vec<u8>,
+    service: AuthService,
+}
 
 custom_error! {pub IIIFError
     JsonError{source: serde_json::Error} = "Invalid IIIF info.json file: {source}"
@@ -33,16 +46,47 @@ impl Dezoomer for IIIF {
     }
 
     fn zoom_levels(&mut self, data: &DezoomerInput) -> Result<ZoomLevels, DezoomerError> {
+        if let Some(pending) = &self.pending_auth {
+            if data.uri != pending.service.token {
+                return Err(DezoomerError::NeedsData { uri: pending.service.token.clone() });
+            }
+            let pending = self.pending_auth.take().expect("checked above");
+            let token = match &data.contents {
+                PageContents::Success(contents) => auth::parse_token(contents),
+                PageContents::Error(e) => Err(e.to_string()),
+                PageContents::Unknown => Err("the token service was not loaded".into()),
+            };
+            let access_token = token.map_err(|err| warn!(
+                "Unable to get an access token for this IIIF image ({}). \
+                Only a degraded version of the image may be available. \
+                Log in at {} in your browser, and then give your cookies to dezoomify-rs \
+                with -H \"Cookie: ...\"",
+                err, pending.service.login.as_deref().unwrap_or("the image website")
+            )).ok();
+            return Ok(zoom_levels(&pending.info_uri, &pending.raw_info, access_token)?);
+        }
         let with_contents = data.with_contents()?;
         let contents = with_contents.contents;
         let uri = with_contents.uri;
-        Ok(zoom_levels(uri, contents)?)
+        if let Some(service) = auth::find_auth_service(contents) {
+            info!("The IIIF image is protected by an authentication service. Requesting a token from {}",
+                  service.token);
+            let token_uri = service.token.clone();
+            self.pending_auth = Some(PendingAuth {
+                info_uri: uri.to_string(),
+                raw_info: contents.to_vec(),
+                service,
+            });
+            return Err(DezoomerError::NeedsData { uri: token_uri });
+        }
+        Ok(zoom_levels(uri, contents, None)?)
     }
 }
 
-fn zoom_levels(url: &str, raw_info: &[u8]) -> Result<ZoomLevels, IIIFError> {
+fn zoom_levels(url: &str, raw_info: &[u8], access_token: Option<String>) -> Result<ZoomLevels, IIIFError> {
+    let access_token: Option<Arc<str>> = access_token.map(Arc::from);
     match serde_json::from_slice(raw_info) {
-        Ok(info) => zoom_levels_from_info(url, info),
+        Ok(info) => zoom_levels_from_info(url, info, access_token),
         Err(e) => {
             // Due to the very fault-tolerant way we parse iiif manifests, a single javascript
             // object with a 'width' and a 'height' field is enough to be detected as an IIIF level
@@ -57,7 +101,7 @@ fn zoom_levels(url: &str, raw_info: &[u8]) -> Result<ZoomLevels, IIIFError> {
                     }
                     keep
                 })
-                .flat_map(|info| zoom_levels_from_info(url, info).into_iter().flatten())
+                .flat_map(|info| zoom_levels_from_info(url, info, access_token.clone()).into_iter().flatten())
                 .collect();
             if levels.is_empty() {
                 Err(e.into())
@@ -71,7 +115,7 @@ fn zoom_levels(url: &str, raw_info: &[u8]) -> Result<ZoomLevels, IIIFError> {
     }
 }
 
-fn zoom_levels_from_info(url: &str, mut image_info: ImageInfo) -> Result<ZoomLevels, IIIFError> {
+fn zoom_levels_from_info(url: &str, mut image_info: ImageInfo, access_token: Option<Arc<str>>) -> Result<ZoomLevels, IIIFError> {
     image_info.remove_test_id();
     let img = Arc::new(image_info);
     let tiles = img.tiles();
@@ -89,6 +133,7 @@ fn zoom_levels_from_info(url: &str, mut image_info: ImageInfo) -> Result<ZoomLev
             info!("Chose the following image parameters: tile_size=({}) quality={} format={}",
                   tile_size, quality, format);
             let page_info = &img; // Required to allow the move
+            let access_token = &access_token;
             tile_info
                 .scale_factors
                 .iter()
@@ -100,6 +145,7 @@ fn zoom_levels_from_info(url: &str, mut image_info: ImageInfo) -> Result<ZoomLev
                     quality: Arc::clone(&quality),
                     format: Arc::clone(&format),
                     size_format,
+                    access_token: access_token.clone(),
                 })
         })
         .into_zoom_levels();
@@ -114,6 +160,8 @@ struct IIIFZoomLevel {
     quality: Arc<str>,
     format: Arc<str>,
     size_format: TileSizeFormat,
+    /// Given by the authentication service of the image, if it has one
+    access_token: Option<Arc<str>>,
 }
 
 impl TilesRect for IIIFZoomLevel {
@@ -143,6 +191,12 @@ impl TilesRect for IIIFZoomLevel {
             format = self.format,
         )
     }
+
+    fn http_headers(&self) -> HashMap<String, String> {
+        self.access_token.iter()
+            .map(|token| ("Authorization".to_string(), format!("Bearer {}", token)))
+            .collect()
+    }
 }
 
 struct TileSizeFormatter { w: u32, h: u32, format: TileSizeFormat }
@@ -193,7 +247,7 @@ fn test_tiles() {
            "supports" : ["regionByPct","sizeByForcedWh","sizeByWh","sizeAboveFull","rotationBy90s","mirroring","gray"] }
       ]
     }"#;
-    let mut levels = zoom_levels("test.com", data).unwrap();
+    let mut levels = zoom_levels("test.com", data, None).unwrap();
     let tiles: Vec<String> = levels[6]
         .next_tiles(None)
         .into_iter()
@@ -211,7 +265,7 @@ fn test_missing_id() {
       "width" : 600,
       "height" : 350
     }"#;
-    let mut levels = zoom_levels("http://test.com/info.json", data).unwrap();
+    let mut levels = zoom_levels("http://test.com/info.json", data, None).unwrap();
     let tiles: Vec<String> = levels[0]
         .next_tiles(None)
         .into_iter()
@@ -236,7 +290,7 @@ fn test_false_positive() {
         tilesUrl:   "./ORIONFINAL/"
     };
     "#;
-    let res = zoom_levels("https://orion2020v5b.spaceforeverybody.com/", data);
+    let res = zoom_levels("https://orion2020v5b.spaceforeverybody.com/", data, None);
     assert!(res.is_err(), "openseadragon zoomify image should not be misdetected");
 }
 
@@ -254,7 +308,7 @@ fn test_qualities() {
         "formats" : [ "png", "zorglub" ],
         "scale_factors": [ 10 ]
     }"#;
-    let mut levels = zoom_levels("test.com", data).unwrap();
+    let mut levels = zoom_levels("test.com", data, None).unwrap();
     let level = &mut levels[0];
     assert_eq!(level.size_hint(), Some(Vec2d { x: 515, y: 381 }));
     let tiles: Vec<String> = level
@@ -137,6 +137,7 @@ impl TilesRect for Level {
         TileReference {
             url: self.tile_url(pos),
             position: self.tile_size() * pos,
+            cell_size: None,
         }
     }
 }
@@ -164,8 +165,8 @@ fn test_cube() {
     assert_eq!(levels[0].size_hint(), Some(Vec2d { x: 1000, y: 100 }));
     assert_eq!(format!("{:?}", levels[0]), "Krpano Cube forward");
     assert_eq!(levels[0].next_tiles(None), vec![
-        TileReference { url: "http://example.com/f/1/1.jpg".to_string(), position: Vec2d { x: 0, y: 0 } },
-        TileReference { url: "http://example.com/f/1/2.jpg".to_string(), position: Vec2d { x: 512, y: 0 } }]);
+        TileReference { url: "http://example.com/f/1/1.jpg".to_string(), position: Vec2d { x: 0, y: 0 }, cell_size: None },
+        TileReference { url: "http://example.com/f/1/2.jpg".to_string(), position: Vec2d { x: 512, y: 0 }, cell_size: None }]);
 }
 
 #[test]
@@ -182,6 +183,30 @@ fn test_flat_multires() {
     assert_eq!(levels[1].size_hint(), Some(Vec2d { x: 3, y: 4 }));
     assert_eq!(format!("{:?}", levels[0]), "Krpano Flat");
     assert_eq!(levels[1].next_tiles(None), vec![
-        TileReference { url: "http://test.com/level=2%20x=01%20y=01".to_string(), position: Vec2d { x: 0, y: 0 } },
-        TileReference { url: "http://test.com/level=2%20x=01%20y=02".to_string(), position: Vec2d { x: 0, y: 3 } }]);
-}
\ No newline at end of file
+        TileReference { url: "http://test.com/level=2%20x=01%20y=01".to_string(), position: Vec2d { x: 0, y: 0 }, cell_size: None },
+        TileReference { url: "http://test.com/level=2%20x=01%20y=02".to_string(), position: Vec2d { x: 0, y: 3 }, cell_size: None }]);
+}
+
+#[test]
+fn test_multires_cube_face() {
+    let levels = load_from_properties(
+        "http://example.com/tour.xml",
+        r#"<krpano>
+        <image type="cube" multires="true" tilesize="512">
+            <cube url="pano.tiles/%s/l%l/%0v/l%l_%s_%0v_%0h.jpg" multires="512,640,1280,2560"/>
+        </image>
+        </krpano>"#.as_bytes(),
+    ).unwrap();
+    // Three levels of six faces each
+    assert_eq!(levels.len(), 18);
+    let mut front = levels.into_iter().filter(|l| format!("{:?}", l) == "Krpano Cube forward").last().unwrap();
+    assert_eq!(front.size_hint(), Some(Vec2d { x: 2560, y: 2560 }));
+    let tiles = front.next_tiles(None);
+    assert_eq!(tiles.len(), 25);
+    let tile = |url: &str, x, y| TileReference { url: url.to_string(), position: Vec2d { x, y }, cell_size: None };
+    assert_eq!(tiles[..2], [
+        tile("http://example.com/pano.tiles/f/l3/01/l3_f_01_01.jpg", 0, 0),
+        tile("http://example.com/pano.tiles/f/l3/01/l3_f_01_02.jpg", 512, 0),
+    ]);
+    assert_eq!(tiles[24], tile("http://example.com/pano.tiles/f/l3/05/l3_f_05_05.jpg", 2048, 2048));
+}
@@ -1,27 +1,40 @@
 use std::{fs, fmt, io};
+use std::collections::HashMap;
 use std::io::BufRead;
 use std::path::PathBuf;
-use std::time::Duration;
+use std::sync::Arc;
+use std::time::Instant;
 
+use futures::FutureExt;
 use futures::stream::StreamExt;
 use indicatif::{ProgressBar, ProgressStyle};
 use itertools::Itertools;
-use log::{debug, info, warn};
+use log::{debug, error, info, warn};
 use reqwest::Client;
 
 pub use arguments::Arguments;
-use dezoomer::{PostProcessFn, TileFetchResult, ZoomLevel, ZoomLevelIter};
+pub use capabilities::Capabilities;
+use dezoomer::{TileFetchResult, TileGrid, ZoomLevel, ZoomLevelIter};
 use dezoomer::{Dezoomer, DezoomerError, DezoomerInput, ZoomLevels};
 use dezoomer::TileReference;
 pub use errors::ZoomError;
-use network::{client, fetch_uri};
-use output_file::get_outname;
-use tile::Tile;
+use network::{client, fetch_uri, probe_uri, TileClients};
+use output_file::{get_outname, image_outfile, level_outfile};
+use tile::{DecodeOptions, DownloadContext, Tile, TileStages};
 pub use vec2d::Vec2d;
 
 use crate::encoder::tile_buffer::TileBuffer;
-use crate::output_file::reserve_output_file;
+use crate::output_file::{is_existing_output, rename_to_content_hash, reserve_output_file, reserve_unique_output_file};
 use crate::dezoomer::PageContents;
+use crate::stitch_offset::StitchOffsetCorrector;
+use crate::pause::PauseControl;
+use crate::crop::Crop;
+use crate::provenance::ProvenanceLog;
+use crate::preview::DiscoveryPreview;
+use crate::generic::GridFile;
+use crate::token::{TokenChain, TokenSource};
+use crate::retry::{RetryBudget, RetryPolicy};
+use crate::template_check::TemplateCheck;
 use std::error::Error;
 use std::env::current_dir;
 
@@ -33,6 +46,22 @@ mod vec2d;
 mod errors;
 mod output_file;
 mod network;
+mod stitch_offset;
+mod tile_metadata;
+mod color_profile;
+mod pause;
+mod retry;
+mod capabilities;
+mod verify;
+mod template_check;
+mod browser_request;
+mod crop;
+mod provenance;
+mod token;
+mod gallery;
+mod preview;
+#[cfg(feature = "websocket")]
+mod websocket;
 
 pub mod auto;
 pub mod custom_yaml;
@@ -45,6 +74,8 @@ pub mod zoomify;
 pub mod krpano;
 pub mod nypl;
 pub mod iipimage;
+#[cfg(feature = "script")]
+pub mod script;
 mod json_utils;
 
 fn stdin_line() -> Result<String, ZoomError> {
@@ -57,18 +88,19 @@ fn stdin_line() -> Result<String, ZoomError> {
     Ok(first_line?)
 }
 
+/// Returns the zoom levels, and the uri of the file they were found in
 async fn list_tiles(
     dezoomer: &mut dyn Dezoomer,
     http: &Client,
     uri: &str,
-) -> Result<ZoomLevels, ZoomError> {
+) -> Result<(ZoomLevels, String), ZoomError> {
     let mut i = DezoomerInput {
         uri: String::from(uri),
         contents: PageContents::Unknown,
     };
     loop {
         match dezoomer.zoom_levels(&i) {
-            Ok(levels) => return Ok(levels),
+            Ok(levels) => return Ok((levels, i.uri)),
             Err(DezoomerError::NeedsData { uri }) => {
                 let contents = fetch_uri(&uri, http).await.into();
                 debug!("Response for metadata file '{}': {:?}", uri, &contents);
@@ -99,9 +131,19 @@ fn level_picker(mut levels: Vec<ZoomLevel>) -> Result<ZoomLevel, ZoomError> {
 }
 
 fn choose_level(mut levels: Vec<ZoomLevel>, args: &Arguments) -> Result<ZoomLevel, ZoomError> {
+    if let Some(index) = args.level {
+        let count = levels.len();
+        return if index < count { Ok(levels.swap_remove(index)) } else {
+            Err(ZoomError::NoSuchLevel { index, count })
+        };
+    }
     match levels.len() {
         0 => Err(ZoomError::NoLevels),
-        1 => Ok(levels.swap_remove(0)),
+        1 => {
+            if args.preview { warn!("There is no smaller zoom level, the full image will be downloaded"); }
+            if args.target_mp.is_some() { warn!("There is a single zoom level, --target-mp is ignored"); }
+            Ok(levels.swap_remove(0))
+        }
         _ => {
             let pos = args
                 .best_size(levels.iter().filter_map(|l| l.size_hint()))
@@ -119,6 +161,67 @@ fn choose_level(mut levels: Vec<ZoomLevel>, args: &Arguments) -> Result<ZoomLeve
     }
 }
 
+/// Number of tiles of each level that are checked with --prefer-complete
+const COMPLETENESS_SAMPLE: usize = 8;
+
+/// Selects the largest level, among the ones allowed by the size options, whose sampled tiles
+/// are all available. The largest allowed level is selected if none is complete.
+/// The levels are not changed: the tiles are sampled from the tiles they list in advance.
+async fn choose_complete_level(mut levels: Vec<ZoomLevel>, args: &Arguments, manifest_uri: &str) -> Result<ZoomLevel, ZoomError> {
+    if levels.len() < 2 { return choose_level(levels, args); }
+    let max_area = args.best_size(levels.iter().filter_map(|l| l.size_hint())).map(Vec2d::area);
+    let sized = levels.iter().any(|l| l.size_hint().is_some());
+    let mut candidates: Vec<(usize, u64)> = levels.iter().enumerate().filter_map(|(i, level)| {
+        let rank = if sized {
+            level.size_hint().map(Vec2d::area).filter(|&area| max_area.is_none_or(|max| area <= max))
+        } else {
+            // Without sizes, as in scripts, the levels with the most tiles are the largest ones
+            level.known_tiles().map(|tiles| tiles.len() as u64)
+        };
+        rank.map(|rank| (i, rank))
+    }).collect();
+    candidates.sort_by_key(|&(_, rank)| std::cmp::Reverse(rank));
+    let candidates: Vec<usize> = candidates.into_iter().map(|(i, _)| i).collect();
+    for &i in &candidates {
+        let level = &levels[i];
+        let tiles = match level.known_tiles() {
+            Some(tiles) => tiles,
+            None => {
+                info!("{} cannot be checked before it is downloaded", level.name());
+                continue;
+            }
+        };
+        let clients = TileClients::new(&level.http_headers(), &level.host_http_headers(), args, Some(manifest_uri))?;
+        let clients = &clients;
+        let missing = futures::stream::iter(spread_sample(&tiles, COMPLETENESS_SAMPLE))
+            .map(|tile| async move { (tile, probe_uri(&tile.url, clients.for_url(&tile.url)).await) })
+            .buffer_unordered(args.parallelism)
+            .filter(|(_, available)| futures::future::ready(!available))
+            .next().await;
+        match missing {
+            Some((tile, _)) => info!("{} is incomplete: the tile '{}' is missing", level.name(), tile.url),
+            None => {
+                info!("Selected {}, whose sampled tiles are all available", level.name());
+                return Ok(levels.swap_remove(i));
+            }
+        }
+    }
+    match candidates.first() {
+        Some(&i) => {
+            warn!("No zoom level has all its sampled tiles available. Downloading {}", levels[i].name());
+            Ok(levels.swap_remove(i))
+        }
+        None => choose_level(levels, args),
+    }
+}
+
+/// At most `count` items, evenly spread in the slice, including the first and the last one
+fn spread_sample<T>(items: &[T], count: usize) -> impl Iterator<Item=&T> {
+    let count = count.min(items.len());
+    let last = items.len().saturating_sub(1);
+    (0..count).map(move |i| &items[if count > 1 { i * last / (count - 1) } else { 0 }])
+}
+
 fn progress_bar(n: usize) -> ProgressBar {
     let progress = ProgressBar::new(n as u64);
     progress.set_style(
@@ -129,35 +232,268 @@ fn progress_bar(n: usize) -> ProgressBar {
     progress
 }
 
-async fn find_zoomlevel(args: &Arguments) -> Result<ZoomLevel, ZoomError> {
+/// Returns the zoom levels to download, with their indices among all the levels of the image,
+/// and the uri of the file that described them.
+/// This is a single chosen level, unless the input describes several distinct images,
+/// or a range of levels was requested.
+async fn find_zoomlevels(args: &Arguments) -> Result<(Vec<(usize, ZoomLevel)>, String), ZoomError> {
+    find_zoomlevels_at(args, &args.choose_input_uri()?).await
+}
+
+async fn find_zoomlevels_at(args: &Arguments, uri: &str) -> Result<(Vec<(usize, ZoomLevel)>, String), ZoomError> {
+    let mut dezoomer = args.find_dezoomer()?;
+    let http_client = client(args.headers(), args, Some(uri))?;
+    info!("Trying to locate a zoomable image...");
+    let (zoom_levels, manifest_uri) = list_tiles(dezoomer.as_mut(), &http_client, uri).await?;
+    let distinct_images = !zoom_levels.is_empty() && zoom_levels.iter().all(|l| l.is_distinct_image());
+    if let Some(range) = &args.levels {
+        info!("Found {} zoom levels", zoom_levels.len());
+        let count = zoom_levels.len();
+        let selected: Vec<_> = zoom_levels.into_iter().enumerate().filter(|(i, _)| range.contains(i)).collect();
+        if selected.is_empty() { return Err(ZoomError::NoSuchLevel { index: *range.start(), count }); }
+        Ok((selected, manifest_uri))
+    } else if distinct_images && args.level.is_none() {
+        info!("Found {} images", zoom_levels.len());
+        Ok((zoom_levels.into_iter().enumerate().collect(), manifest_uri))
+    } else if args.prefer_complete {
+        info!("Found {} zoom levels", zoom_levels.len());
+        let level = choose_complete_level(zoom_levels, args, &manifest_uri).await?;
+        Ok((vec![(0, level)], manifest_uri))
+    } else {
+        info!("Found {} zoom levels", zoom_levels.len());
+        Ok((vec![(0, choose_level(zoom_levels, args)?)], manifest_uri))
+    }
+}
+
+/// What is known about a zoom level before downloading it
+#[derive(Debug, Clone, PartialEq)]
+pub struct LevelSummary {
+    /// The index to give to --level in order to download this level
+    pub index: usize,
+    pub name: String,
+    pub size: Option<Vec2d>,
+    pub tile_count: Option<u64>,
+}
+
+impl LevelSummary {
+    fn new(index: usize, level: &ZoomLevel) -> Self {
+        LevelSummary {
+            index,
+            name: level.name(),
+            size: level.size_hint(),
+            tile_count: level.tile_grid().map(|grid| grid.tile_count.area()),
+        }
+    }
+}
+
+impl std::fmt::Display for LevelSummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{: >2}. {}", self.index, self.name)?;
+        if self.size.is_none() {
+            write!(f, " (the size will only be known once the tiles are downloaded)")?;
+        }
+        Ok(())
+    }
+}
+
+/// Find the zoom levels of the image, and describe them without downloading any tile
+pub async fn list_levels(args: &Arguments) -> Result<Vec<LevelSummary>, ZoomError> {
     let mut dezoomer = args.find_dezoomer()?;
     let uri = args.choose_input_uri()?;
     let http_client = client(args.headers(), args, Some(&uri))?;
-    info!("Trying to locate a zoomable image...");
-    let zoom_levels: Vec<ZoomLevel> = list_tiles(dezoomer.as_mut(), &http_client, &uri).await?;
-    info!("Found {} zoom levels", zoom_levels.len());
-    choose_level(zoom_levels, args)
+    let (zoom_levels, _) = list_tiles(dezoomer.as_mut(), &http_client, &uri).await?;
+    if zoom_levels.is_empty() { return Err(ZoomError::NoLevels); }
+    Ok(zoom_levels.iter().enumerate().map(|(i, level)| LevelSummary::new(i, level)).collect())
+}
+
+/// The result of a successful image download
+#[derive(Debug)]
+pub struct Download {
+    /// The path of the resulting image file
+    pub saved_as: PathBuf,
+    /// The layout of the tiles in the downloaded zoom level, if it is known
+    pub tile_grid: Option<TileGrid>,
 }
 
 pub async fn dezoomify(args: &Arguments) -> Result<PathBuf, ZoomError> {
-    let zoom_level = find_zoomlevel(&args).await?;
+    dezoomify_download(args).await.map(|download| download.saved_as)
+}
+
+/// Download an image, and return information about what was downloaded.
+/// When the input describes several distinct images, they are all downloaded,
+/// and the last one is returned.
+pub async fn dezoomify_download(args: &Arguments) -> Result<Download, ZoomError> {
+    let mut downloads = dezoomify_images(args).await?;
+    downloads.pop().ok_or(ZoomError::NoLevels)
+}
+
+/// Download all the images described by the input, or all the selected levels,
+/// one after the other
+pub async fn dezoomify_images(args: &Arguments) -> Result<Vec<Download>, ZoomError> {
+    let images = if args.gallery { find_gallery_images(args).await? } else { vec![find_zoomlevels(&args).await?] };
+    let count = images.iter().map(|(zoom_levels, _)| zoom_levels.len()).sum();
+    let mut downloads = Vec::with_capacity(count);
+    let mut failed = 0;
+    let mut session = Session::new(args)?;
+    let levels = images.into_iter()
+        .flat_map(|(zoom_levels, manifest_uri)| zoom_levels.into_iter().map(move |l| (l, manifest_uri.clone())));
+    for (index, ((level_index, zoom_level), manifest_uri)) in levels.enumerate() {
+        let outfile = if args.levels.is_some() && !args.gallery {
+            level_outfile(&args.outfile, level_index)
+        } else {
+            image_outfile(&args.outfile, index, count, zoom_level.title().as_deref())
+        };
+        match download_level(&mut session, args, zoom_level, &manifest_uri, &outfile).await {
+            Ok(download) => downloads.push(download),
+            // The other images of a gallery are still downloaded
+            Err(e) if args.gallery => {
+                error!("Unable to download the image {} of the gallery from '{}': {}", index + 1, manifest_uri, e);
+                failed += 1;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    if failed > 0 {
+        return Err(ZoomError::IncompleteGallery { failed, total: count });
+    }
+    Ok(downloads)
+}
+
+/// The zoom levels of the images linked from the gallery page given as input.
+/// The links in which no zoomable image is found are reported and skipped.
+async fn find_gallery_images(args: &Arguments) -> Result<Vec<(Vec<(usize, ZoomLevel)>, String)>, ZoomError> {
+    let uri = args.choose_input_uri()?;
+    let http_client = client(args.headers(), args, Some(&uri))?;
+    let page = fetch_uri(&uri, &http_client).await?;
+    let links = gallery::gallery_links(&String::from_utf8_lossy(&page), &uri, args.gallery_links.as_ref());
+    info!("Found {} links to zoomable images in the gallery page", links.len());
+    let mut images = Vec::with_capacity(links.len());
+    for link in links {
+        match find_zoomlevels_at(args, &link).await {
+            Ok(image) => images.push(image),
+            Err(e) => warn!("Skipping the gallery link '{}': {}", link, e),
+        }
+    }
+    if images.is_empty() { return Err(ZoomError::EmptyGallery { uri }); }
+    Ok(images)
+}
+
+/// What the downloads of the levels of a single run share:
+/// the connections to the servers, the limits on the number of concurrent downloads,
+/// the file in which the tile requests are recorded, and the tokens that authenticate them
+struct Session {
+    stages: TileStages,
+    /// The clients of the previous level, and what they were created from
+    clients: Option<(ClientsKey, TileClients)>,
+    provenance: Option<ProvenanceLog>,
+    token: Option<Arc<TokenSource>>,
+    token_chain: Option<Arc<TokenChain>>,
+}
+
+/// The headers of a level, its headers for specific hosts, and the uri of its manifest
+type ClientsKey = (HashMap<String, String>, HashMap<String, HashMap<String, String>>, Option<String>);
+
+impl Session {
+    fn new(args: &Arguments) -> Result<Self, ZoomError> {
+        let provenance = args.provenance.as_deref().map(ProvenanceLog::create).transpose()?;
+        let token = args.token_url.as_deref()
+            .map(|url| TokenSource::new(url, &args.token_header).map(Arc::new))
+            .transpose()?;
+        let token_chain = args.chain_token.clone()
+            .map(|source| TokenChain::new(source, &args.token_header).map(Arc::new))
+            .transpose()?;
+        Ok(Session { stages: args.tile_stages(), clients: None, provenance, token, token_chain })
+    }
+
+    /// The clients for a level. The ones of the previous level are reused if they send the same headers.
+    fn clients(&mut self, zoom_level: &ZoomLevel, args: &Arguments, manifest_uri: Option<&str>) -> Result<TileClients, ZoomError> {
+        let key = (zoom_level.http_headers(), zoom_level.host_http_headers(), manifest_uri.map(String::from));
+        match &self.clients {
+            Some((previous, clients)) if previous == &key => Ok(clients.clone()),
+            _ => {
+                let clients = TileClients::new(&key.0, &key.1, args, manifest_uri)?
+                    .with_token(self.token.clone())
+                    .with_token_chain(self.token_chain.clone());
+                self.clients = Some((key, clients.clone()));
+                Ok(clients)
+            }
+        }
+    }
+}
+
+async fn download_level(
+    session: &mut Session,
+    args: &Arguments,
+    zoom_level: ZoomLevel,
+    manifest_uri: &str,
+    outfile: &Option<PathBuf>,
+) -> Result<Download, ZoomError> {
     let base_dir = current_dir()?;
-    let outname = get_outname(&args.outfile, &zoom_level.title(), &base_dir,zoom_level.size_hint());
+    let outname = get_outname(outfile, &zoom_level.title(), &base_dir, zoom_level.size_hint());
     let save_as = fs::canonicalize(outname.as_path()).unwrap_or_else(|_e| outname.clone());
-    reserve_output_file(&save_as)?;
-    let tile_buffer: TileBuffer = TileBuffer::new(save_as.clone(), args.compression).await?;
+    if let Some(max_age) = args.skip_existing_max_age() {
+        if is_existing_output(&save_as, max_age) {
+            warn!("Skipping {}: the output file {:?} already exists", zoom_level.name(), save_as);
+            return Ok(Download { saved_as: save_as, tile_grid: zoom_level.tile_grid() });
+        }
+    }
+    let size_hint = zoom_level.size_hint()
+        .map(|size| args.crop_fraction.map_or(size, |fraction| fraction.resolve(size).size));
+    if let Some(size) = args.dimensions.or(size_hint) {
+        // Before the output file is created
+        check_output_size(size, args)?;
+    }
+    let save_as = if outfile.is_some() {
+        reserve_output_file(&save_as, args.overwrite)?;
+        save_as
+    } else {
+        reserve_unique_output_file(&save_as)?
+    };
+    let encoder_options = args.encoder_options();
+    let is_split = encoder_options.split.is_some();
+    let tile_buffer: TileBuffer = TileBuffer::new(save_as.clone(), encoder_options).await?;
     info!("Dezooming {}", zoom_level.name());
-    dezoomify_level(args, zoom_level, tile_buffer).await?;
-    Ok(save_as)
+    let tile_grid = match dezoomify_level_in(session, args, zoom_level, tile_buffer, Some(manifest_uri)).await {
+        Err(e @ ZoomError::OutputTooLarge { .. }) => {
+            // The size of generic templates is found only after the output file is created
+            if save_as.is_file() {
+                if let Err(remove_err) = fs::remove_file(&save_as) {
+                    warn!("Unable to remove the output file {:?}: {}", save_as, remove_err);
+                }
+            }
+            return Err(e);
+        }
+        result => result?,
+    };
+    let saved_as = if is_split {
+        if args.hash_name { warn!("--hash-name is ignored when the image is split into several files"); }
+        encoder::split_encoder::manifest_path(&save_as)
+    } else if args.hash_name && save_as.is_file() {
+        rename_to_content_hash(&save_as, args.name_sidecar)?
+    } else {
+        if args.hash_name { warn!("--hash-name is ignored for outputs that are not a single file"); }
+        save_as
+    };
+    Ok(Download { saved_as, tile_grid })
 }
 
 pub async fn dezoomify_level(
+    args: &Arguments,
+    zoom_level: ZoomLevel,
+    tile_buffer: TileBuffer,
+    manifest_uri: Option<&str>,
+) -> Result<Option<TileGrid>, ZoomError> {
+    dezoomify_level_in(&mut Session::new(args)?, args, zoom_level, tile_buffer, manifest_uri).await
+}
+
+async fn dezoomify_level_in(
+    session: &mut Session,
     args: &Arguments,
     mut zoom_level: ZoomLevel,
     tile_buffer: TileBuffer,
-) -> Result<(), ZoomError> {
-    let level_headers = zoom_level.http_headers();
-    let http_client = client(level_headers.iter().chain(args.headers()), &args, None)?;
+    manifest_uri: Option<&str>,
+) -> Result<Option<TileGrid>, ZoomError> {
+    let http_clients = session.clients(&zoom_level, args, manifest_uri)?;
 
     info!("Creating canvas");
     let mut canvas = tile_buffer;
@@ -166,14 +502,64 @@ pub async fn dezoomify_level(
     let mut total_tiles = 0u64;
     let mut successful_tiles = 0u64;
 
-    let post_process_fn = zoom_level.post_process_fn();
+    let stages = &session.stages;
+    let context = DownloadContext {
+        post_process_fn: zoom_level.post_process_fn(),
+        decode_options: DecodeOptions {
+            position_source: zoom_level.position_source(),
+            convert_srgb: args.convert_srgb,
+            resample: args.resample,
+            keep_encoded: canvas.stores_encoded_tiles(),
+        },
+        clients: &http_clients,
+        stages,
+        provenance: session.provenance.as_ref(),
+    };
 
     progress.set_message("Computing the URLs of the image tiles...");
 
+    let mut offset_corrector = StitchOffsetCorrector::new(
+        args.stitch_offset.unwrap_or_default(),
+        args.stitch_offset_mode,
+    );
+
+    let retry_budget = RetryBudget::new(args.retry_budget);
+    let pause = PauseControl::new(args.pause_file.clone());
+    let mut template_check = TemplateCheck::new(zoom_level.is_guessed(), args.strict);
+    let mut crop = Crop::new(args.crop_fraction);
+    // Only the levels whose size is found by probing the server have a discovery to show
+    let mut preview = args.discovery_preview.clone()
+        .filter(|_| zoom_level.size_hint().is_none())
+        .map(DiscoveryPreview::new);
+    let mut grid_to_save = args.save_grid.as_deref();
     let mut zoom_level_iter = ZoomLevelIter::new(&mut zoom_level);
     let mut last_count = 0;
     let mut last_successes = 0;
-    while let Some(tile_refs) = zoom_level_iter.next_tile_references() {
+    // The part of the resulting image that is covered by the tiles received so far
+    let mut extent = Vec2d::default();
+    // The tiles to download a second time, with the hash of their pixels
+    let mut sample = vec![];
+    let verify_sample = args.verify_sample;
+    while let Some(mut tile_refs) = zoom_level_iter.next_tile_references() {
+        template_check.check(zoom_level_iter.tile_grid(), false)?;
+        if let Some(path) = grid_to_save {
+            // As soon as the grid is found, so that it is kept if the download is interrupted
+            if let Some(grid) = zoom_level_iter.grid_file() {
+                save_grid(&grid, path);
+                grid_to_save = None;
+            }
+        }
+        offset_corrector.learn_grid(&tile_refs);
+        let size_hint = zoom_level_iter.size_hint().map(|size| offset_corrector.correct_size(size));
+        if let Some(size) = size_hint {
+            check_output_size(crop.set_image_size(size), args)?;
+        } else if args.crop_fraction.is_none() {
+            // Each tile has at least one pixel
+            let extent = tile_refs.iter().fold(Vec2d::default(), |e, t| e.max(t.position + Vec2d::square(1)));
+            check_output_size(extent, args)?;
+        }
+        let grid_tile_size = zoom_level_iter.tile_grid().map(|grid| grid.tile_size);
+        tile_refs.retain(|tile_ref| crop.is_needed(tile_ref, grid_tile_size));
         last_count = tile_refs.len() as u64;
         total_tiles += last_count;
         progress.set_length(total_tiles);
@@ -181,25 +567,44 @@ pub async fn dezoomify_level(
         progress.set_message("Requesting the tiles...");
 
         let &Arguments { retries, retry_delay, .. } = args;
-        let mut stream = futures::stream::iter(tile_refs)
-            .map(|tile_ref: TileReference|
-                download_tile(post_process_fn, tile_ref, &http_client, retries, retry_delay))
-            .buffer_unordered(args.parallelism);
+        let retry_budget = &retry_budget;
+        let (pause, progress_ref) = (&pause, &progress);
+        let mut stream = Box::pin(futures::stream::iter(tile_refs)
+            // No new tile is dispatched while the download is paused
+            .then(|tile_ref| async move {
+                pause.wait(progress_ref).await;
+                tile_ref
+            })
+            .map(|tile_ref: TileReference| {
+                let retry = RetryPolicy { retries, retry_delay, budget: retry_budget };
+                let sampled = verify_sample
+                    .filter(|&percent| verify::is_sampled(&tile_ref, percent))
+                    .map(|_| tile_ref.clone());
+                download_tile(&context, retry, tile_ref)
+                    .map(move |result| (sampled, result))
+            })
+            .buffer_unordered(stages.width()));
 
         last_successes = 0;
         let mut tile_size = None;
+        let batch_start = Instant::now();
 
-        if let Some(size) = zoom_level_iter.size_hint() {
-            canvas.set_size(size).await?;
+        if let Some(size) = size_hint {
+            if let Some(mut preview) = preview.take() { preview.finish(); }
+            canvas.set_size(crop.set_image_size(size)).await?;
+            for tile in crop.take_pending(false) { canvas.add_tile(tile).await; }
         }
 
-        while let Some(tile_result) = stream.next().await {
+        while let Some((sampled, tile_result)) = stream.next().await {
             debug!("Received tile result: {:?}", tile_result);
+            template_check.record(&tile_result);
             progress.inc(1);
             let tile = match tile_result {
                 Ok(tile) => {
                     progress.set_message(&format!("Downloaded tile at {}", tile.position()));
+                    if let Some(tile_ref) = sampled { sample.push((tile_ref, verify::pixels_hash(&tile))); }
                     tile_size.replace(tile.size());
+                    if let Some(preview) = &mut preview { preview.add_tile(&tile); }
                     last_successes += 1;
                     Some(tile)
                 }
@@ -215,16 +620,35 @@ pub async fn dezoomify_level(
                     })
                 }
             };
-            if let Some(tile) = tile { canvas.add_tile(tile).await; }
+            if let Some(tile) = tile.and_then(|tile| crop.place(offset_corrector.place(tile))) {
+                extent = extent.max(tile.bottom_right());
+                check_output_size(extent, args)?;
+                canvas.add_tile(tile).await;
+            }
         }
         successful_tiles += last_successes;
+        if let Some(preview) = &mut preview { preview.batch_done(); }
         zoom_level_iter.set_fetch_result(TileFetchResult {
             count: last_count,
             successes: last_successes,
             tile_size,
+            duration: Some(batch_start.elapsed()),
         });
     }
 
+    debug!("Up to {} tiles were fetched and {} decoded at the same time",
+           stages.fetch.peak(), stages.decode.peak());
+    let tile_grid = zoom_level_iter.tile_grid();
+    if let Some(path) = grid_to_save {
+        match zoom_level_iter.grid_file() {
+            Some(grid) => save_grid(&grid, path),
+            None => warn!("The layout of the tiles was not saved to {:?}: \
+                           only the layouts of generic templates can be saved", path),
+        }
+    }
+    if let Some(mut preview) = preview { preview.finish(); }
+    template_check.check(tile_grid, true)?;
+    for tile in crop.take_pending(true) { canvas.add_tile(tile).await; }
     progress.set_message("Downloaded all tiles. Finalizing the image file.");
     canvas.finalize().await?;
 
@@ -232,41 +656,56 @@ pub async fn dezoomify_level(
     if successful_tiles == 0 { return Err(ZoomError::NoTile); }
 
     if last_successes < last_count {
-        Err(ZoomError::PartialDownload { successful_tiles, total_tiles })
-    } else {
-        Ok(())
+        return Err(ZoomError::PartialDownload { successful_tiles, total_tiles });
+    }
+    if !sample.is_empty() {
+        info!("Downloading {} tiles a second time to check that they did not change", sample.len());
+        let &Arguments { retries, retry_delay, .. } = args;
+        verify::verify_sample(sample, stages.width(), |tile_ref| {
+            let retry = RetryPolicy { retries, retry_delay, budget: &retry_budget };
+            download_tile(&context, retry, tile_ref)
+        }).await?;
+    }
+    Ok(tile_grid)
+}
+
+/// Saves the layout of the tiles for --save-grid. The download continues if it cannot be saved.
+fn save_grid(grid: &GridFile, path: &std::path::Path) {
+    match grid.save(path) {
+        Ok(()) => info!("Saved the layout of the tiles to {:?}", path),
+        Err(e) => warn!("Unable to save the layout of the tiles to {:?}: {}", path, e),
+    }
+}
+
+/// Stops the download of an image that would be larger than allowed by --max-output-pixels,
+/// given its size or the size of a part of it.
+/// When --dimensions is given, it is the size of the output whatever the size of the image.
+fn check_output_size(size: Vec2d, args: &Arguments) -> Result<(), ZoomError> {
+    let size = args.dimensions.unwrap_or(size);
+    match args.max_output_pixels {
+        Some(max_pixels) if u64::from(size.x) * u64::from(size.y) > max_pixels => {
+            Err(ZoomError::OutputTooLarge { width: size.x, height: size.y, max_pixels })
+        }
+        _ => Ok(()),
     }
 }
 
 async fn download_tile(
-    post_process_fn: PostProcessFn,
+    context: &DownloadContext<'_>,
+    retry: RetryPolicy<'_>,
     tile_reference: TileReference,
-    client: &reqwest::Client,
-    retries: usize,
-    retry_delay: Duration,
 ) -> Result<Tile, TileDownloadError> {
-    let mut res = Tile::download(post_process_fn, &tile_reference, client).await;
+    let download = || Tile::download(context, &tile_reference);
     // The initial delay after which a failed request is retried depends on the position of the tile
     // in order to avoid sending repeated "bursts" of requests to a server that is struggling
     let n = 100;
     let idx: f64 = ((tile_reference.position.x + tile_reference.position.y) % n).into();
-    let mut wait_time = retry_delay + Duration::from_secs_f64(idx * retry_delay.as_secs_f64() / n as f64);
-    for _ in 0..retries {
-        res = Tile::download(post_process_fn, &tile_reference, client).await;
-        match &res {
-            Ok(_) => { break; },
-            Err(e) => {
-                warn!("{}. Retrying tile download in {:?}.", e, wait_time);
-                tokio::time::sleep(wait_time).await;
-                wait_time *= 2;
-            }
-        }
-    }
+    let res = retry.run(idx / f64::from(n), download).await;
     res.map_err(|cause| TileDownloadError { tile_reference, cause })
 }
 
 #[derive(Debug)]
-struct TileDownloadError {
+pub(crate) struct TileDownloadError {
     tile_reference: TileReference,
     cause: ZoomError,
 }
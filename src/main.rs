@@ -2,7 +2,7 @@ use colour::{green_ln, red_ln};
 use human_panic::setup_panic;
 use structopt::StructOpt;
 
-use dezoomify_rs::{Arguments, dezoomify, ZoomError};
+use dezoomify_rs::{Arguments, Capabilities, dezoomify_images, list_levels, ZoomError};
 
 #[tokio::main]
 async fn main() {
@@ -12,8 +12,25 @@ async fn main() {
     let args: Arguments = Arguments::from_args();
     init_log(&args);
 
+    if args.capabilities {
+        let capabilities = Capabilities::of_this_build();
+        println!("{}", serde_json::to_string_pretty(&capabilities).expect("capabilities are serializable"));
+        return;
+    }
+
+    if args.list_levels {
+        match list_levels(&args).await {
+            Ok(levels) => levels.iter().for_each(|level| println!("{}", level)),
+            Err(err) => {
+                red_ln!("ERROR {}", err);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
     loop {
-        match dezoomify(&args).await {
+        match dezoomify_images(&args).await {
             Err(err) => {
                 red_ln!("ERROR {}", err);
                 has_errors = true;
@@ -24,13 +41,15 @@ async fn main() {
                     }
                 }
             },
-            Ok(saved_as) => {
-                green_ln!("Image successfully saved to '{}' (current working directory: {})",
-                         saved_as.to_string_lossy(),
-                         std::env::current_dir()
-                             .map(|p| p.to_string_lossy().to_string())
-                             .unwrap_or_else(|_e| "unknown".into())
-                );
+            Ok(downloads) => {
+                for download in downloads {
+                    green_ln!("Image successfully saved to '{}' (current working directory: {})",
+                             download.saved_as.to_string_lossy(),
+                             std::env::current_dir()
+                                 .map(|p| p.to_string_lossy().to_string())
+                                 .unwrap_or_else(|_e| "unknown".into())
+                    );
+                }
             }
         }
         if has_args {
@@ -1,28 +1,92 @@
 use log::debug;
-use reqwest::{Client, header};
+use reqwest::{Client, header, StatusCode};
 use std::collections::HashMap;
-use std::iter::once;
-use std::path::PathBuf;
+use std::io::SeekFrom;
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use tokio::fs;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
 use url::Url;
 
 use crate::arguments::Arguments;
+use crate::token::{TokenChain, TokenSource};
 use crate::ZoomError;
 
 /// Fetch data, either from an URL or a path to a local file.
 /// If uri doesnt start with "http(s)://", it is considered to be a path
-/// to a local file
+/// to a local file.
+/// If uri ends with `#bytes=first-last`, only the given part of the file is fetched.
+/// If uri starts with "ws(s)://", the tile is fetched from a websocket.
 // TODO: return Bytes
 pub async fn fetch_uri(uri: &str, http: &Client) -> Result<Vec<u8>, ZoomError> {
+    fetch_uri_info(uri, http, &mut ResponseInfo::default()).await
+}
+
+/// What is known about the response to a request, even when it failed
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ResponseInfo {
+    /// The HTTP status of the response. None for local files, and requests that got no response.
+    pub status: Option<u16>,
+    pub etag: Option<String>,
+    pub headers: header::HeaderMap,
+}
+
+/// Like `fetch_uri`, and fills `info` with what the server sent back
+pub async fn fetch_uri_info(uri: &str, http: &Client, info: &mut ResponseInfo) -> Result<Vec<u8>, ZoomError> {
+    fetch_uri_with_header(uri, http, info, None).await
+}
+
+/// Like `fetch_uri_info`, and sends the given header with HTTP requests
+pub async fn fetch_uri_with_header(
+    uri: &str,
+    http: &Client,
+    info: &mut ResponseInfo,
+    extra_header: Option<(&header::HeaderName, &header::HeaderValue)>,
+) -> Result<Vec<u8>, ZoomError> {
+    let (uri, range) = split_byte_range(uri);
     if uri.starts_with("http://") || uri.starts_with("https://") {
-        debug!("Loading url: '{}'", uri);
-        let response = http.get(uri).send()
-            .await?.error_for_status()?;
+        debug!("Loading url: '{}' (range: {:?})", uri, range);
+        let mut request = http.get(uri);
+        if let Some(range) = &range {
+            request = request.header(header::RANGE, format!("bytes={}-{}", range.start(), range.end()));
+        }
+        if let Some((name, value)) = extra_header {
+            request = request.header(name, value);
+        }
+        let response = request.send().await?;
+        info.status = Some(response.status().as_u16());
+        info.etag = response.headers().get(header::ETAG)
+            .and_then(|etag| etag.to_str().ok())
+            .map(String::from);
+        info.headers = response.headers().clone();
+        let response = response.error_for_status()?;
+        let partial = response.status() == StatusCode::PARTIAL_CONTENT;
+        // Unknown for compressed responses, whose declared length is the one of the compressed body
+        let expected_len = response.content_length();
         let mut contents = Vec::new();
         let bytes = response.bytes().await?;
         contents.extend(bytes);
+        check_length(uri, contents.len() as u64, expected_len)?;
         debug!("Loaded url: '{}'", uri);
-        Ok(contents)
+        match range {
+            // The server ignored the range and sent the whole file
+            Some(range) if !partial => slice_range(contents, range),
+            _ => Ok(contents),
+        }
+    } else if is_websocket_url(uri) {
+        fetch_websocket_uri(uri, &header::HeaderMap::new()).await
+    } else if let Some(range) = range {
+        debug!("Loading bytes {:?} of file: '{}'", range, uri);
+        let mut file = fs::File::open(uri).await?;
+        file.seek(SeekFrom::Start(*range.start())).await?;
+        let mut result = Vec::new();
+        file.take(range_len(&range)).read_to_end(&mut result).await?;
+        if (result.len() as u64) < range_len(&range) {
+            let len = range.start() + result.len() as u64;
+            return Err(ZoomError::ByteRangeOutOfBounds { first: *range.start(), last: *range.end(), len });
+        }
+        Ok(result)
     } else {
         debug!("Loading file: '{}'", uri);
         let result = fs::read(uri).await?;
@@ -31,19 +95,129 @@ pub async fn fetch_uri(uri: &str, http: &Client) -> Result<Vec<u8>, ZoomError> {
     }
 }
 
+/// Fetches a tile from a ws:// or wss:// uri, sending the given headers with the opening handshake
+pub async fn fetch_websocket_uri(uri: &str, headers: &header::HeaderMap) -> Result<Vec<u8>, ZoomError> {
+    #[cfg(feature = "websocket")]
+    return crate::websocket::fetch_websocket(uri, headers).await;
+    #[cfg(not(feature = "websocket"))]
+    {
+        let _ = (uri, headers);
+        Err(ZoomError::Io {
+            source: crate::errors::make_io_err(
+                "Fetching tiles from websockets requires dezoomify-rs to be compiled with the 'websocket' feature"
+            ),
+        })
+    }
+}
+
+/// The uri of the file that mirrors a web url in a local directory, if it exists.
+/// Files are looked up as `root/host/path/file@query`, with `host+port` when the port is
+/// not the default one, like `wget --restrict-file-names=windows` stores them.
+/// The byte range of the url, if any, is kept.
+pub async fn local_mirror(root: &Path, uri: &str) -> Option<String> {
+    let (without_range, _) = split_byte_range(uri);
+    let url = Url::parse(without_range).ok().filter(|url| url.scheme() == "http" || url.scheme() == "https")?;
+    let mut host = url.host_str()?.to_string();
+    if let Some(port) = url.port() { host += &format!("+{}", port); }
+    let mut path = root.join(escape_file_name(&host));
+    // The segments are kept percent-encoded, as most tools that mirror websites store them
+    for segment in url.path_segments()? {
+        if segment.is_empty() || segment == ".." || segment.contains(std::path::is_separator) { return None; }
+        path.push(segment);
+    }
+    if let Some(query) = url.query() {
+        let file_name = format!("{}@{}", path.file_name()?.to_str()?, escape_file_name(query));
+        path.set_file_name(file_name);
+    }
+    if !fs::metadata(&path).await.ok()?.is_file() { return None; }
+    let range = &uri[without_range.len()..];
+    let local = path.to_str()?.to_string();
+    debug!("Using the local file '{}' for '{}'", local, uri);
+    Some(local + range)
+}
+
+/// Percent-encodes the characters that cannot be part of a file name
+fn escape_file_name(name: &str) -> String {
+    name.chars().map(|c| match c {
+        '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => format!("%{:02X}", c as u32),
+        c => c.to_string(),
+    }).collect()
+}
+
+/// Checks that a tile is available without downloading it, with a HEAD request.
+/// Servers that do not support HEAD requests are assumed to have the tile.
+pub async fn probe_uri(uri: &str, http: &Client) -> bool {
+    let (uri, _) = split_byte_range(uri);
+    if uri.starts_with("http://") || uri.starts_with("https://") {
+        match http.head(uri).send().await {
+            Ok(response) => {
+                let status = response.status();
+                debug!("HEAD request for '{}': {}", uri, status);
+                status.is_success() || status == StatusCode::METHOD_NOT_ALLOWED || status == StatusCode::NOT_IMPLEMENTED
+            }
+            Err(err) => {
+                debug!("Unable to probe '{}': {}", uri, err);
+                false
+            }
+        }
+    } else if is_websocket_url(uri) {
+        true
+    } else {
+        fs::metadata(uri).await.is_ok()
+    }
+}
+
+/// A connection that was closed early gives a body that is shorter than announced,
+/// which must not be decoded as if it were complete
+fn check_length(uri: &str, received: u64, expected: Option<u64>) -> Result<(), ZoomError> {
+    match expected {
+        Some(expected) if received != expected => {
+            debug!("Truncated response from '{}': {} bytes instead of {}", uri, received, expected);
+            Err(ZoomError::TruncatedResponse { received, expected })
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Separates an uri from the byte range at its end, if any.
+/// A tile can be stored as a part of a larger file, designated by an uri
+/// ending with `#bytes=first-last`, where both offsets are included, as in an HTTP Range header.
+pub fn split_byte_range(uri: &str) -> (&str, Option<RangeInclusive<u64>>) {
+    let parse = |spec: &str| -> Option<RangeInclusive<u64>> {
+        let mut parts = spec.splitn(2, '-').map(|n| n.parse::<u64>());
+        match (parts.next(), parts.next()) {
+            (Some(Ok(first)), Some(Ok(last))) if first <= last => Some(first..=last),
+            _ => None,
+        }
+    };
+    uri.rfind("#bytes=")
+        .and_then(|idx| parse(&uri[idx + "#bytes=".len()..]).map(|range| (&uri[..idx], Some(range))))
+        .unwrap_or((uri, None))
+}
+
+fn range_len(range: &RangeInclusive<u64>) -> u64 {
+    range.end() - range.start() + 1
+}
+
+fn slice_range(mut contents: Vec<u8>, range: RangeInclusive<u64>) -> Result<Vec<u8>, ZoomError> {
+    let len = contents.len() as u64;
+    if *range.end() >= len {
+        return Err(ZoomError::ByteRangeOutOfBounds { first: *range.start(), last: *range.end(), len });
+    }
+    contents.truncate(*range.end() as usize + 1);
+    Ok(contents.split_off(*range.start() as usize))
+}
+
 
 pub fn client<'a, I: Iterator<Item=(&'a String, &'a String)>>(
     headers: I,
     args: &Arguments,
     uri: Option<&str>,
 ) -> Result<reqwest::Client, ZoomError> {
-    let referer = uri.or_else(|| args.input_uri.as_deref()).unwrap_or("").to_string();
-    let header_map = default_headers()
-        .iter()
-        .chain(once((&"Referer".to_string(), &referer)))
-        .chain(headers.map(|(k, v)| (k, v)))
-        .map(|(name, value)| Ok((name.parse()?, value.parse()?)))
-        .collect::<Result<header::HeaderMap, ZoomError>>()?;
+    build_client(header_map(headers, args, uri)?, args)
+}
+
+fn build_client(header_map: header::HeaderMap, args: &Arguments) -> Result<reqwest::Client, ZoomError> {
     debug!("Creating an http client with the following headers: {:?}", header_map);
     let client = reqwest::Client::builder()
         .default_headers(header_map)
@@ -55,6 +229,125 @@ pub fn client<'a, I: Iterator<Item=(&'a String, &'a String)>>(
     Ok(client)
 }
 
+/// Computes the headers to send with each request.
+/// When a header is given multiple times, the last value takes precedence.
+/// Unless disabled with `--no-referer`, the given uri (or the input uri) is used as the
+/// default referer, when it is a web address.
+pub fn header_map<'a, I: Iterator<Item=(&'a String, &'a String)>>(
+    headers: I,
+    args: &Arguments,
+    uri: Option<&str>,
+) -> Result<header::HeaderMap, ZoomError> {
+    let referer = uri.or_else(|| args.input_uri().map(String::as_str))
+        .filter(|uri| !args.no_referer && is_web_url(uri));
+    let mut header_map = header::HeaderMap::new();
+    let mut set = |name: &str, value: &str| -> Result<(), ZoomError> {
+        header_map.insert(name.parse::<header::HeaderName>()?, value.parse()?);
+        Ok(())
+    };
+    for (name, value) in default_headers() { set(&name, &value)?; }
+    if let Some(referer) = referer { set("Referer", referer)?; }
+    for (name, value) in headers { set(name, value)?; }
+    Ok(header_map)
+}
+
+/// The HTTP clients used to download the tiles of a zoom level.
+/// Some hosts may require specific headers, so they get their own client.
+/// Clones share the same connections.
+#[derive(Clone)]
+pub struct TileClients {
+    default: Client,
+    default_headers: header::HeaderMap,
+    /// The clients of the hosts that have specific headers, with these headers
+    by_host: HashMap<String, (Client, header::HeaderMap)>,
+    local_root: Option<PathBuf>,
+    token: Option<Arc<TokenSource>>,
+    token_chain: Option<Arc<TokenChain>>,
+}
+
+impl TileClients {
+    /// Creates clients that send the level headers, then the host-specific headers,
+    /// then the headers given on the command line, each one overriding the previous ones.
+    /// `manifest_uri` is the address of the file that described the level,
+    /// used as the referer when none of the headers sets one.
+    pub fn new(
+        level_headers: &HashMap<String, String>,
+        host_headers: &HashMap<String, HashMap<String, String>>,
+        args: &Arguments,
+        manifest_uri: Option<&str>,
+    ) -> Result<Self, ZoomError> {
+        let default_headers = header_map(level_headers.iter().chain(args.headers()), args, manifest_uri)?;
+        let default = build_client(default_headers.clone(), args)?;
+        let by_host = host_headers.iter().map(|(host, headers)| {
+            let headers = level_headers.iter().chain(headers).chain(args.headers());
+            let header_map = header_map(headers, args, manifest_uri)?;
+            Ok((host.to_lowercase(), (build_client(header_map.clone(), args)?, header_map)))
+        }).collect::<Result<_, ZoomError>>()?;
+        Ok(TileClients {
+            default,
+            default_headers,
+            by_host,
+            local_root: args.local_root.clone(),
+            token: None,
+            token_chain: None,
+        })
+    }
+
+    /// The client to use to fetch the given URL
+    pub fn for_url(&self, url: &str) -> &Client {
+        self.host_clients(url).map_or(&self.default, |(client, _)| client)
+    }
+
+    /// The headers sent by the client of the given URL,
+    /// for the requests that are not made by an http client
+    pub fn headers_for_url(&self, url: &str) -> &header::HeaderMap {
+        self.host_clients(url).map_or(&self.default_headers, |(_, headers)| headers)
+    }
+
+    fn host_clients(&self, url: &str) -> Option<&(Client, header::HeaderMap)> {
+        Url::parse(url).ok().and_then(|url| url.host_str().and_then(|host| self.by_host.get(host)))
+    }
+
+    /// The directory in which tiles are looked for before they are downloaded
+    pub fn local_root(&self) -> Option<&Path> { self.local_root.as_deref() }
+
+    /// Authenticates the requests for tiles with the given token
+    pub fn with_token(self, token: Option<Arc<TokenSource>>) -> Self {
+        TileClients { token, ..self }
+    }
+
+    pub fn token(&self) -> Option<&TokenSource> { self.token.as_deref() }
+
+    /// Sends with each request for a tile the token found in the response to the previous one
+    pub fn with_token_chain(self, token_chain: Option<Arc<TokenChain>>) -> Self {
+        TileClients { token_chain, ..self }
+    }
+
+    pub fn token_chain(&self) -> Option<&TokenChain> { self.token_chain.as_deref() }
+}
+
+/// Clients that send the same headers to all hosts
+impl From<Client> for TileClients {
+    fn from(default: Client) -> Self {
+        TileClients {
+            default,
+            default_headers: header::HeaderMap::new(),
+            by_host: HashMap::new(),
+            local_root: None,
+            token: None,
+            token_chain: None,
+        }
+    }
+}
+
+pub fn is_websocket_url(uri: &str) -> bool {
+    uri.starts_with("ws://") || uri.starts_with("wss://")
+}
+
+fn is_web_url(uri: &str) -> bool {
+    Url::parse(uri).map(|url| url.scheme() == "http" || url.scheme() == "https").unwrap_or(false)
+}
+
 pub fn default_headers() -> HashMap<String, String> {
     serde_yaml::from_str(include_str!("default_headers.yaml")).unwrap()
 }
@@ -91,4 +384,83 @@ fn test_resolve_relative() {
     assert_eq!(resolve_relative("http://a.b", "c/d"), "http://a.b/c/d");
     assert_eq!(resolve_relative("http://a.b/x", "c/d"), "http://a.b/c/d");
     assert_eq!(resolve_relative("http://a.b/x/", "c/d"), "http://a.b/x/c/d");
-}
\ No newline at end of file
+}
+#[allow(clippy::field_reassign_with_default)]
+#[test]
+fn test_header_precedence() {
+    let mut args = Arguments::default();
+    args.headers = vec![("X-Cli".into(), "cli".into())];
+    let level_headers: Vec<(String, String)> = vec![
+        ("Referer".into(), "http://level".into()),
+        ("X-Cli".into(), "level".into()),
+    ];
+    let headers = level_headers.iter().map(|(k, v)| (k, v)).chain(args.headers());
+    let map = header_map(headers, &args, Some("http://input")).unwrap();
+    assert_eq!(map.get_all("Referer").iter().collect::<Vec<_>>(), vec!["http://level"]);
+    assert_eq!(map["X-Cli"], "cli");
+    assert!(map.contains_key("User-Agent"));
+}
+
+#[allow(clippy::field_reassign_with_default)]
+#[test]
+fn test_default_referer() {
+    let mut args = Arguments::default();
+    args.input_uri = Some("http://page".into());
+    let no_headers = std::iter::empty();
+    let map = header_map(no_headers.clone(), &args, Some("http://manifest/info.json")).unwrap();
+    assert_eq!(map["Referer"], "http://manifest/info.json");
+    let map = header_map(no_headers.clone(), &args, None).unwrap();
+    assert_eq!(map["Referer"], "http://page");
+    let map = header_map(no_headers.clone(), &args, Some("/home/me/tiles.yaml")).unwrap();
+    assert!(!map.contains_key("Referer"), "local paths should not be leaked");
+    args.no_referer = true;
+    let map = header_map(no_headers, &args, Some("http://manifest/info.json")).unwrap();
+    assert!(!map.contains_key("Referer"));
+    let explicit = ("Referer".to_string(), "http://explicit".to_string());
+    let map = header_map(std::iter::once((&explicit.0, &explicit.1)), &args, None).unwrap();
+    assert_eq!(map["Referer"], "http://explicit");
+}
+
+#[tokio::test]
+async fn test_local_mirror() {
+    let dir = tempdir::TempDir::new("dezoomify-rs-mirror").unwrap();
+    let mirror = dir.path().join("example.com+8080").join("tiles");
+    std::fs::create_dir_all(&mirror).unwrap();
+    std::fs::write(mirror.join("tile.jpg@x=0&y=1"), b"0,1").unwrap();
+    std::fs::write(mirror.join("tile.jpg@x=1&y=0"), b"1,0").unwrap();
+    std::fs::write(mirror.join("tile.jpg@path=a%2Fb"), b"a/b").unwrap();
+    let root = dir.path();
+    let local = |uri: &'static str| local_mirror(root, uri);
+    for &(uri, contents) in &[
+        ("http://example.com:8080/tiles/tile.jpg?x=0&y=1", "0,1"),
+        ("http://example.com:8080/tiles/tile.jpg?x=1&y=0", "1,0"),
+        ("http://example.com:8080/tiles/tile.jpg?path=a/b", "a/b"),
+    ] {
+        let file = local(uri).await.unwrap_or_else(|| panic!("{} should be mirrored", uri));
+        assert_eq!(std::fs::read_to_string(file).unwrap(), contents);
+    }
+    let file = local("http://example.com:8080/tiles/tile.jpg?x=0&y=1#bytes=0-1").await.unwrap();
+    assert!(file.ends_with("tile.jpg@x=0&y=1#bytes=0-1"), "{}", file);
+    assert_eq!(local("http://example.com:8080/tiles/tile.jpg?x=1&y=1").await, None);
+    assert_eq!(local("http://example.com:8080/tiles/tile.jpg").await, None);
+    assert_eq!(local("http://example.org:8080/tiles/tile.jpg?x=0&y=1").await, None);
+}
+
+#[test]
+fn test_split_byte_range() {
+    assert_eq!(split_byte_range("http://a.b/blob.bin#bytes=10-19"), ("http://a.b/blob.bin", Some(10..=19)));
+    assert_eq!(split_byte_range("blob.bin"), ("blob.bin", None));
+    assert_eq!(split_byte_range("http://a.b/#bytes=9-1"), ("http://a.b/#bytes=9-1", None));
+    assert_eq!(slice_range(b"abcdef".to_vec(), 1..=3).unwrap(), b"bcd");
+    assert!(slice_range(b"abc".to_vec(), 1..=3).is_err());
+}
+
+#[test]
+fn test_check_length() {
+    assert!(check_length("http://a.b/t.jpg", 10, Some(10)).is_ok());
+    assert!(check_length("http://a.b/t.jpg", 10, None).is_ok());
+    assert!(matches!(
+        check_length("http://a.b/t.jpg", 4, Some(10)),
+        Err(ZoomError::TruncatedResponse { received: 4, expected: 10 })
+    ));
+}
@@ -113,6 +113,7 @@ impl TilesRect for Level {
         TileReference {
             url: self.tile_url(pos),
             position: self.tile_size() * pos - delta,
+            cell_size: None,
         }
     }
 }
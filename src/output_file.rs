@@ -1,16 +1,120 @@
 use std::convert::TryFrom;
 use std::ffi::OsString;
-use std::fs::OpenOptions;
+use std::fs::{File, OpenOptions};
+use std::io::{self, IsTerminal};
 use std::path::{PathBuf, Path};
+use std::str::FromStr;
+use std::time::{Duration, SystemTime};
 
-use log::info;
+use log::{info, warn};
 use sanitize_filename_reader_friendly::sanitize;
+use sha2::{Digest, Sha256};
 
 use crate::{Vec2d, ZoomError};
 
-pub fn reserve_output_file(path: &PathBuf) -> Result<(), ZoomError> {
-    OpenOptions::new().write(true).create_new(true).open(path)?;
-    Ok(())
+/// Whether an existing output file is recent enough to not be downloaded again.
+/// If max_age is None, any existing file is considered recent enough.
+pub fn is_existing_output(path: &Path, max_age: Option<Duration>) -> bool {
+    let modified = match std::fs::metadata(path).and_then(|m| m.modified()) {
+        Ok(modified) => modified,
+        Err(_) => return false,
+    };
+    match max_age {
+        None => true,
+        Some(max_age) => SystemTime::now()
+            .duration_since(modified)
+            .map(|age| age < max_age)
+            .unwrap_or(true) // The file was modified in the future
+    }
+}
+
+/// What to do when the output file given by the user already exists
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OverwritePolicy {
+    /// Leave the existing file alone, and do not download the image
+    #[default]
+    Never,
+    /// Ask the user, and refuse when the program is not run interactively
+    Ask,
+    Always,
+}
+
+impl FromStr for OverwritePolicy {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "never" => Ok(OverwritePolicy::Never),
+            "ask" => Ok(OverwritePolicy::Ask),
+            "always" => Ok(OverwritePolicy::Always),
+            _ => Err("Invalid overwrite policy. Expected 'never', 'ask' or 'always'"),
+        }
+    }
+}
+
+impl OverwritePolicy {
+    fn allows_overwrite(self, path: &Path) -> bool {
+        match self {
+            OverwritePolicy::Never => false,
+            OverwritePolicy::Always => true,
+            OverwritePolicy::Ask => confirm_overwrite(path),
+        }
+    }
+}
+
+fn confirm_overwrite(path: &Path) -> bool {
+    if !io::stdin().is_terminal() {
+        warn!("Not overwriting {:?} without asking: the program is not run interactively", path);
+        return false;
+    }
+    eprint!("The file {:?} already exists. Overwrite it? [y/N] ", path);
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer).is_ok() && matches!(answer.trim(), "y" | "Y" | "yes")
+}
+
+/// Creates the output file given by the user. If it already exists,
+/// it is emptied if the policy allows it to be overwritten.
+pub fn reserve_output_file(path: &Path, policy: OverwritePolicy) -> Result<(), ZoomError> {
+    match OpenOptions::new().write(true).create_new(true).open(path) {
+        Ok(_) => Ok(()),
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+            if !policy.allows_overwrite(path) {
+                return Err(ZoomError::OutputExists { path: path.to_string_lossy().to_string() });
+            }
+            info!("Overwriting {:?}", path);
+            OpenOptions::new().write(true).truncate(true).open(path)?;
+            Ok(())
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Creates an output file with an automatically chosen name, and returns its path.
+/// If another dezoomify-rs process running at the same time already took the name,
+/// then the next free suffix is used, so that no file is ever overwritten.
+pub fn reserve_unique_output_file(path: &Path) -> Result<PathBuf, ZoomError> {
+    let mut candidate = path.to_path_buf();
+    for i in 1.. {
+        match OpenOptions::new().write(true).create_new(true).open(&candidate) {
+            Ok(_) => break,
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+                info!("File {:?} was created by another process. Trying another file name...", &candidate);
+                candidate = with_suffix(path, i);
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(candidate)
+}
+
+/// Appends a numbered suffix to the name of a file: a.png becomes a_0001.png
+fn with_suffix(path: &Path, i: usize) -> PathBuf {
+    let filename = path.file_stem().map(OsString::from).unwrap_or_default();
+    let ext = path.extension().map(OsString::from).unwrap_or_default();
+    let mut name = OsString::from(&filename);
+    name.push(&format!("_{:04}.", i));
+    name.push(&ext);
+    path.with_file_name(name)
 }
 
 pub fn get_outname(
@@ -37,23 +141,77 @@ pub fn get_outname(
             .map(|s| sanitize(s))
             .filter(|s| !s.is_empty())
             .unwrap_or_else(|| "dezoomified".into());
-        let mut path = base_dir.join(base).with_extension(extension);
+        let base_path = base_dir.join(base).with_extension(extension);
+        let mut path = base_path.clone();
 
         // append a suffix (_1,_2,..) to `outname` if  the file already exists
-        let filename = path.file_stem().map(OsString::from).unwrap_or_default();
-        let ext = path.extension().map(OsString::from).unwrap_or_default();
         for i in 1.. {
             if !path.exists() { break; }
             info!("File {:?} already exists. Trying another file name...", &path);
-            let mut name = OsString::from(&filename);
-            name.push(&format!("_{:04}.", i));
-            name.push(&ext);
-            path.set_file_name(name);
+            path = with_suffix(&base_path, i);
         }
         path
     }
 }
 
+/// The output file of one of several images described by the same input.
+/// In the given output path, `{index}` is replaced by the number of the image, starting at 1,
+/// and `{name}` by its title. If the path contains neither of them, the number of the image
+/// is appended to the file name. When there is no output path, the images are named after their titles.
+pub fn image_outfile(outfile: &Option<PathBuf>, index: usize, count: usize, name: Option<&str>) -> Option<PathBuf> {
+    let path = outfile.as_ref()?;
+    if count <= 1 { return Some(path.clone()); }
+    let path_str = path.to_string_lossy();
+    let number = index + 1;
+    if path_str.contains("{index}") || path_str.contains("{name}") {
+        let name = name.map(sanitize).filter(|s| !s.is_empty()).unwrap_or_else(|| number.to_string());
+        Some(path_str.replace("{index}", &number.to_string()).replace("{name}", &name).into())
+    } else {
+        Some(with_suffix(path, number))
+    }
+}
+
+/// The output file of one of the zoom levels selected with --levels.
+/// In the given output path, `{level}` is replaced by the index of the level.
+/// If the path does not contain it, the index of the level is appended to the file name.
+pub fn level_outfile(outfile: &Option<PathBuf>, level: usize) -> Option<PathBuf> {
+    let path = outfile.as_ref()?;
+    let path_str = path.to_string_lossy();
+    if path_str.contains("{level}") {
+        Some(path_str.replace("{level}", &level.to_string()).into())
+    } else {
+        Some(with_suffix(path, level))
+    }
+}
+
+/// Renames a file to the hexadecimal SHA-256 hash of its contents, keeping its extension,
+/// and returns its new path. If a file with the same hash already exists, it is kept,
+/// and the given file is removed.
+/// When `sidecar` is true, the original file name is written to `{hash}.name.txt`.
+pub fn rename_to_content_hash(path: &Path, sidecar: bool) -> Result<PathBuf, ZoomError> {
+    let mut hasher = Sha256::new();
+    io::copy(&mut File::open(path)?, &mut hasher)?;
+    let hash: String = hasher.finalize().iter().map(|b| format!("{:02x}", b)).collect();
+    let mut name = OsString::from(&hash);
+    if let Some(ext) = path.extension() {
+        name.push(".");
+        name.push(ext);
+    }
+    let hashed = path.with_file_name(name);
+    if hashed.exists() {
+        info!("{:?} is identical to the existing {:?}", path, hashed);
+        std::fs::remove_file(path)?;
+    } else {
+        info!("Renaming {:?} to {:?}", path, hashed);
+        std::fs::rename(path, &hashed)?;
+    }
+    if sidecar {
+        let original = path.file_name().unwrap_or_default().to_string_lossy();
+        std::fs::write(path.with_file_name(format!("{}.name.txt", hash)), original.as_bytes())?;
+    }
+    Ok(hashed)
+}
+
 #[allow(clippy::expect_fun_call)]
 #[cfg(test)]
 mod tests {
@@ -115,6 +273,71 @@ mod tests {
         })
     }
 
+    #[test]
+    fn test_concurrent_reservations() {
+        let base_dir = TempDir::new("dezoomify-rs-test-reserve").unwrap();
+        let path = base_dir.as_ref().join("image.png");
+        // Two downloads chose the same name before any of them created its file
+        let names: Vec<PathBuf> = std::iter::repeat_n(path.clone(), 50)
+            .map(|p| std::thread::spawn(move || reserve_unique_output_file(&p).unwrap()))
+            .collect::<Vec<_>>()
+            .into_iter()
+            .map(|t| t.join().unwrap())
+            .collect();
+        let distinct: std::collections::HashSet<_> = names.iter().collect();
+        assert_eq!(distinct.len(), names.len(), "{:?}", names);
+        assert!(names.contains(&path));
+        assert!(names.contains(&base_dir.as_ref().join("image_0001.png")));
+    }
+
+    #[test]
+    fn test_overwrite_policy() {
+        let base_dir = TempDir::new("dezoomify-rs-test-overwrite").unwrap();
+        let path = base_dir.as_ref().join("image.png");
+        std::fs::write(&path, b"previous download").unwrap();
+        match reserve_output_file(&path, OverwritePolicy::Never) {
+            Err(ZoomError::OutputExists { path: existing }) => assert_eq!(Path::new(&existing), path),
+            other => panic!("An existing file should not be overwritten, got {:?}", other),
+        }
+        assert_eq!(std::fs::read(&path).unwrap(), b"previous download");
+        reserve_output_file(&path, OverwritePolicy::Always).unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"");
+        let new_path = base_dir.as_ref().join("new.png");
+        reserve_output_file(&new_path, OverwritePolicy::Never).unwrap();
+        assert!(new_path.exists());
+    }
+
+    #[test]
+    fn test_rename_to_content_hash() {
+        let base_dir = TempDir::new("dezoomify-rs-test-hash").unwrap();
+        let first = base_dir.as_ref().join("first.png");
+        std::fs::write(&first, b"abc").unwrap();
+        let hashed = rename_to_content_hash(&first, true).unwrap();
+        let hash = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+        assert_eq!(hashed, base_dir.as_ref().join(format!("{}.png", hash)));
+        assert_eq!(std::fs::read(&hashed).unwrap(), b"abc");
+        assert!(!first.exists());
+        let sidecar = base_dir.as_ref().join(format!("{}.name.txt", hash));
+        assert_eq!(std::fs::read_to_string(sidecar).unwrap(), "first.png");
+
+        // A duplicate download is merged with the existing file
+        let second = base_dir.as_ref().join("second.png");
+        std::fs::write(&second, b"abc").unwrap();
+        assert_eq!(rename_to_content_hash(&second, false).unwrap(), hashed);
+        assert!(!second.exists());
+    }
+
+    #[test]
+    fn test_is_existing_output() {
+        let base_dir = TempDir::new("dezoomify-rs-test-existing").unwrap();
+        let path = base_dir.as_ref().join("existing.png");
+        assert!(!is_existing_output(&path, None));
+        File::create(&path).unwrap();
+        assert!(is_existing_output(&path, None));
+        assert!(is_existing_output(&path, Some(Duration::from_secs(3600))));
+        assert!(!is_existing_output(&path, Some(Duration::from_secs(0))));
+    }
+
     #[test]
     fn switch_to_png_for_large_files() {
         let base_dir = TempDir::new("dezoomify-rs-test-png").unwrap();
@@ -133,4 +356,15 @@ mod tests {
             assert_eq!(outname, expected_result);
         }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_image_outfile() {
+        let outfile = Some(PathBuf::from("/out/book.png"));
+        assert_eq!(image_outfile(&outfile, 0, 1, Some("a")), outfile);
+        assert_eq!(image_outfile(&None, 1, 2, Some("a")), None);
+        assert_eq!(image_outfile(&outfile, 1, 2, Some("a")), Some(PathBuf::from("/out/book_0002.png")));
+        let templated = Some(PathBuf::from("/out/{index} - {name}.png"));
+        assert_eq!(image_outfile(&templated, 1, 2, Some("cover: back")), Some(PathBuf::from("/out/2 - cover_ back.png")));
+        assert_eq!(image_outfile(&templated, 1, 2, None), Some(PathBuf::from("/out/2 - 2.png")));
+    }
+}
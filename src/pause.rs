@@ -0,0 +1,70 @@
+use std::path::PathBuf;
+use std::time::Duration;
+
+use indicatif::ProgressBar;
+use log::info;
+
+/// Lets the user pause a download by creating a control file, and resume it by deleting the file
+pub struct PauseControl {
+    file: Option<PathBuf>,
+    /// How often the control file is checked during a pause
+    poll_interval: Duration,
+}
+
+impl PauseControl {
+    pub fn new(file: Option<PathBuf>) -> Self {
+        PauseControl { file, poll_interval: Duration::from_millis(500) }
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.file.as_ref().is_some_and(|file| file.exists())
+    }
+
+    /// Returns once the download is not paused. The progress is reported when a pause begins.
+    pub async fn wait(&self, progress: &ProgressBar) {
+        if !self.is_paused() { return; }
+        let message = format!(
+            "Paused after {} of {} tiles. Delete {:?} to resume.",
+            progress.position(), progress.length(), self.file.as_ref().unwrap_or(&PathBuf::new())
+        );
+        info!("{}", message);
+        progress.set_message(&message);
+        while self.is_paused() {
+            tokio::time::sleep(self.poll_interval).await;
+        }
+        info!("Resuming the download");
+        progress.set_message("Requesting the tiles...");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::StreamExt;
+    use tokio::time::timeout;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_pause_and_resume() {
+        let file = std::env::temp_dir().join(format!("dezoomify-rs-pause-{}", std::process::id()));
+        let pause = PauseControl { file: Some(file.clone()), poll_interval: Duration::from_millis(5) };
+        let progress = ProgressBar::hidden();
+        let pause_ref = &pause;
+        let progress_ref = &progress;
+        let mut dispatched = Box::pin(futures::stream::iter(0..3).then(|i| async move {
+            pause_ref.wait(progress_ref).await;
+            i
+        }));
+        assert_eq!(dispatched.next().await, Some(0));
+
+        std::fs::write(&file, b"").unwrap();
+        assert!(pause.is_paused());
+        let halted = timeout(Duration::from_millis(100), dispatched.next()).await;
+        assert!(halted.is_err(), "no tile should be dispatched during a pause");
+
+        std::fs::remove_file(&file).unwrap();
+        let resumed = timeout(Duration::from_secs(5), dispatched.next()).await;
+        assert_eq!(resumed, Ok(Some(1)));
+        assert_eq!(dispatched.next().await, Some(2));
+    }
+}
@@ -0,0 +1,124 @@
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use image::{imageops, ImageFormat, ImageResult, Rgba, RgbaImage};
+use log::{debug, warn};
+
+use crate::tile::Tile;
+use crate::Vec2d;
+
+/// Minimum time between two writes of the preview
+const PREVIEW_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Width in the preview of the first tile, from which the scale of the preview is computed
+const PREVIEW_TILE_WIDTH: u32 = 32;
+
+/// Side of the squares of the checkerboard drawn where no tile was found yet
+const CHECKER_SIZE: u32 = 8;
+
+/// A small image of the tiles found so far in a level whose tiles are discovered by probing the server,
+/// written regularly during the discovery, so that the template can be checked before the whole image
+/// is downloaded. Only reduced copies of the tiles are kept.
+pub struct DiscoveryPreview {
+    path: PathBuf,
+    /// Number of pixels of the image for each pixel of the preview, set by the first tile
+    scale: Option<u32>,
+    thumbnails: Vec<(Vec2d, RgbaImage)>,
+    /// Whether tiles were added since the preview was last written
+    changed: bool,
+    last_write: Option<Instant>,
+}
+
+impl DiscoveryPreview {
+    pub fn new(path: PathBuf) -> Self {
+        DiscoveryPreview { path, scale: None, thumbnails: vec![], changed: false, last_write: None }
+    }
+
+    pub fn add_tile(&mut self, tile: &Tile) {
+        let size = tile.size();
+        let scale = *self.scale.get_or_insert_with(|| (size.x / PREVIEW_TILE_WIDTH).max(1));
+        let reduced = (size / scale).max(Vec2d::square(1));
+        let thumbnail = imageops::thumbnail(&tile.image.to_rgba8(), reduced.x, reduced.y);
+        self.thumbnails.push((tile.position() / scale, thumbnail));
+        self.changed = true;
+    }
+
+    /// To be called after each batch of tiles. The preview is written if it was not written recently.
+    pub fn batch_done(&mut self) {
+        if !matches!(self.last_write, Some(time) if time.elapsed() < PREVIEW_INTERVAL) {
+            self.write();
+        }
+    }
+
+    /// Writes the tiles that were found since the last write, at the end of the discovery
+    pub fn finish(&mut self) { self.write() }
+
+    fn write(&mut self) {
+        if !self.changed { return; }
+        self.changed = false;
+        self.last_write = Some(Instant::now());
+        match self.write_image() {
+            Ok(()) => debug!("Wrote a preview of {} tiles to {:?}", self.thumbnails.len(), self.path),
+            Err(e) => warn!("Unable to write the discovery preview to {:?}: {}", self.path, e),
+        }
+    }
+
+    fn write_image(&self) -> ImageResult<()> {
+        let extent = self.thumbnails.iter().fold(Vec2d::default(), |extent, (position, thumbnail)| {
+            extent.max(*position + Vec2d::from(thumbnail.dimensions()))
+        });
+        let mut image = RgbaImage::from_fn(extent.x, extent.y, |x, y| {
+            let light = (x / CHECKER_SIZE + y / CHECKER_SIZE) & 1 == 0;
+            if light { Rgba([204, 204, 204, 255]) } else { Rgba([153, 153, 153, 255]) }
+        });
+        for (position, thumbnail) in &self.thumbnails {
+            imageops::replace(&mut image, thumbnail, position.x, position.y);
+        }
+        // The preview can be opened while it is replaced
+        let format = ImageFormat::from_path(&self.path)?;
+        let partial = self.path.with_extension("partial");
+        image.save_with_format(&partial, format)?;
+        std::fs::rename(&partial, &self.path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{DynamicImage, GenericImageView, ImageBuffer, Rgb};
+
+    use super::*;
+
+    fn tile(x: u32, y: u32, color: u8) -> Tile {
+        let image = DynamicImage::ImageRgb8(ImageBuffer::from_pixel(256, 256, Rgb([color, 0, 0])));
+        Tile { position: Vec2d { x, y }, image, encoded: None }
+    }
+
+    #[test]
+    fn test_preview_of_the_first_row() {
+        let dir = tempdir::TempDir::new("dezoomify-rs-preview").unwrap();
+        let path = dir.path().join("preview.png");
+        let mut preview = DiscoveryPreview::new(path.clone());
+        preview.batch_done();
+        assert!(!path.exists(), "no tile was found yet");
+        for column in 0..3 { preview.add_tile(&tile(256 * column, 0, 10 + column as u8)); }
+        preview.batch_done();
+        let written = image::open(&path).unwrap();
+        // Each tile of 256 pixels is 32 pixels wide in the preview
+        assert_eq!(written.dimensions(), (96, 32));
+        for column in 0..3 {
+            assert_eq!(written.get_pixel(32 * column + 16, 16), Rgba([10 + column as u8, 0, 0, 255]));
+        }
+
+        // The second row is written at the end of the discovery, with a checkerboard where tiles are missing
+        preview.add_tile(&tile(0, 256, 20));
+        preview.batch_done();
+        assert_eq!(image::open(&path).unwrap().dimensions(), (96, 32), "the preview was written too recently");
+        preview.finish();
+        let written = image::open(&path).unwrap();
+        assert_eq!(written.dimensions(), (96, 64));
+        assert_eq!(written.get_pixel(16, 48), Rgba([20, 0, 0, 255]));
+        assert_eq!(written.get_pixel(95, 63), Rgba([204, 204, 204, 255]));
+        assert_eq!(written.get_pixel(87, 63), Rgba([153, 153, 153, 255]));
+    }
+}
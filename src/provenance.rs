@@ -0,0 +1,83 @@
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::warn;
+use serde::Serialize;
+
+use crate::network::ResponseInfo;
+use crate::ZoomError;
+
+/// A file in which each tile request is recorded, as a line of json, as soon as it is answered
+pub struct ProvenanceLog {
+    file: Mutex<File>,
+}
+
+#[derive(Serialize, Debug)]
+struct ProvenanceRecord<'a> {
+    url: &'a str,
+    status: Option<u16>,
+    etag: Option<&'a str>,
+    timestamp: String,
+    /// Size of the response body
+    bytes: Option<usize>,
+    error: Option<String>,
+}
+
+impl ProvenanceLog {
+    /// Records are appended, so that the successive runs of an interrupted download are all kept
+    pub fn create(path: &Path) -> Result<Self, ZoomError> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(ProvenanceLog { file: Mutex::new(file) })
+    }
+
+    pub fn record(&self, url: &str, info: &ResponseInfo, result: &Result<Vec<u8>, ZoomError>) {
+        let record = ProvenanceRecord {
+            url,
+            status: info.status,
+            etag: info.etag.as_deref(),
+            timestamp: rfc3339(SystemTime::now()),
+            bytes: result.as_ref().ok().map(Vec::len),
+            error: result.as_ref().err().map(ZoomError::to_string),
+        };
+        let mut line = serde_json::to_vec(&record).expect("records are always serializable");
+        line.push(b'\n');
+        // A single write per record, so that lines are never interleaved
+        let mut file = self.file.lock().unwrap_or_else(|e| e.into_inner());
+        if let Err(e) = file.write_all(&line) {
+            warn!("Unable to write the provenance of '{}': {}", url, e);
+        }
+    }
+}
+
+/// Formats a time as `2021-03-04T05:06:07.089Z`
+fn rfc3339(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (days, secs_of_day) = ((secs / 86400) as i64, secs % 86400);
+    // Conversion of a number of days to a date of the proleptic gregorian calendar,
+    // from http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+            year, month, day,
+            secs_of_day / 3600, secs_of_day / 60 % 60, secs_of_day % 60,
+            since_epoch.subsec_millis())
+}
+
+#[test]
+fn test_rfc3339() {
+    use std::time::Duration;
+    assert_eq!(rfc3339(UNIX_EPOCH), "1970-01-01T00:00:00.000Z");
+    assert_eq!(rfc3339(UNIX_EPOCH + Duration::from_millis(951_782_400_123)), "2000-02-29T00:00:00.123Z");
+    assert_eq!(rfc3339(UNIX_EPOCH + Duration::from_secs(1_614_834_367)), "2021-03-04T05:06:07.000Z");
+}
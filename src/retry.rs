@@ -0,0 +1,173 @@
+use std::future::Future;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use log::warn;
+
+use crate::ZoomError;
+
+/// How many times, and how often, a failed tile download is attempted again
+#[derive(Clone, Copy)]
+pub struct RetryPolicy<'a> {
+    pub retries: usize,
+    pub retry_delay: Duration,
+    pub budget: &'a RetryBudget,
+}
+
+impl RetryPolicy<'_> {
+    /// Runs the given attempt until it succeeds or there are no retries left. The delay before the first retry is increased by
+    /// the given fraction of `retry_delay`, and then doubles after each retry.
+    pub async fn run<T, F, Fut>(&self, delay_fraction: f64, mut attempt: F) -> Result<T, ZoomError>
+        where F: FnMut() -> Fut, Fut: Future<Output=Result<T, ZoomError>> {
+        let mut res = attempt().await;
+        let mut wait_time = self.retry_delay + self.retry_delay.mul_f64(delay_fraction);
+        for _ in 0..self.retries {
+            let err = match &res {
+                Ok(_) => break,
+                Err(err) => err,
+            };
+            match FailureKind::of(err) {
+                // A resolution failure says nothing about the tile, so it is not taken from the budget
+                FailureKind::Dns => warn!("{}. Unable to find the server. Retrying in {:?}.", err, wait_time),
+                FailureKind::Other if !self.budget.take() => {
+                    warn!("{}. Not retrying: the retry budget is exhausted.", err);
+                    break;
+                }
+                FailureKind::Other => warn!("{}. Retrying tile download in {:?}.", err, wait_time),
+            }
+            tokio::time::sleep(wait_time).await;
+            wait_time *= 2;
+            res = attempt().await;
+        }
+        res
+    }
+}
+
+/// The number of retries that remain for all the tiles of an image
+pub struct RetryBudget(Option<AtomicUsize>);
+
+impl RetryBudget {
+    pub fn new(budget: Option<usize>) -> Self { RetryBudget(budget.map(AtomicUsize::new)) }
+
+    /// Uses one retry from the budget. Returns false if there are none left.
+    fn take(&self) -> bool {
+        match &self.0 {
+            None => true,
+            Some(remaining) => remaining
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+                .is_ok(),
+        }
+    }
+}
+
+/// What a failed download says about the chances of the next attempt
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FailureKind {
+    /// The name of the server could not be resolved. This is usually a transient failure
+    /// of the network, so it is retried without using the retry budget.
+    Dns,
+    Other,
+}
+
+impl FailureKind {
+    fn of(err: &ZoomError) -> Self {
+        match err {
+            ZoomError::Networking { source } if source.is_connect() && is_dns_error(source) => FailureKind::Dns,
+            _ => FailureKind::Other,
+        }
+    }
+}
+
+/// The http client does not expose the cause of connection errors,
+/// but describes resolution failures as dns errors
+fn is_dns_error(err: &(dyn std::error::Error + 'static)) -> bool {
+    let mut cause = err.source();
+    while let Some(err) = cause {
+        if err.to_string().starts_with("dns error") { return true; }
+        cause = err.source();
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fmt;
+    use std::net::TcpListener;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+
+    /// An error chain like the one of the http client
+    #[derive(Debug)]
+    struct MockError(&'static str, Option<Box<MockError>>);
+
+    impl fmt::Display for MockError {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result { f.write_str(self.0) }
+    }
+
+    impl std::error::Error for MockError {
+        fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+            self.1.as_deref().map(|e| e as &(dyn std::error::Error + 'static))
+        }
+    }
+
+    #[test]
+    fn test_dns_errors_are_recognized() {
+        let chain = |cause| MockError("error sending request",
+            Some(Box::new(MockError("error trying to connect", Some(Box::new(MockError(cause, None)))))));
+        assert!(is_dns_error(&chain("dns error: failed to lookup address information")));
+        assert!(!is_dns_error(&chain("tcp connect error: Connection refused")));
+        assert!(!is_dns_error(&MockError("dns error", None)), "only the causes are examined");
+    }
+
+    /// The error of a request to a local port on which nothing listens
+    async fn connection_error() -> ZoomError {
+        let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let client = reqwest::Client::new();
+        client.get(&format!("http://127.0.0.1:{}/tile.jpg", port)).send().await.unwrap_err().into()
+    }
+
+    /// The error of a request to a host that cannot exist
+    async fn resolution_error() -> ZoomError {
+        let client = reqwest::Client::new();
+        client.get("http://tiles.dezoomify-rs.invalid/tile.jpg").send().await.unwrap_err().into()
+    }
+
+    #[tokio::test]
+    async fn test_connection_failures_use_the_budget() {
+        let budget = RetryBudget::new(Some(1));
+        let policy = RetryPolicy { retries: 3, retry_delay: Duration::from_millis(1), budget: &budget };
+        let attempts = AtomicUsize::new(0);
+        assert_eq!(FailureKind::of(&connection_error().await), FailureKind::Other);
+        let res: Result<(), _> = policy.run(0., || async {
+            attempts.fetch_add(1, Ordering::SeqCst);
+            Err(connection_error().await)
+        }).await;
+        assert!(matches!(res, Err(ZoomError::Networking { .. })));
+        // A single retry was left in the budget
+        assert_eq!(attempts.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_resolution_failures_are_retried_without_the_budget() {
+        let budget = RetryBudget::new(Some(0));
+        let policy = RetryPolicy { retries: 3, retry_delay: Duration::from_millis(1), budget: &budget };
+        let attempts = AtomicUsize::new(0);
+        assert_eq!(FailureKind::of(&resolution_error().await), FailureKind::Dns);
+        // The name of the server is resolved at the third attempt
+        let res = policy.run(0., || async {
+            if attempts.fetch_add(1, Ordering::SeqCst) < 2 { Err(resolution_error().await) } else { Ok("tile") }
+        }).await;
+        assert_eq!(res.unwrap(), "tile");
+        assert_eq!(attempts.load(Ordering::SeqCst), 3);
+
+        // A failure that persists after all the retries is final
+        attempts.store(0, Ordering::SeqCst);
+        let res: Result<(), _> = policy.run(0., || async {
+            attempts.fetch_add(1, Ordering::SeqCst);
+            Err(resolution_error().await)
+        }).await;
+        assert!(matches!(res, Err(ZoomError::Networking { .. })));
+        assert_eq!(attempts.load(Ordering::SeqCst), 4);
+    }
+}
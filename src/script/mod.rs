@@ -0,0 +1,152 @@
+use std::convert::TryFrom;
+
+use custom_error::custom_error;
+use rhai::{Array, Engine, Scope, AST};
+
+use crate::dezoomer::*;
+
+/// A dezoomer that computes the tiles of an image with a rhai script.
+/// The script must define the following functions:
+///  - `grid(level)`, the number of columns and rows of tiles in the given level, as `[columns, rows]`
+///  - `tile_url(x, y, level)`, the url of the tile at column x and row y
+///  - `tile_position(x, y)`, the position of the top left corner of this tile, in pixels, as `[x, y]`
+///
+/// It can also define `levels()`, the number of zoom levels, which defaults to 1.
+/// The input uri is available to the script through the `input()` function.
+pub struct ScriptDezoomer {
+    script: String,
+}
+
+impl ScriptDezoomer {
+    pub fn new(script: String) -> Self {
+        ScriptDezoomer { script }
+    }
+}
+
+custom_error! {pub ScriptError
+    Parse{source: rhai::ParseError} = "Invalid script: {source}",
+    Eval{message: String} = "Error while running the script: {message}",
+    BadPair{function: &'static str} = "'{function}' should return an array of two positive integers",
+}
+
+impl From<Box<rhai::EvalAltResult>> for ScriptError {
+    fn from(err: Box<rhai::EvalAltResult>) -> Self {
+        ScriptError::Eval { message: err.to_string() }
+    }
+}
+
+impl Dezoomer for ScriptDezoomer {
+    fn name(&self) -> &'static str {
+        "script"
+    }
+
+    fn zoom_levels(&mut self, data: &DezoomerInput) -> Result<ZoomLevels, DezoomerError> {
+        let mut engine = Engine::new();
+        let input = data.uri.clone();
+        engine.register_fn("input", move || input.clone());
+        let ast = engine.compile(&self.script).map_err(ScriptError::from).map_err(DezoomerError::wrap)?;
+        script_levels(&engine, &ast).map_err(DezoomerError::wrap)
+    }
+}
+
+fn script_levels(engine: &Engine, ast: &AST) -> Result<ZoomLevels, ScriptError> {
+    let mut scope = Scope::new();
+    let level_count: i64 = if ast.iter_functions().any(|f| f.name == "levels") {
+        engine.call_fn(&mut scope, ast, "levels", ())?
+    } else {
+        1
+    };
+    let mut levels: ZoomLevels = vec![];
+    for level in 0..level_count {
+        let grid = pair(engine.call_fn(&mut scope, ast, "grid", (level, ))?, "grid")?;
+        // Generate all the tiles now, so that errors in the script are reported early
+        let mut tiles = vec![];
+        for y in 0..grid.y {
+            for x in 0..grid.x {
+                let (x, y) = (i64::from(x), i64::from(y));
+                let url: String = engine.call_fn(&mut scope, ast, "tile_url", (x, y, level))?;
+                let position: Array = engine.call_fn(&mut scope, ast, "tile_position", (x, y))?;
+                tiles.push(TileReference { url, position: pair(position, "tile_position")?, cell_size: None });
+            }
+        }
+        levels.push(Box::new(ScriptLevel { level, grid, tiles }));
+    }
+    Ok(levels)
+}
+
+fn pair(values: Array, function: &'static str) -> Result<Vec2d, ScriptError> {
+    let int = |i: usize| values.get(i)
+        .and_then(|v| v.as_int().ok())
+        .and_then(|v| u32::try_from(v).ok())
+        .ok_or(ScriptError::BadPair { function });
+    if values.len() != 2 { return Err(ScriptError::BadPair { function }); }
+    Ok(Vec2d { x: int(0)?, y: int(1)? })
+}
+
+struct ScriptLevel {
+    level: i64,
+    grid: Vec2d,
+    tiles: Vec<TileReference>,
+}
+
+impl TileProvider for ScriptLevel {
+    fn next_tiles(&mut self, previous: Option<TileFetchResult>) -> Vec<TileReference> {
+        if previous.is_some() {
+            return vec![];
+        }
+        std::mem::take(&mut self.tiles)
+    }
+
+    fn known_tiles(&self) -> Option<Vec<TileReference>> {
+        Some(self.tiles.clone())
+    }
+
+    fn name(&self) -> String {
+        format!("{:?} ({} x {} tiles)", self, self.grid.x, self.grid.y)
+    }
+}
+
+impl std::fmt::Debug for ScriptLevel {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Script level {}", self.level)
+    }
+}
+
+#[test]
+fn test_row_major_script() {
+    let script = r#"
+        fn grid(level) { [3, 2] }
+        fn tile_url(x, y, level) { input() + "/" + x + "_" + y + ".jpg" }
+        fn tile_position(x, y) { [x * 256, y * 256] }
+    "#;
+    let mut dezoomer = ScriptDezoomer::new(script.into());
+    let mut levels = dezoomer.zoom_levels(&DezoomerInput {
+        uri: "http://example.com".into(),
+        contents: PageContents::Unknown,
+    }).unwrap();
+    assert_eq!(levels.len(), 1);
+    // Listing the tiles in advance keeps them for the download
+    let known = levels[0].known_tiles();
+    let tiles = levels[0].next_tiles(None);
+    assert_eq!(known.as_ref(), Some(&tiles));
+    // The same tiles as the ones of the generic dezoomer for "http://example.com/{{X}}_{{Y}}.jpg"
+    let expected: Vec<TileReference> = (0..2)
+        .flat_map(|y| (0..3).map(move |x| TileReference {
+            url: format!("http://example.com/{}_{}.jpg", x, y),
+            position: Vec2d { x: x * 256, y: y * 256 },
+            cell_size: None,
+        }))
+        .collect();
+    assert_eq!(tiles, expected);
+}
+
+#[test]
+fn test_script_errors() {
+    let run = |script: &str| ScriptDezoomer::new(script.into()).zoom_levels(&DezoomerInput {
+        uri: "http://example.com".into(),
+        contents: PageContents::Unknown,
+    });
+    assert!(run("fn grid(level) {").is_err());
+    assert!(run("fn grid(level) { [1, -1] }").is_err());
+    assert!(run("fn grid(level) { [1, 1] }").is_err(), "tile_url is missing");
+}
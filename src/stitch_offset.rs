@@ -0,0 +1,213 @@
+use std::str::FromStr;
+
+use image::GenericImageView;
+use image::imageops::FilterType;
+
+use crate::dezoomer::TileReference;
+use crate::tile::Tile;
+use crate::Vec2d;
+
+/// How a stitching offset is applied to the tiles
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StitchOffsetMode {
+    /// All tiles are shifted by the same amount
+    Global,
+    /// The tile in column c and row r is shifted by c times the horizontal offset
+    /// and r times the vertical offset
+    Cumulative,
+}
+
+impl FromStr for StitchOffsetMode {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "global" => Ok(StitchOffsetMode::Global),
+            "cumulative" => Ok(StitchOffsetMode::Cumulative),
+            _ => Err("Invalid stitch offset mode. Expected 'global' or 'cumulative'"),
+        }
+    }
+}
+
+/// An offset, in pixels, that can be negative or fractional
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Offset {
+    pub dx: f64,
+    pub dy: f64,
+}
+
+impl FromStr for Offset {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err_msg = "Invalid offset. Expected two numbers separated by a comma, such as '-2,0'";
+        let mut parts = s.splitn(2, ',').map(|n| n.trim().parse::<f64>());
+        match (parts.next(), parts.next()) {
+            (Some(Ok(dx)), Some(Ok(dy))) if dx.is_finite() && dy.is_finite() => Ok(Offset { dx, dy }),
+            _ => Err(err_msg),
+        }
+    }
+}
+
+/// Corrects the position of tiles from servers that consistently misplace them by a few pixels
+#[derive(Debug)]
+pub struct StitchOffsetCorrector {
+    offset: Offset,
+    mode: StitchOffsetMode,
+    /// Distance between two consecutive columns and rows of tiles; zero while unknown
+    grid_step: Vec2d,
+}
+
+impl StitchOffsetCorrector {
+    pub fn new(offset: Offset, mode: StitchOffsetMode) -> Self {
+        StitchOffsetCorrector { offset, mode, grid_step: Vec2d::default() }
+    }
+
+    /// Learns the spacing of the grid from a batch of tiles, before they are downloaded,
+    /// so that the corrections do not depend on the order in which the tiles arrive
+    pub fn learn_grid(&mut self, tiles: &[TileReference]) {
+        if self.offset == Offset::default() { return; }
+        for tile in tiles {
+            self.grid_step.x = min_non_zero(self.grid_step.x, tile.position.x);
+            self.grid_step.y = min_non_zero(self.grid_step.y, tile.position.y);
+        }
+    }
+
+    /// Moves a downloaded tile. Its exact position is computed from the index of the tile,
+    /// so that the rounding errors of fractional offsets do not add up from tile to tile.
+    /// The tile then covers all the pixels it partly overlaps: it starts at the pixel of its
+    /// exact position, and is enlarged by at most one pixel to end at the pixel of its exact end.
+    pub fn place(&self, tile: Tile) -> Tile {
+        if self.offset == Offset::default() { return tile; }
+        let (col, row) = self.grid_index(tile.position);
+        let (x, width) = span(tile.position.x, tile.size().x, self.offset.dx * col);
+        let (y, height) = span(tile.position.y, tile.size().y, self.offset.dy * row);
+        if (width, height) == tile.image.dimensions() {
+            return Tile { position: Vec2d { x, y }, ..tile };
+        }
+        let image = tile.image.resize_exact(width, height, FilterType::Triangle);
+        Tile { position: Vec2d { x, y }, image, encoded: None }
+    }
+
+    /// The size of an image once its tiles are moved, rounded up to contain the last pixels
+    pub fn correct_size(&self, size: Vec2d) -> Vec2d {
+        if self.offset == Offset::default() || size.area() == 0 { return size; }
+        let (col, row) = self.grid_index(size - Vec2d::square(1));
+        let extent = |len: u32, shift: f64| (f64::from(len) + shift).ceil().max(1.).min(f64::from(u32::MAX)) as u32;
+        Vec2d { x: extent(size.x, self.offset.dx * col), y: extent(size.y, self.offset.dy * row) }
+    }
+
+    /// The number of offsets by which the pixel at the given position is shifted
+    fn grid_index(&self, position: Vec2d) -> (f64, f64) {
+        match self.mode {
+            StitchOffsetMode::Global => (1., 1.),
+            StitchOffsetMode::Cumulative => (
+                grid_index(position.x, self.grid_step.x),
+                grid_index(position.y, self.grid_step.y),
+            ),
+        }
+    }
+}
+
+fn min_non_zero(current: u32, candidate: u32) -> u32 {
+    match (current, candidate) {
+        (_, 0) => current,
+        (0, _) => candidate,
+        _ => current.min(candidate),
+    }
+}
+
+fn grid_index(position: u32, step: u32) -> f64 {
+    position.checked_div(step).map(f64::from).unwrap_or(0.)
+}
+
+/// The first pixel and the number of pixels covered by a tile moved by a fractional shift.
+/// Tiles moved before the start of the image are moved to its start, and keep their size.
+fn span(position: u32, len: u32, shift: f64) -> (u32, u32) {
+    let start = f64::from(position) + shift;
+    if start < 0. { return (0, len); }
+    let first = start.floor().min(f64::from(u32::MAX));
+    let end = (start + f64::from(len)).ceil().min(f64::from(u32::MAX));
+    (first as u32, (end - first) as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use image::DynamicImage;
+
+    use super::*;
+
+    fn row_of_tiles(tile_width: u32, count: u32) -> Vec<TileReference> {
+        (0..count).rev().map(|i| TileReference {
+            url: i.to_string(),
+            position: Vec2d { x: i * tile_width, y: 0 },
+            cell_size: None,
+        }).collect()
+    }
+
+    /// Moves the tiles, downloaded as images of the given size. Returns their horizontal spans.
+    fn place(corrector: &mut StitchOffsetCorrector, tiles: &[TileReference], size: Vec2d) -> Vec<(u32, u32)> {
+        corrector.learn_grid(tiles);
+        let mut spans: Vec<(u32, u32)> = tiles.iter().map(|tile_ref| {
+            let tile = Tile { position: tile_ref.position, image: DynamicImage::new_rgb8(size.x, size.y), encoded: None };
+            let placed = corrector.place(tile);
+            (placed.position.x, placed.size().x)
+        }).collect();
+        spans.sort_unstable();
+        spans
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!("-2, 3".parse(), Ok(Offset { dx: -2., dy: 3. }));
+        assert_eq!("0.5,0".parse(), Ok(Offset { dx: 0.5, dy: 0. }));
+        assert!("2".parse::<Offset>().is_err());
+        assert!("NaN,0".parse::<Offset>().is_err());
+        assert_eq!("cumulative".parse(), Ok(StitchOffsetMode::Cumulative));
+    }
+
+    #[test]
+    fn test_global_offset() {
+        let mut corrector = StitchOffsetCorrector::new(Offset { dx: 3., dy: 1. }, StitchOffsetMode::Global);
+        let tiles = row_of_tiles(10, 3);
+        assert_eq!(place(&mut corrector, &tiles, Vec2d { x: 10, y: 5 }), vec![(3, 10), (13, 10), (23, 10)]);
+        let tile = corrector.place(Tile { position: Vec2d { x: 0, y: 0 }, image: DynamicImage::new_rgb8(10, 5), encoded: None });
+        assert_eq!(tile.position.y, 1);
+        assert_eq!(corrector.correct_size(Vec2d { x: 30, y: 5 }), Vec2d { x: 33, y: 6 });
+    }
+
+    #[test]
+    fn test_cumulative_offset_aligns_seams() {
+        // The server says the tiles are 10 pixels wide, but each one
+        // repeats the last 2 pixel columns of the previous one
+        let tiles = row_of_tiles(10, 4);
+        let mut corrector = StitchOffsetCorrector::new(Offset { dx: -2., dy: 0. }, StitchOffsetMode::Cumulative);
+        let size = Vec2d { x: 10, y: 10 };
+        assert_eq!(place(&mut corrector, &tiles, size), vec![(0, 10), (8, 10), (16, 10), (24, 10)]);
+        assert_eq!(corrector.correct_size(Vec2d { x: 40, y: 10 }), Vec2d { x: 34, y: 10 });
+        // The grid spacing is remembered between batches
+        let next_batch = vec![TileReference { url: "4".into(), position: Vec2d { x: 40, y: 0 }, cell_size: None }];
+        assert_eq!(place(&mut corrector, &next_batch, size), vec![(32, 10)]);
+    }
+
+    #[test]
+    fn test_fractional_spacing_does_not_drift() {
+        // The server says the tiles are 256 pixels wide, but they are really 256.5 pixels apart
+        let count = 1000;
+        let tiles = row_of_tiles(256, count);
+        let mut corrector = StitchOffsetCorrector::new(Offset { dx: 0.5, dy: 0. }, StitchOffsetMode::Cumulative);
+        let spans = place(&mut corrector, &tiles, Vec2d { x: 256, y: 1 });
+        for (i, &(x, width)) in spans.iter().enumerate() {
+            let exact = i as f64 * 256.5;
+            assert!(f64::from(x) <= exact && exact - f64::from(x) < 1., "tile {} is at {}", i, x);
+            assert!(f64::from(x + width) >= exact + 256., "tile {} ends at {}", i, x + width);
+        }
+        // Each tile ends where the next one starts
+        assert!(spans.windows(2).all(|w| w[0].0 + w[0].1 == w[1].0), "{:?}", spans);
+        let last = spans[count as usize - 1];
+        assert_eq!(last, (256_243, 257));
+        // The image is enlarged to contain the whole last tile
+        let size = corrector.correct_size(Vec2d { x: 256 * count, y: 1 });
+        assert_eq!(size, Vec2d { x: last.0 + last.1, y: 1 });
+    }
+}
@@ -0,0 +1,193 @@
+use std::fmt;
+
+use itertools::Itertools;
+use log::warn;
+
+use crate::dezoomer::TileGrid;
+use crate::errors::BufferToImageError;
+use crate::tile::Tile;
+use crate::verify::pixels_hash;
+use crate::{TileDownloadError, Vec2d, ZoomError};
+
+/// Number of tiles, among the first ones downloaded, that are checked
+const PROBES: usize = 8;
+
+/// Number of identical tiles after which the tile coordinates are considered to be ignored.
+/// Images often start with a few tiles of the same background color.
+const IDENTICAL_TILES: usize = 4;
+
+/// A sign that the template used to guess the tiles of an image is wrong
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TemplateWarning {
+    /// No tile could be decoded, and the server sent this kind of document instead
+    NotAnImage { kind: &'static str, count: usize },
+    /// All the tiles have the same pixels
+    IdenticalTiles { count: usize },
+    /// The image has a single tile, which has the size of the tiles of a larger image
+    SingleTile { tile_size: Vec2d },
+}
+
+impl fmt::Display for TemplateWarning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TemplateWarning::NotAnImage { kind, count } => write!(
+                f, "none of the {} first tiles is an image: the server sent {}. \
+                The template may be the url of a web page instead of the one of a tile.", count, kind
+            ),
+            TemplateWarning::IdenticalTiles { count } => write!(
+                f, "the {} first tiles are identical. \
+                The server may be ignoring the tile coordinates of the template.", count
+            ),
+            TemplateWarning::SingleTile { tile_size } => write!(
+                f, "the image is a single tile of {}, the typical size of a tile from a larger image. \
+                The tile coordinates may be at the wrong place in the template.", tile_size
+            ),
+        }
+    }
+}
+
+enum Probe {
+    Image { hash: [u8; 32] },
+    NotAnImage { kind: &'static str },
+    Failed,
+}
+
+/// Looks at the first tiles of a level whose tiles are guessed,
+/// and reports the signs that the template is wrong before the whole image is downloaded
+pub struct TemplateCheck {
+    enabled: bool,
+    strict: bool,
+    probes: Vec<Probe>,
+    done: bool,
+}
+
+impl TemplateCheck {
+    pub fn new(enabled: bool, strict: bool) -> Self {
+        TemplateCheck { enabled, strict, probes: Vec::with_capacity(PROBES), done: false }
+    }
+
+    pub fn record(&mut self, result: &Result<Tile, TileDownloadError>) {
+        if !self.enabled || self.done || self.probes.len() >= PROBES { return; }
+        self.probes.push(match result {
+            Ok(tile) => Probe::Image { hash: pixels_hash(tile) },
+            Err(TileDownloadError {
+                    cause: ZoomError::BufferToImage { source: BufferToImageError::NotAnImage { kind } }, ..
+                }) => Probe::NotAnImage { kind },
+            Err(_) => Probe::Failed,
+        });
+    }
+
+    /// Reports the warnings once enough tiles were downloaded, or the layout of the tiles is known.
+    /// When `finished`, there are no more tiles to wait for.
+    /// In strict mode, the warnings are returned as an error.
+    pub fn check(&mut self, grid: Option<TileGrid>, finished: bool) -> Result<(), ZoomError> {
+        if !self.enabled || self.done { return Ok(()); }
+        if !finished && grid.is_none() && self.probes.len() < PROBES { return Ok(()); }
+        self.done = true;
+        let warnings = self.warnings(grid);
+        if warnings.is_empty() { return Ok(()); }
+        let reasons = warnings.iter().map(|w| format!(" - {}", w)).join("\n");
+        if self.strict {
+            Err(ZoomError::SuspiciousTemplate { reasons })
+        } else {
+            warn!("The tile template is probably wrong:\n{}\n\
+                   Check the template, or use --strict to stop the download in such cases.", reasons);
+            Ok(())
+        }
+    }
+
+    fn warnings(&self, grid: Option<TileGrid>) -> Vec<TemplateWarning> {
+        let mut warnings = vec![];
+        let hashes: Vec<&[u8; 32]> = self.probes.iter().filter_map(|p| match p {
+            Probe::Image { hash } => Some(hash),
+            _ => None,
+        }).collect();
+        let not_images = self.probes.iter().filter_map(|p| match p {
+            Probe::NotAnImage { kind } => Some(*kind),
+            _ => None,
+        });
+        if hashes.is_empty() {
+            if let Some(kind) = not_images.clone().next() {
+                warnings.push(TemplateWarning::NotAnImage { kind, count: not_images.count() });
+            }
+        }
+        if hashes.len() >= IDENTICAL_TILES && hashes.iter().all(|&h| h == hashes[0]) {
+            warnings.push(TemplateWarning::IdenticalTiles { count: hashes.len() });
+        }
+        if let Some(grid) = grid {
+            let tile_size = grid.tile_size;
+            if grid.tile_count == (Vec2d { x: 1, y: 1 })
+                && tile_size.x == tile_size.y && tile_size.x >= 256 && tile_size.x.is_power_of_two() {
+                warnings.push(TemplateWarning::SingleTile { tile_size });
+            }
+        }
+        warnings
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{DynamicImage, ImageBuffer, Rgb};
+
+    use crate::dezoomer::TileReference;
+
+    use super::*;
+
+    fn failure(cause: ZoomError) -> Result<Tile, TileDownloadError> {
+        let tile_reference = TileReference { url: "tile.jpg".into(), position: Vec2d::default(), cell_size: None };
+        Err(TileDownloadError { tile_reference, cause })
+    }
+
+    fn tile(color: u8) -> Result<Tile, TileDownloadError> {
+        let image = DynamicImage::ImageRgb8(ImageBuffer::from_pixel(4, 4, Rgb([color; 3])));
+        Ok(Tile { position: Vec2d::default(), image, encoded: None })
+    }
+
+    #[test]
+    fn test_html_template_is_reported() {
+        let mut check = TemplateCheck::new(true, true);
+        for _ in 0..3 {
+            check.record(&failure(BufferToImageError::NotAnImage { kind: "an HTML page" }.into()));
+        }
+        assert_eq!(check.warnings(None), vec![TemplateWarning::NotAnImage { kind: "an HTML page", count: 3 }]);
+        // Not enough tiles were downloaded yet
+        assert!(check.check(None, false).is_ok());
+        match check.check(None, true) {
+            Err(ZoomError::SuspiciousTemplate { reasons }) => assert!(reasons.contains("HTML"), "{}", reasons),
+            other => panic!("The HTML tiles should be reported, got {:?}", other),
+        }
+        // The warnings are reported once
+        assert!(check.check(None, true).is_ok());
+    }
+
+    #[test]
+    fn test_missing_html_tiles_are_expected() {
+        // Servers often answer with an error page for the tiles outside of the image
+        let mut check = TemplateCheck::new(true, true);
+        check.record(&tile(1));
+        check.record(&failure(BufferToImageError::NotAnImage { kind: "an HTML page" }.into()));
+        check.record(&tile(2));
+        assert!(check.warnings(None).is_empty());
+    }
+
+    #[test]
+    fn test_identical_tiles_and_single_tile() {
+        let mut check = TemplateCheck::new(true, false);
+        (0..IDENTICAL_TILES).for_each(|_| check.record(&tile(7)));
+        let grid = TileGrid {
+            origin: Vec2d::default(),
+            tile_count: Vec2d { x: 1, y: 1 },
+            tile_size: Vec2d { x: 512, y: 512 },
+            image_size: Vec2d { x: 512, y: 512 },
+        };
+        assert_eq!(check.warnings(Some(grid)), vec![
+            TemplateWarning::IdenticalTiles { count: IDENTICAL_TILES },
+            TemplateWarning::SingleTile { tile_size: grid.tile_size },
+        ]);
+        // Without --strict, the download continues
+        assert!(check.check(Some(grid), false).is_ok());
+        let mut disabled = TemplateCheck::new(false, true);
+        disabled.record(&tile(7));
+        assert!(disabled.check(Some(grid), true).is_ok());
+    }
+}
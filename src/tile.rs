@@ -1,14 +1,96 @@
+use std::future::Future;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
 use image::{GenericImageView, DynamicImage};
+use image::imageops::FilterType;
+use log::{debug, warn};
+use tokio::sync::Semaphore;
 
 use crate::{Vec2d, ZoomError};
-use crate::dezoomer::{PostProcessFn, TileReference};
+use crate::dezoomer::{PositionSource, PostProcessFn, TileReference};
 use crate::errors::BufferToImageError;
-use crate::network::fetch_uri;
+use crate::network::{fetch_uri_info, fetch_websocket_uri, is_websocket_url, local_mirror, ResponseInfo, TileClients};
+use crate::provenance::ProvenanceLog;
+use crate::color_profile::{convert_to_srgb, icc_profile};
+use crate::tile_metadata::metadata_position;
+
+/// How the downloaded bytes of a tile are turned into an image
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DecodeOptions {
+    pub position_source: PositionSource,
+    /// Convert the pixels of tiles that have an embedded color profile to sRGB
+    pub convert_srgb: bool,
+    /// How tiles are scaled to the size of their cell
+    pub resample: Resample,
+    /// Keep the bytes of the tiles whose pixels are not modified, for the outputs that store them
+    pub keep_encoded: bool,
+}
+
+/// Interpolation used to scale tiles
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Resample {
+    Nearest,
+    #[default]
+    Bilinear,
+    Lanczos,
+}
+
+impl Resample {
+    fn filter(self) -> FilterType {
+        match self {
+            Resample::Nearest => FilterType::Nearest,
+            Resample::Bilinear => FilterType::Triangle,
+            Resample::Lanczos => FilterType::Lanczos3,
+        }
+    }
+}
+
+impl FromStr for Resample {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "nearest" => Ok(Resample::Nearest),
+            "bilinear" => Ok(Resample::Bilinear),
+            "lanczos" => Ok(Resample::Lanczos),
+            _ => Err("Invalid resampling filter. Expected 'nearest', 'bilinear' or 'lanczos'"),
+        }
+    }
+}
+
+/// What the download of a tile depends on, besides the tile itself:
+/// it is the same for all the tiles of a zoom level
+#[derive(Clone, Copy)]
+pub struct DownloadContext<'a> {
+    pub post_process_fn: PostProcessFn,
+    pub decode_options: DecodeOptions,
+    pub clients: &'a TileClients,
+    pub stages: &'a TileStages,
+    /// Where the requests for the tiles are recorded
+    pub provenance: Option<&'a ProvenanceLog>,
+}
+
+impl<'a> DownloadContext<'a> {
+    /// Tiles that are decoded with the default options, and whose requests are not recorded
+    pub fn new(clients: &'a TileClients, stages: &'a TileStages) -> Self {
+        DownloadContext {
+            post_process_fn: PostProcessFn::None,
+            decode_options: DecodeOptions::default(),
+            clients,
+            stages,
+            provenance: None,
+        }
+    }
+}
 
 #[derive(Clone)]
 pub struct Tile {
     pub image: image::DynamicImage,
     pub position: Vec2d,
+    /// The image file the pixels were decoded from, when it was kept
+    pub encoded: Option<Arc<[u8]>>,
 }
 
 impl Tile {
@@ -16,42 +98,213 @@ impl Tile {
     pub fn bottom_right(&self) -> Vec2d {
         self.size() + self.position
     }
-    pub async fn download(
-        post_process_fn: PostProcessFn,
-        tile_reference: &TileReference,
-        client: &reqwest::Client,
-    ) -> Result<Tile, ZoomError> {
-        let bytes = fetch_uri(&tile_reference.url, client).await?;
+    pub async fn download(context: &DownloadContext<'_>, tile_reference: &TileReference) -> Result<Tile, ZoomError> {
+        let &DownloadContext { post_process_fn, decode_options: options, clients, stages, provenance } = context;
+        let local = match clients.local_root() {
+            Some(root) => local_mirror(root, &tile_reference.url).await,
+            None => None,
+        };
+        let uri = local.as_deref().unwrap_or(&tile_reference.url);
+        let client = clients.for_url(uri);
+        let mut info = ResponseInfo::default();
+        let fetched = match (clients.token(), clients.token_chain()) {
+            _ if local.is_some() => stages.fetch.run(fetch_uri_info(uri, client, &mut info)).await,
+            _ if is_websocket_url(uri) => stages.fetch.run(fetch_websocket_uri(uri, clients.headers_for_url(uri))).await,
+            (Some(token), _) => stages.fetch.run(token.fetch(uri, client, &mut info)).await,
+            (None, Some(chain)) => chain.fetch(&stages.fetch, uri, client, &mut info).await,
+            (None, None) => stages.fetch.run(fetch_uri_info(uri, client, &mut info)).await,
+        };
+        if let Some(provenance) = provenance { provenance.record(uri, &info, &fetched); }
+        let bytes = fetched?;
         let tile_reference = tile_reference.clone();
 
-        let tile: Result<Tile, BufferToImageError> = tokio::spawn(async move {
-            tokio::task::block_in_place(move || {
-                let transformed_bytes =
-                    if let PostProcessFn::Fn(post_process) = post_process_fn {
-                        post_process(&tile_reference, bytes)
-                            .map_err(|e|
-                                BufferToImageError::PostProcessing { e }
-                            )?
-                    } else {
-                        bytes
-                    };
-
-                Ok(Tile {
-                    image: image::load_from_memory(&transformed_bytes)?,
-                    position: tile_reference.position,
+        // The decoding task is only spawned once there is room in the decoding stage
+        let tile: Result<Tile, BufferToImageError> = stages.decode.run(async move {
+            tokio::spawn(async move {
+                tokio::task::block_in_place(move || {
+                    Tile::decode(post_process_fn, options, &tile_reference, bytes)
                 })
-            })
+            }).await
         }).await?;
         Ok(tile?)
     }
+    fn decode(
+        post_process_fn: PostProcessFn,
+        options: DecodeOptions,
+        tile_reference: &TileReference,
+        bytes: Vec<u8>,
+    ) -> Result<Tile, BufferToImageError> {
+        let transformed_bytes =
+            if let PostProcessFn::Fn(post_process) = post_process_fn {
+                post_process(tile_reference, bytes)
+                    .map_err(|e|
+                        BufferToImageError::PostProcessing { e }
+                    )?
+            } else {
+                bytes
+            };
+
+        let position = match options.position_source {
+            PositionSource::Reference => tile_reference.position,
+            PositionSource::Exif => metadata_position(&transformed_bytes).unwrap_or_else(|| {
+                warn!("No position in the metadata of {}", tile_reference.url);
+                tile_reference.position
+            }),
+        };
+        let mut image = decode_image(&transformed_bytes).map_err(|error| {
+            match document_kind(&transformed_bytes) {
+                Some(kind) => BufferToImageError::NotAnImage { kind },
+                None => error.into(),
+            }
+        })?;
+        // Tiles that do not start with their signature cannot be stored as image files
+        let mut unchanged = image::guess_format(&transformed_bytes).is_ok();
+        if options.convert_srgb {
+            if let Some(icc) = icc_profile(&transformed_bytes) {
+                image = convert_to_srgb(image, &icc);
+                unchanged = false;
+            }
+        }
+        if let Some(cell) = tile_reference.cell_size {
+            if Vec2d::from(image.dimensions()) != cell {
+                image = image.resize_exact(cell.x, cell.y, options.resample.filter());
+                unchanged = false;
+            }
+        }
+        let encoded = if options.keep_encoded && unchanged { Some(transformed_bytes.into()) } else { None };
+        Ok(Tile { image: normalize_pixels(image), position, encoded })
+    }
     pub fn empty(position: Vec2d, size: Vec2d) -> Tile {
-        Tile { image: DynamicImage::new_rgba8(size.x, size.y), position }
+        Tile { image: DynamicImage::new_rgba8(size.x, size.y), position, encoded: None }
     }
     pub fn position(&self) -> Vec2d {
         self.position
     }
 }
 
+/// Decodes the bytes of a tile.
+/// The format is guessed from the first bytes of the tile, not from its url,
+/// because servers often send tiles in another format than the one in the url.
+/// When that fails, the other common formats are tried, including from the position of
+/// their signature, for tiles that have a few bytes of garbage before the actual image.
+fn decode_image(bytes: &[u8]) -> image::ImageResult<DynamicImage> {
+    use image::ImageFormat::{Jpeg, Png, WebP};
+    let error = match image::load_from_memory(bytes) {
+        Ok(image) => return Ok(image),
+        Err(error) => error,
+    };
+    let candidates = [Png, Jpeg, WebP].iter().map(|&format| (0, format))
+        .chain(embedded_signatures(bytes));
+    for (offset, format) in candidates {
+        if let Ok(image) = image::load_from_memory_with_format(&bytes[offset..], format) {
+            debug!("Decoded a tile as {:?} from byte {} after a first failure: {}", format, offset, error);
+            return Ok(image);
+        }
+    }
+    Err(error)
+}
+
+/// Tiles are stored as rgb pixels whatever their color type in the source file
+/// (palette, grayscale or bgr), so that they are composited consistently.
+/// Palettes are already expanded by the decoders. Tiles keep their transparency and their precision,
+/// and the tiles that already have rgb pixels, such as most jpeg tiles, are not copied.
+fn normalize_pixels(image: DynamicImage) -> DynamicImage {
+    match image {
+        DynamicImage::ImageLuma8(_) | DynamicImage::ImageBgr8(_) => DynamicImage::ImageRgb8(image.into_rgb8()),
+        DynamicImage::ImageLumaA8(_) | DynamicImage::ImageBgra8(_) => DynamicImage::ImageRgba8(image.into_rgba8()),
+        DynamicImage::ImageLuma16(_) => DynamicImage::ImageRgb16(image.into_rgb16()),
+        DynamicImage::ImageLumaA16(_) => DynamicImage::ImageRgba16(image.into_rgba16()),
+        image => image,
+    }
+}
+
+/// Recognizes the text documents that servers commonly send instead of a tile,
+/// such as an error page
+fn document_kind(bytes: &[u8]) -> Option<&'static str> {
+    let start = bytes.strip_prefix(b"\xEF\xBB\xBF").unwrap_or(bytes);
+    let start = &start[start.iter().position(|b| !b.is_ascii_whitespace())?..];
+    let start = String::from_utf8_lossy(&start[..start.len().min(64)]).to_ascii_lowercase();
+    if start.starts_with("<!doctype html") || start.starts_with("<html") {
+        Some("an HTML page")
+    } else if start.starts_with('<') {
+        Some("an XML document")
+    } else if start.starts_with('{') || start.starts_with('[') {
+        Some("a JSON document")
+    } else {
+        None
+    }
+}
+
+/// Positions of the signatures of common image formats in the first bytes of a tile
+fn embedded_signatures(bytes: &[u8]) -> impl Iterator<Item=(usize, image::ImageFormat)> + '_ {
+    use image::ImageFormat::{Jpeg, Png, WebP};
+    const SEARCH_LEN: usize = 1024;
+    (1..bytes.len().min(SEARCH_LEN)).filter_map(move |offset| {
+        let rest = &bytes[offset..];
+        if rest.starts_with(b"\x89PNG\r\n\x1a\n") {
+            Some((offset, Png))
+        } else if rest.starts_with(&[0xFF, 0xD8, 0xFF]) {
+            Some((offset, Jpeg))
+        } else if rest.starts_with(b"RIFF") && rest.get(8..12) == Some(b"WEBP") {
+            Some((offset, WebP))
+        } else {
+            None
+        }
+    })
+}
+
+/// A step of the download of tiles, that only a limited number of tiles can go through at once
+pub struct Stage {
+    semaphore: Semaphore,
+    limit: usize,
+    active: AtomicUsize,
+    peak: AtomicUsize,
+}
+
+impl Stage {
+    pub fn new(limit: usize) -> Self {
+        let limit = limit.max(1);
+        Stage { semaphore: Semaphore::new(limit), limit, active: AtomicUsize::new(0), peak: AtomicUsize::new(0) }
+    }
+
+    /// Waits until there is room in the stage, then runs the given future
+    pub async fn run<F: Future>(&self, future: F) -> F::Output {
+        let _permit = self.semaphore.acquire().await.expect("the stage semaphore is never closed");
+        let active = self.active.fetch_add(1, Ordering::SeqCst) + 1;
+        self.peak.fetch_max(active, Ordering::SeqCst);
+        let _guard = ActiveGuard(&self.active);
+        future.await
+    }
+
+    pub fn limit(&self) -> usize { self.limit }
+
+    /// The largest number of tiles that were in the stage at the same time
+    pub fn peak(&self) -> usize { self.peak.load(Ordering::SeqCst) }
+}
+
+/// Leaves a stage when dropped, even if the future that was running in it was cancelled
+struct ActiveGuard<'a>(&'a AtomicUsize);
+
+impl Drop for ActiveGuard<'_> {
+    fn drop(&mut self) { self.0.fetch_sub(1, Ordering::SeqCst); }
+}
+
+/// Tiles are first fetched from the network, then decoded. Each of these stages has its own
+/// concurrency limit, so that a tile waiting to be decoded does not prevent others from being fetched.
+pub struct TileStages {
+    pub fetch: Stage,
+    pub decode: Stage,
+}
+
+impl TileStages {
+    pub fn new(fetch: usize, decode: usize) -> Self {
+        TileStages { fetch: Stage::new(fetch), decode: Stage::new(decode) }
+    }
+
+    /// The maximum number of tiles that can be in the pipeline at the same time
+    pub fn width(&self) -> usize { self.fetch.limit() + self.decode.limit() }
+}
+
 impl std::fmt::Debug for Tile {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         f.debug_struct("Tile")
@@ -71,4 +324,184 @@ impl PartialEq for Tile {
                 other.image.get_pixel(x, y) == pix
             })
     }
-}
\ No newline at end of file
+}
+#[tokio::test(flavor = "multi_thread")]
+async fn test_tile_format_is_sniffed() {
+    use image::{ImageBuffer, ImageOutputFormat, Rgb};
+    let image = DynamicImage::ImageRgb8(ImageBuffer::from_pixel(3, 2, Rgb([1, 2, 3])));
+    let mut png_bytes = vec![];
+    image.write_to(&mut png_bytes, ImageOutputFormat::Png).unwrap();
+    // A png tile with the extension of a jpeg
+    let dir = tempdir::TempDir::new("dezoomify-rs-png-tile").unwrap();
+    let path = dir.path().join("tile.jpg");
+    std::fs::write(&path, png_bytes).unwrap();
+    let tile_reference = TileReference {
+        url: path.to_string_lossy().to_string(),
+        position: Vec2d { x: 3, y: 4 },
+        cell_size: None,
+    };
+    let stages = TileStages::new(1, 1);
+    let clients = TileClients::from(reqwest::Client::new());
+    let tile = Tile::download(&DownloadContext::new(&clients, &stages), &tile_reference).await.unwrap();
+    assert_eq!(tile, Tile { image, position: Vec2d { x: 3, y: 4 }, encoded: None });
+}
+
+#[test]
+fn test_tiles_of_all_color_types_match() {
+    use image::{GenericImage, ImageBuffer, ImageOutputFormat, Luma, LumaA, Rgb};
+    let colors = [[200, 10, 10], [10, 200, 10], [10, 10, 200], [90, 90, 90]];
+    let encode = |image: DynamicImage| {
+        let mut bytes = vec![];
+        image.write_to(&mut bytes, ImageOutputFormat::Png).unwrap();
+        bytes
+    };
+    let encode_png = |size, color, depth, palette: Option<Vec<u8>>, data: &[u8]| {
+        let mut bytes = vec![];
+        let mut encoder = png::Encoder::new(&mut bytes, size, size);
+        encoder.set_color(color);
+        encoder.set_depth(depth);
+        if let Some(palette) = palette { encoder.set_palette(palette); }
+        encoder.write_header().unwrap().write_image_data(data).unwrap();
+        bytes
+    };
+    let palette_bytes = encode_png(2, png::ColorType::Indexed, png::BitDepth::Eight, Some(colors.concat()), &[0, 1, 2, 3]);
+    let truecolor = DynamicImage::ImageRgb8(ImageBuffer::from_fn(2, 2, |x, y| Rgb(colors[(2 * y + x) as usize])));
+    let gray = DynamicImage::ImageLuma8(ImageBuffer::from_pixel(2, 2, Luma([90])));
+    let decode = |bytes: &[u8], x| {
+        let tile_reference = TileReference { url: "tile.png".into(), position: Vec2d { x, y: 0 }, cell_size: None };
+        Tile::decode(PostProcessFn::None, DecodeOptions::default(), &tile_reference, bytes.to_vec()).unwrap()
+    };
+    let tiles = [decode(&palette_bytes, 0), decode(&encode(truecolor), 2), decode(&encode(gray), 4)];
+    // Side by side, the tiles have the same colors
+    let mut composite = image::RgbImage::new(6, 2);
+    for tile in &tiles {
+        assert_eq!(tile.image.color(), image::ColorType::Rgb8, "tile at {}", tile.position);
+        composite.copy_from(tile.image.as_rgb8().unwrap(), tile.position.x, tile.position.y).unwrap();
+    }
+    for (x, y, &pixel) in composite.enumerate_pixels() {
+        let expected = if x < 4 { colors[(2 * y + x % 2) as usize] } else { colors[3] };
+        assert_eq!(pixel.0, expected, "pixel {},{}", x, y);
+    }
+
+    // Transparency and precision are kept
+    let gray_alpha = DynamicImage::ImageLumaA8(ImageBuffer::from_pixel(1, 1, LumaA([90, 128])));
+    let tile = decode(&encode(gray_alpha), 0);
+    assert_eq!(tile.image.as_rgba8().unwrap().get_pixel(0, 0).0, [90, 90, 90, 128]);
+    let gray16 = encode_png(1, png::ColorType::Grayscale, png::BitDepth::Sixteen, None, &[0x12, 0x34]);
+    let tile = decode(&gray16, 0);
+    assert_eq!(tile.image.as_rgb16().unwrap().get_pixel(0, 0).0, [0x1234; 3]);
+}
+
+#[test]
+fn test_tile_is_scaled_to_its_cell() {
+    use image::{ImageBuffer, ImageOutputFormat, Luma};
+    // A half resolution tile, with a black and a white pixel
+    let image = DynamicImage::ImageLuma8(ImageBuffer::from_fn(2, 1, |x, _| Luma([x as u8 * 200])));
+    let mut png_bytes = vec![];
+    image.write_to(&mut png_bytes, ImageOutputFormat::Png).unwrap();
+    let tile_reference = TileReference {
+        url: "tile.png".into(),
+        position: Vec2d::default(),
+        cell_size: Some(Vec2d { x: 4, y: 2 }),
+    };
+    let row = |resample| {
+        let options = DecodeOptions { resample, ..Default::default() };
+        let tile = Tile::decode(PostProcessFn::None, options, &tile_reference, png_bytes.clone()).unwrap();
+        assert_eq!(tile.size(), Vec2d { x: 4, y: 2 });
+        (0..4).map(|x| tile.image.get_pixel(x, 1).0[0]).collect::<Vec<_>>()
+    };
+    assert_eq!(row(Resample::Nearest), vec![0, 0, 200, 200]);
+    // Pixel centers are at 0.25, 0.75, 1.25 and 1.75 in the original tile
+    assert_eq!(row(Resample::Bilinear), vec![0, 50, 150, 200]);
+    let lanczos = row(Resample::Lanczos);
+    assert!(lanczos[1] > 0 && lanczos[1] < lanczos[2] && lanczos[2] < 200, "{:?}", lanczos);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_mislabeled_webp_tile_is_decoded() {
+    // A 1x1 lossy webp image
+    let webp = base64::decode("UklGRiIAAABXRUJQVlA4IBYAAAAwAQCdASoBAAEADsD+JaQAA3AAAAAA").unwrap();
+    let stages = TileStages::new(1, 1);
+    let client = TileClients::from(reqwest::Client::new());
+    // Labeled as a jpeg, and preceded by garbage that prevents sniffing the format from the first bytes:
+    // the blank line of a script that writes the tile, and a byte order mark followed by text
+    let prefixes: [(&str, &[u8]); 2] = [
+        ("padded-webp-tile.jpg", b"\r\n"),
+        ("junk-webp-tile.jpg", b"\xEF\xBB\xBFjunk"),
+    ];
+    let dir = tempdir::TempDir::new("dezoomify-rs-webp-tiles").unwrap();
+    for (name, prefix) in &prefixes {
+        assert!(image::load_from_memory(&[*prefix, &webp[..]].concat()).is_err(), "{} is sniffed", name);
+        let path = dir.path().join(name);
+        std::fs::write(&path, [*prefix, &webp[..]].concat()).unwrap();
+        let url = path.to_string_lossy().to_string();
+        let tile_reference = TileReference { url, position: Vec2d::default(), cell_size: None };
+        let tile = Tile::download(&DownloadContext::new(&client, &stages), &tile_reference)
+            .await
+            .unwrap_or_else(|e| panic!("{} should be decoded: {}", name, e));
+        assert_eq!(tile.size(), Vec2d::square(1));
+    }
+    assert!(decode_image(b"\xEF\xBB\xBFjunk, not an image").is_err());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_tiles_are_converted_to_srgb() {
+    use image::{ImageBuffer, Rgb};
+    use crate::color_profile::tests::{png_with_profile, swapped_primaries_profile};
+    let image = DynamicImage::ImageRgb8(ImageBuffer::from_pixel(2, 2, Rgb([255, 0, 0])));
+    let dir = tempdir::TempDir::new("dezoomify-rs-tagged-tile").unwrap();
+    let path = dir.path().join("tile.png");
+    std::fs::write(&path, png_with_profile(&image, &swapped_primaries_profile())).unwrap();
+    let url = path.to_string_lossy().to_string();
+    let tile_reference = TileReference { url, position: Vec2d::default(), cell_size: None };
+    let stages = TileStages::new(1, 1);
+    let client = TileClients::from(reqwest::Client::new());
+    let download = |convert_srgb| {
+        let decode_options = DecodeOptions { convert_srgb, ..Default::default() };
+        let context = DownloadContext { decode_options, ..DownloadContext::new(&client, &stages) };
+        let tile_reference = &tile_reference;
+        async move { Tile::download(&context, tile_reference).await }
+    };
+    let unconverted = download(false).await.unwrap();
+    assert_eq!(unconverted.image.get_pixel(1, 1), image::Rgba([255, 0, 0, 255]));
+    let converted = download(true).await.unwrap();
+    let [r, g, b, _] = converted.image.get_pixel(1, 1).0;
+    assert!(r < 3 && g > 252 && b < 3, "the red of the profile is the green of sRGB, got {:?}", (r, g, b));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_decode_concurrency_is_independent_of_fetch_concurrency() {
+    use futures::stream::StreamExt;
+    use image::ImageOutputFormat;
+    use std::time::Duration;
+
+    static DECODING: AtomicUsize = AtomicUsize::new(0);
+    static MAX_DECODING: AtomicUsize = AtomicUsize::new(0);
+    fn slow_decode(_: &TileReference, bytes: Vec<u8>) -> Result<Vec<u8>, Box<dyn std::error::Error + Send>> {
+        let decoding = DECODING.fetch_add(1, Ordering::SeqCst) + 1;
+        MAX_DECODING.fetch_max(decoding, Ordering::SeqCst);
+        std::thread::sleep(Duration::from_millis(20));
+        DECODING.fetch_sub(1, Ordering::SeqCst);
+        Ok(bytes)
+    }
+
+    let mut png_bytes = vec![];
+    DynamicImage::new_rgb8(2, 2).write_to(&mut png_bytes, ImageOutputFormat::Png).unwrap();
+    let dir = tempdir::TempDir::new("dezoomify-rs-stage-tile").unwrap();
+    let path = dir.path().join("tile.png");
+    std::fs::write(&path, png_bytes).unwrap();
+    let url = path.to_string_lossy().to_string();
+    let tile_reference = TileReference { url, position: Vec2d::default(), cell_size: None };
+
+    let stages = TileStages::new(4, 2);
+    let client = TileClients::from(reqwest::Client::new());
+    let context = DownloadContext { post_process_fn: PostProcessFn::Fn(slow_decode), ..DownloadContext::new(&client, &stages) };
+    let results: Vec<_> = futures::stream::iter(0..16)
+        .map(|_| Tile::download(&context, &tile_reference))
+        .buffer_unordered(stages.width())
+        .collect().await;
+    assert!(results.iter().all(Result::is_ok));
+    assert_eq!(MAX_DECODING.load(Ordering::SeqCst), 2);
+    assert_eq!(stages.decode.peak(), 2);
+    assert_eq!(stages.fetch.peak(), 4, "fetching should not wait for the decoding of other tiles");
+}
@@ -0,0 +1,54 @@
+use lazy_static::lazy_static;
+use regex::bytes::Regex;
+
+use crate::Vec2d;
+
+lazy_static! {
+    static ref XMP_PACKET_RE: Regex = Regex::new(r"(?s)<x:xmpmeta.*?</x:xmpmeta>").unwrap();
+    static ref POSITION_RE: Regex = Regex::new(
+        r#"(?:\w+:)?(?P<axis>[XY])Position(?:\s*=\s*["'](?P<attr>[^"']*)["']|\s*>(?P<elem>[^<]*)<)"#
+    ).unwrap();
+}
+
+/// Finds the position of a tile in the XMP packet embedded in its file.
+/// The position is given by the XPosition and YPosition tags, in any namespace,
+/// either as attributes or as elements. They may be written as integers or as rationals.
+pub fn metadata_position(bytes: &[u8]) -> Option<Vec2d> {
+    let packet = XMP_PACKET_RE.find(bytes)?.as_bytes();
+    let (mut x, mut y) = (None, None);
+    for caps in POSITION_RE.captures_iter(packet) {
+        let value = caps.name("attr").or_else(|| caps.name("elem"))
+            .and_then(|value| std::str::from_utf8(value.as_bytes()).ok())
+            .and_then(|value| parse_number(value.trim()));
+        // Closing tags also match, and have no value
+        let value = match value { Some(value) => value, None => continue };
+        match &caps["axis"] {
+            b"X" => x = x.or(Some(value)),
+            _ => y = y.or(Some(value)),
+        }
+    }
+    Some(Vec2d { x: x?, y: y? })
+}
+
+/// Parses "12" or "24/2"
+fn parse_number(s: &str) -> Option<u32> {
+    let mut parts = s.splitn(2, '/');
+    let numerator: u32 = parts.next()?.trim().parse().ok()?;
+    match parts.next() {
+        Some(denominator) => numerator.checked_div(denominator.trim().parse().ok()?),
+        None => Some(numerator),
+    }
+}
+
+#[test]
+fn test_metadata_position() {
+    let attributes = br#"JFIF...<x:xmpmeta xmlns:x="adobe:ns:meta/"><rdf:RDF><rdf:Description
+        tiff:XPosition="256" tiff:YPosition='512/2'/></rdf:RDF></x:xmpmeta>..."#;
+    assert_eq!(metadata_position(attributes), Some(Vec2d { x: 256, y: 256 }));
+    let elements = br#"<x:xmpmeta><rdf:Description>
+        <exif:YPosition>3</exif:YPosition><exif:XPosition> 7 </exif:XPosition>
+        </rdf:Description></x:xmpmeta>"#;
+    assert_eq!(metadata_position(elements), Some(Vec2d { x: 7, y: 3 }));
+    assert_eq!(metadata_position(br#"<x:xmpmeta tiff:XPosition="1"></x:xmpmeta>"#), None);
+    assert_eq!(metadata_position(b"tiff:XPosition=\"1\" tiff:YPosition=\"1\""), None);
+}
@@ -0,0 +1,193 @@
+use std::str::FromStr;
+use std::sync::Mutex;
+
+use log::debug;
+use reqwest::header::{HeaderName, HeaderValue};
+use reqwest::Client;
+use regex::bytes::Regex;
+use serde::Deserialize;
+
+use crate::network::{fetch_uri, fetch_uri_with_header, ResponseInfo};
+use crate::tile::Stage;
+use crate::ZoomError;
+
+/// A short-lived token that authenticates the tile requests.
+/// It is fetched from an url before the first tile is requested, and fetched again
+/// when the server refuses a tile, which happens when the token expired during a long download.
+pub struct TokenSource {
+    url: String,
+    header_name: HeaderName,
+    /// The value of the header, in which `{token}` is replaced by the token
+    header_template: String,
+    /// The header value of the current token, with the number of tokens fetched so far
+    current: Mutex<Option<(u64, HeaderValue)>>,
+    /// Held while a new token is fetched, so that tiles refused at the same time fetch a single one
+    refreshing: tokio::sync::Mutex<()>,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    #[serde(alias = "access_token")]
+    token: String,
+}
+
+impl TokenSource {
+    /// `header` is written as `Name: value`, such as `Authorization: Bearer {token}`
+    pub fn new(url: &str, header: &str) -> Result<Self, ZoomError> {
+        let (header_name, header_template) = parse_header_template(header)?;
+        Ok(TokenSource {
+            url: url.to_string(),
+            header_name,
+            header_template,
+            current: Mutex::new(None),
+            refreshing: tokio::sync::Mutex::new(()),
+        })
+    }
+
+    fn current(&self) -> Option<(u64, HeaderValue)> {
+        self.current.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    /// Fetches a new token, unless another one was fetched since the given one was refused
+    async fn refresh(&self, http: &Client, refused: u64) -> Result<(u64, HeaderValue), ZoomError> {
+        let _refreshing = self.refreshing.lock().await;
+        let generation = match self.current() {
+            Some((generation, value)) if generation != refused => return Ok((generation, value)),
+            current => current.map_or(0, |(generation, _)| generation),
+        };
+        debug!("Fetching a new token from '{}'", self.url);
+        let body = fetch_uri(&self.url, http).await?;
+        let token = parse_token(&String::from_utf8_lossy(&body));
+        let value = HeaderValue::from_str(&self.header_template.replace("{token}", &token))?;
+        let current = (generation + 1, value);
+        *self.current.lock().unwrap_or_else(|e| e.into_inner()) = Some(current.clone());
+        Ok(current)
+    }
+
+    /// Fetches an url with the token. When it is refused, the request is sent once more with a new token.
+    pub async fn fetch(&self, uri: &str, http: &Client, info: &mut ResponseInfo) -> Result<Vec<u8>, ZoomError> {
+        let (generation, value) = match self.current() {
+            Some(current) => current,
+            None => self.refresh(http, 0).await?,
+        };
+        let result = fetch_uri_with_header(uri, http, info, Some((&self.header_name, &value))).await;
+        if !matches!(info.status, Some(401) | Some(403)) { return result; }
+        debug!("The token was refused for '{}'", uri);
+        let (_, value) = self.refresh(http, generation).await?;
+        *info = ResponseInfo::default();
+        fetch_uri_with_header(uri, http, info, Some((&self.header_name, &value))).await
+    }
+}
+
+/// Where the token for the next request is found in the response to a tile
+#[derive(Debug, Clone)]
+pub enum ChainSource {
+    Header(HeaderName),
+    /// The first group of the expression, or the whole match if it has no group
+    Body(Box<Regex>),
+}
+
+impl FromStr for ChainSource {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            Some(("header", name)) => HeaderName::from_bytes(name.trim().as_bytes())
+                .map(ChainSource::Header).map_err(|e| e.to_string()),
+            Some(("body", expression)) => Regex::new(expression)
+                .map(|regex| ChainSource::Body(Box::new(regex))).map_err(|e| e.to_string()),
+            _ => Err("Invalid token source. Expected 'header:NAME' or 'body:REGULAR_EXPRESSION'".into()),
+        }
+    }
+}
+
+/// A token that is valid for a single request, given by the server in its response to each tile
+/// for the request of the next one. The tiles are then requested one after the other.
+pub struct TokenChain {
+    source: ChainSource,
+    header_name: HeaderName,
+    header_template: String,
+    /// The token for the next request, locked during each request so that a token is used only once
+    next: tokio::sync::Mutex<ChainState>,
+}
+
+enum ChainState {
+    /// The first tile is requested without a token
+    Start,
+    Next(HeaderValue),
+    /// The response to this url had no token, and the previous one cannot be used again
+    Broken(String),
+}
+
+impl TokenChain {
+    pub fn new(source: ChainSource, header: &str) -> Result<Self, ZoomError> {
+        let (header_name, header_template) = parse_header_template(header)?;
+        Ok(TokenChain { source, header_name, header_template, next: tokio::sync::Mutex::new(ChainState::Start) })
+    }
+
+    /// Fetches an url with the token of the previous response, and keeps the one of this response.
+    /// When a response has no token, the next requests fail instead of reusing the spent one.
+    pub async fn fetch(&self, stage: &Stage, uri: &str, http: &Client, info: &mut ResponseInfo)
+                       -> Result<Vec<u8>, ZoomError> {
+        // The lock is taken once there is room in the stage, so that it is held only during a request
+        stage.run(async {
+            let mut next = self.next.lock().await;
+            let header = match &*next {
+                ChainState::Start => None,
+                ChainState::Next(value) => Some((&self.header_name, value)),
+                ChainState::Broken(previous) => return Err(ZoomError::BrokenTokenChain { uri: previous.clone() }),
+            };
+            let result = fetch_uri_with_header(uri, http, info, header).await;
+            *next = ChainState::Broken(uri.to_string());
+            match self.token(info, &result) {
+                Some(token) => *next = ChainState::Next(HeaderValue::from_str(&self.header_template.replace("{token}", &token))?),
+                None => debug!("The response for '{}' has no token for the next request", uri),
+            }
+            result
+        }).await
+    }
+
+    fn token(&self, info: &ResponseInfo, result: &Result<Vec<u8>, ZoomError>) -> Option<String> {
+        match &self.source {
+            ChainSource::Header(name) => info.headers.get(name)?.to_str().ok().map(String::from),
+            ChainSource::Body(expression) => {
+                let captures = expression.captures(result.as_ref().ok()?)?;
+                let token = captures.get(1).or_else(|| captures.get(0))?;
+                Some(String::from_utf8_lossy(token.as_bytes()).into_owned())
+            }
+        }
+    }
+}
+
+/// Parses a header written as `Name: value`, in which `{token}` is replaced by the token.
+/// A header without value sends the token alone.
+fn parse_header_template(header: &str) -> Result<(HeaderName, String), ZoomError> {
+    let (name, template) = header.split_once(':').unwrap_or((header, "{token}"));
+    Ok((HeaderName::from_bytes(name.trim().as_bytes())?, template.trim().to_string()))
+}
+
+/// The token is either the whole body of the response, or a field of a json object
+fn parse_token(body: &str) -> String {
+    serde_json::from_str::<TokenResponse>(body)
+        .map(|response| response.token)
+        .unwrap_or_else(|_| body.trim().to_string())
+}
+
+#[test]
+fn test_parse_token() {
+    assert_eq!(parse_token(" abc.def\n"), "abc.def");
+    assert_eq!(parse_token(r#"{"access_token": "abc", "expires_in": 60}"#), "abc");
+    assert_eq!(parse_token(r#"{"token": "abc"}"#), "abc");
+}
+
+#[test]
+fn test_chained_token_sources() {
+    let chain = |source: &str| TokenChain::new(source.parse().unwrap(), "X-Token").unwrap();
+    let mut info = ResponseInfo::default();
+    info.headers.insert("x-next-token", HeaderValue::from_static("abc"));
+    assert_eq!(chain("header:X-Next-Token").token(&info, &Ok(vec![])), Some("abc".into()));
+    let body = Ok(b"\xff\xd8 image bytes next=def;".to_vec());
+    assert_eq!(chain("body:next=(\\w+)").token(&info, &body), Some("def".into()));
+    assert_eq!(chain("body:next=(\\w+)").token(&info, &Err(ZoomError::NoTile)), None);
+    assert!("cookie:a".parse::<ChainSource>().is_err());
+}
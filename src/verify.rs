@@ -0,0 +1,82 @@
+use futures::stream::StreamExt;
+use image::GenericImageView;
+use log::warn;
+use sha2::{Digest, Sha256};
+
+use crate::dezoomer::TileReference;
+use crate::tile::Tile;
+use crate::{TileDownloadError, ZoomError};
+
+/// Whether a tile is downloaded a second time after the download, given the percentage of tiles
+/// that are. The sample is computed from the urls of the tiles, so the same tiles are checked
+/// in every run, without having to remember which ones were chosen.
+pub fn is_sampled(tile_ref: &TileReference, percent: f64) -> bool {
+    let hash = Sha256::digest(tile_ref.url.as_bytes());
+    let value = u64::from_be_bytes([hash[0], hash[1], hash[2], hash[3], hash[4], hash[5], hash[6], hash[7]]);
+    (value as f64) < percent / 100. * (u64::MAX as f64)
+}
+
+/// Tiles are compared by their decoded pixels, so that a server that re-encodes
+/// the same image differently is not reported
+pub fn pixels_hash(tile: &Tile) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(tile.image.width().to_be_bytes());
+    hasher.update(tile.image.height().to_be_bytes());
+    hasher.update(tile.image.as_bytes());
+    hasher.finalize().into()
+}
+
+/// Downloads the sampled tiles again, and checks that their pixels did not change.
+/// Tiles that cannot be downloaded again are not counted as changed.
+pub async fn verify_sample<F, Fut>(sample: Vec<(TileReference, [u8; 32])>, parallelism: usize, download: F) -> Result<(), ZoomError>
+    where F: Fn(TileReference) -> Fut, Fut: std::future::Future<Output=Result<Tile, TileDownloadError>> {
+    let checked = sample.len();
+    let results: Vec<bool> = futures::stream::iter(sample)
+        .map(|(tile_ref, hash)| {
+            let url = tile_ref.url.clone();
+            let downloaded = download(tile_ref);
+            async move {
+                match downloaded.await {
+                    Ok(tile) if pixels_hash(&tile) == hash => false,
+                    Ok(_) => {
+                        warn!("The tile '{}' is different from the one that was downloaded first", url);
+                        true
+                    }
+                    Err(err) => {
+                        warn!("Unable to verify a tile: {}", err);
+                        false
+                    }
+                }
+            }
+        })
+        .buffer_unordered(parallelism)
+        .collect().await;
+    let changed = results.into_iter().filter(|&changed| changed).count();
+    if changed > 0 {
+        Err(ZoomError::TileVerification { changed, checked })
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sample_size() {
+        let tiles: Vec<TileReference> = (0..10_000).map(|i| TileReference {
+            url: format!("http://example.com/{}.jpg", i),
+            position: Default::default(),
+            cell_size: None,
+        }).collect();
+        let sampled = |percent| tiles.iter().filter(|t| is_sampled(t, percent)).count();
+        assert_eq!(sampled(0.), 0);
+        assert_eq!(sampled(100.), tiles.len());
+        let ten_percent = sampled(10.);
+        assert!((900..1100).contains(&ten_percent), "{} tiles sampled", ten_percent);
+        // The same tiles are sampled every time, and a larger sample contains the smaller one
+        assert_eq!(sampled(10.), ten_percent);
+        assert!(tiles.iter().all(|t| !is_sampled(t, 10.) || is_sampled(t, 20.)));
+    }
+}
@@ -0,0 +1,50 @@
+use futures::{SinkExt, StreamExt};
+use log::debug;
+use reqwest::header::HeaderMap;
+use tokio_tungstenite::connect_async;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::Message;
+
+use crate::errors::make_io_err;
+use crate::ZoomError;
+
+/// The headers that are always written by the websocket client, and cannot be given twice
+const HANDSHAKE_HEADERS: &[&str] = &["host", "connection", "upgrade", "sec-websocket-version", "sec-websocket-key"];
+
+/// Fetches a tile from a server that sends tiles over a websocket in response to requests.
+/// The part of the uri after `#` is the request, sent as a text message,
+/// and the first binary message that the server sends back is the tile.
+/// Each tile uses its own connection, so that tiles can be fetched in parallel.
+/// The given headers are sent with the opening handshake, except the ones that the handshake sets itself.
+pub async fn fetch_websocket(uri: &str, headers: &HeaderMap) -> Result<Vec<u8>, ZoomError> {
+    let (endpoint, request) = match uri.find('#') {
+        Some(pos) => (&uri[..pos], Some(&uri[pos + 1..])),
+        None => (uri, None),
+    };
+    debug!("Connecting to websocket '{}' (request: {:?})", endpoint, request);
+    let mut handshake = endpoint.into_client_request().map_err(make_io_err)?;
+    for (name, value) in headers {
+        if !HANDSHAKE_HEADERS.contains(&name.as_str()) {
+            handshake.headers_mut().insert(name, value.clone());
+        }
+    }
+    let (mut socket, _) = connect_async(handshake).await.map_err(make_io_err)?;
+    if let Some(request) = request {
+        socket.send(Message::Text(request.to_string())).await.map_err(make_io_err)?;
+    }
+    while let Some(message) = socket.next().await {
+        match message.map_err(make_io_err)? {
+            Message::Binary(tile) => {
+                debug!("Received {} bytes from websocket '{}'", tile.len(), endpoint);
+                // The tile was received, the server may not bother to close the connection cleanly
+                let _ = socket.close(None).await;
+                return Ok(tile);
+            }
+            Message::Close(_) => break,
+            _ => {}
+        }
+    }
+    Err(ZoomError::Io {
+        source: make_io_err(format!("The websocket '{}' was closed before sending a tile", endpoint)),
+    })
+}
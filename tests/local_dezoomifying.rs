@@ -26,6 +26,1178 @@ pub async fn local_generic_tiles() {
     ).await.unwrap()
 }
 
+#[tokio::test(flavor = "multi_thread")]
+pub async fn generic_tile_grid() {
+    use dezoomify_rs::{dezoomify_download, Vec2d};
+    use dezoomify_rs::dezoomer::TileGrid;
+    let mut args: Arguments = Default::default();
+    args.input_uri = Some("testdata/generic/map_{{X}}_{{Y}}.jpg".into());
+    args.retries = 0;
+    let tmp_file = TmpFile("generic_tile_grid.png");
+    args.outfile = Some(tmp_file.to_path_buf());
+    let download = dezoomify_download(&args).await.expect("Dezooming failed");
+    assert_eq!(download.tile_grid, Some(TileGrid {
+        origin: Vec2d { x: 0, y: 0 },
+        tile_count: Vec2d { x: 2, y: 2 },
+        tile_size: Vec2d { x: 256, y: 256 },
+        image_size: Vec2d { x: 512, y: 512 },
+    }));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+pub async fn skip_existing_output() {
+    let mut args: Arguments = Default::default();
+    args.input_uri = Some("testdata/generic/map_{{X}}_{{Y}}.jpg".into());
+    args.retries = 0;
+    args.skip_newer_than = Some(1);
+    let tmp_file = TmpFile("skip_existing.png");
+    let path = tmp_file.to_path_buf();
+    std::fs::write(&path, b"previous download").unwrap();
+    args.outfile = Some(path.clone());
+    dezoomify(&args).await.expect("Skipping should not fail");
+    assert_eq!(std::fs::read(&path).unwrap(), b"previous download");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+pub async fn forced_dimensions() {
+    use dezoomify_rs::Vec2d;
+    let expected = image::open("testdata/generic/map_expected.png").unwrap().to_rgba8();
+    for &(width, height) in &[(300, 200), (600, 700)] {
+        let mut args: Arguments = Default::default();
+        args.input_uri = Some("testdata/generic/map_{{X}}_{{Y}}.jpg".into());
+        args.retries = 0;
+        args.dimensions = Some(Vec2d { x: width, y: height });
+        let tmp_file = TmpFile("forced_dimensions.tif");
+        args.outfile = Some(tmp_file.to_path_buf());
+        dezoomify(&args).await.expect("Dezooming failed");
+        let actual = image::open(tmp_file.to_path_buf()).unwrap().to_rgba8();
+        assert_eq!(actual.dimensions(), (width, height));
+        // The tiles are at the same place, whatever the size of the image
+        assert_eq!(actual.get_pixel(100, 150), expected.get_pixel(100, 150));
+        if width > expected.width() {
+            assert_eq!(actual.get_pixel(width - 1, height - 1).0, [0, 0, 0, 0], "the padding should be transparent");
+        }
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+pub async fn byte_ranges_from_a_concatenated_blob() {
+    let mut blob = Vec::new();
+    let mut tiles = String::from("tiles:\n");
+    for &(x, y) in &[(0, 0), (0, 1), (1, 0), (1, 1)] {
+        let tile = std::fs::read(format!("testdata/generic/map_{}_{}.jpg", x, y)).unwrap();
+        tiles += &format!(
+            "  - {{ url: \"{{url}}\", offset: {}, length: {}, x: {}, y: {} }}\n",
+            blob.len(), tile.len(), x * 256, y * 256
+        );
+        blob.extend(tile);
+    }
+
+    // Serve the blob, answering only to requests for a range of bytes
+    let url = mock_server(move |request| {
+        let range = request.lines()
+            .find_map(|l| l.strip_prefix("range: bytes="))
+            .expect("the tile should be requested with a range");
+        let mut bounds = range.trim().splitn(2, '-').map(|n| n.parse::<usize>().unwrap());
+        let (first, last) = (bounds.next().unwrap(), bounds.next().unwrap());
+        (206, blob[first..=last].to_vec())
+    }).await + "/blob.bin";
+
+    let dir = tempdir::TempDir::new("dezoomify-rs-byte-ranges").unwrap();
+    let yaml_path = dir.path().join("tiles.yaml");
+    std::fs::write(&yaml_path, tiles.replace("{url}", &url)).unwrap();
+    test_image(yaml_path.to_str().unwrap(), "testdata/generic/map_expected.png").await.unwrap()
+}
+
+#[tokio::test(flavor = "multi_thread")]
+pub async fn iiif_access_token_is_sent_with_tiles() {
+    let tile = std::fs::read("testdata/generic/map_0_0.jpg").unwrap();
+    let base = mock_server(move |request| {
+        let path = request.split_whitespace().nth(1).unwrap_or("").to_string();
+        let host = request.lines().find_map(|l| l.strip_prefix("host: ")).unwrap().trim().to_string();
+        if path == "/info.json" {
+            (200, format!(r#"{{
+                "@id": "http://{host}/image", "width": 256, "height": 256,
+                "tiles": [{{ "width": 256, "scaleFactors": [1] }}],
+                "service": {{
+                    "@id": "http://{host}/login", "profile": "http://iiif.io/api/auth/1/login",
+                    "service": [{{ "@id": "http://{host}/token", "profile": "http://iiif.io/api/auth/1/token" }}]
+                }}
+            }}"#, host = host).into_bytes())
+        } else if path == "/token" {
+            (200, br#"{"accessToken": "s3cr3t", "expiresIn": 3600}"#.to_vec())
+        } else if request.contains("authorization: bearer s3cr3t") {
+            (200, tile.clone())
+        } else {
+            (401, b"missing token".to_vec())
+        }
+    }).await;
+    let mut args: Arguments = Default::default();
+    args.input_uri = Some(base + "/info.json");
+    args.retries = 0;
+    let tmp_file = TmpFile("iiif_access_token.png");
+    args.outfile = Some(tmp_file.to_path_buf());
+    dezoomify(&args).await.expect("The tiles should be downloaded with the access token");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[allow(clippy::field_reassign_with_default)]
+pub async fn tiles_are_requested_with_the_manifest_as_referer() {
+    let tile = std::fs::read("testdata/generic/map_0_0.jpg").unwrap();
+    let base = iiif_mock_server(256, 256, &[1], move |_, request| {
+        let host = request.lines().find_map(|l| l.strip_prefix("host: ")).unwrap().trim();
+        if request.contains(&format!("referer: http://{}/info.json", host)) {
+            (200, tile.clone())
+        } else {
+            (403, b"bad referer".to_vec())
+        }
+    }).await;
+    let mut args: Arguments = Default::default();
+    args.input_uri = Some(base + "/info.json");
+    args.retries = 0;
+    let tmp_file = TmpFile("manifest_referer.png");
+    args.outfile = Some(tmp_file.to_path_buf());
+    dezoomify(&args).await.expect("The tiles should be requested with the manifest as referer");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[allow(clippy::field_reassign_with_default)]
+pub async fn list_levels_without_downloading() {
+    use dezoomify_rs::{list_levels, Vec2d};
+    let mut args: Arguments = Default::default();
+    args.input_uri = Some("testdata/zoomify/test_custom_size/ImageProperties.xml".into());
+    let levels = list_levels(&args).await.unwrap();
+    let mut described: Vec<_> = levels.iter()
+        .map(|l| (l.size.map(|Vec2d { x, y }| (x, y)), l.tile_count))
+        .collect();
+    described.sort();
+    assert_eq!(described, vec![
+        (Some((212, 256)), Some(1)),
+        (Some((425, 513)), Some(6)),
+        (Some((851, 1026)), Some(20)),
+        (Some((1702, 2052)), Some(63)),
+    ]);
+    assert_eq!(levels.iter().map(|l| l.index).collect::<Vec<_>>(), vec![0, 1, 2, 3]);
+    assert!(levels[0].to_string().starts_with(" 0. "), "{}", levels[0]);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[allow(clippy::field_reassign_with_default)]
+pub async fn output_named_after_its_hash() {
+    use sha2::{Digest, Sha256};
+    let out_dir = tempdir::TempDir::new("dezoomify-rs-hash-name").unwrap();
+    let mut args: Arguments = Default::default();
+    args.input_uri = Some("testdata/zoomify/test_custom_size/ImageProperties.xml".into());
+    args.largest = true;
+    args.hash_name = true;
+    args.outfile = Some(out_dir.path().join("image.png"));
+    let saved_as = dezoomify(&args).await.expect("Dezooming failed");
+    let hash = Sha256::digest(&std::fs::read(&saved_as).unwrap());
+    let expected_name: String = hash.iter().map(|b| format!("{:02x}", b)).collect::<String>() + ".png";
+    assert_eq!(saved_as.file_name().unwrap().to_string_lossy(), expected_name);
+    assert!(!out_dir.path().join("image.png").exists());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[allow(clippy::field_reassign_with_default)]
+pub async fn tiles_positioned_by_their_xmp_metadata() {
+    use image::{ImageBuffer, ImageOutputFormat, Rgb};
+    let dir = tempdir::TempDir::new("dezoomify-rs-xmp").unwrap();
+    // Both tiles have the same position in the yaml file, but not in their metadata
+    let tiles = [(0, [255, 0, 0], 0), (1, [0, 0, 255], 8)];
+    for &(name, color, x) in &tiles {
+        let mut jpeg = vec![];
+        DynamicImage::ImageRgb8(ImageBuffer::from_pixel(8, 8, Rgb(color)))
+            .write_to(&mut jpeg, ImageOutputFormat::Jpeg(100)).unwrap();
+        let xmp = format!(
+            "http://ns.adobe.com/xap/1.0/\0<x:xmpmeta xmlns:x=\"adobe:ns:meta/\"><rdf:RDF><rdf:Description \
+            tiff:XPosition=\"{}\" tiff:YPosition=\"0\"/></rdf:RDF></x:xmpmeta>", x);
+        // An APP1 segment, right after the start of image marker
+        let mut segment = vec![0xFF, 0xE1];
+        segment.extend_from_slice(&(xmp.len() as u16 + 2).to_be_bytes());
+        segment.extend_from_slice(xmp.as_bytes());
+        jpeg.splice(2..2, segment);
+        std::fs::write(dir.path().join(format!("tile_{}.jpg", name)), jpeg).unwrap();
+    }
+    let yaml = dir.path().join("tiles.yaml");
+    let url_template = dir.path().join("tile_{{i}}.jpg");
+    std::fs::write(&yaml, format!(r#"
+url_template: '{}'
+variables:
+  - {{ name: i, from: 0, to: 1 }}
+x_template: "0"
+y_template: "0"
+position_from: exif
+"#, url_template.to_string_lossy())).unwrap();
+    let mut args: Arguments = Default::default();
+    args.input_uri = Some(yaml.to_string_lossy().to_string());
+    args.retries = 0;
+    args.outfile = Some(dir.path().join("result.png"));
+    let saved_as = dezoomify(&args).await.expect("Dezooming failed");
+    let result = image::open(saved_as).unwrap().to_rgb8();
+    assert_eq!(result.dimensions(), (16, 8));
+    let is_close = |actual: &Rgb<u8>, expected: [u8; 3]| {
+        actual.0.iter().zip(expected.iter()).all(|(&a, &b)| (a as i32 - b as i32).abs() < 16)
+    };
+    assert!(is_close(result.get_pixel(4, 4), [255, 0, 0]), "{:?}", result.get_pixel(4, 4));
+    assert!(is_close(result.get_pixel(12, 4), [0, 0, 255]), "{:?}", result.get_pixel(12, 4));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[allow(clippy::field_reassign_with_default)]
+pub async fn preview_downloads_the_smallest_level() {
+    let tile = std::fs::read("testdata/generic/map_0_0.jpg").unwrap();
+    // A 1024x1024 image with three levels, made of 256x256 tiles
+    let base = iiif_mock_server(1024, 1024, &[1, 2, 4], move |_, _| (200, tile.clone())).await;
+    let mut args: Arguments = Default::default();
+    args.input_uri = Some(base + "/info.json");
+    args.retries = 0;
+    args.preview = true;
+    let tmp_file = TmpFile("zoomify_preview.png");
+    args.outfile = Some(tmp_file.to_path_buf());
+    let saved_as = dezoomify(&args).await.expect("Dezooming failed");
+    assert_eq!(image::open(saved_as).unwrap().dimensions(), (256, 256));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[allow(clippy::field_reassign_with_default)]
+pub async fn range_of_levels() {
+    let tile = std::fs::read("testdata/generic/map_0_0.jpg").unwrap();
+    // A 1024x1024 image with four levels, made of 256x256 tiles
+    let base = iiif_mock_server(1024, 1024, &[1, 2, 4, 8], move |_, _| (200, tile.clone())).await;
+    let dir = tempdir::TempDir::new("dezoomify-rs-levels").unwrap();
+    let mut args: Arguments = Default::default();
+    args.input_uri = Some(base + "/info.json");
+    args.retries = 0;
+    args.outfile = Some(dir.path().join("level_{level}.png"));
+    let levels = dezoomify_rs::list_levels(&args).await.unwrap();
+    assert_eq!(levels.len(), 4);
+    args.levels = Some(1..=2);
+    let downloads = dezoomify_rs::dezoomify_images(&args).await.expect("Dezooming failed");
+    assert_eq!(downloads.len(), 2);
+    for (download, level) in downloads.iter().zip(&levels[1..=2]) {
+        assert_eq!(download.saved_as, dir.path().join(format!("level_{}.png", level.index)));
+        let size = level.size.unwrap();
+        assert_eq!(image::open(&download.saved_as).unwrap().dimensions(), (size.x, size.y));
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[allow(clippy::field_reassign_with_default)]
+pub async fn prefer_complete_skips_a_level_with_missing_tiles() {
+    let tile = std::fs::read("testdata/generic/map_0_0.jpg").unwrap();
+    // A 512x512 image with a level of 2x2 tiles, whose last tile is missing, and a level of a single tile
+    let base = iiif_mock_server(512, 512, &[1, 2], move |path, _| {
+        if path == "/image/256,256,256,256/256,256/0/default.jpg" { (404, vec![]) } else { (200, tile.clone()) }
+    }).await;
+    let dir = tempdir::TempDir::new("dezoomify-rs-complete").unwrap();
+    let mut args: Arguments = Default::default();
+    args.input_uri = Some(base + "/info.json");
+    args.retries = 0;
+    args.prefer_complete = true;
+    args.outfile = Some(dir.path().join("complete.png"));
+    let saved_as = dezoomify(&args).await.expect("Dezooming failed");
+    assert_eq!(image::open(saved_as).unwrap().dimensions(), (256, 256));
+}
+
+#[cfg(feature = "script")]
+#[tokio::test(flavor = "multi_thread")]
+#[allow(clippy::field_reassign_with_default)]
+pub async fn prefer_complete_downloads_the_selected_script_level() {
+    let tile = std::fs::read("testdata/generic/map_0_0.jpg").unwrap();
+    // A level of 2x2 tiles whose last tile is missing, and a level of a single tile
+    let base = mock_server(move |request| {
+        let path = request.split_whitespace().nth(1).unwrap_or("");
+        if path == "/0/1_1.jpg" { (404, vec![]) } else { (200, tile.clone()) }
+    }).await;
+    let dir = tempdir::TempDir::new("dezoomify-rs-complete-script").unwrap();
+    let script = dir.path().join("levels.rhai");
+    std::fs::write(&script, r#"
+        fn levels() { 2 }
+        fn grid(level) { if level == 0 { [2, 2] } else { [1, 1] } }
+        fn tile_url(x, y, level) { input() + "/" + level + "/" + x + "_" + y + ".jpg" }
+        fn tile_position(x, y) { [x * 256, y * 256] }
+    "#).unwrap();
+    let mut args: Arguments = Default::default();
+    args.input_uri = Some(base);
+    args.script = Some(script);
+    args.retries = 0;
+    args.prefer_complete = true;
+    args.outfile = Some(dir.path().join("complete.png"));
+    let saved_as = dezoomify(&args).await.expect("the complete level should be downloaded");
+    assert_eq!(image::open(saved_as).unwrap().dimensions(), (256, 256));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[allow(clippy::field_reassign_with_default)]
+pub async fn fractional_crop_downloads_only_the_overlapping_tiles() {
+    use std::sync::{Arc, Mutex};
+    let tile = std::fs::read("testdata/generic/map_0_0.jpg").unwrap();
+    let requested = Arc::new(Mutex::new(vec![]));
+    let requested_by_server = Arc::clone(&requested);
+    // A 1024x1024 image made of 4x4 tiles of 256x256
+    let base = iiif_mock_server(1024, 1024, &[1], move |path, _| {
+        requested_by_server.lock().unwrap().push(path.to_string());
+        (200, tile.clone())
+    }).await;
+    let dir = tempdir::TempDir::new("dezoomify-rs-crop").unwrap();
+    let mut args: Arguments = Default::default();
+    args.input_uri = Some(base + "/info.json");
+    args.retries = 0;
+    args.outfile = Some(dir.path().join("quarter.png"));
+    args.crop_fraction = <Arguments as structopt::StructOpt>::from_iter_safe(&["dezoomify-rs", "--crop-fraction", "0,0,0.5,0.5"])
+        .unwrap().crop_fraction;
+    let saved_as = dezoomify(&args).await.expect("Dezooming failed");
+    let mut requested = requested.lock().unwrap().clone();
+    requested.sort();
+    assert_eq!(requested, vec![
+        "/image/0,0,256,256/256,256/0/default.jpg",
+        "/image/0,256,256,256/256,256/0/default.jpg",
+        "/image/256,0,256,256/256,256/0/default.jpg",
+        "/image/256,256,256,256/256,256/0/default.jpg",
+    ]);
+    assert_eq!(image::open(saved_as).unwrap().dimensions(), (512, 512));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[allow(clippy::field_reassign_with_default)]
+pub async fn provenance_of_each_tile_is_recorded() {
+    let tile = std::fs::read("testdata/generic/map_0_0.jpg").unwrap();
+    let tile_len = tile.len();
+    // A 512x512 image with four tiles, one of which is missing
+    let base = raw_iiif_mock_server(512, 512, &[1], move |path, _| {
+        let (status, body) = if path.starts_with("/image/256,256,") {
+            ("404 Not Found", vec![])
+        } else {
+            ("200 OK", tile.clone())
+        };
+        let mut response = format!(
+            "HTTP/1.1 {}\r\nETag: \"{}\"\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            status, path.len(), body.len()
+        ).into_bytes();
+        response.extend(body);
+        response
+    }).await;
+    let dir = tempdir::TempDir::new("dezoomify-rs-provenance").unwrap();
+    let mut args: Arguments = Default::default();
+    args.input_uri = Some(base.clone() + "/info.json");
+    args.retries = 0;
+    args.outfile = Some(dir.path().join("provenance.png"));
+    let provenance_path = dir.path().join("provenance.jsonl");
+    args.provenance = Some(provenance_path.clone());
+    match dezoomify(&args).await {
+        Err(ZoomError::PartialDownload { successful_tiles: 3, total_tiles: 4 }) => {}
+        other => panic!("The missing tile should make the download partial, got {:?}", other),
+    }
+    let records: Vec<serde_json::Value> = std::fs::read_to_string(&provenance_path).unwrap()
+        .lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+    assert_eq!(records.len(), 4, "{:?}", records);
+    for record in &records {
+        let url = record["url"].as_str().unwrap();
+        let path = url.strip_prefix(&base).unwrap();
+        assert_eq!(record["etag"], format!("\"{}\"", path.len()));
+        assert!(record["timestamp"].as_str().unwrap().ends_with('Z'), "{}", record);
+        if path.starts_with("/image/256,256,") {
+            assert_eq!(record["status"], 404);
+            assert!(record["bytes"].is_null());
+            assert!(record["error"].is_string());
+        } else {
+            assert_eq!(record["status"], 200);
+            assert_eq!(record["bytes"], tile_len);
+            assert!(record["error"].is_null());
+        }
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[allow(clippy::field_reassign_with_default)]
+pub async fn all_the_images_of_a_gallery_are_downloaded() {
+    let tile = std::fs::read("testdata/generic/map_0_0.jpg").unwrap();
+    let base = mock_server(move |request| {
+        let path = request.split_whitespace().nth(1).unwrap_or("").to_string();
+        let host = request.lines().find_map(|l| l.strip_prefix("host: ")).unwrap().trim().to_string();
+        if path == "/gallery.html" {
+            (200, br#"<a href="/about.html">About</a>
+                <a href="first/info.json"><img src="first.jpg"></a>
+                <a href="/missing/info.json">Removed image</a>
+                <a href="/second/info.json"><img src="second.jpg"></a>"#.to_vec())
+        } else if path == "/first/info.json" || path == "/second/info.json" {
+            let id = path.trim_end_matches("/info.json");
+            (200, format!(r#"{{
+                "@id": "http://{host}{id}", "width": 256, "height": 256,
+                "tiles": [{{ "width": 256, "scaleFactors": [1] }}]
+            }}"#, host = host, id = id).into_bytes())
+        } else if path.ends_with("/default.jpg") {
+            (200, tile.clone())
+        } else {
+            (404, b"not found".to_vec())
+        }
+    }).await;
+    let dir = tempdir::TempDir::new("dezoomify-rs-gallery").unwrap();
+    let mut args: Arguments = Default::default();
+    args.input_uri = Some(base + "/gallery.html");
+    args.gallery = true;
+    args.retries = 0;
+    args.outfile = Some(dir.path().join("image_{index}.png"));
+    let downloads = dezoomify_rs::dezoomify_images(&args).await.expect("the images of the gallery should be downloaded");
+    let saved: Vec<PathBuf> = downloads.into_iter().map(|d| d.saved_as).collect();
+    assert_eq!(saved, vec![dir.path().join("image_1.png"), dir.path().join("image_2.png")]);
+    assert!(saved.iter().all(|path| image::open(path).unwrap().dimensions() == (256, 256)));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[allow(clippy::field_reassign_with_default)]
+pub async fn gallery_download_continues_after_a_failed_image() {
+    let tile = std::fs::read("testdata/generic/map_0_0.jpg").unwrap();
+    let base = mock_server(move |request| {
+        let path = request.split_whitespace().nth(1).unwrap_or("").to_string();
+        let host = request.lines().find_map(|l| l.strip_prefix("host: ")).unwrap().trim().to_string();
+        if path == "/gallery.html" {
+            (200, br#"<a href="/first/info.json">First</a>
+                <a href="/broken/info.json">Its tiles are missing</a>
+                <a href="/second/info.json">Second</a>"#.to_vec())
+        } else if path.ends_with("/info.json") {
+            let id = path.trim_end_matches("/info.json");
+            (200, format!(r#"{{
+                "@id": "http://{host}{id}", "width": 256, "height": 256,
+                "tiles": [{{ "width": 256, "scaleFactors": [1] }}]
+            }}"#, host = host, id = id).into_bytes())
+        } else if path.ends_with("/default.jpg") && !path.starts_with("/broken/") {
+            (200, tile.clone())
+        } else {
+            (404, b"not found".to_vec())
+        }
+    }).await;
+    let dir = tempdir::TempDir::new("dezoomify-rs-gallery-errors").unwrap();
+    let mut args: Arguments = Default::default();
+    args.input_uri = Some(base + "/gallery.html");
+    args.gallery = true;
+    args.retries = 0;
+    args.outfile = Some(dir.path().join("image_{index}.png"));
+    match dezoomify_rs::dezoomify_images(&args).await {
+        Err(ZoomError::IncompleteGallery { failed: 1, total: 3 }) => {}
+        other => panic!("One image of the gallery should have failed, got {:?}", other.map(|d| d.len())),
+    }
+    assert_eq!(image::open(dir.path().join("image_1.png")).unwrap().dimensions(), (256, 256));
+    assert_eq!(image::open(dir.path().join("image_3.png")).unwrap().dimensions(), (256, 256));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[allow(clippy::field_reassign_with_default)]
+pub async fn each_tile_is_requested_with_the_token_of_the_previous_one() {
+    use std::sync::{Arc, Mutex};
+    let tile = std::fs::read("testdata/generic/map_0_0.jpg").unwrap();
+    // The token given with the last tile, which is the only one accepted for the next tile
+    let issued: Arc<Mutex<Option<u32>>> = Arc::new(Mutex::new(None));
+    let refused = Arc::new(Mutex::new(0));
+    let refused_ref = Arc::clone(&refused);
+    let base = raw_iiif_mock_server(512, 512, &[1], move |_, request| {
+        let mut issued = issued.lock().unwrap();
+        let sent = request.lines().find_map(|l| l.strip_prefix("x-tile-token: t")).map(|t| t.trim().parse().unwrap());
+        let (status, token, body) = if sent == *issued {
+            let next = issued.map_or(0, |t| t + 1);
+            *issued = Some(next);
+            ("200 OK", Some(next), tile.clone())
+        } else {
+            *refused_ref.lock().unwrap() += 1;
+            ("403 Forbidden", None, b"wrong token".to_vec())
+        };
+        let token = token.map(|t| format!("X-Next-Token: t{}\r\n", t)).unwrap_or_default();
+        let mut response = format!("HTTP/1.1 {}\r\n{}Content-Length: {}\r\nConnection: close\r\n\r\n",
+                                   status, token, body.len()).into_bytes();
+        response.extend(body);
+        response
+    }).await;
+    let dir = tempdir::TempDir::new("dezoomify-rs-chain-token").unwrap();
+    let mut args: Arguments = Default::default();
+    args.input_uri = Some(base + "/info.json");
+    args.retries = 0;
+    args.outfile = Some(dir.path().join("chained.png"));
+    args.chain_token = Some("header:X-Next-Token".parse().unwrap());
+    args.token_header = "X-Tile-Token: {token}".into();
+    dezoomify(&args).await.expect("each tile should be requested with the token of the previous one");
+    assert_eq!(*refused.lock().unwrap(), 0);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[allow(clippy::field_reassign_with_default)]
+pub async fn spent_chained_tokens_are_not_sent_again() {
+    use std::sync::{Arc, Mutex};
+    let tile = std::fs::read("testdata/generic/map_0_0.jpg").unwrap();
+    // The tokens sent by the client
+    let sent_tokens: Arc<Mutex<Vec<Option<String>>>> = Arc::new(Mutex::new(vec![]));
+    let sent_ref = Arc::clone(&sent_tokens);
+    let base = raw_iiif_mock_server(512, 512, &[1], move |_, request| {
+        let mut sent = sent_ref.lock().unwrap();
+        sent.push(request.lines().find_map(|l| l.strip_prefix("x-tile-token: ")).map(|t| t.trim().to_string()));
+        // The second tile fails, without a token for the next one
+        let (status, token, body) = if sent.len() == 1 {
+            ("200 OK", "X-Next-Token: t0\r\n", tile.clone())
+        } else {
+            ("500 Internal Server Error", "", b"unavailable".to_vec())
+        };
+        let mut response = format!("HTTP/1.1 {}\r\n{}Content-Length: {}\r\nConnection: close\r\n\r\n",
+                                   status, token, body.len()).into_bytes();
+        response.extend(body);
+        response
+    }).await;
+    let dir = tempdir::TempDir::new("dezoomify-rs-spent-token").unwrap();
+    let mut args: Arguments = Default::default();
+    args.input_uri = Some(base + "/info.json");
+    args.retries = 2;
+    args.retry_delay = std::time::Duration::from_millis(1);
+    args.outfile = Some(dir.path().join("chained.png"));
+    args.chain_token = Some("header:X-Next-Token".parse().unwrap());
+    args.token_header = "X-Tile-Token: {token}".into();
+    match dezoomify(&args).await {
+        Err(ZoomError::PartialDownload { successful_tiles: 1, total_tiles: 4 }) => {}
+        other => panic!("Only the first tile should be downloaded, got {:?}", other),
+    }
+    assert_eq!(*sent_tokens.lock().unwrap(), vec![None, Some("t0".to_string())]);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[allow(clippy::field_reassign_with_default)]
+pub async fn a_saved_grid_is_downloaded_without_probing() {
+    use std::sync::{Arc, Mutex};
+    let requested = Arc::new(Mutex::new(vec![]));
+    let requested_ref = Arc::clone(&requested);
+    let base = mock_server(move |request| {
+        let path = request.split_whitespace().nth(1).unwrap_or("").to_string();
+        requested_ref.lock().unwrap().push(path.clone());
+        match std::fs::read(format!("testdata/generic{}", path)) {
+            Ok(tile) if path.starts_with("/map_") => (200, tile),
+            _ => (404, b"not found".to_vec()),
+        }
+    }).await;
+    let dir = tempdir::TempDir::new("dezoomify-rs-saved-grid").unwrap();
+    let grid = dir.path().join("grid.json");
+    let mut args: Arguments = Default::default();
+    args.input_uri = Some(base + "/map_{{X}}_{{Y}}.jpg");
+    args.retries = 0;
+    args.outfile = Some(dir.path().join("probed.png"));
+    args.save_grid = Some(grid.clone());
+    dezoomify(&args).await.expect("the size of the image should be found by probing its tiles");
+    assert!(requested.lock().unwrap().len() > 4, "the tiles around the image are probed");
+
+    requested.lock().unwrap().clear();
+    args.save_grid = None;
+    args.load_grid = Some(grid);
+    args.outfile = Some(dir.path().join("loaded.png"));
+    dezoomify(&args).await.expect("the image should be downloaded with the saved grid");
+    // The other dezoomers request the template itself before it is recognized as one
+    let mut requested: Vec<String> = requested.lock().unwrap().iter().filter(|p| !p.contains("%7b")).cloned().collect();
+    requested.sort();
+    assert_eq!(requested, vec!["/map_0_0.jpg", "/map_0_1.jpg", "/map_1_0.jpg", "/map_1_1.jpg"]);
+    let probed = image::open(dir.path().join("probed.png")).unwrap();
+    let loaded = image::open(dir.path().join("loaded.png")).unwrap();
+    assert_eq!(probed.dimensions(), loaded.dimensions());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[allow(clippy::field_reassign_with_default)]
+pub async fn tiles_in_the_local_root_are_not_downloaded() {
+    use std::sync::{Arc, Mutex};
+    let tile = std::fs::read("testdata/generic/map_0_0.jpg").unwrap();
+    let requested = Arc::new(Mutex::new(vec![]));
+    let requested_ref = Arc::clone(&requested);
+    let base = iiif_mock_server(512, 512, &[1], move |path, _| {
+        requested_ref.lock().unwrap().push(path.to_string());
+        (200, tile.clone())
+    }).await;
+    let dir = tempdir::TempDir::new("dezoomify-rs-local-root").unwrap();
+    let local_tiles = ["/image/0,0,256,256/256,256/0/default.jpg", "/image/256,256,256,256/256,256/0/default.jpg"];
+    let host = base.trim_start_matches("http://").replace(':', "+");
+    for path in &local_tiles {
+        let file = dir.path().join("mirror").join(&host).join(&path[1..]);
+        std::fs::create_dir_all(file.parent().unwrap()).unwrap();
+        std::fs::copy("testdata/generic/map_0_0.jpg", file).unwrap();
+    }
+    let mut args: Arguments = Default::default();
+    args.input_uri = Some(base + "/info.json");
+    args.retries = 0;
+    args.outfile = Some(dir.path().join("local_root.png"));
+    args.local_root = Some(dir.path().join("mirror"));
+    dezoomify(&args).await.expect("the tiles should be read from the disk or downloaded");
+    let mut requested = requested.lock().unwrap().clone();
+    requested.sort();
+    assert_eq!(requested, vec![
+        "/image/0,256,256,256/256,256/0/default.jpg",
+        "/image/256,0,256,256/256,256/0/default.jpg",
+    ]);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[allow(clippy::field_reassign_with_default)]
+pub async fn too_large_image_is_stopped_before_its_tiles_are_downloaded() {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    let tile = std::fs::read("testdata/generic/map_0_0.jpg").unwrap();
+    let tile_requests = Arc::new(AtomicUsize::new(0));
+    let tile_requests_ref = Arc::clone(&tile_requests);
+    let base = iiif_mock_server(512, 512, &[1], move |_, _| {
+        tile_requests_ref.fetch_add(1, Ordering::SeqCst);
+        (200, tile.clone())
+    }).await;
+    let dir = tempdir::TempDir::new("dezoomify-rs-max-pixels").unwrap();
+    let mut args: Arguments = Default::default();
+    args.input_uri = Some(base + "/info.json");
+    args.outfile = Some(dir.path().join("too_large.png"));
+    args.max_output_pixels = Some(512 * 512 - 1);
+    match dezoomify(&args).await {
+        Err(ZoomError::OutputTooLarge { width: 512, height: 512, max_pixels }) => assert_eq!(max_pixels, 512 * 512 - 1),
+        other => panic!("The image should be too large, got {:?}", other),
+    }
+    assert_eq!(tile_requests.load(Ordering::SeqCst), 0);
+    assert!(!dir.path().join("too_large.png").exists());
+
+    args.max_output_pixels = Some(512 * 512);
+    dezoomify(&args).await.expect("the image has the maximal size");
+    assert_eq!(tile_requests.load(Ordering::SeqCst), 4);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[allow(clippy::field_reassign_with_default)]
+pub async fn too_large_generic_image_leaves_no_output_file() {
+    use dezoomify_rs::Vec2d;
+    let dir = tempdir::TempDir::new("dezoomify-rs-max-pixels-generic").unwrap();
+    let mut args: Arguments = Default::default();
+    args.input_uri = Some("testdata/generic/map_{{X}}_{{Y}}.jpg".into());
+    args.retries = 0;
+    args.outfile = Some(dir.path().join("too_large.png"));
+    args.max_output_pixels = Some(300 * 300);
+    match dezoomify(&args).await {
+        Err(ZoomError::OutputTooLarge { .. }) => {}
+        other => panic!("The image should be too large, got {:?}", other),
+    }
+    assert!(!dir.path().join("too_large.png").exists());
+
+    // The forced dimensions are the size of the output
+    args.dimensions = Some(Vec2d { x: 300, y: 301 });
+    match dezoomify(&args).await {
+        Err(ZoomError::OutputTooLarge { width: 300, height: 301, .. }) => {}
+        other => panic!("The forced dimensions should be too large, got {:?}", other),
+    }
+    assert!(!dir.path().join("too_large.png").exists());
+    args.dimensions = Some(Vec2d { x: 300, y: 300 });
+    dezoomify(&args).await.expect("the tiles are cropped to the forced dimensions");
+    let output = image::open(dir.path().join("too_large.png")).unwrap();
+    assert_eq!(output.dimensions(), (300, 300));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[allow(clippy::field_reassign_with_default)]
+pub async fn expired_token_is_refreshed() {
+    use std::sync::{Arc, Mutex};
+    let tile = std::fs::read("testdata/generic/map_0_0.jpg").unwrap();
+    // The number of tokens issued, and the number of tiles served with the last one.
+    // Each token expires after two tiles.
+    let tokens = Arc::new(Mutex::new((0, 0)));
+    let tokens_ref = Arc::clone(&tokens);
+    let base = iiif_mock_server(512, 512, &[1], move |path, request| {
+        let authorization = request.lines().find_map(|l| l.strip_prefix("authorization: ")).map(str::trim);
+        let mut tokens = tokens_ref.lock().unwrap();
+        if path == "/token" {
+            *tokens = (tokens.0 + 1, 0);
+            (200, format!(r#"{{"access_token": "token-{}"}}"#, tokens.0).into_bytes())
+        } else if authorization == Some(&format!("bearer token-{}", tokens.0)) && tokens.1 < 2 {
+            tokens.1 += 1;
+            (200, tile.clone())
+        } else {
+            (401, vec![])
+        }
+    }).await;
+    let dir = tempdir::TempDir::new("dezoomify-rs-token").unwrap();
+    let mut args: Arguments = Default::default();
+    args.input_uri = Some(base.clone() + "/info.json");
+    args.outfile = Some(dir.path().join("token.png"));
+    args.token_url = Some(base + "/token");
+    args.retries = 0;
+    args.parallelism = 1;
+    dezoomify(&args).await.expect("the tiles should be downloaded with a new token");
+    assert_eq!(tokens.lock().unwrap().0, 2, "the first token expired after two tiles");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[allow(clippy::field_reassign_with_default)]
+pub async fn retry_budget_is_shared_by_all_tiles() {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+    let tile_requests = Arc::new(AtomicUsize::new(0));
+    let counter = Arc::clone(&tile_requests);
+    // A 512x512 image with four tiles, that all fail
+    let base = iiif_mock_server(512, 512, &[1], move |_, _| {
+        counter.fetch_add(1, Ordering::SeqCst);
+        (500, b"broken".to_vec())
+    }).await;
+    let mut args: Arguments = Default::default();
+    args.input_uri = Some(base + "/info.json");
+    args.retries = 3;
+    args.retry_delay = Duration::from_millis(1);
+    args.retry_budget = Some(2);
+    let tmp_file = TmpFile("retry_budget.png");
+    args.outfile = Some(tmp_file.to_path_buf());
+    assert!(dezoomify(&args).await.is_err());
+    // One request per tile, plus the two retries of the budget,
+    // instead of the twelve retries that three per tile would allow
+    assert_eq!(tile_requests.load(Ordering::SeqCst), 4 + 2);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[allow(clippy::field_reassign_with_default)]
+pub async fn missing_tiles_are_retried() {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+    let tile_requests = Arc::new(AtomicUsize::new(0));
+    let counter = Arc::clone(&tile_requests);
+    let base = iiif_mock_server(256, 256, &[1], move |_, _| {
+        counter.fetch_add(1, Ordering::SeqCst);
+        (404, b"not found".to_vec())
+    }).await;
+    let mut args: Arguments = Default::default();
+    args.input_uri = Some(base + "/info.json");
+    args.retries = 3;
+    args.retry_delay = Duration::from_millis(1);
+    let tmp_file = TmpFile("missing_tiles.png");
+    args.outfile = Some(tmp_file.to_path_buf());
+    assert!(dezoomify(&args).await.is_err());
+    assert_eq!(tile_requests.load(Ordering::SeqCst), 1 + 3);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[allow(clippy::field_reassign_with_default)]
+pub async fn truncated_tiles_are_retried() {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+    let tile = std::fs::read("testdata/generic/map_0_0.jpg").unwrap();
+    let tile_requests = Arc::new(AtomicUsize::new(0));
+    let counter = Arc::clone(&tile_requests);
+    let base = raw_iiif_mock_server(256, 256, &[1], move |_, _| {
+        // The connection of the first tile request is dropped in the middle of the body
+        let sent = if counter.fetch_add(1, Ordering::SeqCst) == 0 { tile.len() / 2 } else { tile.len() };
+        let mut response = format!(
+            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", tile.len()
+        ).into_bytes();
+        response.extend_from_slice(&tile[..sent]);
+        response
+    }).await;
+    let mut args: Arguments = Default::default();
+    args.input_uri = Some(base + "/info.json");
+    args.retries = 1;
+    args.retry_delay = Duration::from_millis(1);
+    let tmp_file = TmpFile("truncated_tiles.png");
+    args.outfile = Some(tmp_file.to_path_buf());
+    dezoomify(&args).await.unwrap();
+    assert_eq!(tile_requests.load(Ordering::SeqCst), 2, "the truncated tile should be downloaded again");
+    let expected = image::open("testdata/generic/map_0_0.jpg").unwrap().to_rgb8();
+    let actual = image::open(tmp_file.to_path_buf()).unwrap().to_rgb8();
+    assert_eq!(actual.dimensions(), expected.dimensions());
+    assert_eq!(actual.into_raw(), expected.into_raw());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[allow(clippy::field_reassign_with_default)]
+pub async fn several_images_from_one_yaml() {
+    use dezoomify_rs::dezoomify_images;
+    let dir = tempdir::TempDir::new("dezoomify-rs-images").unwrap();
+    let testdata = std::fs::canonicalize("testdata/generic").unwrap();
+    let yaml = dir.path().join("tiles.yaml");
+    // Two one-tile images, sharing a variable
+    std::fs::write(&yaml, format!(r#"
+variables:
+  - {{ name: x, value: 0 }}
+x_template: "x"
+y_template: "0"
+images:
+  - name: left
+    url_template: '{dir}/map_{{{{x}}}}_0.jpg'
+  - name: right
+    url_template: '{dir}/map_{{{{x + 1}}}}_0.jpg'
+"#, dir = testdata.to_string_lossy())).unwrap();
+    let mut args: Arguments = Default::default();
+    args.input_uri = Some(yaml.to_string_lossy().to_string());
+    args.retries = 0;
+    args.outfile = Some(dir.path().join("page_{name}.png"));
+    let downloads = dezoomify_images(&args).await.expect("Dezooming failed");
+    let saved: Vec<PathBuf> = downloads.into_iter().map(|d| d.saved_as).collect();
+    assert_eq!(saved, vec![dir.path().join("page_left.png"), dir.path().join("page_right.png")]);
+    for (path, tile) in saved.iter().zip(&["map_0_0.jpg", "map_1_0.jpg"]) {
+        let expected = image::open(testdata.join(tile)).unwrap().to_rgb8();
+        let actual = image::open(path).unwrap().to_rgb8();
+        assert_eq!(actual.dimensions(), expected.dimensions());
+        assert!(actual == expected, "{:?} should contain the pixels of {}", path, tile);
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[allow(clippy::field_reassign_with_default)]
+pub async fn zip_tiles_stored_as_downloaded_or_converted() {
+    use std::io::Read;
+    let formats = [("source", "jpg", image::ImageFormat::Jpeg), ("png", "png", image::ImageFormat::Png),
+        ("jpg", "jpg", image::ImageFormat::Jpeg)];
+    for (tiles_format, extension, expected) in &formats {
+        let mut args: Arguments = Default::default();
+        args.input_uri = Some("testdata/generic/map_{{X}}_{{Y}}.jpg".into());
+        args.retries = 0;
+        args.output_tiles_format = tiles_format.parse().unwrap();
+        let name = format!("{}_tiles.zip", tiles_format);
+        let tmp_file = TmpFile(&name);
+        args.outfile = Some(tmp_file.to_path_buf());
+        dezoomify(&args).await.expect("Dezooming failed");
+        let mut archive = zip::ZipArchive::new(std::fs::File::open(tmp_file.to_path_buf()).unwrap()).unwrap();
+        for (x, y) in &[(0, 0), (1, 0), (0, 1), (1, 1)] {
+            let mut tile = vec![];
+            let file = format!("tiles/{}_{}.{}", x * 256, y * 256, extension);
+            archive.by_name(&file).unwrap().read_to_end(&mut tile).unwrap();
+            let downloaded = std::fs::read(format!("testdata/generic/map_{}_{}.jpg", x, y)).unwrap();
+            // By default, the archive contains the downloaded files themselves
+            assert_eq!(tile == downloaded, *tiles_format == "source", "{} in {}", file, name);
+            assert_eq!(image::guess_format(&tile).unwrap(), *expected);
+            let decoded = image::load_from_memory_with_format(&tile, *expected).unwrap();
+            assert_eq!(decoded.dimensions(), (256, 256));
+        }
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[allow(clippy::field_reassign_with_default)]
+pub async fn incremental_tiff_matches_assembled_image() {
+    let mut images = vec![];
+    for &incremental in &[false, true] {
+        let mut args: Arguments = Default::default();
+        args.input_uri = Some("testdata/generic/map_{{X}}_{{Y}}.jpg".into());
+        args.retries = 0;
+        args.incremental = incremental;
+        let name = format!("incremental_{}.tiff", incremental);
+        let tmp_file = TmpFile(&name);
+        args.outfile = Some(tmp_file.to_path_buf());
+        dezoomify(&args).await.expect("Dezooming failed");
+        images.push(image::open(tmp_file.to_path_buf()).unwrap().to_rgb8());
+    }
+    assert_eq!(images[0].dimensions(), (512, 512));
+    assert!(images[0] == images[1], "the incremental tiff should have the pixels of the assembled one");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[allow(clippy::field_reassign_with_default)]
+pub async fn pff_servlet() {
+    // A 512x512 image in two levels: four 256x256 tiles, and the whole image in a single tile.
+    // With no jpeg header table, the tiles start right after the 0x424 bytes of the file header
+    // and the 8 bytes of each of the 5 entries of the tile index, at byte 1100.
+    // Each tile is stored in 1000 bytes.
+    let tiles: Vec<Vec<u8>> = ["0_0", "1_0", "0_1", "1_1", "0_0"].iter()
+        .map(|name| std::fs::read(format!("testdata/generic/map_{}.jpg", name)).unwrap())
+        .collect();
+    let server = mock_server(move |request| {
+        let param = |name: &str| -> Option<u64> {
+            let start = request.find(&format!("{}=", name))? + name.len() + 1;
+            request[start..].split(['&', ' ']).next()?.parse().ok()
+        };
+        match param("requesttype") {
+            Some(1) => (200, b"Error=0&newSize=126&reply_data=<PFFHEADER WIDTH=\"512\" HEIGHT=\"512\" \
+                NUMTILES=\"5\" NUMIMAGES=\"1\" HEADERSIZE=\"0\" VERSION=\"106\" TILESIZE=\"256\"/>".to_vec()),
+            Some(2) if param("begin") == Some(1060) && param("end") == Some(1100) =>
+                (200, b"Error=0&newSize=40&reply_data=1100, 1000 2000 3000 4000 5000".to_vec()),
+            Some(0) => match (param("begin"), param("end")) {
+                (Some(begin), Some(end)) if begin >= 1100 && end == begin + 1000 =>
+                    (200, tiles[(begin as usize - 1100) / 1000].clone()),
+                _ => (404, vec![]),
+            },
+            _ => (404, vec![]),
+        }
+    }).await;
+    let mut args: Arguments = Default::default();
+    args.input_uri = Some(format!("{}/servlet?file=image.pff&requestType=1", server));
+    args.largest = true;
+    args.retries = 0;
+    let tmp_file = TmpFile("pff_servlet.png");
+    args.outfile = Some(tmp_file.to_path_buf());
+    dezoomify(&args).await.expect("Dezooming failed");
+    let actual = image::open(tmp_file.to_path_buf()).unwrap();
+    assert_images_equal(actual, image::open("testdata/generic/map_expected.png").unwrap());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[allow(clippy::field_reassign_with_default)]
+pub async fn changed_tiles_are_reported() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    let first = std::fs::read("testdata/generic/map_0_0.jpg").unwrap();
+    let second = std::fs::read("testdata/generic/map_1_0.jpg").unwrap();
+    // The second tile is different every time it is requested
+    let requests = AtomicUsize::new(0);
+    let server = mock_server(move |request| {
+        if request.starts_with("get /tile_0.jpg") { return (200, first.clone()); }
+        match requests.fetch_add(1, Ordering::SeqCst) {
+            0 => (200, first.clone()),
+            _ => (200, second.clone()),
+        }
+    }).await;
+    let dir = tempdir::TempDir::new("dezoomify-rs-verify").unwrap();
+    let yaml = dir.path().join("tiles.yaml");
+    std::fs::write(&yaml, format!(r#"
+url_template: "{server}/tile_{{{{x}}}}.jpg"
+variables:
+  - {{ name: x, from: 0, to: 1 }}
+x_template: "x * 256"
+y_template: "0"
+"#, server = server)).unwrap();
+    let mut args: Arguments = Default::default();
+    args.input_uri = Some(yaml.to_string_lossy().to_string());
+    args.retries = 0;
+    args.verify_sample = Some(100.);
+    let outfile = dir.path().join("verified.png");
+    args.outfile = Some(outfile.clone());
+    match dezoomify(&args).await {
+        Err(ZoomError::TileVerification { changed, checked }) => assert_eq!((changed, checked), (1, 2)),
+        other => panic!("The changed tile should be reported, got {:?}", other),
+    }
+    assert_eq!(image::open(outfile).unwrap().dimensions(), (512, 256));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[allow(clippy::field_reassign_with_default)]
+pub async fn header_with_several_values() {
+    let tile = std::fs::read("testdata/generic/map_0_0.jpg").unwrap();
+    // The server only sends the tile to clients that have both cookies
+    let server = mock_server(move |request| {
+        if request.contains("cookie: session=abc; consent=yes\r\n") { (200, tile.clone()) } else { (403, vec![]) }
+    }).await;
+    let dir = tempdir::TempDir::new("dezoomify-rs-headers").unwrap();
+    let yaml = dir.path().join("tiles.yaml");
+    std::fs::write(&yaml, format!(r#"
+url_template: "{server}/tile_{{{{x}}}}.jpg"
+variables:
+  - {{ name: x, value: 0 }}
+y_template: "0"
+headers:
+  Cookie: [session=abc, consent=yes]
+"#, server = server)).unwrap();
+    let mut args: Arguments = Default::default();
+    args.input_uri = Some(yaml.to_string_lossy().to_string());
+    args.retries = 0;
+    args.outfile = Some(dir.path().join("headers.png"));
+    let saved = dezoomify(&args).await.expect("Dezooming failed");
+    assert_eq!(image::open(saved).unwrap().dimensions(), (256, 256));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[allow(clippy::field_reassign_with_default)]
+pub async fn host_headers_are_sent_to_their_host() {
+    use std::sync::{Arc, Mutex};
+    let tile = std::fs::read("testdata/generic/map_0_0.jpg").unwrap();
+    let received = Arc::new(Mutex::new(vec![]));
+    let received_by_server = Arc::clone(&received);
+    let server = mock_server(move |request| {
+        let header = |name: &str| request.lines()
+            .find_map(|l| l.strip_prefix(name)).unwrap_or_default().to_string();
+        let host = header("host: ");
+        let host = host.split(':').next().unwrap_or_default().to_string();
+        received_by_server.lock().unwrap().push((host, header("referer: ")));
+        (200, tile.clone())
+    }).await;
+    let port = server.rsplit(':').next().unwrap();
+    let dir = tempdir::TempDir::new("dezoomify-rs-host-headers").unwrap();
+    let yaml = dir.path().join("tiles.yaml");
+    // The same server, reached through two host names
+    std::fs::write(&yaml, format!(r#"
+tiles:
+  - {{ url: "http://127.0.0.1:{port}/a.jpg", x: 0, y: 0 }}
+  - {{ url: "http://localhost:{port}/b.jpg", x: 256, y: 0 }}
+headers:
+  Referer: "http://base.example.com/"
+host_headers:
+  127.0.0.1:
+    Referer: "http://a.example.com/viewer"
+"#, port = port)).unwrap();
+    let mut args: Arguments = Default::default();
+    args.input_uri = Some(yaml.to_string_lossy().to_string());
+    args.retries = 0;
+    args.outfile = Some(dir.path().join("host_headers.png"));
+    dezoomify(&args).await.expect("Dezooming failed");
+    let mut received = received.lock().unwrap().clone();
+    received.sort();
+    assert_eq!(received, vec![
+        ("127.0.0.1".to_string(), "http://a.example.com/viewer".to_string()),
+        ("localhost".to_string(), "http://base.example.com/".to_string()),
+    ]);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[allow(clippy::field_reassign_with_default)]
+pub async fn html_template_is_reported() {
+    // A template copied from the address of the viewer, instead of the one of its tiles
+    let server = mock_server(|_| {
+        (200, b"<!DOCTYPE html><html><body>Image viewer</body></html>".to_vec())
+    }).await;
+    let dir = tempdir::TempDir::new("dezoomify-rs-template").unwrap();
+    let mut args: Arguments = Default::default();
+    args.input_uri = Some(format!("{}/viewer?x={{{{X}}}}&y={{{{Y}}}}", server));
+    args.retries = 0;
+    args.strict = true;
+    args.outfile = Some(dir.path().join("template.png"));
+    match dezoomify(&args).await {
+        Err(ZoomError::SuspiciousTemplate { reasons }) => assert!(reasons.contains("HTML page"), "{}", reasons),
+        other => panic!("The HTML template should be reported, got {:?}", other),
+    }
+}
+
+#[cfg(feature = "websocket")]
+#[tokio::test(flavor = "multi_thread")]
+#[allow(clippy::field_reassign_with_default)]
+pub async fn tiles_from_a_websocket() {
+    use std::sync::{Arc, Mutex};
+    use futures::{SinkExt, StreamExt};
+    use image::{ImageBuffer, ImageOutputFormat, Rgb};
+    use tokio_tungstenite::tungstenite::handshake::server::{Request, Response};
+    use tokio_tungstenite::tungstenite::Message;
+    // A server that answers requests such as "x=1&y=0" with a 2x2 tile of a color that depends on its position
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let handshakes = Arc::new(Mutex::new(vec![]));
+    let received = Arc::clone(&handshakes);
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let received = Arc::clone(&received);
+            tokio::spawn(async move {
+                // The type of the error is imposed by the handshake callback of tungstenite
+                #[allow(clippy::result_large_err)]
+                let record_headers = move |request: &Request, response: Response| {
+                    let header = |name| request.headers().get(name).map(|v| v.to_str().unwrap().to_string());
+                    received.lock().unwrap().push((header("x-api-key"), header("user-agent")));
+                    Ok(response)
+                };
+                let mut socket = tokio_tungstenite::accept_hdr_async(stream, record_headers).await.unwrap();
+                while let Some(Ok(Message::Text(request))) = socket.next().await {
+                    let coords: Vec<u8> = request.split('&')
+                        .map(|part| part[2..].parse().unwrap())
+                        .collect();
+                    let color = Rgb([coords[0] * 100, coords[1] * 100, 50]);
+                    let mut tile = vec![];
+                    DynamicImage::ImageRgb8(ImageBuffer::from_pixel(2, 2, color))
+                        .write_to(&mut tile, ImageOutputFormat::Png).unwrap();
+                    socket.send(Message::Text("tile follows".into())).await.unwrap();
+                    socket.send(Message::Binary(tile)).await.unwrap();
+                }
+            });
+        }
+    });
+    let dir = tempdir::TempDir::new("dezoomify-rs-websocket").unwrap();
+    let yaml = dir.path().join("tiles.yaml");
+    std::fs::write(&yaml, format!(r#"
+url_template: "ws://{addr}/tiles#x={{{{x}}}}&y={{{{y}}}}"
+variables:
+  - {{ name: x, from: 0, to: 1 }}
+  - {{ name: y, from: 0, to: 1 }}
+x_template: "x * 2"
+y_template: "y * 2"
+"#, addr = addr)).unwrap();
+    let mut args: Arguments = Default::default();
+    args.input_uri = Some(yaml.to_string_lossy().to_string());
+    args.retries = 0;
+    args.headers = vec![("X-Api-Key".into(), "secret".into())];
+    args.outfile = Some(dir.path().join("websocket.png"));
+    let saved = dezoomify(&args).await.expect("Dezooming failed");
+    let image = image::open(saved).unwrap().to_rgb8();
+    assert_eq!(image.dimensions(), (4, 4));
+    for &(x, y) in &[(0, 0), (1, 0), (0, 1), (1, 1)] {
+        let expected = Rgb([x as u8 * 100, y as u8 * 100, 50]);
+        assert_eq!(image.get_pixel(x * 2 + 1, y * 2), &expected, "tile {},{}", x, y);
+    }
+    // The headers given on the command line, and the default ones, are sent with each handshake
+    let handshakes = handshakes.lock().unwrap();
+    assert_eq!(handshakes.len(), 4);
+    for (api_key, user_agent) in handshakes.iter() {
+        assert_eq!(api_key.as_deref(), Some("secret"));
+        assert!(user_agent.as_deref().unwrap_or_default().starts_with("Mozilla/5.0"), "{:?}", user_agent);
+    }
+}
+
+/// Starts an http server on a random local port, that answers each request
+/// with the status and body computed by `respond` from the lowercased request.
+/// Returns the url of the server.
+#[allow(dead_code)] // Unused in benchmarks
+async fn mock_server<F>(respond: F) -> String
+    where F: Fn(&str) -> (u16, Vec<u8>) + Send + Sync + 'static {
+    raw_mock_server(move |request| {
+        let (status, body) = respond(request);
+        let mut response = format!(
+            "HTTP/1.1 {} Mock\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            status, body.len()
+        ).into_bytes();
+        response.extend(body);
+        response
+    }).await
+}
+
+/// Like `mock_server`, but `respond` computes the raw bytes of the whole response,
+/// and the connection is closed after they are sent.
+#[allow(dead_code)] // Unused in benchmarks
+async fn raw_mock_server<F>(respond: F) -> String
+    where F: Fn(&str) -> Vec<u8> + Send + Sync + 'static {
+    use std::sync::Arc;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    let respond = Arc::new(respond);
+    tokio::spawn(async move {
+        loop {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let respond = Arc::clone(&respond);
+            tokio::spawn(async move {
+                let mut request = Vec::new();
+                let mut buf = [0u8; 1024];
+                while !request.ends_with(b"\r\n\r\n") {
+                    let n = socket.read(&mut buf).await.unwrap();
+                    if n == 0 { return; }
+                    request.extend_from_slice(&buf[..n]);
+                }
+                let response = respond(&String::from_utf8_lossy(&request).to_lowercase());
+                socket.write_all(&response).await.unwrap();
+            });
+        }
+    });
+    url
+}
+
+/// Starts a mock IIIF server whose `/info.json` describes an image of the given size,
+/// made of tiles of 256x256 pixels at the given scale factors.
+/// The other requests are answered by `respond`, from their path and the lowercased request.
+#[allow(dead_code)] // Unused in benchmarks
+async fn iiif_mock_server<F>(width: u32, height: u32, scale_factors: &[u32], respond: F) -> String
+    where F: Fn(&str, &str) -> (u16, Vec<u8>) + Send + Sync + 'static {
+    raw_iiif_mock_server(width, height, scale_factors, move |path, request| {
+        let (status, body) = respond(path, request);
+        let mut response = format!(
+            "HTTP/1.1 {} Mock\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            status, body.len()
+        ).into_bytes();
+        response.extend(body);
+        response
+    }).await
+}
+
+/// Like `iiif_mock_server`, but `respond` computes the raw bytes of the whole response
+#[allow(dead_code)] // Unused in benchmarks
+async fn raw_iiif_mock_server<F>(width: u32, height: u32, scale_factors: &[u32], respond: F) -> String
+    where F: Fn(&str, &str) -> Vec<u8> + Send + Sync + 'static {
+    let scale_factors = format!("{:?}", scale_factors);
+    raw_mock_server(move |request| {
+        let path = request.split_whitespace().nth(1).unwrap_or("");
+        if path != "/info.json" { return respond(path, request); }
+        let host = request.lines().find_map(|l| l.strip_prefix("host: ")).unwrap().trim();
+        let info = format!(r#"{{
+            "@id": "http://{host}/image", "width": {width}, "height": {height},
+            "tiles": [{{ "width": 256, "scaleFactors": {scale_factors} }}]
+        }}"#, host = host, width = width, height = height, scale_factors = scale_factors);
+        format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", info.len(), info).into_bytes()
+    }).await
+}
+
 #[allow(clippy::needless_lifetimes)]
 #[allow(clippy::field_reassign_with_default)]
 pub async fn dezoom_image<'a>(input: &str, expected: &'a str) -> Result<TmpFile<'a>, ZoomError> {